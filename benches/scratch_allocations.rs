@@ -0,0 +1,170 @@
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    sync::atomic::{AtomicU64, Ordering},
+};
+use bio::io::fasta::Record;
+use criterion::{
+    criterion_group, criterion_main,
+    measurement::{Measurement, ValueFormatter},
+    Criterion, Throughput,
+};
+
+use aligned_nearest_neighbor::nearest_neighbor::{pair_counts, ComparisonConfig, NeighborConfig, NeighborSearcher};
+
+
+const NUM_QUERIES: usize = 20;
+const NUM_DB_RECORDS: usize = 2000;
+const ALIGNMENT_LENGTH: usize = 500;
+const TOP_K: usize = 5;
+
+
+/// The system allocator, counting the allocations and reallocations of every thread.
+struct CountingAllocator;
+
+static ALLOCATIONS: AtomicU64 = AtomicU64::new(0);
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        unsafe { System.alloc(layout) }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        unsafe { System.dealloc(ptr, layout) }
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        unsafe { System.realloc(ptr, layout, new_size) }
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAllocator = CountingAllocator;
+
+
+/// A criterion measurement of the number of allocations made by the benchmarked code, rather than its time.
+struct Allocations;
+
+
+impl Measurement for Allocations {
+    type Intermediate = u64;
+    type Value = u64;
+
+    fn start(&self) -> u64 {
+        ALLOCATIONS.load(Ordering::Relaxed)
+    }
+
+    fn end(&self, start: u64) -> u64 {
+        ALLOCATIONS.load(Ordering::Relaxed) - start
+    }
+
+    fn add(&self, v1: &u64, v2: &u64) -> u64 {
+        v1 + v2
+    }
+
+    fn zero(&self) -> u64 {
+        0
+    }
+
+    fn to_f64(&self, value: &u64) -> f64 {
+        *value as f64
+    }
+
+    fn formatter(&self) -> &dyn ValueFormatter {
+        &AllocationFormatter
+    }
+}
+
+
+struct AllocationFormatter;
+
+
+impl ValueFormatter for AllocationFormatter {
+    fn scale_values(&self, _typical_value: f64, _values: &mut [f64]) -> &'static str {
+        "allocs"
+    }
+
+    /// The benchmarks set no throughput, so the counts are left as they are.
+    fn scale_throughputs(&self, _typical_value: f64, _throughput: &Throughput, _values: &mut [f64]) -> &'static str {
+        "allocs"
+    }
+
+    fn scale_for_machines(&self, _values: &mut [f64]) -> &'static str {
+        "allocs"
+    }
+}
+
+
+/// Random aligned records of `ALIGNMENT_LENGTH` columns, drawn from a fixed linear congruential generator so that
+/// every run benchmarks the same alignment.
+fn random_records(prefix: &str, num_records: usize, state: &mut u64) -> Vec<Record> {
+    (0..num_records)
+        .map(|record_idx| {
+            let seq: Vec<u8> = (0..ALIGNMENT_LENGTH)
+                .map(|_| {
+                    *state = state.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+                    b"ACGT-"[(*state >> 33) as usize % 5]
+                })
+                .collect();
+            Record::with_attrs(&format!("{}{}", prefix, record_idx), None, &seq)
+        })
+        .collect()
+}
+
+
+/// The `TOP_K` best records for `query` by a plain scan, scoring them into a buffer allocated for this query, as
+/// the search did before it kept a buffer per thread.
+fn scan_top_k<'a>(query: &Record, db_records: &[&'a Record], config: &ComparisonConfig) -> Vec<(&'a Record, f64)> {
+    let mut scored: Vec<(usize, f64)> = Vec::with_capacity(db_records.len());
+    for (db_idx, other) in db_records.iter().enumerate() {
+        let value = config.value(&pair_counts(query.seq(), other.seq(), config));
+        if !value.is_nan() {
+            scored.push((db_idx, value));
+        }
+    }
+    scored.sort_unstable_by(|a, b| b.1.total_cmp(&a.1).then(b.0.cmp(&a.0)));
+    scored.truncate(TOP_K);
+    scored.into_iter().map(|(db_idx, value)| (db_records[db_idx], value)).collect()
+}
+
+
+/// The top-k search, which scores the database records of each query into the buffer kept by its thread, against
+/// the same scan allocating a buffer per query: the search makes one allocation per query, for its result, where
+/// the scan makes two. Both run on the benchmark's thread. Run with `cargo bench --bench scratch_allocations`.
+fn bench_scratch<M: Measurement>(c: &mut Criterion<M>, group_name: &str) {
+    let mut state: u64 = 42;
+    let queries = random_records("q", NUM_QUERIES, &mut state);
+    let db = random_records("d", NUM_DB_RECORDS, &mut state);
+    let db_refs: Vec<&Record> = db.iter().collect();
+    let config = NeighborConfig { top_k: TOP_K, ..Default::default() };
+    let searcher = NeighborSearcher::build(&db_refs, &config).unwrap();
+
+    let mut group = c.benchmark_group(group_name);
+    group.bench_function("searcher_thread_scratch", |b| {
+        b.iter(|| queries.iter().map(|query| searcher.top_k(query).unwrap().len()).sum::<usize>())
+    });
+    group.bench_function("scan_buffer_per_query", |b| {
+        b.iter(|| queries.iter().map(|query| scan_top_k(query, &db_refs, &config.comparison).len()).sum::<usize>())
+    });
+    group.finish();
+}
+
+
+fn bench_scratch_allocations(c: &mut Criterion<Allocations>) {
+    bench_scratch(c, "top_k_allocations");
+}
+
+
+fn bench_scratch_time(c: &mut Criterion) {
+    bench_scratch(c, "top_k_time");
+}
+
+
+criterion_group! {
+    name = allocations;
+    config = Criterion::default().with_measurement(Allocations);
+    targets = bench_scratch_allocations
+}
+criterion_group!(time, bench_scratch_time);
+criterion_main!(allocations, time);
@@ -0,0 +1,72 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use bio::io::fasta::Record;
+use criterion::{criterion_group, criterion_main, Criterion};
+
+use aligned_nearest_neighbor::nearest_neighbor::{pair_counts, ComparisonConfig, NeighborConfig, NeighborSearcher};
+
+
+const NUM_QUERIES: usize = 20;
+const NUM_DB_RECORDS: usize = 2000;
+const ALIGNMENT_LENGTH: usize = 500;
+
+
+/// Random aligned records of `ALIGNMENT_LENGTH` columns, drawn from a fixed linear congruential generator so that
+/// every run benchmarks the same alignment.
+fn random_records(prefix: &str, num_records: usize, state: &mut u64) -> Vec<Record> {
+    (0..num_records)
+        .map(|record_idx| {
+            let seq: Vec<u8> = (0..ALIGNMENT_LENGTH)
+                .map(|_| {
+                    *state = state.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+                    b"ACGT-"[(*state >> 33) as usize % 5]
+                })
+                .collect();
+            Record::with_attrs(&format!("{}{}", prefix, record_idx), None, &seq)
+        })
+        .collect()
+}
+
+
+/// The nearest neighbor of `query` by a plain scan, updating `counter` once per pair if given.
+fn scan_nearest<'a>(query: &Record, db_records: &[&'a Record], config: &ComparisonConfig, counter: Option<&AtomicU64>) -> (&'a Record, f64) {
+    let mut best: (&Record, f64) = (db_records[0], f64::NEG_INFINITY);
+    for other in db_records.iter().rev() {
+        let value = config.value(&pair_counts(query.seq(), other.seq(), config));
+        if let Some(counter) = counter {
+            counter.fetch_add(1, Ordering::Relaxed);
+        }
+        if value > best.1 {
+            best = (other, value);
+        }
+    }
+    best
+}
+
+
+/// The cost of counting the compared pairs: the search, which updates its counters once per query, against the
+/// same scan without any counter and with an atomic update per pair. Run with `cargo bench --bench search_counters`.
+fn bench_search_counters(c: &mut Criterion) {
+    let mut state: u64 = 42;
+    let queries = random_records("q", NUM_QUERIES, &mut state);
+    let db = random_records("d", NUM_DB_RECORDS, &mut state);
+    let db_refs: Vec<&Record> = db.iter().collect();
+    let config = NeighborConfig::default();
+    let searcher = NeighborSearcher::build(&db_refs, &config).unwrap();
+
+    let mut group = c.benchmark_group("nearest");
+    group.bench_function("searcher_counted_per_query", |b| {
+        b.iter(|| queries.iter().map(|query| searcher.nearest(query).unwrap().1).sum::<f64>())
+    });
+    group.bench_function("scan_without_counters", |b| {
+        b.iter(|| queries.iter().map(|query| scan_nearest(query, &db_refs, &config.comparison, None).1).sum::<f64>())
+    });
+    let counter = AtomicU64::new(0);
+    group.bench_function("scan_counted_per_pair", |b| {
+        b.iter(|| queries.iter().map(|query| scan_nearest(query, &db_refs, &config.comparison, Some(&counter)).1).sum::<f64>())
+    });
+    group.finish();
+}
+
+
+criterion_group!(benches, bench_search_counters);
+criterion_main!(benches);
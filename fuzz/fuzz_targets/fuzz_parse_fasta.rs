@@ -0,0 +1,15 @@
+#![no_main]
+
+use std::io::Cursor;
+use libfuzzer_sys::fuzz_target;
+
+use aligned_nearest_neighbor::parse_all_records_from_reader;
+
+
+// Arbitrary bytes must parse into aligned records or an error, never panic.
+fuzz_target!(|data: &[u8]| {
+    if let Ok(records) = parse_all_records_from_reader(Cursor::new(data)) {
+        assert!(!records.is_empty());
+        assert!(records.iter().all(|record| record.seq().len() == records[0].seq().len()));
+    }
+});
@@ -0,0 +1,22 @@
+#![no_main]
+
+use bio::io::fasta::Record;
+use libfuzzer_sys::fuzz_target;
+
+use aligned_nearest_neighbor::nearest_neighbor::pct_identity;
+
+
+// Each pair of input bytes is one alignment column. The identity of any two equal-length records,
+// all-gap ones included, is a fraction or NaN, and one more residue makes the lengths differ.
+fuzz_target!(|data: &[u8]| {
+    let x: Vec<u8> = data.chunks_exact(2).map(|column| column[0]).collect();
+    let y: Vec<u8> = data.chunks_exact(2).map(|column| column[1]).collect();
+    let x = Record::with_attrs("x", None, &x);
+    let y_record = Record::with_attrs("y", None, &y);
+
+    let identity = pct_identity(&x, &y_record).expect("Records of equal length can be compared");
+    assert!(identity.is_nan() || (0.0..=1.0).contains(&identity));
+
+    let longer = Record::with_attrs("y", None, &[y.as_slice(), b"A"].concat());
+    assert!(pct_identity(&x, &longer).is_err());
+});
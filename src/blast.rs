@@ -0,0 +1,154 @@
+use std::io::Write;
+
+use crate::{
+    nearest_neighbor::{pair_counts, ComparisonConfig, NearestNeighborError},
+    output::NearestNeighborResultRow,
+};
+
+
+/// The 12 standard columns of BLAST tabular output (`-outfmt 6`), in order.
+pub const BLAST6_COLUMNS: [&str; 12] = [
+    "qseqid", "sseqid", "pident", "length", "mismatch", "gapopen", "qstart", "qend", "sstart", "send", "evalue", "bitscore",
+];
+
+
+/// The BLAST-like description of an aligned pair, see [`blast_hit`].
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct BlastHit {
+    /// The identity of the pair with the search's comparison options, whatever the search metric.
+    pub identity: f64,
+    /// The number of compared columns, rounded, see [`crate::nearest_neighbor::PairCounts::compared`].
+    pub length: usize,
+    /// The number of compared columns holding two different residues, rounded.
+    pub mismatches: usize,
+    /// The number of runs of columns where the same one of the two sequences is gapped. Columns gapped
+    /// in both sequences don't interrupt a run.
+    pub gap_opens: usize,
+    /// The 1-based ungapped coordinates, in each sequence, of the first and last alignment columns where
+    /// both sequences hold a residue. All 0 if there is no such column.
+    pub query_start: usize,
+    pub query_end: usize,
+    pub subject_start: usize,
+    pub subject_end: usize,
+}
+
+
+/// Describe an aligned pair of byte sequences of equal length as a BLAST hit.
+///
+/// The identity, length and mismatches come from the pair's counts, so they cover the whole alignment,
+/// terminal gaps included, as the search does. The coordinates span the columns where both sequences
+/// hold a residue, counting each sequence's residues from its first column.
+pub fn blast_hit(query: &[u8], subject: &[u8], config: &ComparisonConfig) -> BlastHit {
+    let counts = pair_counts(query, subject, config);
+    let mut hit = BlastHit {
+        identity: counts.identity(),
+        length: counts.compared.round() as usize,
+        mismatches: (counts.compared - counts.matches - counts.single_gaps).round() as usize,
+        ..Default::default()
+    };
+
+    // The number of residues of each sequence up to the current column, included.
+    let (mut query_pos, mut subject_pos) = (0usize, 0usize);
+    // Whether the query (true) or the subject (false) is gapped in the current gap run, if any.
+    let mut gap_run: Option<bool> = None;
+    for (q, s) in query.iter().zip(subject.iter()) {
+        let (q_gap, s_gap) = (config.gap_chars.contains(*q), config.gap_chars.contains(*s));
+        query_pos += !q_gap as usize;
+        subject_pos += !s_gap as usize;
+        match (q_gap, s_gap) {
+            (true, true) => {}
+            (false, false) => {
+                gap_run = None;
+                if hit.query_start == 0 {
+                    (hit.query_start, hit.subject_start) = (query_pos, subject_pos);
+                }
+                (hit.query_end, hit.subject_end) = (query_pos, subject_pos);
+            }
+            _ => {
+                if gap_run != Some(q_gap) {
+                    hit.gap_opens += 1;
+                    gap_run = Some(q_gap);
+                }
+            }
+        }
+    }
+    hit
+}
+
+
+/// Write the rows as BLAST tabular output (`-outfmt 6`): the [`BLAST6_COLUMNS`], without a header, so that
+/// tools reading BLAST hits can read the neighbors.
+///
+/// The pairs aren't local alignments with a statistical model, so two columns are placeholders: the
+/// `evalue` is always `0.0`, and the `bitscore` is the `pident`, so that sorting the hits by bit score
+/// ranks them by identity. The `pident` and `bitscore` have `precision` decimal places.
+///
+/// Fails on a row without its [`NearestNeighborResultRow::blast`] hit.
+pub fn write_blast6<W: Write>(
+    rows: &[NearestNeighborResultRow],
+    writer: &mut W,
+    precision: usize,
+) -> Result<(), NearestNeighborError> {
+    for row in rows.iter() {
+        let hit = row.blast.as_ref().ok_or_else(|| NearestNeighborError::InvalidInput(format!(
+            "No BLAST hit was computed for {} and {}", row.query_id, row.neighbor_id,
+        )))?;
+        let pident = 100.0 * hit.identity;
+        writeln!(
+            writer, "{}\t{}\t{:.*}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t0.0\t{:.*}",
+            row.query_id, row.neighbor_id, precision, pident, hit.length, hit.mismatches, hit.gap_opens,
+            hit.query_start, hit.query_end, hit.subject_start, hit.subject_end, precision, pident,
+        )?;
+    }
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+    use crate::{
+        nearest_neighbor::{compute_store_nearest_neighbors, ComparisonConfig, NeighborConfig},
+        output::{OutputConfig, OutputFormat},
+        parse_all_records,
+    };
+    use super::{blast_hit, BlastHit};
+
+    #[test]
+    fn test_blast_hit() {
+        let hit = blast_hit(b"--ACGTACGT--", b"TTACG-ACCTGG", &ComparisonConfig::default());
+        assert_eq!(hit, BlastHit {
+            identity: 0.5,
+            length: 12,
+            mismatches: 1,
+            gap_opens: 3,
+            query_start: 1,
+            query_end: 8,
+            subject_start: 3,
+            subject_end: 9,
+        });
+        // A double gap doesn't interrupt a gap run, and a pair without a shared residue has no coordinates.
+        let hit = blast_hit(b"AC--", b"--G-", &ComparisonConfig::default());
+        assert_eq!((hit.gap_opens, hit.query_start, hit.subject_end), (2, 0, 0));
+    }
+
+    #[test]
+    fn test_blast6_output() {
+        let records = parse_all_records(PathBuf::from("tests/inputs/blast6/seqs.fasta")).unwrap();
+        let out_path = std::env::temp_dir().join("aligned_nearest_neighbor_test_blast6.tsv");
+        let output_config = OutputConfig { format: OutputFormat::Blast6, precision: 2, ..Default::default() };
+        compute_store_nearest_neighbors(
+            records,
+            &out_path,
+            Some(vec!["q1".to_owned(), "q2".to_owned()]),
+            Some(vec!["s1".to_owned(), "s2".to_owned()]),
+            &NeighborConfig::default(),
+            &output_config,
+        ).unwrap();
+        assert_eq!(
+            std::fs::read_to_string(&out_path).unwrap(),
+            std::fs::read_to_string("tests/inputs/blast6/expected.tsv").unwrap(),
+        );
+        std::fs::remove_file(&out_path).unwrap();
+    }
+}
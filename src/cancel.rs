@@ -0,0 +1,36 @@
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+};
+
+
+/// A flag shared with a running search, to stop it early, e.g. on Ctrl-C.
+///
+/// Workers check it between queries, so a cancelled search stops within one query's worth of work
+/// per thread. Clones share the same flag.
+#[derive(Debug, Clone, Default)]
+pub struct CancellationToken {
+    cancelled: Arc<AtomicBool>,
+}
+
+
+impl CancellationToken {
+    pub fn new() -> Self {
+        CancellationToken::default()
+    }
+
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Relaxed)
+    }
+}
+
+
+/// Keep the results of the queries completed before the first one skipped by a cancellation,
+/// so that partial output is always a prefix of the full output.
+pub fn completed_prefix<T>(results: Vec<Option<T>>) -> Vec<T> {
+    results.into_iter().map_while(std::convert::identity).collect()
+}
@@ -0,0 +1,228 @@
+use std::{
+    fs::{File, OpenOptions},
+    io::{BufWriter, Seek, SeekFrom, Write},
+    path::Path,
+};
+use bio::io::fasta::Record;
+
+use crate::{
+    columns::consensus_record,
+    nearest_neighbor::{filter_records, neighbor_rows, NearestNeighborError, NeighborConfig, RecordSelector},
+    output::{temp_path, write_results_tsv, OutputConfig},
+};
+
+
+/// The default number of queries between two checkpoints.
+pub const DEFAULT_CHECKPOINT_INTERVAL: usize = 1000;
+
+
+/// The progress of a checkpointed search: the queries are searched in order, and the results of the
+/// first `completed_queries` are the first `output_bytes` bytes of the TSV output.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Checkpoint {
+    /// The number of queries of the search, to detect a checkpoint of another search.
+    pub num_queries: usize,
+    pub completed_queries: usize,
+    pub output_bytes: u64,
+}
+
+
+impl Checkpoint {
+    /// The checkpoint as a single-line JSON object.
+    pub fn to_json(&self) -> String {
+        format!(
+            "{{\"num_queries\": {}, \"completed_queries\": {}, \"output_bytes\": {}}}",
+            self.num_queries, self.completed_queries, self.output_bytes,
+        )
+    }
+
+    /// Parse a checkpoint written by [`Checkpoint::to_json`].
+    pub fn from_json(json: &str) -> Result<Checkpoint, NearestNeighborError> {
+        let field = |key: &str| -> Result<u64, NearestNeighborError> {
+            let pattern = format!("\"{}\":", key);
+            json.find(&pattern)
+                .map(|start| json[start + pattern.len()..].trim_start())
+                .and_then(|rest| rest[..rest.find(|ch: char| !ch.is_ascii_digit()).unwrap_or(rest.len())].parse().ok())
+                .ok_or_else(|| NearestNeighborError::InvalidInput(format!("Invalid checkpoint: missing {}", key)))
+        };
+        Ok(Checkpoint {
+            num_queries: field("num_queries")? as usize,
+            completed_queries: field("completed_queries")? as usize,
+            output_bytes: field("output_bytes")?,
+        })
+    }
+
+    /// Read the checkpoint at `path`, or `None` if there is no such file.
+    pub fn read(path: &Path) -> Result<Option<Checkpoint>, NearestNeighborError> {
+        match std::fs::read_to_string(path) {
+            Ok(json) => Checkpoint::from_json(&json).map(Some),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Write the checkpoint to `path` atomically: to `<path>.tmp` first, then renamed over `path`,
+    /// so that an interruption leaves either the previous or the new checkpoint.
+    pub fn write_atomic(&self, path: &Path) -> Result<(), NearestNeighborError> {
+        let tmp_path = temp_path(path);
+        let mut file = File::create(&tmp_path)?;
+        writeln!(file, "{}", self.to_json())?;
+        file.sync_all()?;
+        std::fs::rename(&tmp_path, path)?;
+        Ok(())
+    }
+}
+
+
+/// Compute all nearest neighbors as [`crate::nearest_neighbor::compute_store_nearest_neighbors`] does, but
+/// in batches of `interval` queries, appending each batch to the TSV output and recording it in a checkpoint.
+///
+/// If `checkpoint_path` holds the checkpoint of an interrupted run of the same search, the output is cut
+/// back to the checkpointed results, and the search resumes after the completed queries. The checkpoint
+/// is removed once all queries are done. Invariant columns are never dropped, so
+/// [`NeighborConfig::compress_invariant`] is ignored; the identities are the same.
+///
+/// # Arguments
+///
+/// * `records` - The records, as for `compute_store_nearest_neighbors`.
+/// * `out_path` - The TSV output.
+/// * `query_ids`, `db_ids` - The optional query and database subsets.
+/// * `config`, `output_config` - The search and output options. Only the TSV format is supported.
+/// * `checkpoint_path` - Where to keep the checkpoint.
+/// * `interval` - The number of queries between two checkpoints.
+#[allow(clippy::too_many_arguments)]
+pub fn compute_store_nearest_neighbors_checkpointed(
+    records: Vec<Record>,
+    out_path: &Path,
+    query_ids: Option<Vec<String>>,
+    db_ids: Option<Vec<String>>,
+    config: &NeighborConfig,
+    output_config: &OutputConfig,
+    checkpoint_path: &Path,
+    interval: usize,
+) -> Result<(), NearestNeighborError> {
+    run_checkpointed(
+        records, out_path, query_ids, db_ids, config, output_config, checkpoint_path, interval, &mut |_| {},
+    )
+}
+
+
+/// The batch loop of [`compute_store_nearest_neighbors_checkpointed`], calling `on_checkpoint` after each
+/// checkpoint is written.
+#[allow(clippy::too_many_arguments)]
+fn run_checkpointed(
+    records: Vec<Record>,
+    out_path: &Path,
+    query_ids: Option<Vec<String>>,
+    db_ids: Option<Vec<String>>,
+    config: &NeighborConfig,
+    output_config: &OutputConfig,
+    checkpoint_path: &Path,
+    interval: usize,
+    on_checkpoint: &mut dyn FnMut(&Checkpoint),
+) -> Result<(), NearestNeighborError> {
+    let query_records: Vec<&Record> = config.select_queries(filter_records(&records, &RecordSelector::ids(query_ids.as_deref())));
+    let mut db_records: Vec<&Record> =
+        config.select_database(filter_records(&records, &RecordSelector::ids(db_ids.as_deref())));
+    let consensus: Option<Record> = config.include_consensus.then(|| consensus_record(&db_records));
+    if let Some(consensus) = &consensus {
+        db_records.push(consensus);
+    }
+
+    let mut checkpoint = match Checkpoint::read(checkpoint_path)? {
+        Some(checkpoint) if checkpoint.num_queries != query_records.len() => {
+            return Err(NearestNeighborError::InvalidInput(format!(
+                "The checkpoint {} is for a search of {} queries, not {}",
+                checkpoint_path.display(), checkpoint.num_queries, query_records.len(),
+            )));
+        }
+        Some(checkpoint) => {
+            tracing::info!(
+                "Resuming from {}: {} of {} queries were completed",
+                checkpoint_path.display(), checkpoint.completed_queries, checkpoint.num_queries,
+            );
+            checkpoint
+        }
+        None => Checkpoint { num_queries: query_records.len(), completed_queries: 0, output_bytes: 0 },
+    };
+
+    // Drop any result written after the last checkpoint.
+    let mut file = OpenOptions::new().write(true).create(true).truncate(false).open(out_path)?;
+    file.set_len(checkpoint.output_bytes)?;
+    file.seek(SeekFrom::End(0))?;
+    let mut writer = BufWriter::new(file);
+
+    for batch in query_records[checkpoint.completed_queries..].chunks(interval.max(1)) {
+        let (rows, num_completed) = neighbor_rows(batch, &db_records, batch, &db_records, config, output_config)?;
+        write_results_tsv(&rows, &mut writer, output_config)?;
+        writer.flush()?;
+        writer.get_ref().sync_data()?;
+        checkpoint.completed_queries += num_completed;
+        checkpoint.output_bytes = writer.get_ref().metadata()?.len();
+        checkpoint.write_atomic(checkpoint_path)?;
+        on_checkpoint(&checkpoint);
+        if num_completed < batch.len() {
+            return Err(NearestNeighborError::Cancelled(checkpoint.completed_queries));
+        }
+    }
+    match std::fs::remove_file(checkpoint_path) {
+        Err(err) if err.kind() != std::io::ErrorKind::NotFound => Err(err.into()),
+        _ => Ok(()),
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        fs::OpenOptions,
+        io::Write,
+        panic::{catch_unwind, AssertUnwindSafe},
+        path::PathBuf,
+    };
+    use crate::{
+        nearest_neighbor::{compute_store_nearest_neighbors, NeighborConfig},
+        output::OutputConfig,
+        parse_all_records,
+    };
+    use super::{run_checkpointed, Checkpoint};
+
+    #[test]
+    fn test_checkpoint_json() {
+        let checkpoint = Checkpoint { num_queries: 10, completed_queries: 4, output_bytes: 123 };
+        assert_eq!(checkpoint.to_json(), "{\"num_queries\": 10, \"completed_queries\": 4, \"output_bytes\": 123}");
+        assert_eq!(Checkpoint::from_json(&checkpoint.to_json()).unwrap(), checkpoint);
+        assert!(Checkpoint::from_json("{\"num_queries\": 10}").is_err());
+    }
+
+    #[test]
+    fn test_resume_after_interruption() {
+        let records = parse_all_records(PathBuf::from("tests/inputs/query_db/seqs.fasta")).unwrap();
+        assert!(records.len() > 2);
+        let out_dir = std::env::temp_dir().join("aligned_nearest_neighbor_test_checkpoint");
+        std::fs::create_dir_all(&out_dir).unwrap();
+        let (config, output_config) = (NeighborConfig::default(), OutputConfig::default());
+
+        let expected_path = out_dir.join("expected.tsv");
+        compute_store_nearest_neighbors(records.clone(), &expected_path, None, None, &config, &output_config).unwrap();
+
+        // Panic right after the first checkpoint, leaving a partly written row behind it.
+        let (out_path, checkpoint_path) = (out_dir.join("out.tsv"), out_dir.join("out.checkpoint"));
+        let interrupted = catch_unwind(AssertUnwindSafe(|| {
+            run_checkpointed(
+                records.clone(), &out_path, None, None, &config, &output_config, &checkpoint_path, 1,
+                &mut |checkpoint| {
+                    OpenOptions::new().append(true).open(&out_path).unwrap().write_all(b"partial\t").unwrap();
+                    panic!("Interrupted after {} queries", checkpoint.completed_queries);
+                },
+            )
+        }));
+        assert!(interrupted.is_err());
+        assert_eq!(Checkpoint::read(&checkpoint_path).unwrap().unwrap().completed_queries, 1);
+
+        run_checkpointed(records, &out_path, None, None, &config, &output_config, &checkpoint_path, 1, &mut |_| {}).unwrap();
+        assert_eq!(std::fs::read_to_string(&out_path).unwrap(), std::fs::read_to_string(&expected_path).unwrap());
+        assert!(!checkpoint_path.exists());
+        std::fs::remove_dir_all(&out_dir).unwrap();
+    }
+}
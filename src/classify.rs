@@ -0,0 +1,179 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    io::Write,
+};
+use bio::io::fasta::Record;
+
+use crate::nearest_neighbor::NearestNeighborError;
+
+
+/// The label assigned to queries that can't be classified.
+pub const UNCLASSIFIED: &str = "unclassified";
+
+
+/// The outcome of a k-NN vote for a single query.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Classification {
+    pub query_id: String,
+    /// The nearest neighbor, and its identity to the query.
+    pub best_neighbor_id: String,
+    pub best_identity: f64,
+    /// The winning label, or [`UNCLASSIFIED`].
+    pub predicted_label: String,
+    /// Number of labeled neighbors voting for each label.
+    pub votes: BTreeMap<String, usize>,
+    /// Mean identity of the neighbors voting for the predicted label (NaN if unclassified).
+    pub mean_identity: f64,
+}
+
+
+/// Classify a query by majority vote over the labels of its nearest neighbors.
+///
+/// Neighbors without a label don't vote. Ties in the vote count are broken by the larger summed
+/// identity of the supporting neighbors, then by the lexicographically smallest label, so the
+/// outcome only depends on the neighbor list and never on thread scheduling.
+/// The query is [`UNCLASSIFIED`] if no neighbor is labeled, or if the best identity is below
+/// `min_identity`.
+///
+/// # Arguments
+///
+/// * `query` - The query Fasta record.
+/// * `neighbors` - The query's neighbors, sorted from best to worst.
+/// * `labels` - The record ID to label mapping.
+/// * `min_identity` - An optional lower bound for the best identity.
+pub fn classify_query(
+    query: &Record,
+    neighbors: &[(&Record, f64)],
+    labels: &HashMap<String, String>,
+    min_identity: Option<f64>,
+) -> Classification {
+    let (best_neighbor_id, best_identity) = match neighbors.first() {
+        Some((neighbor, idty)) => (neighbor.id().to_owned(), *idty),
+        None => (String::new(), f64::NAN),
+    };
+
+    // label -> (votes, summed identity)
+    let mut tally: BTreeMap<String, (usize, f64)> = BTreeMap::new();
+    for (neighbor, idty) in neighbors.iter() {
+        if let Some(label) = labels.get(neighbor.id()) {
+            let entry = tally.entry(label.clone()).or_insert((0, 0.0));
+            entry.0 += 1;
+            entry.1 += idty;
+        }
+    }
+
+    let below_threshold = match min_identity {
+        Some(min_idty) => best_identity.is_nan() || best_identity < min_idty,
+        None => false,
+    };
+    let mut winner: Option<(&String, usize, f64)> = None;
+    if !below_threshold {
+        // BTreeMap iterates labels in ascending order, so a strict comparison keeps the smallest label on ties.
+        for (label, (count, idty_sum)) in tally.iter() {
+            let is_better = match winner {
+                None => true,
+                Some((_, best_count, best_sum)) => {
+                    *count > best_count || (*count == best_count && *idty_sum > best_sum)
+                }
+            };
+            if is_better {
+                winner = Some((label, *count, *idty_sum));
+            }
+        }
+    }
+
+    let (predicted_label, mean_identity) = match winner {
+        Some((label, count, idty_sum)) => (label.clone(), idty_sum / (count as f64)),
+        None => (UNCLASSIFIED.to_owned(), f64::NAN),
+    };
+    Classification {
+        query_id: query.id().to_owned(),
+        best_neighbor_id,
+        best_identity,
+        predicted_label,
+        votes: tally.into_iter().map(|(label, (count, _))| (label, count)).collect(),
+        mean_identity,
+    }
+}
+
+
+/// Write classifications as
+/// `query_id, neighbor_id, identity, predicted_label, votes, mean_identity` TSV rows,
+/// where votes are formatted as `label:count` pairs separated by commas, and identities have `precision`
+/// decimal places.
+pub fn write_classifications<W: Write>(
+    results: &[Classification],
+    writer: &mut W,
+    precision: usize,
+) -> Result<(), NearestNeighborError> {
+    for result in results.iter() {
+        let votes: Vec<String> = result.votes.iter()
+            .map(|(label, count)| format!("{}:{}", label, count))
+            .collect();
+        writeln!(
+            writer, "{}\t{}\t{:.*}\t{}\t{}\t{:.*}",
+            result.query_id,
+            result.best_neighbor_id,
+            precision,
+            result.best_identity,
+            result.predicted_label,
+            votes.join(","),
+            precision,
+            result.mean_identity,
+        )?;
+    }
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use bio::io::fasta::Record;
+    use super::{classify_query, UNCLASSIFIED};
+
+    fn to_labels(pairs: &[(&str, &str)]) -> HashMap<String, String> {
+        pairs.iter().map(|(id, label)| (id.to_string(), label.to_string())).collect()
+    }
+
+    #[test]
+    fn test_classify_tie_broken_by_identity() {
+        let query = Record::with_attrs("q", None, b"AAAA");
+        let a1 = Record::with_attrs("a1", None, b"AAAA");
+        let b1 = Record::with_attrs("b1", None, b"AAAC");
+        let b2 = Record::with_attrs("b2", None, b"AACC");
+        let a2 = Record::with_attrs("a2", None, b"ACCC");
+        let labels = to_labels(&[("a1", "A"), ("a2", "A"), ("b1", "B"), ("b2", "B")]);
+        let neighbors = [(&a1, 1.0), (&b1, 0.75), (&b2, 0.5), (&a2, 0.25)];
+
+        // Two votes each; A sums to 1.25 and B to 1.25 as well, so the smallest label wins.
+        let result = classify_query(&query, &neighbors, &labels, None);
+        assert_eq!(result.predicted_label, "A");
+        assert_eq!(result.votes["A"], 2);
+        assert_eq!(result.votes["B"], 2);
+
+        // With the first three only, B wins the vote despite A holding the best hit.
+        let result = classify_query(&query, &neighbors[..3], &labels, None);
+        assert_eq!(result.predicted_label, "B");
+        assert_eq!(result.mean_identity, 0.625);
+
+        // One vote each, so the larger identity wins regardless of label order.
+        let result = classify_query(&query, &[(&b1, 0.75), (&a2, 0.25)], &labels, None);
+        assert_eq!(result.predicted_label, "B");
+        assert_eq!(result.best_neighbor_id, "b1");
+    }
+
+    #[test]
+    fn test_classify_unclassified() {
+        let query = Record::with_attrs("q", None, b"AAAA");
+        let a1 = Record::with_attrs("a1", None, b"AACC");
+        let labels = to_labels(&[("a1", "A")]);
+
+        let result = classify_query(&query, &[(&a1, 0.5)], &labels, Some(0.9));
+        assert_eq!(result.predicted_label, UNCLASSIFIED);
+        assert!(result.mean_identity.is_nan());
+
+        let result = classify_query(&query, &[(&a1, 0.5)], &HashMap::new(), None);
+        assert_eq!(result.predicted_label, UNCLASSIFIED);
+    }
+}
@@ -0,0 +1,98 @@
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{BufRead, BufReader},
+    path::Path,
+};
+use bio::io::fasta::Record;
+
+use crate::{check_record_lengths, FastaParseError, FastaParseErrorKind};
+
+
+const CLUSTAL_HEADER: &str = "CLUSTAL";
+
+
+/// Parse an aligned Clustal file (`.aln`, as written by ClustalW and Clustal Omega) into Fasta records.
+///
+/// The first non-empty line must be the `CLUSTAL` header. Conservation lines, which start with
+/// whitespace, are skipped. Multi-block files are supported by concatenating the rows of each ID in order.
+/// The optional residue count ending a sequence row must be the number of non-gap characters of its
+/// record up to the end of the row.
+pub fn parse_all_records_clustal(path: &Path) -> Result<Vec<Record>, FastaParseError> {
+    let file = File::open(path)?;
+    let reader = BufReader::new(file);
+
+    let mut saw_header = false;
+    let mut ids: Vec<String> = vec![];
+    let mut seqs: HashMap<String, Vec<u8>> = HashMap::new();
+    for (line_idx, line) in reader.lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        if !saw_header {
+            if !line.starts_with(CLUSTAL_HEADER) {
+                return Err(FastaParseError {
+                    message: format!("Missing '{}' header on line {}", CLUSTAL_HEADER, line_idx + 1),
+                    kind: FastaParseErrorKind::FormatError,
+                    source: None,
+                });
+            }
+            saw_header = true;
+            continue;
+        }
+        if line.starts_with(char::is_whitespace) {
+            continue;
+        }
+
+        let mut fields = line.split_whitespace();
+        let (id, seq, count) = match (fields.next(), fields.next(), fields.next(), fields.next()) {
+            (Some(id), Some(seq), None, None) => (id, seq, None),
+            (Some(id), Some(seq), Some(count), None) if count.parse::<usize>().is_ok() => (id, seq, count.parse().ok()),
+            _ => {
+                return Err(FastaParseError {
+                    message: format!("Expected '<id> <sequence> [<count>]' on line {}", line_idx + 1),
+                    kind: FastaParseErrorKind::FormatError,
+                    source: None,
+                });
+            }
+        };
+        let record_seq = seqs.entry(id.to_owned())
+            .or_insert_with(|| {
+                ids.push(id.to_owned());
+                vec![]
+            });
+        record_seq.extend_from_slice(seq.as_bytes());
+        if let Some(count) = count {
+            let num_residues = record_seq.iter().filter(|b| **b != b'-' && **b != b'.').count();
+            if num_residues != count {
+                return Err(FastaParseError {
+                    message: format!(
+                        "Record {} has {} residues up to line {}, but the line counts {}",
+                        id, num_residues, line_idx + 1, count,
+                    ),
+                    kind: FastaParseErrorKind::LengthMismatch,
+                    source: None,
+                });
+            }
+        }
+    }
+
+    let records: Vec<Record> = ids.iter()
+        .map(|id| Record::with_attrs(id, None, &seqs[id]))
+        .collect();
+    if let Some(first) = records.first()
+        && let Some(other) = records.iter().find(|record| record.seq().len() != first.seq().len())
+    {
+        return Err(FastaParseError {
+            message: format!(
+                "Record {} has length {}, but record {} has length {}",
+                other.id(), other.seq().len(), first.id(), first.seq().len(),
+            ),
+            kind: FastaParseErrorKind::LengthMismatch,
+            source: None,
+        });
+    }
+    check_record_lengths(&records)?;
+    Ok(records)
+}
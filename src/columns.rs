@@ -0,0 +1,505 @@
+use std::{io::Write, path::Path};
+use rayon::prelude::*;
+use bio::io::fasta::Record;
+
+use crate::nearest_neighbor::{is_unknown_residue, NearestNeighborError, GAP};
+
+
+/// The number of columns tallied together by one worker. Each block is traversed record by record,
+/// so that reads stay sequential within each sequence even on very wide alignments.
+const COLUMN_BLOCK_SIZE: usize = 1024;
+
+/// The record ID given to a consensus sequence added to the database.
+pub const CONSENSUS_ID: &str = "consensus";
+
+/// The character emitted by the consensus when the majority residue is below the threshold.
+const CONSENSUS_AMBIGUOUS: u8 = b'N';
+
+
+/// Summary of the characters found in a single alignment column.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct ColumnStats {
+    /// Number of gaps in the column.
+    pub num_gaps: usize,
+    /// Number of non-gap characters in the column.
+    pub num_residues: usize,
+    /// The most frequent non-gap character, ties broken by the smallest byte. A gap for all-gap columns.
+    pub majority_residue: u8,
+    /// Number of occurrences of the majority residue.
+    pub majority_count: usize,
+    /// Shannon entropy (in bits) of the non-gap characters. Zero for all-gap columns.
+    pub entropy: f32,
+}
+
+
+impl ColumnStats {
+    fn from_counts(counts: &[usize; 256]) -> ColumnStats {
+        let mut stats = ColumnStats {
+            num_gaps: counts[GAP as usize],
+            majority_residue: GAP,
+            ..Default::default()
+        };
+        // Ascending iteration with a strict comparison keeps the smallest byte on ties.
+        for (ch, count) in counts.iter().enumerate() {
+            if ch as u8 == GAP {
+                continue;
+            }
+            stats.num_residues += *count;
+            if *count > stats.majority_count {
+                stats.majority_residue = ch as u8;
+                stats.majority_count = *count;
+            }
+        }
+        for (ch, count) in counts.iter().enumerate() {
+            if ch as u8 != GAP && *count > 0 {
+                let p = (*count as f32) / (stats.num_residues as f32);
+                stats.entropy -= p * p.log2();
+            }
+        }
+        stats
+    }
+
+    /// Fraction of the records that have a gap in this column.
+    pub fn gap_fraction(&self) -> f32 {
+        (self.num_gaps as f32) / ((self.num_gaps + self.num_residues) as f32)
+    }
+
+    /// Frequency of the majority residue among the non-gap characters (NaN for all-gap columns).
+    pub fn majority_frequency(&self) -> f32 {
+        (self.majority_count as f32) / (self.num_residues as f32)
+    }
+
+    /// The consensus character of this column: a gap if gaps are strictly more frequent than the
+    /// majority residue, `N` if the majority frequency is below `threshold`, else the majority residue.
+    pub fn consensus(&self, threshold: f32) -> u8 {
+        if self.num_gaps > self.majority_count {
+            GAP
+        } else if self.majority_frequency() < threshold {
+            CONSENSUS_AMBIGUOUS
+        } else {
+            self.majority_residue
+        }
+    }
+}
+
+
+/// Compute the statistics of every alignment column, using multiple worker threads.
+///
+/// # Arguments
+///
+/// * `records` - The aligned Fasta records. The alignment length is taken from the first record.
+pub fn compute_column_stats(records: &[&Record]) -> Vec<ColumnStats> {
+    let aln_len = match records.first() {
+        None => return vec![],
+        Some(record) => record.seq().len(),
+    };
+
+    let block_starts: Vec<usize> = (0..aln_len).step_by(COLUMN_BLOCK_SIZE).collect();
+    block_starts.par_iter()
+        .flat_map_iter(|block_start| {
+            let block_end = (block_start + COLUMN_BLOCK_SIZE).min(aln_len);
+            let mut counts: Vec<[usize; 256]> = vec![[0usize; 256]; block_end - block_start];
+            for record in records.iter() {
+                let seq = record.seq();
+                let block = &seq[(*block_start).min(seq.len())..block_end.min(seq.len())];
+                for (col_counts, ch) in counts.iter_mut().zip(block.iter()) {
+                    col_counts[*ch as usize] += 1;
+                }
+                // Records are expected to be aligned; treat a short record as gapped past its end.
+                for col_counts in counts.iter_mut().skip(block.len()) {
+                    col_counts[GAP as usize] += 1;
+                }
+            }
+            counts.into_iter().map(|col_counts| ColumnStats::from_counts(&col_counts))
+        })
+        .collect()
+}
+
+
+/// Compute the conservation score of every alignment column: one minus the Shannon entropy of its
+/// non-gap characters, normalized by the largest possible entropy. Fully conserved columns score 1,
+/// and columns where every residue is equally frequent score 0.
+///
+/// The entropy is normalized by `log2` of the number of distinct non-gap characters in the whole
+/// alignment (at least 2), so that the same scale suits nucleotide and protein alignments.
+/// All-gap columns hold no residue to be conserved, and score 0, so that they carry no weight
+/// when the scores are used as column weights.
+pub fn compute_conservation_scores(records: &[&Record]) -> Vec<f32> {
+    let present: [bool; 256] = records.par_iter()
+        .map(|record| {
+            let mut present = [false; 256];
+            for ch in record.seq().iter() {
+                present[*ch as usize] = true;
+            }
+            present
+        })
+        .reduce(|| [false; 256], |mut x, y| {
+            x.iter_mut().zip(y.iter()).for_each(|(xi, yi)| *xi |= *yi);
+            x
+        });
+    let num_residues = present.iter()
+        .enumerate()
+        .filter(|(ch, is_present)| **is_present && *ch as u8 != GAP)
+        .count();
+    let max_entropy = (num_residues.max(2) as f32).log2();
+
+    compute_column_stats(records).iter()
+        .map(|stats| {
+            if stats.num_residues == 0 {
+                0.0
+            } else {
+                (1.0 - stats.entropy / max_entropy).clamp(0.0, 1.0)
+            }
+        })
+        .collect()
+}
+
+
+/// Write per-column conservation scores as a `column, conservation` TSV table with a header row.
+/// Columns are numbered from 1.
+pub fn write_conservation_scores<W: Write>(scores: &[f32], writer: &mut W) -> Result<(), NearestNeighborError> {
+    writeln!(writer, "column\tconservation")?;
+    for (col, score) in scores.iter().enumerate() {
+        writeln!(writer, "{}\t{}", col + 1, score)?;
+    }
+    Ok(())
+}
+
+
+/// Compute the majority-rule consensus of a set of aligned records.
+///
+/// For each alignment column, the most frequent non-gap character is chosen. If the gap
+/// character is strictly more frequent than every other character (including all-gap columns),
+/// the consensus emits a gap instead.
+/// Ties between non-gap characters are broken by picking the lexicographically smallest byte.
+///
+/// # Arguments
+///
+/// * `records` - The aligned Fasta records. The alignment length is taken from the first record.
+///
+/// # Returns
+///
+/// The consensus sequence, with the same length as the alignment.
+pub fn compute_consensus(records: &[&Record]) -> Vec<u8> {
+    compute_consensus_with_threshold(records, 0.0)
+}
+
+
+/// Compute the majority-rule consensus as in [`compute_consensus`], emitting `N` for columns where
+/// the majority residue's frequency among the non-gap characters is below `threshold`.
+pub fn compute_consensus_with_threshold(records: &[&Record], threshold: f32) -> Vec<u8> {
+    compute_column_stats(records)
+        .iter()
+        .map(|stats| stats.consensus(threshold))
+        .collect()
+}
+
+
+/// Build a Fasta record of the consensus of `records`, named [`CONSENSUS_ID`].
+pub fn consensus_record(records: &[&Record]) -> Record {
+    Record::with_attrs(CONSENSUS_ID, None, &compute_consensus(records))
+}
+
+
+/// Write per-column statistics as a TSV table with a header row. Columns are numbered from 1.
+pub fn write_column_stats<W: Write>(stats: &[ColumnStats], writer: &mut W) -> Result<(), NearestNeighborError> {
+    writeln!(writer, "column\tgap_fraction\tmajority_residue\tmajority_frequency\tentropy")?;
+    for (col, col_stats) in stats.iter().enumerate() {
+        writeln!(
+            writer, "{}\t{}\t{}\t{}\t{}",
+            col + 1,
+            col_stats.gap_fraction(),
+            col_stats.majority_residue as char,
+            col_stats.majority_frequency(),
+            col_stats.entropy,
+        )?;
+    }
+    Ok(())
+}
+
+
+/// The 0-based indices of the columns kept by trimming: those whose gap fraction is at most
+/// `max_gap_fraction`. Columns made entirely of gaps are always dropped.
+pub fn trimmed_columns(stats: &[ColumnStats], max_gap_fraction: f32) -> Vec<usize> {
+    stats.iter()
+        .enumerate()
+        .filter(|(_, col_stats)| col_stats.num_residues > 0 && col_stats.gap_fraction() <= max_gap_fraction)
+        .map(|(col, _)| col)
+        .collect()
+}
+
+
+/// The columns a nearest-neighbor search needs to compare, see [`informative_columns`].
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ColumnSelection {
+    /// The 0-based indices of the kept columns.
+    pub columns: Vec<usize>,
+    /// Number of dropped columns holding the same residue in every record. Each of them is a match
+    /// in every pair, so it is added back to both counts of each comparison.
+    pub invariant_matches: u64,
+    /// Number of dropped columns that are gaps in every record. Each of them is a double gap in every
+    /// pair, so it is added back to the double gaps of each comparison.
+    pub gap_columns: u64,
+}
+
+
+/// Select the columns that can change the identity of some pair of records.
+///
+/// All-gap columns are always dropped, since double gaps are never compared, and counted in
+/// [`ColumnSelection::gap_columns`].
+/// With `drop_invariant`, columns holding the same known residue in every record are dropped as well,
+/// and counted in [`ColumnSelection::invariant_matches`] so that identities stay exact.
+pub fn informative_columns(stats: &[ColumnStats], drop_invariant: bool) -> ColumnSelection {
+    let mut selection = ColumnSelection::default();
+    for (col, col_stats) in stats.iter().enumerate() {
+        if col_stats.num_residues == 0 {
+            selection.gap_columns += 1;
+            continue;
+        }
+        // Unknown residues may be excluded from the comparisons, so their columns are never counted as matches.
+        let invariant = col_stats.num_gaps == 0
+            && col_stats.majority_count == col_stats.num_residues
+            && !is_unknown_residue(col_stats.majority_residue);
+        if drop_invariant && invariant {
+            selection.invariant_matches += 1;
+            continue;
+        }
+        selection.columns.push(col);
+    }
+    selection
+}
+
+
+/// Restrict a record to the given 0-based columns, preserving its ID and description.
+pub fn trim_record(record: &Record, columns: &[usize]) -> Record {
+    let seq = record.seq();
+    let trimmed: Vec<u8> = columns.iter()
+        .map(|col| seq.get(*col).copied().unwrap_or(GAP))
+        .collect();
+    Record::with_attrs(record.id(), record.desc(), &trimmed)
+}
+
+
+/// Restrict each record to the given 0-based columns, preserving IDs and descriptions.
+pub fn trim_records(records: &[Record], columns: &[usize]) -> Vec<Record> {
+    records.par_iter()
+        .map(|record| trim_record(record, columns))
+        .collect()
+}
+
+
+/// Write the mapping of old to new column indices (both 1-based) as a TSV table with a header row.
+/// Dropped columns are mapped to `NA`.
+pub fn write_column_map<W: Write>(aln_len: usize, columns: &[usize], writer: &mut W) -> Result<(), NearestNeighborError> {
+    writeln!(writer, "old_column\tnew_column")?;
+    let mut kept = columns.iter().enumerate().peekable();
+    for col in 0..aln_len {
+        match kept.peek() {
+            Some((new_col, old_col)) if **old_col == col => {
+                writeln!(writer, "{}\t{}", col + 1, new_col + 1)?;
+                kept.next();
+            }
+            _ => writeln!(writer, "{}\tNA", col + 1)?,
+        }
+    }
+    Ok(())
+}
+
+
+/// Parse a column mask: 1-based columns or inclusive ranges of columns such as `341-805`, separated by
+/// whitespace or commas. Lines starting with `#` are ignored.
+///
+/// # Arguments
+///
+/// * `text` - The contents of the mask file.
+/// * `aln_len` - The alignment length, which every column must be within.
+///
+/// # Returns
+///
+/// Whether each column of the alignment is included.
+pub fn parse_column_mask(text: &str, aln_len: usize) -> Result<Vec<bool>, String> {
+    let mut included: Vec<bool> = vec![false; aln_len];
+    let tokens = text.lines()
+        .map(|line| line.trim())
+        .filter(|line| !line.starts_with('#'))
+        .flat_map(|line| line.split(|ch: char| ch == ',' || ch.is_whitespace()))
+        .filter(|token| !token.is_empty());
+    for token in tokens {
+        let (start, end) = token.split_once('-').unwrap_or((token, token));
+        let (start, end): (usize, usize) = match (start.parse(), end.parse()) {
+            (Ok(start), Ok(end)) => (start, end),
+            _ => return Err(format!("Invalid column range {:?}", token)),
+        };
+        if start == 0 || start > end {
+            return Err(format!("Invalid column range {:?}", token));
+        }
+        if end > aln_len {
+            return Err(format!("Column range {:?} is outside the alignment of {} columns", token, aln_len));
+        }
+        included[start - 1..end].fill(true);
+    }
+    Ok(included)
+}
+
+
+/// Parse column weights: one non-negative weight per line, for every column of the alignment in order.
+/// Blank lines and lines starting with `#` are ignored.
+pub fn parse_column_weights(text: &str, aln_len: usize) -> Result<Vec<f32>, String> {
+    let weights: Vec<f32> = text.lines()
+        .enumerate()
+        .map(|(line_idx, line)| (line_idx + 1, line.trim()))
+        .filter(|(_, line)| !line.is_empty() && !line.starts_with('#'))
+        .map(|(line_num, line)| match line.parse::<f32>() {
+            Ok(weight) if weight.is_finite() && weight >= 0.0 => Ok(weight),
+            Ok(_) => Err(format!("Line {}: weight {} is not a finite non-negative number", line_num, line)),
+            Err(_) => Err(format!("Line {}: invalid weight {:?}", line_num, line)),
+        })
+        .collect::<Result<_, _>>()?;
+    if weights.len() != aln_len {
+        return Err(format!("Found {} weights, expected one per alignment column ({})", weights.len(), aln_len));
+    }
+    Ok(weights)
+}
+
+
+/// Read a column mask and column weights (see [`parse_column_mask`] and [`parse_column_weights`]) into
+/// the weight of each column, masked-out columns having weight 0.
+///
+/// # Returns
+///
+/// The column weights, or `None` if neither file is given.
+pub fn read_column_weights(
+    mask_path: Option<&Path>,
+    weights_path: Option<&Path>,
+    aln_len: usize,
+) -> Result<Option<Vec<f32>>, NearestNeighborError> {
+    let mut weights: Vec<f32> = match weights_path {
+        None if mask_path.is_none() => return Ok(None),
+        None => vec![1.0; aln_len],
+        Some(path) => parse_column_weights(&std::fs::read_to_string(path)?, aln_len).map_err(|message| file_error(path, message))?,
+    };
+    if let Some(path) = mask_path {
+        let included = parse_column_mask(&std::fs::read_to_string(path)?, aln_len).map_err(|message| file_error(path, message))?;
+        for (weight, included) in weights.iter_mut().zip(included) {
+            if !included {
+                *weight = 0.0;
+            }
+        }
+    }
+    Ok(Some(weights))
+}
+
+
+fn file_error(path: &Path, message: String) -> NearestNeighborError {
+    NearestNeighborError::InvalidInput(format!("{}: {}", path.display(), message))
+}
+
+#[cfg(test)]
+mod tests {
+    use bio::io::fasta::Record;
+    use super::{
+        compute_column_stats, compute_conservation_scores, compute_consensus, compute_consensus_with_threshold,
+        parse_column_mask, parse_column_weights, trim_records, trimmed_columns,
+    };
+
+    #[test]
+    fn test_consensus() {
+        // Column 0: clear majority. Columns 1-2: ties (smallest byte wins).
+        // Column 3: all gaps. Column 4: gaps dominate.
+        let x = Record::with_attrs("x", None, b"AAT-A");
+        let y = Record::with_attrs("y", None, b"ACG--");
+        let z = Record::with_attrs("z", None, b"CCT--");
+        let w = Record::with_attrs("w", None, b"GAG--");
+        let records = [&x, &y, &z, &w];
+        assert_eq!(compute_consensus(&records), b"AAG--".to_vec());
+
+        assert!(compute_consensus(&[]).is_empty());
+    }
+
+    #[test]
+    fn test_column_stats() {
+        let x = Record::with_attrs("x", None, b"AAA-");
+        let y = Record::with_attrs("y", None, b"ACC-");
+        let z = Record::with_attrs("z", None, b"AGT-");
+        let w = Record::with_attrs("w", None, b"ATT-");
+        let records = [&x, &y, &z, &w];
+        let stats = compute_column_stats(&records);
+
+        assert_eq!(stats.len(), 4);
+        assert_eq!(stats[0].majority_residue, b'A');
+        assert_eq!(stats[0].majority_frequency(), 1.0);
+        assert_eq!(stats[0].entropy, 0.0);
+        assert_eq!(stats[1].entropy, 2.0);
+        assert_eq!(stats[2].majority_residue, b'T');
+        assert_eq!(stats[2].majority_frequency(), 0.5);
+        assert_eq!(stats[2].entropy, 1.5);
+        assert_eq!(stats[3].gap_fraction(), 1.0);
+        assert_eq!(stats[3].entropy, 0.0);
+
+        assert_eq!(compute_consensus_with_threshold(&records, 0.0), b"AAT-".to_vec());
+        assert_eq!(compute_consensus_with_threshold(&records, 0.5), b"ANT-".to_vec());
+        assert_eq!(compute_consensus_with_threshold(&records, 0.6), b"ANN-".to_vec());
+    }
+
+    #[test]
+    fn test_trim() {
+        let records = [
+            Record::with_attrs("x", Some("first record"), b"A--A-"),
+            Record::with_attrs("y", None, b"A-CC-"),
+            Record::with_attrs("z", None, b"A-C--"),
+            Record::with_attrs("w", None, b"A---A"),
+        ];
+        let record_refs: Vec<&Record> = records.iter().collect();
+        let stats = compute_column_stats(&record_refs);
+
+        // Column 1 is all gaps, column 4 is 75% gaps.
+        assert_eq!(trimmed_columns(&stats, 1.0), vec![0, 2, 3, 4]);
+        assert_eq!(trimmed_columns(&stats, 0.5), vec![0, 2, 3]);
+
+        let trimmed = trim_records(&records, &trimmed_columns(&stats, 0.5));
+        assert_eq!(trimmed[0].id(), "x");
+        assert_eq!(trimmed[0].desc(), Some("first record"));
+        assert_eq!(trimmed[0].seq(), b"A-A");
+        assert_eq!(trimmed[3].seq(), b"A--");
+    }
+
+    #[test]
+    fn test_conservation_scores() {
+        // Column 0 is fully conserved, column 1 holds random nucleotides, and column 2 only gaps.
+        let mut state: u64 = 7;
+        let records: Vec<Record> = (0..400)
+            .map(|i| {
+                state = state.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+                let seq = [b'A', b"ACGT"[(state >> 62) as usize], b'-'];
+                Record::with_attrs(&format!("r{}", i), None, &seq)
+            })
+            .collect();
+        let record_refs: Vec<&Record> = records.iter().collect();
+        let scores = compute_conservation_scores(&record_refs);
+        assert_eq!(scores.len(), 3);
+        assert_eq!(scores[0], 1.0);
+        assert!(scores[1] < 0.05);
+        assert_eq!(scores[2], 0.0);
+    }
+
+    #[test]
+    fn test_column_mask() {
+        let included = parse_column_mask("# V2\n2-3, 5\n\n7 7\n", 8).unwrap();
+        assert_eq!(included, [false, true, true, false, true, false, true, false]);
+        assert_eq!(parse_column_mask("3-2", 8).unwrap_err(), "Invalid column range \"3-2\"");
+        assert_eq!(parse_column_mask("0", 8).unwrap_err(), "Invalid column range \"0\"");
+        assert_eq!(parse_column_mask("2-x", 8).unwrap_err(), "Invalid column range \"2-x\"");
+        assert_eq!(
+            parse_column_mask("341-805", 800).unwrap_err(),
+            "Column range \"341-805\" is outside the alignment of 800 columns",
+        );
+    }
+
+    #[test]
+    fn test_column_weights() {
+        assert_eq!(parse_column_weights("# entropy\n1\n0.5\n\n0\n", 3).unwrap(), [1.0, 0.5, 0.0]);
+        assert_eq!(parse_column_weights("1\n-1\n", 2).unwrap_err(), "Line 2: weight -1 is not a finite non-negative number");
+        assert_eq!(parse_column_weights("1\nx\n", 2).unwrap_err(), "Line 2: invalid weight \"x\"");
+        assert_eq!(parse_column_weights("1\n", 2).unwrap_err(), "Found 1 weights, expected one per alignment column (2)");
+    }
+}
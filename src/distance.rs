@@ -0,0 +1,307 @@
+use std::{cmp::Ordering, fmt::Debug};
+#[cfg(feature = "cli")]
+use clap::ValueEnum;
+
+use crate::{nearest_neighbor::NearestNeighborError, sequence::Sequence};
+
+
+/// The measure reported for each pair, and used to rank the neighbors of a query.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "cli", derive(ValueEnum))]
+pub enum Metric {
+    /// The fraction of compared columns holding the same residue. Larger is nearer.
+    #[default]
+    Identity,
+    /// The Jukes-Cantor (1969) distance, correcting the p-distance for multiple substitutions.
+    /// Smaller is nearer.
+    Jc69,
+    /// The Kimura 2-parameter (1980) distance, correcting separately for transitions and
+    /// transversions. Smaller is nearer.
+    K2p,
+    /// The fraction of compared columns with a positive substitution score. Larger is nearer.
+    /// Requires a score matrix.
+    Similarity,
+    /// The summed substitution score of the columns without gaps. Larger is nearer.
+    /// Requires a score matrix.
+    Score,
+}
+
+
+impl Metric {
+    pub fn is_distance(&self) -> bool {
+        matches!(self, Metric::Jc69 | Metric::K2p)
+    }
+
+    pub fn needs_score_matrix(&self) -> bool {
+        matches!(self, Metric::Similarity | Metric::Score)
+    }
+}
+
+
+/// Which end of the ranking a search reports for each query.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "cli", derive(ValueEnum))]
+pub enum SearchMode {
+    /// The nearest neighbors: the largest identities, or the smallest distances.
+    #[default]
+    Nearest,
+    /// The most divergent records: the smallest identities, or the largest distances.
+    Farthest,
+}
+
+
+/// A measure of a pair of aligned sequences, to search neighbors with a measure other than the built-in
+/// [`Metric`]s, see [`crate::nearest_neighbor::NeighborConfig::custom_metric`]. The built-in metrics are
+/// measured by [`crate::nearest_neighbor::ComparisonConfig`], which implements it.
+pub trait PairwiseMetric: Debug + Send + Sync {
+    /// The value of a pair of aligned sequences of the same length. NaN if the pair has no value, as for
+    /// a pair without any compared column: such a pair is never reported.
+    fn compare(&self, x: &[u8], y: &[u8]) -> Result<f64, NearestNeighborError>;
+
+    /// Whether smaller values are nearer, as for a distance, rather than larger ones, as for an identity.
+    fn is_distance(&self) -> bool;
+}
+
+
+/// The number of columns holding different bytes, see [`Sequence::hamming_distance`]. Smaller is nearer.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct HammingDistance;
+
+
+impl PairwiseMetric for HammingDistance {
+    fn compare(&self, x: &[u8], y: &[u8]) -> Result<f64, NearestNeighborError> {
+        Ok(Sequence(x).hamming_distance(Sequence(y))? as f64)
+    }
+
+    fn is_distance(&self) -> bool {
+        true
+    }
+}
+
+
+/// How a search ranks the values of its metric: the best value is the nearest one, or the farthest
+/// one in [`SearchMode::Farthest`]. Pairs with an undefined (NaN) value never rank.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Ranking {
+    /// Whether smaller values are nearer, see [`PairwiseMetric::is_distance`].
+    pub distance: bool,
+    pub mode: SearchMode,
+}
+
+
+impl Ranking {
+    fn prefers_smaller(&self) -> bool {
+        self.distance == (self.mode == SearchMode::Nearest)
+    }
+
+    /// A value that any defined value is at least as good as: the search starts from it.
+    pub fn worst_value(&self) -> f64 {
+        if self.prefers_smaller() { f64::INFINITY } else { f64::NEG_INFINITY }
+    }
+
+    /// Whether `value` is at least as good as `other`. Always false if `value` is NaN.
+    pub fn is_at_least_as_good(&self, value: f64, other: f64) -> bool {
+        if self.prefers_smaller() { value <= other } else { value >= other }
+    }
+
+    /// Order two defined values from best to worst.
+    pub fn cmp_best_first(&self, a: f64, b: f64) -> Ordering {
+        if self.prefers_smaller() { a.total_cmp(&b) } else { b.total_cmp(&a) }
+    }
+}
+
+
+/// The Jukes-Cantor distance `-3/4 * ln(1 - 4p/3)` of a p-distance.
+/// Returns `None` for a saturated pair, where the log argument is not positive.
+pub fn jc69_distance(p: f64) -> Option<f64> {
+    let arg = 1.0 - 4.0 * p / 3.0;
+    (arg > 0.0).then(|| -0.75 * arg.ln())
+}
+
+
+/// The Kimura 2-parameter distance `-1/2 * ln(1 - 2P - Q) - 1/4 * ln(1 - 2Q)`, from the fractions
+/// of compared columns that are transitions (`P`) and transversions (`Q`).
+/// Returns `None` for a saturated pair, where a log argument is not positive.
+pub fn k2p_distance(transitions: f64, transversions: f64) -> Option<f64> {
+    let arg1 = 1.0 - 2.0 * transitions - transversions;
+    let arg2 = 1.0 - 2.0 * transversions;
+    (arg1 > 0.0 && arg2 > 0.0).then(|| -0.5 * arg1.ln() - 0.25 * arg2.ln())
+}
+
+
+fn is_purine(ch: u8) -> bool {
+    matches!(ch.to_ascii_uppercase(), b'A' | b'G')
+}
+
+
+fn is_pyrimidine(ch: u8) -> bool {
+    matches!(ch.to_ascii_uppercase(), b'C' | b'T' | b'U')
+}
+
+
+/// Whether a substitution between two residues is a transition, i.e. between two purines (`A`, `G`)
+/// or two pyrimidines (`C`, `T`, `U`). Case is ignored.
+pub fn is_transition(x: u8, y: u8) -> bool {
+    !x.eq_ignore_ascii_case(&y)
+        && ((is_purine(x) && is_purine(y)) || (is_pyrimidine(x) && is_pyrimidine(y)))
+}
+
+
+/// Whether a substitution between two residues is a strict transversion, i.e. between a purine and a
+/// pyrimidine. Unlike the transversions of [`Metric::K2p`], gaps and ambiguity codes are never transversions.
+pub fn is_transversion(x: u8, y: u8) -> bool {
+    (is_purine(x) && is_pyrimidine(y)) || (is_pyrimidine(x) && is_purine(y))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use bio::io::fasta::Record;
+    use crate::nearest_neighbor::{
+        compare_records, compute_nearest_neighbors, compute_store_nearest_neighbors, compute_top_k_neighbors,
+        ComparisonConfig, NearestNeighborError, NeighborConfig,
+    };
+    use crate::output::OutputConfig;
+    use super::{
+        is_transition, is_transversion, jc69_distance, k2p_distance, HammingDistance, Metric, PairwiseMetric, Ranking,
+        SearchMode,
+    };
+
+    /// A toy distance counting each mismatch at a CpG site of `x` three times.
+    #[derive(Debug)]
+    struct CpgWeightedDistance;
+
+    impl PairwiseMetric for CpgWeightedDistance {
+        fn compare(&self, x: &[u8], y: &[u8]) -> Result<f64, NearestNeighborError> {
+            let is_cpg = |col: usize| x[col..].starts_with(b"CG") || (col > 0 && x[col - 1..].starts_with(b"CG"));
+            Ok((0..x.len())
+                .filter(|col| x[*col] != y[*col])
+                .map(|col| if is_cpg(col) { 3.0 } else { 1.0 })
+                .sum())
+        }
+
+        fn is_distance(&self) -> bool {
+            true
+        }
+    }
+
+    #[test]
+    fn test_distances() {
+        assert!(is_transition(b'A', b'g'));
+        assert!(is_transition(b'C', b'T'));
+        assert!(!is_transition(b'A', b'C'));
+        assert!(!is_transition(b'A', b'-'));
+        assert!(is_transversion(b'A', b't'));
+        assert!(is_transversion(b'U', b'G'));
+        assert!(!is_transversion(b'A', b'G'));
+        assert!(!is_transversion(b'A', b'N'));
+        assert!(!is_transversion(b'C', b'-'));
+
+        assert!((jc69_distance(0.2).unwrap() - 0.2326162).abs() < 1e-6);
+        assert!((k2p_distance(0.2, 0.0).unwrap() - 0.2554128).abs() < 1e-6);
+        assert_eq!(jc69_distance(0.0), Some(0.0));
+        assert_eq!(jc69_distance(0.75), None);
+        assert_eq!(k2p_distance(0.1, 0.5), None);
+    }
+
+    #[test]
+    fn test_metric_values() {
+        // 10 compared columns: 7 matches, 2 transitions (A/G, C/T), and a gap against a residue,
+        // which counts as a transversion. The double-gap column is excluded.
+        let x = Record::with_attrs("x", None, b"ACGTACGT-C-");
+        let y = Record::with_attrs("y", None, b"GCGTACGTAT-");
+        let counts = compare_records(&x, &y, &ComparisonConfig::default()).unwrap();
+        assert_eq!((counts.matches, counts.compared, counts.transitions), (7.0, 10.0, 2.0));
+
+        let value = |metric: Metric| ComparisonConfig { metric, ..Default::default() }.value(&counts);
+        assert_eq!(value(Metric::Identity), 0.7);
+        assert!((value(Metric::Jc69) - 0.3831192).abs() < 1e-6);
+        assert!((value(Metric::K2p) - 0.4023595).abs() < 1e-6);
+
+        // Saturated pairs are infinitely far, unless capped.
+        let z = Record::with_attrs("z", None, b"TTACGTAC-T-");
+        let counts = compare_records(&x, &z, &ComparisonConfig::default()).unwrap();
+        let config = ComparisonConfig { metric: Metric::Jc69, ..Default::default() };
+        assert_eq!(config.value(&counts), f64::INFINITY);
+        let config = ComparisonConfig { metric: Metric::Jc69, saturation_cap: Some(5.0), ..Default::default() };
+        assert_eq!(config.value(&counts), 5.0);
+    }
+
+    #[test]
+    fn test_ranking() {
+        let nearest = Ranking { distance: false, mode: SearchMode::Nearest };
+        assert!(nearest.is_at_least_as_good(0.9, 0.5));
+        assert!(!nearest.is_at_least_as_good(f64::NAN, nearest.worst_value()));
+        let farthest = Ranking { distance: false, mode: SearchMode::Farthest };
+        assert!(farthest.is_at_least_as_good(0.5, 0.9));
+        assert!(!farthest.is_at_least_as_good(f64::NAN, farthest.worst_value()));
+        let farthest_distance = Ranking { distance: true, mode: SearchMode::Farthest };
+        assert!(farthest_distance.is_at_least_as_good(f64::INFINITY, 0.5));
+    }
+
+    #[test]
+    fn test_distance_ranking() {
+        let records = [
+            Record::with_attrs("q", None, b"AAAAAAAA"),
+            Record::with_attrs("far", None, b"AACCCCAA"),
+            Record::with_attrs("near", None, b"AAAAAACA"),
+        ];
+        let record_refs: Vec<&Record> = records.iter().collect();
+        let config = NeighborConfig {
+            comparison: ComparisonConfig { metric: Metric::K2p, ..Default::default() },
+            exclude_self: true,
+            top_k: 2,
+            ..Default::default()
+        };
+        let results = compute_top_k_neighbors(&record_refs[..1], &record_refs, &config).unwrap();
+        let ids: Vec<&str> = results[0].iter().map(|(neighbor, _)| neighbor.id()).collect();
+        assert_eq!(ids, ["near", "far"]);
+        assert!(results[0][0].1 < results[0][1].1);
+    }
+
+    #[test]
+    fn test_custom_metric() {
+        let records = [
+            Record::with_attrs("q", None, b"ACGTAA"),
+            Record::with_attrs("cpg", None, b"ATGTAA"),
+            Record::with_attrs("other", None, b"ACGTTT"),
+        ];
+        let record_refs: Vec<&Record> = records.iter().collect();
+        let nearest_id = |config: &NeighborConfig| {
+            compute_nearest_neighbors(&record_refs[..1], &record_refs, config).unwrap()[0].0.id().to_owned()
+        };
+        let config = NeighborConfig { exclude_self: true, ..Default::default() };
+        assert_eq!(nearest_id(&config), "cpg");
+        let hamming = NeighborConfig { custom_metric: Some(Arc::new(HammingDistance)), ..config.clone() };
+        assert_eq!(nearest_id(&hamming), "cpg");
+        assert_eq!(HammingDistance.compare(b"ACGTAA", b"ACGTTT").unwrap(), 2.0);
+
+        // The CpG mismatch now outweighs the two other mismatches.
+        let cpg = NeighborConfig { custom_metric: Some(Arc::new(CpgWeightedDistance)), top_k: 2, ..config };
+        let results = compute_top_k_neighbors(&record_refs[..1], &record_refs, &cpg).unwrap();
+        let hits: Vec<(&str, f64)> = results[0].iter().map(|(neighbor, value)| (neighbor.id(), *value)).collect();
+        assert_eq!(hits, [("other", 2.0), ("cpg", 3.0)]);
+        assert_eq!(cpg.run_metadata(1, 3)[1], ("metric".to_owned(), "custom".to_owned()));
+
+        let short = Record::with_attrs("short", None, b"ACG");
+        assert!(matches!(
+            compute_nearest_neighbors(&record_refs[..1], &[&short], &cpg),
+            Err(NearestNeighborError::HammingDistanceError(_, _)),
+        ));
+
+        // The custom metric sees the all-gap column, which keeps the C and the G of the query apart: dropping it
+        // would make a CpG site of the mismatch against "cpg".
+        let gapped = vec![
+            Record::with_attrs("q", None, b"AC-GTAA"),
+            Record::with_attrs("cpg", None, b"AT-GTAA"),
+            Record::with_attrs("other", None, b"AC-GTTT"),
+        ];
+        let out_path = std::env::temp_dir().join("aligned_nearest_neighbor_test_custom_metric.tsv");
+        let config = NeighborConfig { custom_metric: Some(Arc::new(CpgWeightedDistance)), exclude_self: true, ..Default::default() };
+        compute_store_nearest_neighbors(gapped, &out_path, Some(vec!["q".to_owned()]), None, &config, &OutputConfig::default())
+            .unwrap();
+        assert_eq!(std::fs::read_to_string(&out_path).unwrap(), "q\tcpg\t1.000000\n");
+        std::fs::remove_file(&out_path).unwrap();
+    }
+}
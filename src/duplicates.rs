@@ -0,0 +1,98 @@
+use std::collections::HashMap;
+use bio::io::fasta::Record;
+
+
+/// The records grouped by sequence, so that the neighbors of each distinct sequence are searched once,
+/// see [`crate::nearest_neighbor::NeighborConfig::collapse_duplicates`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DuplicateGroups {
+    /// The indices of the records of each distinct sequence, in record order. The groups are in the order
+    /// of their first record, which stands for the group in the search.
+    groups: Vec<Vec<usize>>,
+}
+
+
+impl DuplicateGroups {
+    /// Group the records by sequence, gaps included. The sequences are hashed, and the records of the same
+    /// hash compared byte for byte, as the keys of a [`HashMap`] are, so that a hash collision never merges
+    /// two different sequences.
+    pub fn new(records: &[&Record]) -> DuplicateGroups {
+        let mut group_by_seq: HashMap<&[u8], usize> = HashMap::with_capacity(records.len());
+        let mut groups: Vec<Vec<usize>> = vec![];
+        for (record_idx, record) in records.iter().enumerate() {
+            let group_idx = *group_by_seq.entry(record.seq()).or_insert_with(|| {
+                groups.push(vec![]);
+                groups.len() - 1
+            });
+            groups[group_idx].push(record_idx);
+        }
+        DuplicateGroups { groups }
+    }
+
+    /// One group per record, for a search without collapsing.
+    pub fn singletons(num_records: usize) -> DuplicateGroups {
+        DuplicateGroups { groups: (0..num_records).map(|record_idx| vec![record_idx]).collect() }
+    }
+
+    /// The number of distinct sequences.
+    pub fn num_unique(&self) -> usize {
+        self.groups.len()
+    }
+
+    /// The number of grouped records.
+    pub fn num_records(&self) -> usize {
+        self.groups.iter().map(Vec::len).sum()
+    }
+
+    /// The index of the first record of the group `group_idx`, which stands for the group in the search.
+    pub fn representative(&self, group_idx: usize) -> usize {
+        self.groups[group_idx][0]
+    }
+
+    /// The indices of the records of the group `group_idx`, in record order.
+    pub fn members(&self, group_idx: usize) -> &[usize] {
+        &self.groups[group_idx]
+    }
+
+    /// Copy the result of each group, in group order, to each of its records, in record order.
+    /// The groups without a result, e.g. skipped by a cancellation, leave their records without one.
+    pub fn expand<T: Clone>(&self, group_results: Vec<Option<T>>) -> Vec<Option<T>> {
+        let mut results: Vec<Option<T>> = vec![None; self.num_records()];
+        for (group, result) in self.groups.iter().zip(group_results) {
+            for record_idx in group.iter() {
+                results[*record_idx] = result.clone();
+            }
+        }
+        results
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use bio::io::fasta::Record;
+    use super::DuplicateGroups;
+
+    #[test]
+    fn test_duplicate_groups() {
+        let records = [
+            Record::with_attrs("a", None, b"AC-T"),
+            Record::with_attrs("b", None, b"ACGT"),
+            Record::with_attrs("c", None, b"AC-T"),
+            Record::with_attrs("d", None, b"AC.T"),
+            Record::with_attrs("e", None, b"ACGT"),
+        ];
+        let record_refs: Vec<&Record> = records.iter().collect();
+        let groups = DuplicateGroups::new(&record_refs);
+        assert_eq!((groups.num_unique(), groups.num_records()), (3, 5));
+        assert_eq!((0..3).map(|group_idx| groups.representative(group_idx)).collect::<Vec<usize>>(), [0, 1, 3]);
+        assert_eq!(groups.members(0), [0, 2]);
+        assert_eq!(groups.members(1), [1, 4]);
+        assert_eq!(groups.members(2), [3]);
+        assert_eq!(groups.expand(vec![Some('x'), None, Some('z')]), [Some('x'), None, Some('x'), Some('z'), None]);
+
+        let singletons = DuplicateGroups::singletons(3);
+        assert_eq!((singletons.num_unique(), singletons.representative(2)), (3, 2));
+        assert_eq!(singletons.expand(vec![Some(1), Some(2)]), [Some(1), Some(2), None]);
+    }
+}
@@ -0,0 +1,23 @@
+use std::io::Read;
+use bio::io::{fasta::Record, fastq::Reader as FastqReader};
+
+use crate::{FastaParseError, FastaParseErrorKind};
+
+
+/// Parse aligned FASTQ records, such as aligned reads with gaps in their sequence lines, into Fasta records
+/// with the same IDs, descriptions and sequences. The quality lines are discarded. The records sharing an
+/// ID are kept, and their lengths aren't checked: see [`crate::parse_all_records_multi`] for both checks.
+pub fn parse_fastq_records<R: Read>(reader: R) -> Result<Vec<Record>, FastaParseError> {
+    FastqReader::new(reader)
+        .records()
+        .enumerate()
+        .map(|(record_idx, record)| {
+            let record = record.map_err(|err| FastaParseError {
+                message: format!("Invalid FASTQ record at index {}: {}", record_idx, err),
+                kind: FastaParseErrorKind::FormatError,
+                source: None,
+            })?;
+            Ok(Record::with_attrs(record.id(), record.desc(), record.seq()))
+        })
+        .collect()
+}
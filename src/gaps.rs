@@ -0,0 +1,111 @@
+use std::{
+    fmt::{Debug, Display, Formatter},
+    str::FromStr,
+};
+
+use crate::nearest_neighbor::GAP;
+
+
+/// The set of characters treated as gaps by the comparisons, e.g. `-.~`, or `?` for the missing data
+/// of NEXUS alignments. Defaults to `-` alone.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct GapChars {
+    /// Bit `ch % 64` of word `ch / 64` is set for each gap character `ch`.
+    bits: [u64; 4],
+}
+
+
+impl GapChars {
+    pub fn new(chars: &[u8]) -> GapChars {
+        let mut bits = [0u64; 4];
+        for ch in chars.iter() {
+            bits[(*ch >> 6) as usize] |= 1u64 << (*ch & 63);
+        }
+        GapChars { bits }
+    }
+
+    pub fn contains(&self, ch: u8) -> bool {
+        self.bits[(ch >> 6) as usize] & (1u64 << (ch & 63)) != 0
+    }
+
+    /// The gap characters, in byte order.
+    pub fn chars(&self) -> Vec<u8> {
+        (0..=255u8).filter(|ch| self.contains(*ch)).collect()
+    }
+}
+
+
+impl Default for GapChars {
+    fn default() -> Self {
+        GapChars::new(&[GAP])
+    }
+}
+
+
+impl Display for GapChars {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", String::from_utf8_lossy(&self.chars()))
+    }
+}
+
+
+impl Debug for GapChars {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "GapChars({:?})", self.to_string())
+    }
+}
+
+
+impl FromStr for GapChars {
+    type Err = String;
+
+    /// Parse a non-empty string of ASCII gap characters, such as `-.~`. Letters can't be gaps.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.is_empty() {
+            return Err("Expected at least one gap character".to_owned());
+        }
+        if let Some(ch) = s.chars().find(|ch| !ch.is_ascii_punctuation()) {
+            return Err(format!("Invalid gap character {:?}: gaps must be ASCII punctuation", ch));
+        }
+        Ok(GapChars::new(s.as_bytes()))
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use bio::io::fasta::Record;
+    use crate::nearest_neighbor::{compare_records, pct_identity_bytes, ComparisonConfig};
+    use super::GapChars;
+
+    #[test]
+    fn test_gap_chars() {
+        let gaps: GapChars = "~.-".parse().unwrap();
+        assert_eq!(gaps.chars(), b"-.~");
+        assert!(gaps.contains(b'.'));
+        assert!(!gaps.contains(b'A'));
+        assert_eq!(GapChars::default().chars(), b"-");
+        assert_eq!(gaps.to_string(), "-.~");
+        assert!("".parse::<GapChars>().is_err());
+        assert_eq!("-N".parse::<GapChars>().unwrap_err(), "Invalid gap character 'N': gaps must be ASCII punctuation");
+    }
+
+    #[test]
+    fn test_dot_gapped_alignment() {
+        let dots = ComparisonConfig { gap_chars: ".".parse().unwrap(), ..Default::default() };
+        // The two double-gap columns are excluded: 3 matches out of 4 columns.
+        assert_eq!(pct_identity_bytes(b"AC..GT", b"AC..GA", &dots), 0.75);
+        // With the default gaps, `.` is a residue like any other, and double dots match.
+        let x = Record::with_attrs("x", None, b"AC..GT");
+        let y = Record::with_attrs("y", None, b"AC..GA");
+        let counts = compare_records(&x, &y, &ComparisonConfig::default()).unwrap();
+        assert_eq!((counts.matches, counts.compared), (5.0, 6.0));
+
+        // Mixed gap characters still make a double gap, and a dot against a residue is a mismatch.
+        let mixed = ComparisonConfig { gap_chars: "-.".parse().unwrap(), ..Default::default() };
+        let x = Record::with_attrs("x", None, b"A.-CG");
+        let y = Record::with_attrs("y", None, b"A-.C.");
+        let counts = compare_records(&x, &y, &mixed).unwrap();
+        assert_eq!((counts.matches, counts.compared), (2.0, 3.0));
+    }
+}
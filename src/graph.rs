@@ -0,0 +1,284 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fs::File,
+    io::{BufWriter, Write},
+    path::PathBuf,
+};
+use bio::io::fasta::Record;
+#[cfg(feature = "cli")]
+use clap::ValueEnum;
+
+use crate::{
+    nearest_neighbor::NearestNeighborError,
+    output::{write_atomically, NearestNeighborResultRow},
+    stats::record_stats,
+};
+
+
+/// The file format of the neighbor graph.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "cli", derive(ValueEnum))]
+pub enum GraphFormat {
+    /// A `source, target, weight` TSV table with a header. Has no node attributes.
+    #[default]
+    #[cfg_attr(feature = "cli", value(name = "edgelist"))]
+    EdgeList,
+    /// A Graphviz DOT graph.
+    Dot,
+    /// A GraphML document, as read by Gephi and Cytoscape.
+    Graphml,
+}
+
+
+/// Where and how to write the neighbor graph of the reported pairs, see [`write_graph`].
+#[derive(Debug, Clone)]
+pub struct GraphOutput {
+    pub path: PathBuf,
+    pub format: GraphFormat,
+    /// Keep only the mutual neighbors, as undirected edges.
+    pub mutual: bool,
+}
+
+
+/// A node of the neighbor graph, with the attributes used for styling.
+struct GraphNode<'a> {
+    id: &'a str,
+    ungapped_length: Option<usize>,
+    label: Option<&'a str>,
+}
+
+
+/// The edges of the graph: every row as a directed `query -> neighbor` edge, or with `mutual`, one
+/// undirected edge per pair of records that are each other's neighbors, weighted by the first such row.
+fn graph_edges(rows: &[NearestNeighborResultRow], mutual: bool) -> Vec<&NearestNeighborResultRow> {
+    if !mutual {
+        return rows.iter().collect();
+    }
+    let pairs: HashSet<(&str, &str)> = rows.iter()
+        .map(|row| (row.query_id.as_str(), row.neighbor_id.as_str()))
+        .collect();
+    let mut emitted: HashSet<(&str, &str)> = HashSet::new();
+    rows.iter()
+        .filter(|row| {
+            let (query_id, neighbor_id) = (row.query_id.as_str(), row.neighbor_id.as_str());
+            pairs.contains(&(neighbor_id, query_id)) && emitted.insert((query_id.min(neighbor_id), query_id.max(neighbor_id)))
+        })
+        .collect()
+}
+
+
+/// Quote an ID for DOT, escaping backslashes and double quotes.
+fn dot_quote(id: &str) -> String {
+    format!("\"{}\"", id.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+
+/// Escape the XML special characters of a GraphML attribute value or text.
+fn xml_escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for ch in text.chars() {
+        match ch {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            _ => escaped.push(ch),
+        }
+    }
+    escaped
+}
+
+
+/// Write the query-neighbor relation of the result rows as a graph, weighted by the rows' identities,
+/// or distances for a distance [`crate::distance::Metric`].
+///
+/// # Arguments
+///
+/// * `rows` - The reported pairs. Every query and neighbor becomes a node, even without a mutual edge.
+/// * `records` - The searched records, for the ungapped length of each node.
+/// * `labels` - If given, the nodes' labels, written as a `record_label` node attribute.
+/// * `format` - The graph file format.
+/// * `mutual` - Keep only the mutual neighbors, as undirected edges.
+/// * `precision` - The number of decimal places of the edge weights.
+/// * `writer` - Where to write the graph.
+pub fn write_graph<W: Write>(
+    rows: &[NearestNeighborResultRow],
+    records: &[&Record],
+    labels: Option<&HashMap<String, String>>,
+    format: GraphFormat,
+    mutual: bool,
+    precision: usize,
+    writer: &mut W,
+) -> Result<(), NearestNeighborError> {
+    let records_by_id: HashMap<&str, &Record> = records.iter().map(|record| (record.id(), *record)).collect();
+    let mut seen_ids: HashSet<&str> = HashSet::new();
+    let nodes: Vec<GraphNode> = rows.iter()
+        .flat_map(|row| [row.query_id.as_str(), row.neighbor_id.as_str()])
+        .filter(|id| seen_ids.insert(*id))
+        .map(|id| GraphNode {
+            id,
+            ungapped_length: records_by_id.get(id).map(|record| record_stats(record).ungapped_length),
+            label: labels.and_then(|labels| labels.get(id)).map(String::as_str),
+        })
+        .collect();
+    let edges = graph_edges(rows, mutual);
+
+    match format {
+        GraphFormat::EdgeList => {
+            writeln!(writer, "source\ttarget\tweight")?;
+            for edge in edges.iter() {
+                writeln!(writer, "{}\t{}\t{:.*}", edge.query_id, edge.neighbor_id, precision, edge.identity)?;
+            }
+        }
+        GraphFormat::Dot => {
+            let (kind, arrow) = if mutual { ("graph", "--") } else { ("digraph", "->") };
+            writeln!(writer, "{} knn {{", kind)?;
+            for node in nodes.iter() {
+                let mut attributes: Vec<String> = vec![];
+                if let Some(ungapped_length) = node.ungapped_length {
+                    attributes.push(format!("ungapped_length={}", ungapped_length));
+                }
+                if let Some(label) = node.label {
+                    attributes.push(format!("record_label={}", dot_quote(label)));
+                }
+                writeln!(writer, "  {} [{}];", dot_quote(node.id), attributes.join(", "))?;
+            }
+            for edge in edges.iter() {
+                writeln!(
+                    writer, "  {} {} {} [weight={:.*}];",
+                    dot_quote(&edge.query_id), arrow, dot_quote(&edge.neighbor_id), precision, edge.identity,
+                )?;
+            }
+            writeln!(writer, "}}")?;
+        }
+        GraphFormat::Graphml => {
+            writeln!(writer, "<?xml version=\"1.0\" encoding=\"UTF-8\"?>")?;
+            writeln!(writer, "<graphml xmlns=\"http://graphml.graphdrawing.org/xmlns\">")?;
+            writeln!(writer, "  <key id=\"ungapped_length\" for=\"node\" attr.name=\"ungapped_length\" attr.type=\"int\"/>")?;
+            if labels.is_some() {
+                writeln!(writer, "  <key id=\"record_label\" for=\"node\" attr.name=\"record_label\" attr.type=\"string\"/>")?;
+            }
+            writeln!(writer, "  <key id=\"weight\" for=\"edge\" attr.name=\"weight\" attr.type=\"double\"/>")?;
+            let edge_default = if mutual { "undirected" } else { "directed" };
+            writeln!(writer, "  <graph id=\"knn\" edgedefault=\"{}\">", edge_default)?;
+            for node in nodes.iter() {
+                write!(writer, "    <node id=\"{}\">", xml_escape(node.id))?;
+                if let Some(ungapped_length) = node.ungapped_length {
+                    write!(writer, "<data key=\"ungapped_length\">{}</data>", ungapped_length)?;
+                }
+                if let Some(label) = node.label {
+                    write!(writer, "<data key=\"record_label\">{}</data>", xml_escape(label))?;
+                }
+                writeln!(writer, "</node>")?;
+            }
+            for edge in edges.iter() {
+                writeln!(
+                    writer, "    <edge source=\"{}\" target=\"{}\"><data key=\"weight\">{:.*}</data></edge>",
+                    xml_escape(&edge.query_id), xml_escape(&edge.neighbor_id), precision, edge.identity,
+                )?;
+            }
+            writeln!(writer, "  </graph>")?;
+            writeln!(writer, "</graphml>")?;
+        }
+    }
+    Ok(())
+}
+
+
+/// Write the neighbor graph of the result rows to the configured file, see [`write_graph`]. The file is
+/// written atomically, see [`write_atomically`].
+pub fn write_graph_file(
+    rows: &[NearestNeighborResultRow],
+    records: &[&Record],
+    labels: Option<&HashMap<String, String>>,
+    graph_output: &GraphOutput,
+    precision: usize,
+) -> Result<(), NearestNeighborError> {
+    write_atomically(&graph_output.path, |tmp_path| {
+        let mut writer = BufWriter::new(File::create(tmp_path)?);
+        write_graph(rows, records, labels, graph_output.format, graph_output.mutual, precision, &mut writer)?;
+        writer.flush()?;
+        Ok(())
+    })
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use bio::io::fasta::Record;
+    use crate::output::NearestNeighborResultRow;
+    use super::{write_graph, GraphFormat};
+
+    fn row(query_id: &str, neighbor_id: &str, identity: f64) -> NearestNeighborResultRow {
+        NearestNeighborResultRow {
+            query_id: query_id.to_owned(),
+            neighbor_id: neighbor_id.to_owned(),
+            rank: 1,
+            identity,
+            ..Default::default()
+        }
+    }
+
+    fn graph_string(
+        rows: &[NearestNeighborResultRow],
+        records: &[&Record],
+        labels: Option<&HashMap<String, String>>,
+        format: GraphFormat,
+        mutual: bool,
+    ) -> String {
+        let mut buf: Vec<u8> = vec![];
+        write_graph(rows, records, labels, format, mutual, 2, &mut buf).unwrap();
+        String::from_utf8(buf).unwrap()
+    }
+
+    #[test]
+    fn test_edge_list() {
+        let rows = [row("a", "b", 0.9), row("b", "a", 0.9), row("c", "a", 0.5)];
+        let directed = graph_string(&rows, &[], None, GraphFormat::EdgeList, false);
+        assert_eq!(directed, "source\ttarget\tweight\na\tb\t0.90\nb\ta\t0.90\nc\ta\t0.50\n");
+        // Only a and b are each other's neighbors, and their edge is written once.
+        let mutual = graph_string(&rows, &[], None, GraphFormat::EdgeList, true);
+        assert_eq!(mutual, "source\ttarget\tweight\na\tb\t0.90\n");
+    }
+
+    #[test]
+    fn test_dot_escaping() {
+        let records = [Record::with_attrs("a\"1", None, b"AC-T"), Record::with_attrs("b\\2", None, b"ACGT")];
+        let record_refs: Vec<&Record> = records.iter().collect();
+        let labels = HashMap::from([("a\"1".to_owned(), "x".to_owned())]);
+        let rows = [row("a\"1", "b\\2", 0.75)];
+        let dot = graph_string(&rows, &record_refs, Some(&labels), GraphFormat::Dot, false);
+        assert_eq!(
+            dot,
+            "digraph knn {\n  \"a\\\"1\" [ungapped_length=3, record_label=\"x\"];\n  \"b\\\\2\" [ungapped_length=4];\n  \"a\\\"1\" -> \"b\\\\2\" [weight=0.75];\n}\n",
+        );
+        let undirected = graph_string(&[row("a", "b", 1.0), row("b", "a", 1.0)], &[], None, GraphFormat::Dot, true);
+        assert!(undirected.starts_with("graph knn {\n"));
+        assert!(undirected.contains("  \"a\" -- \"b\" [weight=1.00];\n"));
+    }
+
+    #[test]
+    fn test_graphml_round_trip() {
+        let records = [Record::with_attrs("<q&1>", None, b"AC-T"), Record::with_attrs("n'2\"", None, b"ACGT")];
+        let record_refs: Vec<&Record> = records.iter().collect();
+        let labels = HashMap::from([("<q&1>".to_owned(), "A & B".to_owned())]);
+        let rows = [row("<q&1>", "n'2\"", 0.75)];
+        let graphml = graph_string(&rows, &record_refs, Some(&labels), GraphFormat::Graphml, false);
+
+        let document = roxmltree::Document::parse(&graphml).unwrap();
+        let nodes: Vec<roxmltree::Node> = document.descendants().filter(|node| node.has_tag_name("node")).collect();
+        let node_ids: Vec<&str> = nodes.iter().map(|node| node.attribute("id").unwrap()).collect();
+        assert_eq!(node_ids, ["<q&1>", "n'2\""]);
+        let node_data: Vec<(&str, &str)> = nodes[0].children()
+            .filter(|child| child.has_tag_name("data"))
+            .map(|data| (data.attribute("key").unwrap(), data.text().unwrap()))
+            .collect();
+        assert_eq!(node_data, [("ungapped_length", "3"), ("record_label", "A & B")]);
+
+        let edge = document.descendants().find(|node| node.has_tag_name("edge")).unwrap();
+        assert_eq!((edge.attribute("source"), edge.attribute("target")), (Some("<q&1>"), Some("n'2\"")));
+        assert_eq!(edge.first_element_child().unwrap().text(), Some("0.75"));
+    }
+}
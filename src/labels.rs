@@ -0,0 +1,186 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs::File,
+    io::{BufRead, BufReader, Write},
+    path::Path,
+};
+use bio::io::fasta::Record;
+
+use crate::nearest_neighbor::NearestNeighborError;
+
+
+/// A nearest-neighbor hit, annotated with the labels of both records.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LabeledNeighbor {
+    pub query_id: String,
+    pub neighbor_id: String,
+    pub identity: f64,
+    pub query_label: Option<String>,
+    pub neighbor_label: Option<String>,
+}
+
+
+impl LabeledNeighbor {
+    /// Whether the query and neighbor carry the same label. Unlabeled records never match.
+    pub fn label_match(&self) -> bool {
+        match (&self.query_label, &self.neighbor_label) {
+            (Some(query_label), Some(neighbor_label)) => query_label == neighbor_label,
+            _ => false,
+        }
+    }
+}
+
+
+/// Classification accuracy over the labeled queries.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct LabelSummary {
+    /// Number of queries that carry a label.
+    pub total: usize,
+    /// Number of labeled queries whose neighbor has the same label.
+    pub correct: usize,
+    /// Per query label: (correct, total).
+    pub per_label: BTreeMap<String, (usize, usize)>,
+}
+
+
+impl LabelSummary {
+    pub fn accuracy(&self) -> f32 {
+        (self.correct as f32) / (self.total as f32)
+    }
+}
+
+
+/// Parse a two-column TSV of `record_id<TAB>label`. Blank lines are skipped.
+pub fn parse_labels(fpath: &Path) -> Result<HashMap<String, String>, std::io::Error> {
+    let file = File::open(fpath)?;
+    let reader = BufReader::new(file);
+
+    let mut labels: HashMap<String, String> = HashMap::new();
+    for (line_idx, line) in reader.lines().enumerate() {
+        let line = line?;
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        match line.split_once('\t') {
+            Some((id, label)) => {
+                labels.insert(id.to_owned(), label.trim().to_owned());
+            }
+            None => {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!("Expected 2 tab-separated columns on line {}", line_idx + 1),
+                ));
+            }
+        }
+    }
+    Ok(labels)
+}
+
+
+/// Attach labels to nearest-neighbor results.
+pub fn annotate_labels(
+    query_records: &[&Record],
+    results: &[(&Record, f64)],
+    labels: &HashMap<String, String>,
+) -> Vec<LabeledNeighbor> {
+    query_records.iter()
+        .zip(results.iter())
+        .map(|(query, (neighbor, idty))| LabeledNeighbor {
+            query_id: query.id().to_owned(),
+            neighbor_id: neighbor.id().to_owned(),
+            identity: *idty,
+            query_label: labels.get(query.id()).cloned(),
+            neighbor_label: labels.get(neighbor.id()).cloned(),
+        })
+        .collect()
+}
+
+
+/// Compute overall and per-label accuracy. Queries without a label are not counted.
+pub fn summarize_labels(results: &[LabeledNeighbor]) -> LabelSummary {
+    let mut summary = LabelSummary::default();
+    for result in results.iter() {
+        if let Some(query_label) = &result.query_label {
+            let is_match = result.label_match();
+            let entry = summary.per_label.entry(query_label.clone()).or_insert((0, 0));
+            entry.1 += 1;
+            summary.total += 1;
+            if is_match {
+                entry.0 += 1;
+                summary.correct += 1;
+            }
+        }
+    }
+    summary
+}
+
+
+/// Write labeled results as
+/// `query_id, neighbor_id, identity, query_label, neighbor_label, label_match` TSV rows.
+/// Missing labels are written as `NA`, and identities have `precision` decimal places.
+pub fn write_labeled_results<W: Write>(
+    results: &[LabeledNeighbor],
+    writer: &mut W,
+    precision: usize,
+) -> Result<(), NearestNeighborError> {
+    for result in results.iter() {
+        writeln!(
+            writer, "{}\t{}\t{:.*}\t{}\t{}\t{}",
+            result.query_id,
+            result.neighbor_id,
+            precision,
+            result.identity,
+            result.query_label.as_deref().unwrap_or("NA"),
+            result.neighbor_label.as_deref().unwrap_or("NA"),
+            result.label_match(),
+        )?;
+    }
+    Ok(())
+}
+
+
+/// Write a human-readable accuracy summary.
+pub fn write_label_summary<W: Write>(summary: &LabelSummary, writer: &mut W) -> Result<(), NearestNeighborError> {
+    writeln!(writer, "overall\t{}\t{}\t{}", summary.correct, summary.total, summary.accuracy())?;
+    for (label, (correct, total)) in summary.per_label.iter() {
+        writeln!(writer, "{}\t{}\t{}\t{}", label, correct, total, (*correct as f32) / (*total as f32))?;
+    }
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use bio::io::fasta::Record;
+    use crate::nearest_neighbor::{compute_nearest_neighbors, NeighborConfig};
+    use super::{annotate_labels, summarize_labels};
+
+    #[test]
+    fn test_leave_one_out_labels() {
+        let records = [
+            Record::with_attrs("a1", None, b"AAAAAAAA"),
+            Record::with_attrs("a2", None, b"AAAAAAAC"),
+            Record::with_attrs("b1", None, b"CCCCCCCC"),
+            Record::with_attrs("b2", None, b"CCCCCCAA"),
+        ];
+        let labels: HashMap<String, String> = [("a1", "A"), ("a2", "A"), ("b1", "B"), ("b2", "A")]
+            .iter()
+            .map(|(id, label)| (id.to_string(), label.to_string()))
+            .collect();
+        let all_records: Vec<&Record> = records.iter().collect();
+        let config = NeighborConfig { exclude_self: true, ..Default::default() };
+
+        let results = compute_nearest_neighbors(&all_records, &all_records, &config).unwrap();
+        let labeled = annotate_labels(&all_records, &results, &labels);
+        assert_eq!(labeled[0].neighbor_id, "a2");
+        assert_eq!(labeled[2].neighbor_id, "b2");
+        assert!(labeled[0].label_match());
+        assert!(!labeled[2].label_match());
+
+        let summary = summarize_labels(&labeled);
+        assert_eq!(summary.total, 4);
+        assert_eq!(summary.per_label["B"], (0, 1));
+    }
+}
@@ -1,5 +1,7 @@
 use std::{
-    io::{BufRead, BufReader},
+    collections::{HashMap, HashSet},
+    fmt::{Display, Formatter},
+    io::{BufRead, BufReader, Read},
     fs::File,
     path::{Path, PathBuf},
 };
@@ -7,8 +9,58 @@ use bio::io::fasta::{
     Reader as FastaReader,
     Record,
 };
+#[cfg(feature = "cli")]
+use clap::ValueEnum;
+use rayon::prelude::*;
+use regex::Regex;
 
 pub mod nearest_neighbor;
+pub mod blast;
+pub mod cancel;
+pub mod checkpoint;
+pub mod clustal;
+pub mod columns;
+pub mod distance;
+pub mod duplicates;
+pub mod fastq;
+pub mod gaps;
+pub mod graph;
+pub mod pairs;
+pub mod pfm;
+pub mod phylip;
+pub mod sample;
+pub mod sequence;
+pub mod score_matrix;
+pub mod labels;
+pub mod match_matrix;
+pub mod matrix;
+#[cfg(feature = "minhash")]
+pub mod minhash;
+pub mod classify;
+pub mod stockholm;
+pub mod output;
+#[cfg(feature = "parquet")]
+pub mod parquet_writer;
+pub mod progress;
+pub mod shard;
+#[cfg(feature = "sqlite")]
+pub mod sqlite;
+pub mod stats;
+pub mod strand;
+pub mod telemetry;
+pub mod streaming;
+pub mod tree;
+pub mod validate;
+#[cfg(feature = "pyo3")]
+pub mod python;
+#[cfg(feature = "wasm")]
+pub mod wasm;
+
+pub use stockholm::parse_all_records_stockholm;
+pub use clustal::parse_all_records_clustal;
+pub use phylip::parse_all_records_phylip;
+pub use sequence::Sequence;
+pub use distance::{HammingDistance, PairwiseMetric};
 
 
 #[derive(Debug)]
@@ -16,6 +68,13 @@ pub enum FastaParseErrorKind {
     IOError,
     EmptyFile,
     LengthMismatch,
+    FormatError,
+    /// IDs appearing more than once, within a file or across files, each listed once.
+    DuplicateId(Vec<String>),
+    /// A record ID or description holding a tab, newline or carriage return, see [`BadIdPolicy`].
+    BadId,
+    /// A sequence holding a symbol that is neither a residue nor a gap, see [`check_record_symbols`].
+    InvalidSymbol,
 }
 
 
@@ -23,6 +82,20 @@ pub enum FastaParseErrorKind {
 pub struct FastaParseError {
     pub message: String,
     pub kind: FastaParseErrorKind,
+    /// The underlying I/O error, for errors of kind [`FastaParseErrorKind::IOError`].
+    pub source: Option<std::io::Error>,
+}
+
+impl Display for FastaParseError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl std::error::Error for FastaParseError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        self.source.as_ref().map(|err| err as &(dyn std::error::Error + 'static))
+    }
 }
 
 impl From<std::io::Error> for FastaParseError {
@@ -30,6 +103,7 @@ impl From<std::io::Error> for FastaParseError {
         FastaParseError {
             message: format!("IO error: {}", err),
             kind: FastaParseErrorKind::IOError,
+            source: Some(err),
         }
     }
 }
@@ -41,6 +115,7 @@ pub fn parse_record_ids(fpath: &Path) -> Result<Vec<String>, std::io::Error> {
     let reader = BufReader::new(file);
     let mut id_list: Vec<String> = vec![];
     for line in reader.lines() {
+        // `lines` only strips the `\n`: trimming also drops the `\r` of Windows (CRLF) line endings.
         let line = line?.trim().to_owned();
         if line.len() > 0 {
             id_list.push(line);
@@ -50,24 +125,610 @@ pub fn parse_record_ids(fpath: &Path) -> Result<Vec<String>, std::io::Error> {
 }
 
 
+/// Parse an aligned FASTA file, checking that all records share the same alignment length.
+///
+/// Windows (CRLF) line endings are supported: the `bio` reader trims the trailing whitespace of every
+/// header and sequence line, so neither IDs, descriptions nor sequences keep a `\r`.
 pub fn parse_all_records(input_fasta: PathBuf) -> Result<Vec<Record>, FastaParseError> {
     let file = File::open(input_fasta)?;
-    let reader = BufReader::new(file);
+    parse_all_records_from_reader(BufReader::new(file))
+}
+
+
+/// Parse aligned FASTA records from any reader, such as an in-memory buffer, as [`parse_all_records`] does.
+/// Malformed input, including non-UTF-8 bytes, is reported as an error rather than a panic, and so are
+/// duplicate IDs, see [`detect_duplicate_ids`].
+pub fn parse_all_records_from_reader<R: Read>(reader: R) -> Result<Vec<Record>, FastaParseError> {
+    let _span = tracing::info_span!("parse_fasta", file_count = 1).entered();
+    let all_fasta_records = check_record_symbols(parse_records_allowing_duplicates(reader)?, &[])?;
+    check_record_lengths(&all_fasta_records)?;
+    let duplicate_ids = detect_duplicate_ids(&all_fasta_records);
+    if !duplicate_ids.is_empty() {
+        return Err(duplicate_ids_error(duplicate_ids));
+    }
+    Ok(all_fasta_records)
+}
+
 
+/// Parse FASTA records, keeping the records sharing an ID, without checking their lengths.
+fn parse_records_allowing_duplicates<R: Read>(reader: R) -> Result<Vec<Record>, FastaParseError> {
     let fasta_reader =  FastaReader::new(reader);
     let all_fasta_records: Vec<Record> = fasta_reader
         .records()
         .collect::<Result<Vec<Record>, std::io::Error>>()?;
+    Ok(all_fasta_records)
+}
+
+
+/// Whether a sequence symbol is valid in any alignment: a letter, which covers the IUPAC nucleotide and amino
+/// acid codes, the stop codon `*`, or one of the gaps `-` and `.`.
+pub fn is_valid_symbol(ch: u8) -> bool {
+    ch.is_ascii_alphabetic() || matches!(ch, b'-' | b'.' | b'*')
+}
+
+
+/// Check that every symbol of the records is valid, see [`is_valid_symbol`], or one of `allowed_chars`.
+/// A stray `\r`, left in a sequence by the Windows (CRLF) line endings of a format read line by line, is
+/// removed with a warning rather than counted as a mismatch. Fails with a
+/// [`FastaParseErrorKind::InvalidSymbol`] error naming the first record holding another symbol, its 1-based
+/// column and the symbol.
+pub fn check_record_symbols(records: Vec<Record>, allowed_chars: &[u8]) -> Result<Vec<Record>, FastaParseError> {
+    let mut num_stripped: usize = 0;
+    let records: Vec<Record> = records.into_iter()
+        .map(|record| {
+            if !record.seq().contains(&b'\r') {
+                return record;
+            }
+            num_stripped += 1;
+            let seq: Vec<u8> = record.seq().iter().copied().filter(|ch| *ch != b'\r').collect();
+            Record::with_attrs(record.id(), record.desc(), &seq)
+        })
+        .collect();
+    if num_stripped > 0 {
+        tracing::warn!("Removed the carriage returns (\\r) of {} records: the input has Windows line endings", num_stripped);
+    }
+    for record in records.iter() {
+        let invalid = record.seq().iter().position(|ch| !is_valid_symbol(*ch) && !allowed_chars.contains(ch));
+        if let Some(column) = invalid {
+            let symbol = record.seq()[column];
+            return Err(FastaParseError {
+                message: format!(
+                    "Record {} has the invalid symbol {:?} (byte 0x{:02x}) at column {}",
+                    record.id(), symbol as char, symbol, column + 1,
+                ),
+                kind: FastaParseErrorKind::InvalidSymbol,
+                source: None,
+            });
+        }
+    }
+    Ok(records)
+}
+
+
+/// The IDs appearing more than once among `records`, each listed once, in the order of their second
+/// occurrence. Lookups by ID, such as [`nearest_neighbor::filter_records`], can't tell such records apart.
+pub fn detect_duplicate_ids(records: &[Record]) -> Vec<String> {
+    let mut seen_ids: HashSet<&str> = HashSet::new();
+    let mut reported_ids: HashSet<&str> = HashSet::new();
+    records.iter()
+        .filter(|record| !seen_ids.insert(record.id()) && reported_ids.insert(record.id()))
+        .map(|record| record.id().to_owned())
+        .collect()
+}
+
+
+fn duplicate_ids_error(duplicate_ids: Vec<String>) -> FastaParseError {
+    FastaParseError {
+        message: format!("Duplicate record IDs: {}", duplicate_ids.join(", ")),
+        kind: FastaParseErrorKind::DuplicateId(duplicate_ids),
+        source: None,
+    }
+}
+
+
+/// What to do when a record ID appears more than once in the input files.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "cli", derive(ValueEnum))]
+pub enum DuplicateIdPolicy {
+    /// Fail with a [`FastaParseErrorKind::DuplicateId`] error listing the IDs repeated within a file,
+    /// or the first ID repeated across files.
+    #[default]
+    Error,
+    /// Log a warning, and keep only the first record of each ID.
+    Skip,
+    /// Log a warning, and keep all the records. Lookups by ID only find the first record of each ID.
+    Allow,
+}
+
+
+/// The format of an input alignment file.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "cli", derive(ValueEnum))]
+pub enum InputFormat {
+    /// Detect the format of each file from its first non-blank line: `>` for FASTA, `@` for FASTQ,
+    /// `# STOCKHOLM`, `CLUSTAL`, or the record count and alignment length of PHYLIP. A file without such a
+    /// line is detected from its extension, less any `.gz`: Stockholm for `.sto`, `.stk` and `.stockholm`,
+    /// Clustal for `.aln`, PHYLIP for `.phy` and `.phylip`, FASTQ for `.fastq` and `.fq`, and FASTA otherwise.
+    #[default]
+    Auto,
+    /// Aligned multi-FASTA.
+    Fasta,
+    /// Aligned FASTQ, whose quality lines are discarded, see [`fastq::parse_fastq_records`].
+    Fastq,
+    /// Clustal, as written by ClustalW and Clustal Omega, see [`parse_all_records_clustal`].
+    Clustal,
+    /// Relaxed PHYLIP, sequential or interleaved, see [`parse_all_records_phylip`].
+    Phylip,
+    /// Stockholm, as written by Rfam and Pfam, see [`stockholm::parse_stockholm`].
+    Stockholm,
+}
+
+
+impl InputFormat {
+    /// The format of the file at `path`: this format, or the one detected from the file for
+    /// [`InputFormat::Auto`].
+    pub fn resolve(self, path: &Path) -> InputFormat {
+        if self != InputFormat::Auto {
+            return self;
+        }
+        if let Some(format) = sniff_format(path) {
+            return format;
+        }
+        let file_name = path.file_name().map_or(String::new(), |name| name.to_string_lossy().to_ascii_lowercase());
+        let extension = Path::new(file_name.strip_suffix(".gz").unwrap_or(&file_name)).extension();
+        match extension.and_then(|ext| ext.to_str()) {
+            Some("sto" | "stk" | "stockholm") => InputFormat::Stockholm,
+            Some("aln") => InputFormat::Clustal,
+            Some("phy" | "phylip") => InputFormat::Phylip,
+            Some("fastq" | "fq") => InputFormat::Fastq,
+            _ => InputFormat::Fasta,
+        }
+    }
+}
+
+
+/// Open an input file for reading, decompressing it if its name ends with `.gz`. Without the `compress`
+/// feature, a `.gz` file is an [`std::io::ErrorKind::Unsupported`] error.
+pub fn open_input(path: &Path) -> std::io::Result<Box<dyn Read + Send>> {
+    let file = File::open(path)?;
+    if path.extension().is_some_and(|ext| ext == "gz") {
+        #[cfg(feature = "compress")]
+        return Ok(Box::new(flate2::read::MultiGzDecoder::new(BufReader::new(file))));
+        #[cfg(not(feature = "compress"))]
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::Unsupported,
+            "Gzipped input is unavailable; rebuild with `--features compress`.",
+        ));
+    }
+    Ok(Box::new(file))
+}
+
+
+/// The format of the file at `path` told by its first non-blank line, if the file can be read and the
+/// line is recognized.
+fn sniff_format(path: &Path) -> Option<InputFormat> {
+    let reader = BufReader::new(open_input(path).ok()?);
+    let line = reader.lines()
+        .map_while(Result::ok)
+        .find(|line| !line.trim().is_empty())?;
+    let line = line.trim_start();
+    if line.starts_with('>') {
+        return Some(InputFormat::Fasta);
+    }
+    if line.starts_with('@') {
+        return Some(InputFormat::Fastq);
+    }
+    if line.starts_with("# STOCKHOLM") {
+        return Some(InputFormat::Stockholm);
+    }
+    if line.starts_with("CLUSTAL") {
+        return Some(InputFormat::Clustal);
+    }
+    let fields: Vec<&str> = line.split_whitespace().collect();
+    if fields.len() == 2 && fields.iter().all(|field| field.parse::<usize>().is_ok()) {
+        return Some(InputFormat::Phylip);
+    }
+    None
+}
+
+
+/// A record parsed by [`parse_all_records_sourced`], along with the file it was read from.
+#[derive(Debug, Clone)]
+pub struct SourcedRecord<'p> {
+    pub record: Record,
+    pub source: &'p Path,
+}
+
+
+/// Parse several aligned FASTA files in parallel, and concatenate their records in the order of `paths`.
+/// All records, across all files, must share the same alignment length, unless `allow_ragged` is set.
+///
+/// # Arguments
+///
+/// * `paths` - The FASTA files to parse.
+/// * `format` - The format of the files, see [`InputFormat::resolve`].
+/// * `on_duplicate` - How to handle an ID already seen, in the same file or an earlier one.
+/// * `allow_ragged` - Accept records of different lengths, padding the shorter ones with gaps, see
+///   [`pad_records_to_max_length`].
+/// * `allowed_chars` - The symbols accepted in the sequences on top of the residues and gaps, see
+///   [`check_record_symbols`].
+pub fn parse_all_records_multi(
+    paths: &[PathBuf],
+    format: InputFormat,
+    on_duplicate: DuplicateIdPolicy,
+    allow_ragged: bool,
+    allowed_chars: &[u8],
+) -> Result<Vec<Record>, FastaParseError> {
+    let sourced = parse_all_records_sourced(paths, format, on_duplicate, allow_ragged, allowed_chars)?;
+    Ok(sourced.into_iter().map(|sourced| sourced.record).collect())
+}
+
+
+/// Parse the records as [`parse_all_records_multi`] does, keeping track of the file each record was read
+/// from. The errors name the file of the offending record.
+pub fn parse_all_records_sourced<'p>(
+    paths: &'p [PathBuf],
+    format: InputFormat,
+    on_duplicate: DuplicateIdPolicy,
+    allow_ragged: bool,
+    allowed_chars: &[u8],
+) -> Result<Vec<SourcedRecord<'p>>, FastaParseError> {
+    let span = tracing::info_span!("parse_fasta", file_count = paths.len(), record_count = tracing::field::Empty);
+    let _entered = span.enter();
+    let per_file: Vec<Vec<Record>> = paths.par_iter()
+        .map(|path| {
+            parse_file_records(path, format, on_duplicate, allow_ragged, allowed_chars).map_err(|err| FastaParseError {
+                message: format!("{}: {}", path.display(), err.message),
+                ..err
+            })
+        })
+        .collect::<Result<Vec<Vec<Record>>, FastaParseError>>()?;
+
+    let mut seen_ids: HashSet<String> = HashSet::new();
+    let mut all_records: Vec<SourcedRecord> = vec![];
+    for (path, records) in paths.iter().zip(per_file) {
+        for record in records {
+            if seen_ids.insert(record.id().to_owned()) {
+                all_records.push(SourcedRecord { record, source: path });
+                continue;
+            }
+            match on_duplicate {
+                DuplicateIdPolicy::Error => {
+                    return Err(FastaParseError {
+                        message: format!("Duplicate record ID {} in {}", record.id(), path.display()),
+                        kind: FastaParseErrorKind::DuplicateId(vec![record.id().to_owned()]),
+                        source: None,
+                    });
+                }
+                DuplicateIdPolicy::Skip => {
+                    tracing::warn!("Skipping duplicate record ID {} in {}", record.id(), path.display());
+                }
+                DuplicateIdPolicy::Allow => {
+                    tracing::warn!("Keeping duplicate record ID {} in {}", record.id(), path.display());
+                    all_records.push(SourcedRecord { record, source: path });
+                }
+            }
+        }
+    }
+
+    span.record("record_count", all_records.len());
+    if all_records.is_empty() {
+        return Err(FastaParseError {
+            message: "No records found.".to_owned(),
+            kind: FastaParseErrorKind::EmptyFile,
+            source: None,
+        });
+    }
+    if allow_ragged {
+        let (records, sources): (Vec<Record>, Vec<&Path>) = all_records.into_iter()
+            .map(|sourced| (sourced.record, sourced.source))
+            .unzip();
+        return Ok(pad_records_to_max_length(records).into_iter()
+            .zip(sources)
+            .map(|(record, source)| SourcedRecord { record, source })
+            .collect());
+    }
+    // Each file was checked on its own, so a mismatch is between files.
+    let first = &all_records[0];
+    let first_len = first.record.seq().len();
+    if let Some(other) = all_records.iter().find(|sourced| sourced.record.seq().len() != first_len) {
+        return Err(FastaParseError {
+            message: format!(
+                "Record lengths don't match! Record {} in {} has length {}, but record {} in {} has length {}",
+                other.record.id(), other.source.display(), other.record.seq().len(),
+                first.record.id(), first.source.display(), first_len,
+            ),
+            kind: FastaParseErrorKind::LengthMismatch,
+            source: None,
+        });
+    }
+    Ok(all_records)
+}
+
+
+/// The FASTA files to read for the input `paths`, in order: each file as it is, and each directory replaced
+/// by the files it directly holds whose name matches `pattern`, sorted by name. Fails on a directory without
+/// any matching file.
+pub fn expand_input_paths(paths: &[PathBuf], pattern: &glob::Pattern) -> Result<Vec<PathBuf>, std::io::Error> {
+    let mut expanded: Vec<PathBuf> = vec![];
+    for path in paths.iter() {
+        if !path.is_dir() {
+            expanded.push(path.clone());
+            continue;
+        }
+        let mut dir_paths: Vec<PathBuf> = vec![];
+        for entry in std::fs::read_dir(path)? {
+            let entry_path = entry?.path();
+            let matches = entry_path.file_name().is_some_and(|name| pattern.matches(&name.to_string_lossy()));
+            if matches && entry_path.is_file() {
+                dir_paths.push(entry_path);
+            }
+        }
+        if dir_paths.is_empty() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                format!("No file in {} matches {}", path.display(), pattern),
+            ));
+        }
+        dir_paths.sort();
+        expanded.extend(dir_paths);
+    }
+    Ok(expanded)
+}
+
+
+/// Parse one of the files of [`parse_all_records_multi`], failing on invalid symbols, on duplicate IDs only
+/// with [`DuplicateIdPolicy::Error`], and on records of different lengths unless `allow_ragged` is set.
+/// The Stockholm and Clustal files join the rows of an ID, so they have no duplicate IDs, and the records of
+/// the alignment formats are never ragged. The FASTA and FASTQ files ending in `.gz` are decompressed.
+fn parse_file_records(
+    path: &Path,
+    format: InputFormat,
+    on_duplicate: DuplicateIdPolicy,
+    allow_ragged: bool,
+    allowed_chars: &[u8],
+) -> Result<Vec<Record>, FastaParseError> {
+    let records = match format.resolve(path) {
+        InputFormat::Stockholm => parse_all_records_stockholm(path)?,
+        InputFormat::Clustal => parse_all_records_clustal(path)?,
+        InputFormat::Phylip => parse_all_records_phylip(path)?,
+        InputFormat::Fastq => fastq::parse_fastq_records(BufReader::new(open_input(path)?))?,
+        InputFormat::Fasta | InputFormat::Auto => parse_records_allowing_duplicates(BufReader::new(open_input(path)?))?,
+    };
+    let records = check_record_symbols(records, allowed_chars)?;
+    if !allow_ragged {
+        check_record_lengths(&records)?;
+    }
+    let duplicate_ids = detect_duplicate_ids(&records);
+    if !duplicate_ids.is_empty() && on_duplicate == DuplicateIdPolicy::Error {
+        return Err(duplicate_ids_error(duplicate_ids));
+    }
+    Ok(records)
+}
+
+
+/// What to do with a record ID or description holding a tab, newline or carriage return, any of which would
+/// break the rows of the TSV outputs.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "cli", derive(ValueEnum))]
+pub enum BadIdPolicy {
+    /// Write each of them as a two-character escape sequence: `\t`, `\n` and `\r`, see [`escape_tsv_field`].
+    /// A backslash is escaped too, as `\\`, so that [`unescape_tsv_field`] recovers the original header.
+    #[default]
+    Escape,
+    /// Fail with a [`FastaParseErrorKind::BadId`] error naming the first offending record.
+    Error,
+    /// Replace each of them with an underscore.
+    Replace,
+}
+
+
+/// The characters that can't appear in a TSV field.
+const TSV_UNSAFE_CHARS: [char; 3] = ['\t', '\n', '\r'];
+
+/// The characters written as escape sequences by [`escape_tsv_field`].
+const TSV_ESCAPED_CHARS: [char; 4] = ['\\', '\t', '\n', '\r'];
+
+
+/// Escape a TSV field: each tab, newline and carriage return is written as `\t`, `\n` and `\r`, and each
+/// backslash as `\\`, so that an escape sequence can't be mistaken for the same two characters of the original
+/// text. [`unescape_tsv_field`] reverses it.
+pub fn escape_tsv_field(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for ch in text.chars() {
+        match ch {
+            '\\' => escaped.push_str("\\\\"),
+            '\t' => escaped.push_str("\\t"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            _ => escaped.push(ch),
+        }
+    }
+    escaped
+}
+
+
+/// The original text of a field escaped by [`escape_tsv_field`]. A backslash not starting one of its escape
+/// sequences is kept as is.
+pub fn unescape_tsv_field(text: &str) -> String {
+    let mut unescaped = String::with_capacity(text.len());
+    let mut chars = text.chars();
+    while let Some(ch) = chars.next() {
+        if ch != '\\' {
+            unescaped.push(ch);
+            continue;
+        }
+        match chars.next() {
+            Some('\\') => unescaped.push('\\'),
+            Some('t') => unescaped.push('\t'),
+            Some('n') => unescaped.push('\n'),
+            Some('r') => unescaped.push('\r'),
+            Some(other) => {
+                unescaped.push('\\');
+                unescaped.push(other);
+            }
+            None => unescaped.push('\\'),
+        }
+    }
+    unescaped
+}
+
+
+/// Make the IDs and descriptions of `records` safe to write as TSV fields, as set by `policy`, and log a
+/// warning for each changed record. The records without any tab, newline or carriage return, nor with
+/// [`BadIdPolicy::Escape`] any backslash, are kept as is.
+pub fn sanitize_record_headers(records: Vec<Record>, policy: BadIdPolicy) -> Result<Vec<Record>, FastaParseError> {
+    let is_unsafe = |text: &str| match policy {
+        BadIdPolicy::Escape => text.contains(TSV_ESCAPED_CHARS),
+        _ => text.contains(TSV_UNSAFE_CHARS),
+    };
+    let sanitize = |text: &str| -> String {
+        match policy {
+            BadIdPolicy::Replace => text.replace(TSV_UNSAFE_CHARS, "_"),
+            _ => escape_tsv_field(text),
+        }
+    };
+    records.into_iter()
+        .enumerate()
+        .map(|(record_idx, record)| {
+            if !is_unsafe(record.id()) && !record.desc().is_some_and(is_unsafe) {
+                return Ok(record);
+            }
+            let id = sanitize(record.id());
+            if policy == BadIdPolicy::Error {
+                return Err(FastaParseError {
+                    message: format!(
+                        "Record {} (index {}) has a tab, newline or carriage return in its ID or description",
+                        id, record_idx,
+                    ),
+                    kind: FastaParseErrorKind::BadId,
+                    source: None,
+                });
+            }
+            tracing::warn!("Sanitized the header of record {} (index {}) for the TSV outputs", id, record_idx);
+            Ok(Record::with_attrs(&id, record.desc().map(sanitize).as_deref(), record.seq()))
+        })
+        .collect()
+}
+
+
+/// How to shorten the record IDs right after parsing, e.g. to the accession of an NCBI-style ID, so that the
+/// ID files and the outputs use the shortened IDs. An ID without the part to keep, or with an empty one, is kept whole.
+#[derive(Debug, Clone)]
+pub enum IdNormalizer {
+    /// Keep the 1-based `field`-th of the fields separated by `delimiter`, e.g. the 4th field, separated by
+    /// `|`, of `gi|12345|ref|NR_000001.1|`.
+    Split { delimiter: char, field: usize },
+    /// Keep the text captured by the first capture group of the regular expression.
+    Regex(Regex),
+}
+
+
+impl IdNormalizer {
+    /// The shortened `id`, or `None` if it lacks the part to keep, e.g. for an empty field of `gi||ref|`.
+    pub fn normalize<'a>(&self, id: &'a str) -> Option<&'a str> {
+        match self {
+            IdNormalizer::Split { delimiter, field } => {
+                id.split(*delimiter).nth(field.checked_sub(1)?).filter(|field| !field.is_empty())
+            }
+            IdNormalizer::Regex(regex) => regex.captures(id)?.get(1).map(|capture| capture.as_str()),
+        }
+    }
+}
+
+
+/// Shorten the ID of each record with `normalizer`, keeping the description, and log a warning with the number
+/// of IDs kept whole. Two records with different IDs shortened to the same ID are an error of kind
+/// [`FastaParseErrorKind::DuplicateId`], listing the shortened IDs.
+pub fn normalize_record_ids(records: Vec<Record>, normalizer: &IdNormalizer) -> Result<Vec<Record>, FastaParseError> {
+    let mut original_ids: HashMap<String, String> = HashMap::new();
+    let mut colliding_ids: Vec<String> = vec![];
+    let mut num_kept_whole: usize = 0;
+    let normalized: Vec<Record> = records.into_iter()
+        .map(|record| {
+            let id: &str = normalizer.normalize(record.id()).unwrap_or_else(|| {
+                num_kept_whole += 1;
+                record.id()
+            });
+            let original_id = original_ids.entry(id.to_owned()).or_insert_with(|| record.id().to_owned());
+            if original_id != record.id() && !colliding_ids.iter().any(|colliding_id| colliding_id == id) {
+                colliding_ids.push(id.to_owned());
+            }
+            Record::with_attrs(id, record.desc(), record.seq())
+        })
+        .collect();
+    if !colliding_ids.is_empty() {
+        return Err(FastaParseError {
+            message: format!("Different record IDs shortened to the same IDs: {}", colliding_ids.join(", ")),
+            kind: FastaParseErrorKind::DuplicateId(colliding_ids),
+            source: None,
+        });
+    }
+    if num_kept_whole > 0 {
+        tracing::warn!("Kept {} record IDs whole, as they lack the part to keep", num_kept_whole);
+    }
+    Ok(normalized)
+}
+
+
+/// Keep the first record of each distinct sequence, in the original order, and log a warning for
+/// each dropped duplicate. Sequences are compared byte for byte, gaps included.
+pub fn deduplicate_by_sequence(records: Vec<Record>) -> Vec<Record> {
+    let mut first_ids: HashMap<Vec<u8>, String> = HashMap::new();
+    let mut unique: Vec<Record> = Vec::with_capacity(records.len());
+    for record in records {
+        match first_ids.get(record.seq()) {
+            Some(first_id) => {
+                tracing::warn!("Dropping record {}: same sequence as record {}", record.id(), first_id);
+            }
+            None => {
+                first_ids.insert(record.seq().to_vec(), record.id().to_owned());
+                unique.push(record);
+            }
+        }
+    }
+    unique
+}
+
+
+/// Pad the records shorter than the longest one with gaps (`-`) at their end, so that all the records share
+/// its length, and log a warning with the number of padded records.
+///
+/// The padding is compared as any gap: against a residue it counts as a difference, so the identity of a
+/// padded record is computed over the padded length, and is lowered by the residues it lacks.
+pub fn pad_records_to_max_length(records: Vec<Record>) -> Vec<Record> {
+    let max_len: usize = records.iter().map(|record| record.seq().len()).max().unwrap_or(0);
+    let mut num_padded: usize = 0;
+    let padded: Vec<Record> = records.into_iter()
+        .map(|record| {
+            if record.seq().len() == max_len {
+                return record;
+            }
+            num_padded += 1;
+            let mut seq: Vec<u8> = record.seq().to_vec();
+            seq.resize(max_len, b'-');
+            Record::with_attrs(record.id(), record.desc(), &seq)
+        })
+        .collect();
+    if num_padded > 0 {
+        tracing::warn!("Padded {} records with gaps to the alignment length {}", num_padded, max_len);
+    }
+    padded
+}
 
-    if all_fasta_records.len() == 0 {
+
+/// Check that there is at least one record, and that all records share the same alignment length.
+pub fn check_record_lengths(records: &[Record]) -> Result<(), FastaParseError> {
+    if records.is_empty() {
         return Err(FastaParseError {
             message: "No records found.".to_owned(),
             kind: FastaParseErrorKind::EmptyFile,
+            source: None,
         })
     }
 
-    let first_len: usize = all_fasta_records.first().unwrap().seq().len();
-    for (record_idx, record) in all_fasta_records.iter().enumerate() {
+    let first_len: usize = records.first().unwrap().seq().len();
+    for (record_idx, record) in records.iter().enumerate() {
         if record.seq().len() != first_len {
             return Err(FastaParseError {
                 message: format!(
@@ -76,12 +737,12 @@ pub fn parse_all_records(input_fasta: PathBuf) -> Result<Vec<Record>, FastaParse
                     record.seq().len(),
                     record_idx
                 ),
-                kind: FastaParseErrorKind::LengthMismatch
+                kind: FastaParseErrorKind::LengthMismatch,
+                source: None,
             })
         }
     }
-
-    Ok(all_fasta_records)
+    Ok(())
 }
 
 
@@ -89,7 +750,14 @@ pub fn parse_all_records(input_fasta: PathBuf) -> Result<Vec<Record>, FastaParse
 mod tests {
     use std::path::PathBuf;
     use bio::io::fasta::Record;
-    use super::{parse_all_records, parse_record_ids};
+    use std::error::Error;
+    use super::{
+        check_record_symbols, deduplicate_by_sequence, detect_duplicate_ids, expand_input_paths, normalize_record_ids,
+        pad_records_to_max_length, parse_all_records, parse_all_records_multi, parse_all_records_sourced, parse_record_ids,
+        sanitize_record_headers, unescape_tsv_field,
+        BadIdPolicy, DuplicateIdPolicy, FastaParseErrorKind, IdNormalizer, InputFormat,
+    };
+    use crate::nearest_neighbor::{NearestNeighborError, RecordSelector};
 
     #[test]
     fn test_query_db_match() {
@@ -102,9 +770,10 @@ mod tests {
         let query_ids = parse_record_ids(&query_txt).unwrap();
         let records = parse_all_records(fasta_path).unwrap();
 
-        let query_records: Vec<&Record> = crate::nearest_neighbor::filter_records(&records, Some(query_ids));
-        let db_records: Vec<&Record> = crate::nearest_neighbor::filter_records(&records, Some(db_ids));
-        let results = crate::nearest_neighbor::compute_nearest_neighbors(&query_records, &db_records).unwrap();
+        let query_records: Vec<&Record> = crate::nearest_neighbor::filter_records(&records, &RecordSelector::Ids(&query_ids));
+        let db_records: Vec<&Record> = crate::nearest_neighbor::filter_records(&records, &RecordSelector::Ids(&db_ids));
+        let config = crate::nearest_neighbor::NeighborConfig::default();
+        let results = crate::nearest_neighbor::compute_nearest_neighbors(&query_records, &db_records, &config).unwrap();
 
         assert_eq!(results.len(), 2);
         assert_eq!(results.len(), query_records.len());
@@ -117,4 +786,289 @@ mod tests {
         assert_eq!(res.id(), "db_2");
         assert_eq!(idty, 4.0 / 16.0);
     }
+
+    #[test]
+    fn test_io_error_source() {
+        let err = parse_all_records(PathBuf::from("tests/inputs/does_not_exist.fasta")).unwrap_err();
+        assert!(matches!(err.kind, FastaParseErrorKind::IOError));
+        let source = err.source().unwrap();
+        assert!(source.downcast_ref::<std::io::Error>().is_some());
+
+        let err = NearestNeighborError::from(std::io::Error::new(std::io::ErrorKind::NotFound, "missing"));
+        assert_eq!(err.source().unwrap().to_string(), "missing");
+        assert!(NearestNeighborError::EmptyDatabase("q".to_owned()).source().is_none());
+    }
+
+    #[test]
+    fn test_crlf_line_endings() {
+        let test_dir = PathBuf::from("tests/inputs/crlf/");
+        let records = parse_all_records(test_dir.join("seqs.fasta")).unwrap();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0].id(), "seq_1");
+        assert_eq!(records[0].desc(), Some("first record"));
+        assert_eq!(records[0].seq(), b"ACGTAC");
+        assert_eq!(records[1].id(), "seq_2");
+        assert_eq!(records[1].desc(), None);
+
+        assert_eq!(parse_record_ids(&test_dir.join("ids.txt")).unwrap(), ["seq_1", "seq_2"]);
+    }
+
+    #[test]
+    fn test_parse_multi_duplicates() {
+        let fasta_path = PathBuf::from("tests/inputs/query_db/seqs.fasta");
+        let records = parse_all_records(fasta_path.clone()).unwrap();
+        let paths = [fasta_path.clone(), fasta_path];
+
+        let err = parse_all_records_multi(&paths, InputFormat::Auto, DuplicateIdPolicy::Error, false, &[]).unwrap_err();
+        assert!(matches!(&err.kind, FastaParseErrorKind::DuplicateId(ids) if ids == &["query_1"]));
+
+        let merged = parse_all_records_multi(&paths, InputFormat::Auto, DuplicateIdPolicy::Skip, false, &[]).unwrap();
+        assert_eq!(merged.len(), records.len());
+        for (x, y) in merged.iter().zip(records.iter()) {
+            assert_eq!(x.id(), y.id());
+            assert_eq!(x.seq(), y.seq());
+        }
+    }
+
+    #[test]
+    fn test_input_format_detection() {
+        let paths = [
+            PathBuf::from("tests/inputs/simple_stockholm.sto"),
+            PathBuf::from("tests/inputs/simple_test_2.fasta"),
+        ];
+        assert_eq!(InputFormat::Auto.resolve(&paths[0]), InputFormat::Stockholm);
+        assert_eq!(InputFormat::Auto.resolve(&PathBuf::from("family.STK")), InputFormat::Stockholm);
+        assert_eq!(InputFormat::Auto.resolve(&paths[1]), InputFormat::Fasta);
+        assert_eq!(InputFormat::Stockholm.resolve(&paths[1]), InputFormat::Stockholm);
+        assert_eq!(InputFormat::Auto.resolve(&PathBuf::from("tests/inputs/interleaved.phy")), InputFormat::Phylip);
+        assert_eq!(InputFormat::Auto.resolve(&PathBuf::from("tests/inputs/clustal_omega.aln")), InputFormat::Clustal);
+        assert_eq!(InputFormat::Auto.resolve(&PathBuf::from("missing.phy")), InputFormat::Phylip);
+
+        let records = parse_all_records_multi(&paths, InputFormat::Auto, DuplicateIdPolicy::Error, true, &[]).unwrap();
+        assert_eq!(records[0].id(), "seq_1");
+        assert_eq!(records[0].seq(), b"ACGT-ACGTACCGG--");
+    }
+
+    #[test]
+    fn test_parse_fastq() {
+        let dir = PathBuf::from("tests/inputs/fastq");
+        let parse = |name: &str, format: InputFormat| {
+            parse_all_records_multi(&[dir.join(name)], format, DuplicateIdPolicy::Error, false, &[])
+        };
+        let fasta = parse("aligned_reads.fasta", InputFormat::Auto).unwrap();
+        for (name, format) in [
+            ("aligned_reads.fastq", InputFormat::Auto),
+            ("aligned_reads.fastq.gz", InputFormat::Auto),
+            ("aligned_reads.fastq", InputFormat::Fastq),
+        ] {
+            let fastq = parse(name, format).unwrap();
+            assert_eq!(fastq.len(), fasta.len(), "{}", name);
+            for (fastq_record, fasta_record) in fastq.iter().zip(fasta.iter()) {
+                assert_eq!(fastq_record.id(), fasta_record.id());
+                assert_eq!(fastq_record.desc(), fasta_record.desc());
+                assert_eq!(fastq_record.seq(), fasta_record.seq());
+            }
+        }
+        assert_eq!(InputFormat::Auto.resolve(&PathBuf::from("missing.fq.gz")), InputFormat::Fastq);
+
+        let err = parse("duplicate_reads.fastq", InputFormat::Auto).unwrap_err();
+        assert!(matches!(err.kind, FastaParseErrorKind::DuplicateId(_)), "{:?}", err);
+        let err = parse("ragged_reads.fastq", InputFormat::Auto).unwrap_err();
+        assert!(matches!(err.kind, FastaParseErrorKind::LengthMismatch), "{:?}", err);
+        // A FASTA file isn't FASTQ.
+        assert!(parse("aligned_reads.fasta", InputFormat::Fastq).is_err());
+    }
+
+    #[test]
+    fn test_parse_directory_of_files() {
+        let dir = std::env::temp_dir().join("aligned_nearest_neighbor_test_fasta_dir");
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("chr2.fa"), ">c\nACGT\n").unwrap();
+        std::fs::write(dir.join("chr1.fa"), ">a\nACGT\n>b\nACGA\n").unwrap();
+        std::fs::write(dir.join("notes.txt"), "not a FASTA file\n").unwrap();
+
+        // The matching files of a directory come in name order, wherever the directory is in the paths.
+        let pattern = glob::Pattern::new("*.fa").unwrap();
+        let extra = PathBuf::from("tests/inputs/query_db/seqs.fasta");
+        let paths = expand_input_paths(&[extra.clone(), dir.clone()], &pattern).unwrap();
+        assert_eq!(paths, [extra, dir.join("chr1.fa"), dir.join("chr2.fa")]);
+        assert!(expand_input_paths(std::slice::from_ref(&dir), &glob::Pattern::new("*.fasta").unwrap()).is_err());
+
+        let paths = expand_input_paths(std::slice::from_ref(&dir), &pattern).unwrap();
+        let records = parse_all_records_sourced(&paths, InputFormat::Auto, DuplicateIdPolicy::Error, false, &[]).unwrap();
+        let ids: Vec<(&str, &str)> = records.iter()
+            .map(|sourced| (sourced.record.id(), sourced.source.file_name().unwrap().to_str().unwrap()))
+            .collect();
+        assert_eq!(ids, [("a", "chr1.fa"), ("b", "chr1.fa"), ("c", "chr2.fa")]);
+
+        // The lengths and IDs are checked across the files, naming the file of the offending record.
+        std::fs::write(dir.join("chr2.fa"), ">c\nACGTA\n").unwrap();
+        let err = parse_all_records_multi(&paths, InputFormat::Auto, DuplicateIdPolicy::Error, false, &[]).unwrap_err();
+        assert!(matches!(err.kind, FastaParseErrorKind::LengthMismatch));
+        assert!(err.message.contains(&format!("Record c in {} has length 5", dir.join("chr2.fa").display())));
+        std::fs::write(dir.join("chr2.fa"), ">a\nACGT\n").unwrap();
+        let err = parse_all_records_multi(&paths, InputFormat::Auto, DuplicateIdPolicy::Error, false, &[]).unwrap_err();
+        assert!(matches!(&err.kind, FastaParseErrorKind::DuplicateId(ids) if ids == &["a"]));
+        assert!(err.message.contains("chr2.fa"));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_duplicate_ids_in_file() {
+        let fasta_path = PathBuf::from("tests/inputs/duplicate_ids.fasta");
+        let err = parse_all_records(fasta_path.clone()).unwrap_err();
+        assert!(matches!(&err.kind, FastaParseErrorKind::DuplicateId(ids) if ids == &["seq_1"]));
+        assert_eq!(err.message, "Duplicate record IDs: seq_1");
+
+        let paths = [fasta_path];
+        let err = parse_all_records_multi(&paths, InputFormat::Auto, DuplicateIdPolicy::Error, false, &[]).unwrap_err();
+        assert!(matches!(err.kind, FastaParseErrorKind::DuplicateId(_)));
+        assert_eq!(parse_all_records_multi(&paths, InputFormat::Auto, DuplicateIdPolicy::Skip, false, &[]).unwrap().len(), 1);
+        let records = parse_all_records_multi(&paths, InputFormat::Auto, DuplicateIdPolicy::Allow, false, &[]).unwrap();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[1].seq(), b"ACGTTT");
+    }
+
+    #[test]
+    fn test_ragged_records() {
+        let paths = [PathBuf::from("tests/inputs/ragged.fasta")];
+        let err = parse_all_records_multi(&paths, InputFormat::Auto, DuplicateIdPolicy::Error, false, &[]).unwrap_err();
+        assert!(matches!(err.kind, FastaParseErrorKind::LengthMismatch));
+
+        let records = parse_all_records_multi(&paths, InputFormat::Auto, DuplicateIdPolicy::Error, true, &[]).unwrap();
+        let seqs: Vec<&[u8]> = records.iter().map(|record| record.seq()).collect();
+        assert_eq!(seqs, [&b"ACGTACGT"[..], b"ACGT----", b"AC-TAC--"]);
+        assert_eq!(records[1].desc(), Some("fragment"));
+    }
+
+    #[test]
+    fn test_check_record_symbols() {
+        let records = vec![
+            Record::with_attrs("a", None, b"AC-GT.*\r"),
+            Record::with_attrs("b", None, b"ACG?T~*"),
+        ];
+        let err = check_record_symbols(records.clone(), &[]).unwrap_err();
+        assert!(matches!(err.kind, FastaParseErrorKind::InvalidSymbol));
+        assert_eq!(err.message, "Record b has the invalid symbol '?' (byte 0x3f) at column 4");
+
+        let checked = check_record_symbols(records, b"?~").unwrap();
+        assert_eq!(checked[0].seq(), b"AC-GT.*");
+        assert_eq!(checked[1].seq(), b"ACG?T~*");
+    }
+
+    #[test]
+    fn test_pad_records_to_max_length() {
+        let records = vec![Record::with_attrs("a", None, b"AC"), Record::with_attrs("b", None, b"ACG")];
+        let padded = pad_records_to_max_length(records);
+        assert_eq!(padded[0].seq(), b"AC-");
+        assert_eq!(padded[1].seq(), b"ACG");
+        assert!(pad_records_to_max_length(vec![]).is_empty());
+    }
+
+    #[test]
+    fn test_sanitize_record_headers() {
+        let records = || vec![
+            Record::with_attrs("a\tb", Some("line\r\nbreak"), b"A"),
+            Record::with_attrs("c", Some("tab\there"), b"A"),
+            Record::with_attrs("d", Some("plain"), b"A"),
+        ];
+        let headers = |records: Vec<Record>| -> Vec<(String, Option<String>)> {
+            records.iter().map(|record| (record.id().to_owned(), record.desc().map(str::to_owned))).collect()
+        };
+        assert_eq!(headers(sanitize_record_headers(records(), BadIdPolicy::Escape).unwrap()), [
+            ("a\\tb".to_owned(), Some("line\\r\\nbreak".to_owned())),
+            ("c".to_owned(), Some("tab\\there".to_owned())),
+            ("d".to_owned(), Some("plain".to_owned())),
+        ]);
+        assert_eq!(headers(sanitize_record_headers(records(), BadIdPolicy::Replace).unwrap()), [
+            ("a_b".to_owned(), Some("line__break".to_owned())),
+            ("c".to_owned(), Some("tab_here".to_owned())),
+            ("d".to_owned(), Some("plain".to_owned())),
+        ]);
+        let err = sanitize_record_headers(records(), BadIdPolicy::Error).unwrap_err();
+        assert!(matches!(err.kind, FastaParseErrorKind::BadId));
+        assert!(err.message.starts_with("Record a\\tb (index 0)"));
+
+        // A backslash is escaped too, so that an escaped tab and a literal `\t` stay apart.
+        let records = vec![
+            Record::with_attrs("a\tb", Some("x\\ty"), b"A"),
+            Record::with_attrs("a\\tb", Some("back\\slash"), b"A"),
+            Record::with_attrs("c", Some("trailing\\"), b"A"),
+        ];
+        let escaped = headers(sanitize_record_headers(records.clone(), BadIdPolicy::Escape).unwrap());
+        assert_eq!(escaped, [
+            ("a\\tb".to_owned(), Some("x\\\\ty".to_owned())),
+            ("a\\\\tb".to_owned(), Some("back\\\\slash".to_owned())),
+            ("c".to_owned(), Some("trailing\\\\".to_owned())),
+        ]);
+        let unescaped: Vec<(String, Option<String>)> = escaped.iter()
+            .map(|(id, desc)| (unescape_tsv_field(id), desc.as_deref().map(unescape_tsv_field)))
+            .collect();
+        assert_eq!(unescaped, headers(records.clone()));
+        // The other policies leave the backslashes alone.
+        let replaced = headers(sanitize_record_headers(records, BadIdPolicy::Replace).unwrap());
+        assert_eq!(replaced[1], ("a\\tb".to_owned(), Some("back\\slash".to_owned())));
+        assert_eq!(unescape_tsv_field("odd\\q\\"), "odd\\q\\");
+    }
+
+    #[test]
+    fn test_normalize_record_ids() {
+        let ids = |records: &[Record]| -> Vec<String> { records.iter().map(|record| record.id().to_owned()).collect() };
+        let records = parse_all_records(PathBuf::from("tests/inputs/ncbi_ids.fasta")).unwrap();
+
+        let accession = IdNormalizer::Split { delimiter: '|', field: 4 };
+        let normalized = normalize_record_ids(records.clone(), &accession).unwrap();
+        assert_eq!(ids(&normalized), ["NR_000001.1", "NR_000002.1", "NR_000003.1"]);
+        assert_eq!(normalized[0].desc(), Some("16S ribosomal RNA"));
+        assert_eq!(normalized[0].seq(), b"ACGTACGT");
+
+        let gi = IdNormalizer::Regex(regex::Regex::new(r"^gi\|(\d+)\|").unwrap());
+        assert_eq!(ids(&normalize_record_ids(records.clone(), &gi).unwrap()), ["12345", "12346", "12347"]);
+
+        // The IDs without the part to keep are kept whole.
+        let missing_field = IdNormalizer::Split { delimiter: '|', field: 9 };
+        assert_eq!(ids(&normalize_record_ids(records.clone(), &missing_field).unwrap()), ids(&records));
+        // As are those whose field is empty, rather than shortened to an empty ID.
+        let empty_field = vec![Record::with_attrs("gi|12345||", None, b"ACGT"), Record::with_attrs("gi|12346|ref|NR_1|", None, b"ACGT")];
+        assert_eq!(ids(&normalize_record_ids(empty_field, &IdNormalizer::Split { delimiter: '|', field: 3 }).unwrap()), ["gi|12345||", "ref"]);
+        assert_eq!(IdNormalizer::Split { delimiter: '|', field: 4 }.normalize("gi|12345||"), None);
+
+        // All the IDs share their first field.
+        let err = normalize_record_ids(records, &IdNormalizer::Split { delimiter: '|', field: 1 }).unwrap_err();
+        assert!(matches!(&err.kind, FastaParseErrorKind::DuplicateId(ids) if ids == &["gi"]));
+    }
+
+    #[test]
+    fn test_detect_duplicate_ids() {
+        let records = [
+            Record::with_attrs("a", None, b"A"),
+            Record::with_attrs("b", None, b"A"),
+            Record::with_attrs("b", None, b"A"),
+            Record::with_attrs("a", None, b"A"),
+            Record::with_attrs("b", None, b"A"),
+        ];
+        assert_eq!(detect_duplicate_ids(&records), ["b", "a"]);
+        assert!(detect_duplicate_ids(&records[..2]).is_empty());
+    }
+
+    #[test]
+    fn test_deduplicate_by_sequence() {
+        let records = vec![
+            Record::with_attrs("A", None, b"AC-GT"),
+            Record::with_attrs("B", None, b"AC-GT"),
+            Record::with_attrs("C", None, b"AC-GT"),
+        ];
+        let unique = deduplicate_by_sequence(records);
+        assert_eq!(unique.len(), 1);
+        assert_eq!(unique[0].id(), "A");
+
+        // The first occurrences keep their order; a different gap placement is a different sequence.
+        let records = vec![
+            Record::with_attrs("x", None, b"ACG-T"),
+            Record::with_attrs("y", None, b"AC-GT"),
+            Record::with_attrs("z", None, b"ACG-T"),
+        ];
+        let ids: Vec<String> = deduplicate_by_sequence(records).iter().map(|record| record.id().to_owned()).collect();
+        assert_eq!(ids, ["x", "y"]);
+    }
 }
@@ -1,25 +1,247 @@
 use std::{
+    collections::HashSet,
     process::exit,
-    path::{PathBuf},
+    sync::Arc,
+    path::{Path, PathBuf},
+    ffi::OsString,
+    fs::File,
+    io::{self, Write, BufWriter, IsTerminal},
+    time::{Duration, Instant},
 };
-use clap::Parser;
+use clap::{error::ErrorKind, parser::ValueSource, ArgAction, ArgMatches, Parser, Subcommand, Args, CommandFactory, ValueEnum};
+use tracing::{info, warn, error};
+use tracing_subscriber::{fmt::format::FmtSpan, EnvFilter};
+use bio::io::fasta::{Record, Writer as FastaWriter};
+use regex::Regex;
 
 use aligned_nearest_neighbor::{
-    parse_all_records, parse_record_ids,
-    nearest_neighbor::compute_store_nearest_neighbors,
+    deduplicate_by_sequence, expand_input_paths, normalize_record_ids, parse_all_records_multi, parse_record_ids,
+    sanitize_record_headers, BadIdPolicy, DuplicateIdPolicy, IdNormalizer, InputFormat,
+    cancel::CancellationToken,
+    checkpoint::{compute_store_nearest_neighbors_checkpointed, DEFAULT_CHECKPOINT_INTERVAL},
+    nearest_neighbor::{
+        compute_store_nearest_neighbors, compute_nearest_neighbors, compute_top_k_neighbors, filter_records,
+        filter_records_excluding,
+        ComparisonConfig, NeighborConfig, NearestNeighborError, RecordSelector, GAP,
+    },
+    columns::{
+        compute_column_stats, compute_consensus, compute_conservation_scores, consensus_record, read_column_weights,
+        trim_records, trimmed_columns, write_column_map, write_column_stats, write_conservation_scores, CONSENSUS_ID,
+    },
+    pairs::{compute_pairs, parse_pairs_file, write_pairs},
+    pfm::{compute_pfm_neighbors, parse_pfm_from_jaspar},
+    output::{
+        create_output, is_stdout, write_atomically, write_fasta_wrapped, write_results, Compression, IdentitySummaryOutput,
+        MismatchOutput, NearestNeighborResultRow, OutputConfig, OutputFormat, SortKey, DEFAULT_FLUSH_INTERVAL,
+        DEFAULT_PRECISION, DEFAULT_ROW_GROUP_SIZE,
+    },
+    progress::ProgressMode,
+    telemetry::RunSummary,
+    sample::{RecordSample, SampleSize},
+    shard::{merge_shards, Shard},
+    labels::{annotate_labels, parse_labels, summarize_labels, write_label_summary, write_labeled_results},
+    classify::{classify_query, write_classifications, Classification},
+    stats::{
+        compute_record_stats, summarize_alignment, write_record_stats, DEFAULT_BIN_WIDTH, DEFAULT_PERCENTILES,
+        DEFAULT_SAMPLE_PAIRS, Normalization,
+    },
+    streaming::compute_store_nearest_neighbors_streaming,
+    matrix::{
+        compute_identity_matrix, export_upper_triangle, group_statistics_from_matrix, load_identity_matrix,
+        write_group_statistics, write_identity_matrix,
+    },
+    validate::{detect_alphabet, normalize_gaps, normalize_uracil, validate_inputs, Alphabet},
+    distance::{Metric, SearchMode},
+    score_matrix::{BuiltinMatrix, ScoreMatrix},
+    match_matrix::MatchMatrix,
+    gaps::GapChars,
+    graph::{write_graph_file, GraphFormat, GraphOutput},
+    tree::{identity_distances, neighbor_joining, write_newick},
 };
 
+
+/// The exit code of a search cancelled with Ctrl-C, as for a shell command interrupted by SIGINT.
+const CANCELLED_EXIT_CODE: i32 = 130;
+
+
+/// Nearest neighbors and statistics of pre-aligned sequences, by percent identity.
+///
+/// Subcommands:
+///   nn         Report the nearest neighbor(s) of each query (the default when no subcommand is given).
+///   matrix     Compute the all-vs-all identity matrix of a set of records.
+///   pairs      Compute the identity of an explicit list of record pairs.
+///   stats      Summarize the alignment, or report per-record or per-column statistics.
+///   consensus  Compute the consensus sequence of a set of records.
+///   trim       Drop mostly-gap columns and write the trimmed alignment.
+///   merge      Merge the outputs of an `nn --shard` run.
+///   validate   Check the inputs of a run without computing anything.
 #[derive(Parser, Debug)]
-#[command(author, version, about, long_about = None)]
-struct Args {
-    /// The path to the aligned multi-FASTA file.
+#[command(author, version, about, long_about, verbatim_doc_comment)]
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
+
+    /// The minimum level of log messages to show: error, warn, info, debug or trace.
+    /// The `RUST_LOG` environment variable takes precedence when set.
+    #[arg(long, value_name = "LEVEL", global = true, default_value = "info")]
+    log_level: String,
+
+    /// Only show the errors, as --log-level error does.
+    #[arg(long, global = true, conflicts_with_all = ["verbose", "log_level"])]
+    quiet: bool,
+
+    /// Show more log messages. Repeat for more: -v adds the debug messages, such as the number of records
+    /// selected by the ID files, and -vv the trace messages, such as each database chunk read with
+    /// --db-chunk-records.
+    #[arg(short, long, global = true, action = clap::ArgAction::Count, conflicts_with = "log_level")]
+    verbose: u8,
+
+    /// The format of the log messages. Both report the duration of each phase of a run, `parse_fasta`,
+    /// `filter_records`, `compute` and `write_output`, when it ends.
+    #[arg(long, value_enum, global = true, default_value_t = LogFormat::Pretty)]
+    log_format: LogFormat,
+
+    /// What to do when a record ID appears more than once in the --input-fasta files.
+    #[arg(long, value_enum, global = true, default_value_t = DuplicateIdPolicy::Error)]
+    on_duplicate_id: DuplicateIdPolicy,
+
+    /// Keep all the records sharing an ID, with a warning, rather than fail: the same as --on-duplicate-id allow.
+    /// The records are looked up by ID, so only the first record of each ID can be selected by an ID file.
+    #[arg(long, global = true, conflicts_with = "on_duplicate_id")]
+    allow_duplicate_ids: bool,
+
+    /// The files read from an --input-fasta directory: those whose name matches this glob pattern, e.g. '*.fa',
+    /// in name order.
+    #[arg(long, value_name = "PATTERN", global = true, default_value = "*", value_parser = glob::Pattern::new)]
+    fasta_glob: glob::Pattern,
+
+    /// The format of the --input-fasta files. `auto` detects the format of each file from its first non-blank
+    /// line, or else from its extension. The `.` gaps of the Stockholm files are read as `-`, and the quality
+    /// lines of the FASTQ files are discarded. The FASTA and FASTQ files ending in `.gz` are decompressed.
+    #[arg(long, value_enum, global = true, default_value_t = InputFormat::Auto)]
+    input_format: InputFormat,
+
+    /// Accept records of different lengths, padding the shorter ones with gaps at their end to the length
+    /// of the longest. The padding is compared as any gap, so it lowers the identity of the padded records
+    /// against the longer ones: their identity is computed over the padded length.
+    #[arg(long, global = true)]
+    allow_ragged: bool,
+
+    /// What to do with a record ID or description holding a tab, newline or carriage return, which would
+    /// break the TSV rows: escape them as `\t`, `\n` and `\r`, and each backslash as `\\`, fail, or replace them
+    /// with an underscore.
+    #[arg(long, value_enum, global = true, default_value_t = BadIdPolicy::Escape)]
+    on_bad_id: BadIdPolicy,
+
+    /// Also accept these symbols in the sequences, e.g. `?~` or `0123`. The input is otherwise rejected if a
+    /// sequence holds any symbol but a letter, `*`, `-` or `.`, such as a digit. The --gap-chars of `nn` are
+    /// always accepted.
+    #[arg(long, value_name = "CHARS", global = true, default_value = "")]
+    allow_chars: String,
+
+    /// Shorten each record ID right after parsing to one of its fields separated by this character, e.g. `|`
+    /// for the NCBI-style IDs `gi|12345|ref|NR_000001.1|`, see --id-field. The ID files, the labels and the
+    /// outputs all use the shortened IDs.
+    #[arg(long, value_name = "CHAR", global = true, conflicts_with = "id_regex")]
+    id_split_char: Option<char>,
+
+    /// With --id-split-char, the 1-based field kept as the ID, e.g. 4 for the accession of
+    /// `gi|12345|ref|NR_000001.1|`.
+    #[arg(long, value_name = "N", global = true, default_value_t = 1, requires = "id_split_char")]
+    id_field: usize,
+
+    /// Shorten each record ID right after parsing to the text captured by the single capture group of this
+    /// regular expression, e.g. `ref\|([^|]+)\|`. The IDs it doesn't match are kept whole.
+    #[arg(long, value_name = "PATTERN", global = true)]
+    id_regex: Option<String>,
+
+    /// Read the options of the subcommand from this TOML file, whose keys are the option names with underscores,
+    /// e.g. `num_workers = 4` for --num-workers 4, `leave_one_out = true` for --leave-one-out, or
+    /// `input_fasta = ["a.fasta", "b.fasta"]` for a repeated option. The options given on the command line
+    /// override those of the file. See `example_config.toml`.
+    #[arg(long, value_name = "FILE", global = true)]
+    config: Option<PathBuf>,
+}
+
+
+/// The format of the log messages on stderr.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum LogFormat {
+    /// Human-readable lines.
+    Pretty,
+    /// One JSON object per line, with the fields of the event and of its spans, for log collectors.
+    Json,
+}
+
+
+/// How the --input-fasta files are parsed, set by the global options of [`Cli`].
+#[derive(Debug, Clone)]
+struct InputOptions {
+    fasta_glob: glob::Pattern,
+    format: InputFormat,
+    on_duplicate: DuplicateIdPolicy,
+    allow_ragged: bool,
+    on_bad_id: BadIdPolicy,
+    id_normalizer: Option<IdNormalizer>,
+    /// The symbols accepted in the sequences on top of the residues and gaps.
+    allowed_chars: Vec<u8>,
+}
+
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Report the nearest neighbor of each query record (the default when no subcommand is given).
+    Nn(Box<NnArgs>),
+
+    /// Compute the majority-rule consensus of a set of aligned records.
+    Consensus(ConsensusArgs),
+
+    /// Compute the identity of an explicit list of (query, database) record pairs.
+    Pairs(PairsArgs),
+
+    /// Report alignment statistics without computing nearest neighbors.
+    /// Without --per-record, --per-column, --conservation or --consensus-out, summarize the alignment: record count,
+    /// alignment length, column gap fractions, mean pairwise identity over --sample-pairs random pairs,
+    /// and GC content. The summary is printed to stdout, or written as JSON to --out-path.
+    Stats(StatsArgs),
+
+    /// Drop all-gap and mostly-gap columns, and write the trimmed alignment as FASTA.
+    Trim(TrimArgs),
+
+    /// Merge the outputs of an `nn --shard` run back into one file, in the original query order.
+    Merge(MergeArgs),
+
+    /// Compute the all-vs-all identity matrix of a set of aligned records.
+    Matrix(MatrixArgs),
+
+    /// Dry run: check that the inputs of an `nn` run are parseable and consistent, without computing anything.
+    /// All failed checks are listed, and the exit code is 0 only if every check passed.
+    /// Duplicate IDs are always reported, whatever --on-duplicate-id.
+    Validate(ValidateArgs),
+}
+
+
+#[derive(Args, Debug)]
+struct NnArgs {
+    /// The path to the aligned multi-FASTA file, or to a directory of them, see --fasta-glob. Repeat to combine
+    /// several files of the same alignment.
     #[arg(short, long, value_name = "FILE", required = true)]
-    input_fasta: PathBuf,
+    input_fasta: Vec<PathBuf>,
 
-    /// The path to output the result to. The result is a TSV-formatted table.
+    /// The path to output the result to. The result is a TSV-formatted table. Use `-` to write it to stdout, with
+    /// all the logs and the progress bar on stderr.
     #[arg(short, long, value_name = "FILE", required = true)]
     out_path: PathBuf,
 
+    /// Exit with an error, without searching, if --out-path already exists. By default, an existing output
+    /// is overwritten with a warning.
+    #[arg(long, required = false, conflicts_with = "overwrite")]
+    no_overwrite: bool,
+
+    /// Overwrite an existing --out-path without a warning.
+    #[arg(long, required = false)]
+    overwrite: bool,
+
     /// The number of worker threads to use.
     #[arg(short, long, value_name = "NUMBER", required = false, default_value_t = 1)]
     num_workers: usize,
@@ -33,72 +255,1917 @@ struct Args {
     /// If provided, restricts the subset of database to these IDs.
     #[arg(short, long, value_name = "FILE", required = false)]
     database_id_file: Option<PathBuf>,
+
+    /// An optional text file of record IDs, one per line, to leave out of the queries. It applies after
+    /// --query-id-file: the queries are the records listed there, or all the records without it, less the
+    /// records listed here, which win over --query-id-file for the IDs listed in both.
+    #[arg(long, value_name = "FILE", required = false)]
+    query_exclude_file: Option<PathBuf>,
+
+    /// An optional text file of record IDs, one per line, to leave out of the database. It applies after
+    /// --database-id-file: the database is the records listed there, or all the records without it, less the
+    /// records listed here, which win over --database-id-file for the IDs listed in both.
+    #[arg(long, value_name = "FILE", required = false)]
+    database_exclude_file: Option<PathBuf>,
+
+    /// Only use the records whose ID matches this regex as queries, e.g. `^QRY_`. The regex matches anywhere
+    /// in the ID unless anchored. Combined with --query-id-file, the queries are the listed records that match.
+    #[arg(long, value_name = "REGEX", required = false, value_parser = Regex::new)]
+    query_id_regex: Option<Regex>,
+
+    /// Only use the records whose ID matches this regex as the database, e.g. `^REF_`. The regex matches anywhere
+    /// in the ID unless anchored. Combined with --database-id-file, the database is the listed records that match.
+    #[arg(long, value_name = "REGEX", required = false, value_parser = Regex::new)]
+    db_id_regex: Option<Regex>,
+
+    /// Leave out of the queries the records whose fraction of gap columns is above F, with a warning: a mostly
+    /// gapped sequence carries little information, and its identities are unreliable.
+    #[arg(long, value_name = "F", required = false)]
+    max_query_gap_fraction: Option<f32>,
+
+    /// Leave out of the database the records whose fraction of gap columns is above F, with a warning.
+    #[arg(long, value_name = "F", required = false)]
+    max_db_gap_fraction: Option<f32>,
+
+    /// An optional two-column TSV file of `record_id<TAB>label`.
+    /// If provided, the query and neighbor labels are added to the output, along with whether they match.
+    #[arg(long, value_name = "FILE", required = false)]
+    labels: Option<PathBuf>,
+
+    /// An optional text file of database record IDs, one per line, allowed as neighbors, e.g. the records passing
+    /// a downstream quality filter. A query whose nearest neighbor isn't allowed gets the nearest allowed record.
+    /// Unlike --database-id-file, the other database records still count towards the consensus.
+    #[arg(long, value_name = "FILE", required = false, conflicts_with = "labels")]
+    allowed_neighbor_ids: Option<PathBuf>,
+
+    /// Exclude each query itself from its candidate neighbors.
+    #[arg(long, required = false)]
+    leave_one_out: bool,
+
+    /// Where to write the label accuracy summary. Defaults to stderr. Requires --labels.
+    #[arg(long, value_name = "FILE", required = false, requires = "labels")]
+    summary_path: Option<PathBuf>,
+
+    /// The number of nearest neighbors to report per query.
+    /// When greater than 1, the TSV output gains a fourth column with each neighbor's rank.
+    #[arg(long, value_name = "K", required = false, default_value_t = 1)]
+    top_k: usize,
+
+    /// Predict each query's label by majority vote over its --top-k nearest neighbors.
+    /// Requires --labels.
+    #[arg(long, required = false, requires = "labels")]
+    classify: bool,
+
+    /// With --classify, queries whose best identity is below this value are labeled `unclassified`.
+    #[arg(long, value_name = "IDENTITY", required = false, requires = "classify")]
+    min_identity: Option<f64>,
+
+    /// The measure reported in the third TSV column and used to rank the neighbors: the percent
+    /// identity, a distance corrected for multiple substitutions, in which case the nearest
+    /// neighbors are those with the smallest distance, or with --seq-type protein, the similarity
+    /// or the summed substitution score.
+    #[arg(long, value_enum, required = false, default_value_t = Metric::Identity)]
+    metric: Metric,
+
+    /// Report the nearest neighbors of each query, or with `farthest` its most divergent records: the
+    /// smallest identities, or the largest distances. Pairs without any compared column are skipped either way.
+    #[arg(long, value_enum, required = false, default_value_t = SearchMode::Nearest)]
+    mode: SearchMode,
+
+    /// The residue alphabet of the alignment: records with other residues are rejected before the search.
+    /// With `protein`, the columns are also scored with a substitution matrix, and the TSV output gains
+    /// the identity and the similarity (the fraction of columns with a positive score) as last columns.
+    #[arg(long, value_enum, value_name = "TYPE", required = false)]
+    seq_type: Option<Alphabet>,
+
+    /// With --seq-type protein, the built-in substitution matrix to score columns with. Defaults to blosum62.
+    #[arg(long, value_enum, value_name = "MATRIX", required = false, conflicts_with = "score_matrix_file")]
+    score_matrix: Option<BuiltinMatrix>,
+
+    /// With --seq-type protein, a substitution matrix file in the NCBI format, instead of --score-matrix.
+    #[arg(long, value_name = "FILE", required = false)]
+    score_matrix_file: Option<PathBuf>,
+
+    /// A square matrix of match scores between 0 and 1, replacing the exact-match test of the identity,
+    /// e.g. to treat `T` and `U` as identical or `N` as a half match: a header line of symbols, then one
+    /// line per symbol with its scores. Pairs with a symbol missing from the matrix match exactly.
+    #[arg(long, value_name = "FILE", required = false)]
+    match_matrix: Option<PathBuf>,
+
+    /// With --match-matrix, reject the alignment if a record holds a symbol missing from the matrix,
+    /// instead of matching such symbols exactly.
+    #[arg(long, required = false, requires = "match_matrix")]
+    strict_alphabet: bool,
+
+    /// The characters treated as gaps, e.g. `-.~`, or `?` for missing data. A column where both sequences
+    /// hold a gap character, not necessarily the same, is excluded from the identity. If the set holds `-`,
+    /// the other gap characters are replaced by `-` when the records are parsed.
+    #[arg(long, value_name = "CHARS", required = false, default_value = "-.")]
+    gap_chars: GapChars,
+
+    /// Replace `U` by `T` in every record when the records are parsed, so that RNA records match their DNA
+    /// counterparts. By default, `U` is only replaced in nucleotide alignments that hold both `U` and `T`.
+    #[arg(long, required = false)]
+    normalize_ut: bool,
+
+    /// A file of 1-based columns or column ranges such as `341-805`, separated by whitespace or commas:
+    /// only these columns are compared. Columns of the input alignment, before any trimming.
+    #[arg(long, value_name = "FILE", required = false, conflicts_with = "auto_trim")]
+    column_mask: Option<PathBuf>,
+
+    /// A file of one non-negative weight per alignment column, multiplying each column's contribution
+    /// to both the matches and the compared columns. Combines with --column-mask.
+    #[arg(long, value_name = "FILE", required = false, conflicts_with = "auto_trim")]
+    column_weights: Option<PathBuf>,
+
+    /// Exclude the columns where either sequence has an unknown residue (`N`, or `X` for proteins)
+    /// from the identity, as double gaps are, instead of counting them as mismatches.
+    #[arg(long, required = false)]
+    ignore_ambiguous: bool,
+
+    /// Count each run of columns where one of the two sequences is gapped as a single difference,
+    /// whatever its length, so that a long indel weighs like one substitution.
+    #[arg(long, required = false)]
+    gap_run_as_one: bool,
+
+    /// Comma-separated symbols, such as `N,X,?`, to exclude from the identity wherever either sequence
+    /// holds one, as double gaps are, instead of counting them as mismatches. Letters match both cases.
+    #[arg(long, value_name = "CHARS", required = false, value_delimiter = ',')]
+    ignore_chars: Vec<char>,
+
+    /// With a distance --metric, the distance to report for saturated pairs, which are too divergent
+    /// for the correction to be defined. Defaults to `inf`.
+    #[arg(long, value_name = "DISTANCE", required = false)]
+    saturation_cap: Option<f64>,
+
+    /// Skip the pairs compared on fewer than N columns, i.e. the columns left after double gaps and any ignored
+    /// symbols, so that a short overlap can't give a spuriously high identity. A query without any pair left
+    /// fails the search. 0 keeps all pairs.
+    #[arg(long, value_name = "N", required = false, default_value_t = 0)]
+    min_overlap: usize,
+
+    /// Skip the pairs where the neighbor holds a residue in less than a fraction F of the query's residue
+    /// columns, so that a neighbor covering a short part of the query can't win over one covering all of it.
+    /// The pairs are skipped during the search, as with --min-overlap. 0 keeps all pairs.
+    #[arg(long, value_name = "F", required = false, default_value_t = 0.0)]
+    min_query_coverage: f64,
+
+    /// Append the fraction of the query's residue columns where the neighbor also holds a residue to each
+    /// TSV row, as the last column.
+    #[arg(long, required = false, conflicts_with = "labels")]
+    with_query_coverage: bool,
+
+    /// Add the majority-rule consensus of the database records to the database, with ID `consensus`.
+    #[arg(long, required = false)]
+    include_consensus: bool,
+
+    /// Keep only the first record of each distinct aligned sequence, with a warning for each dropped record.
+    #[arg(long, required = false)]
+    deduplicate: bool,
+
+    /// Search the neighbors of each distinct query sequence once, and report them for every query of that
+    /// sequence. Unlike --deduplicate, every query keeps its rows. With --leave-one-out, the database records
+    /// of the same sequence as a query are also left out, as its copies share its neighbors.
+    #[arg(long, required = false, conflicts_with = "deduplicate")]
+    collapse_duplicates: bool,
+
+    /// Append the query's and neighbor's ungapped lengths to each TSV row.
+    #[arg(long, required = false)]
+    with_stats: bool,
+
+    /// Append the pair's transitions, transversions, columns where exactly one sequence is gapped and
+    /// columns excluded as double gaps to each output row, in every output format.
+    #[arg(long, required = false, conflicts_with = "labels")]
+    detailed_counts: bool,
+
+    /// Append the query's second-best neighbor, its identity and the margin from the best identity to each
+    /// TSV row, or `NA` when there is no second neighbor. Ties are broken as for the best neighbor.
+    #[arg(long, required = false, conflicts_with = "labels")]
+    with_runner_up: bool,
+
+    /// Append the rank of the neighbor among all the database records to each TSV row, 1 for the nearest,
+    /// with tied records sharing a rank: e.g. to measure how often an approximate search finds the true
+    /// nearest neighbor. Each query is compared again against the whole database, which doubles the run time
+    /// of an exact search.
+    #[arg(long, required = false, conflicts_with = "labels")]
+    output_rank: bool,
+
+    /// Append the pair's alignment coverage to each TSV row: `alignment_length, informative_sites, query_gaps,
+    /// neighbor_gaps, shared_gaps, mismatches`, counted over all columns, where the gap columns are those
+    /// gapped in the query only, in the neighbor only, or in both.
+    #[arg(long, required = false, conflicts_with = "labels")]
+    extended_output: bool,
+
+    /// Append the pair's `transitions, transversions, ts_tv_ratio` to each TSV row, after the --extended-output
+    /// columns. Only substitutions between unambiguous nucleotides count: gaps and `N` are left out.
+    /// The ratio is `NA` without any transversion.
+    #[arg(long, required = false, conflicts_with = "labels")]
+    ts_tv: bool,
+
+    /// Append the query's and neighbor's full FASTA header lines, the ID followed by the description, to each
+    /// TSV row, after the --ts-tv columns. The records are still looked up by ID, the part before the first space.
+    #[arg(long, required = false, conflicts_with = "labels")]
+    include_description: bool,
+
+    /// Append the `query_gc` and `neighbor_gc` columns to each TSV row, after the --include-description columns:
+    /// the fraction of G and C among the ungapped residues of each record, case-insensitive, or `NA` for an
+    /// all-gap record.
+    #[arg(long, required = false, conflicts_with = "labels")]
+    gc_content: bool,
+
+    /// Write the alignment columns where each query differs from its reported neighbors to this file,
+    /// as a long-format TSV table: `query_id, neighbor_id, column, query_char, neighbor_char, kind`,
+    /// where the kind is `mismatch`, or `gap` for a column gapped in only one of the sequences.
+    #[arg(long, value_name = "FILE", required = false, conflicts_with = "labels")]
+    emit_mismatch_positions: Option<PathBuf>,
+
+    /// With --emit-mismatch-positions, the maximum number of positions listed per pair. Longer lists
+    /// are truncated, and end with a row of kind `truncated`.
+    #[arg(long, value_name = "NUMBER", required = false, default_value_t = 1000, requires = "emit_mismatch_positions")]
+    max_positions: usize,
+
+    /// Also write the query-neighbor relation to this file as a graph, with a node per record and an
+    /// edge weighted by the identity per reported pair. With --labels, the nodes carry their label.
+    #[arg(long, value_name = "FILE", required = false, conflicts_with = "classify")]
+    graph_out: Option<PathBuf>,
+
+    /// Also write the aligned sequences of each reported pair to this multi-FASTA file, as two consecutive
+    /// records: `<query_id>|<rank>|query`, then `<query_id>|<rank>|neighbor`, each described by its own record ID.
+    #[arg(long, value_name = "FILE", required = false, conflicts_with = "labels")]
+    pairs_out: Option<PathBuf>,
+
+    /// The file format of --graph-out.
+    #[arg(long, value_enum, required = false, default_value_t = GraphFormat::EdgeList, requires = "graph_out")]
+    graph_format: GraphFormat,
+
+    /// With --graph-out, keep only the pairs of mutual neighbors, as undirected edges.
+    #[arg(long, required = false, requires = "graph_out")]
+    mutual: bool,
+
+    /// Also write the distribution of the best-hit identities across queries to this file, as TSV:
+    /// the count, mean, median, min, max and --percentiles, then a histogram. Queries without any hit
+    /// are counted separately.
+    #[arg(long, value_name = "FILE", required = false, conflicts_with = "labels")]
+    summary_out: Option<PathBuf>,
+
+    /// With --summary-out, the comma-separated percentiles to report.
+    #[arg(
+        long, value_name = "PERCENTS", required = false, value_delimiter = ',',
+        default_values_t = DEFAULT_PERCENTILES, requires = "summary_out",
+    )]
+    percentiles: Vec<f32>,
+
+    /// With --summary-out, the width of the histogram bins, as an identity fraction. Defaults to 0.5%.
+    #[arg(long, value_name = "WIDTH", required = false, default_value_t = DEFAULT_BIN_WIDTH, requires = "summary_out")]
+    bin_width: f64,
+
+    /// The output file format. `sqlite` and `parquet` require building with the feature of the same name.
+    /// `blast6` writes the 12 columns of BLAST tabular output, with an e-value of 0.0 and the percent
+    /// identity as bit score, since neither has a meaning here.
+    #[arg(long, alias = "output-format", value_enum, required = false, default_value_t = OutputFormat::Tsv)]
+    format: OutputFormat,
+
+    /// The number of decimal places of the identities, distances and ratios in the text outputs. The neighbors
+    /// are ranked and thresholded at full precision whatever this value.
+    #[arg(long, value_name = "DIGITS", required = false, default_value_t = DEFAULT_PRECISION)]
+    precision: usize,
+
+    /// The TSV and BLAST outputs in query order are written as the queries complete: flush them every this
+    /// many rows, so that the results of the completed queries can be followed during a long search.
+    #[arg(long, value_name = "ROWS", required = false, default_value_t = DEFAULT_FLUSH_INTERVAL)]
+    flush_interval: usize,
+
+    /// With --format parquet, the maximum number of rows of a row group. Only the rows of one row group are
+    /// held in memory before they are written.
+    #[arg(long, value_name = "ROWS", required = false, default_value_t = DEFAULT_ROW_GROUP_SIZE)]
+    row_group_size: usize,
+
+    /// Compress the TSV or BLAST output. Defaults to gzip for an --out-path ending in `.gz`, zstd for one
+    /// ending in `.zst`, and none otherwise.
+    #[arg(long, value_enum, value_name = "CODEC", required = false)]
+    compress: Option<Compression>,
+
+    /// Search the queries in batches, appending each batch to the TSV output and recording the progress in
+    /// this JSON file. If the file exists, as left by an interrupted run of the same search, the search resumes
+    /// after the recorded queries. The file is removed once the search completes.
+    #[arg(
+        long, value_name = "FILE", required = false,
+        conflicts_with_all = [
+            "labels", "graph_out", "summary_out", "emit_mismatch_positions", "pairs_out", "allowed_neighbor_ids",
+            "normalize",
+        ],
+    )]
+    checkpoint_file: Option<PathBuf>,
+
+    /// With --checkpoint-file, the number of queries between two checkpoints.
+    #[arg(
+        long, value_name = "NUMBER", required = false, default_value_t = DEFAULT_CHECKPOINT_INTERVAL,
+        requires = "checkpoint_file",
+    )]
+    checkpoint_interval: usize,
+
+    /// Write the identities, or distances, normalized over all the output rows instead: `zscore` writes their
+    /// number of standard deviations from the mean, and `minmax` their position from the smallest (0) to the
+    /// largest (1), so that searches over different databases can be compared. All the values are 0 if they are
+    /// equal. The rows are all held until the search completes, and the derived outputs, such as --summary-out,
+    /// also see the normalized values.
+    #[arg(long, value_enum, value_name = "METHOD", required = false, conflicts_with = "labels")]
+    normalize: Option<Normalization>,
+
+    /// The order of the output rows. Ties keep the query order, and the choice is recorded in --metadata-out.
+    #[arg(long, value_enum, required = false, default_value_t = SortKey::QueryOrder, conflicts_with = "labels")]
+    sort_by: SortKey,
+
+    /// Before searching, drop the columns whose gap fraction exceeds this value, as the `trim`
+    /// subcommand does. Without a value, only all-gap columns are dropped, which leaves identities unchanged.
+    #[arg(long, value_name = "MAX_GAP_FRACTION", required = false, num_args = 0..=1, default_missing_value = "1.0")]
+    auto_trim: Option<f32>,
+
+    /// Skip the columns holding the same residue in every record during the search, and add them back
+    /// to the counts of each pair. Identities are unchanged. All-gap columns are always skipped.
+    #[arg(long, required = false)]
+    compress_invariant: bool,
+
+    /// Stream the database from the input files in chunks of this many records, instead of loading
+    /// the whole alignment into memory. Only the queries are held in memory, so restrict them with
+    /// --query-id-file on large inputs.
+    #[arg(
+        long, value_name = "NUMBER", required = false,
+        conflicts_with_all = [
+            "labels", "include_consensus", "auto_trim", "compress_invariant", "column_mask", "column_weights",
+            "detailed_counts", "emit_mismatch_positions", "with_runner_up", "output_rank", "deduplicate", "graph_out",
+            "extended_output", "ts_tv", "include_description", "checkpoint_file", "pairs_out",
+            "gc_content", "query_exclude_file", "database_exclude_file", "query_id_regex", "db_id_regex", "sample_db",
+            "allowed_neighbor_ids", "max_query_gap_fraction", "max_db_gap_fraction", "normalize_ut",
+            "with_query_coverage", "collapse_duplicates",
+        ],
+    )]
+    db_chunk_records: Option<usize>,
+
+    /// Approximate search: only compute the exact identity against this many database records per query,
+    /// pre-selected by the MinHash similarity of their 5-mers. Requires the `minhash` feature, on by default.
+    #[arg(long, value_name = "C", required = false, conflicts_with = "db_chunk_records")]
+    approx_candidates: Option<usize>,
+
+    /// Also compare the reverse complement of each query, for nucleotide sequences that may be on either
+    /// strand, keeping the better strand of each pair. A `strand` column, `+` or `-`, is appended to each TSV
+    /// row after the --gc-content columns, and the pair's other columns describe the query on that strand.
+    #[arg(long, required = false, conflicts_with_all = ["db_chunk_records", "approx_candidates", "labels"])]
+    reverse_complement: bool,
+
+    /// Search the database records nearest to this position frequency matrix (PFM), in the JASPAR format,
+    /// instead of the neighbors of the queries: a profile of the A, C, G and T frequencies of each alignment
+    /// column, e.g. of a consensus pattern with ambiguous positions. The identity of a record is the mean
+    /// frequency of its residue over its non-gap columns. The rows are reported for a query named after the
+    /// file, e.g. `motif` for `motif.jaspar`.
+    #[arg(long, value_name = "FILE", required = false, conflicts_with_all = [
+        "labels", "db_chunk_records", "checkpoint_file", "shard", "reverse_complement", "approx_candidates",
+        "query_id_file", "query_exclude_file", "query_id_regex", "sample_queries", "collapse_duplicates",
+        "include_consensus",
+    ])]
+    query_pfm: Option<PathBuf>,
+
+    /// Don't show the progress bar.
+    #[arg(long, required = false, conflicts_with_all = ["progress_json", "progress"])]
+    no_progress: bool,
+
+    /// Instead of the progress bar, write `{"done": N, "total": M, "pct": 0.42}` JSON lines to stderr
+    /// at regular intervals.
+    #[arg(long, required = false, conflicts_with = "progress")]
+    progress_json: bool,
+
+    /// How to report progress, counted in compared pairs: `bar`, `none`, or `jsonl`
+    /// (the same as --progress-json).
+    #[arg(long, value_enum, value_name = "MODE", required = false)]
+    progress: Option<ProgressMode>,
+
+    /// The number of seconds between two JSON progress lines.
+    #[arg(long, value_name = "SECONDS", required = false, default_value_t = 1.0)]
+    progress_interval: f64,
+
+    /// Write a JSON summary of the run (pairs compared, wall time, pairs/s, peak RSS) to this file.
+    /// The summary is also logged at the end of the run.
+    #[arg(long, value_name = "FILE", required = false)]
+    metadata_out: Option<PathBuf>,
+
+    /// Only compute the queries of shard I out of N (0-based), i.e. every N-th query starting from the I-th,
+    /// and write them to the output path with a `.shard-I-of-N` suffix. Combine the outputs with `merge`.
+    /// The values of a shard can't be normalized over all the queries, so it can't be combined with --normalize.
+    #[arg(long, value_name = "I/N", required = false, conflicts_with = "normalize")]
+    shard: Option<Shard>,
+
+    /// Only compute the neighbors of a random sample of the queries, after the ID file, exclusion and regex
+    /// filters, for a quick look at the identity distribution: N queries, or a fraction such as 0.01.
+    /// With --shard, the sample is split across the shards.
+    #[arg(long, alias = "sample", value_name = "N|FRACTION", required = false)]
+    sample_queries: Option<SampleSize>,
+
+    /// Only search a random sample of the database records, after the ID file, exclusion and regex filters:
+    /// N records, or a fraction such as 0.1.
+    #[arg(long, value_name = "N|FRACTION", required = false)]
+    sample_db: Option<SampleSize>,
+
+    /// The seed of --sample-queries and --sample-db. The records are drawn from their sorted IDs, so the same
+    /// seed draws the same records whatever their order in the FASTA files.
+    #[arg(long, value_name = "S", required = false, default_value_t = 0)]
+    seed: u64,
+}
+
+
+#[derive(Args, Debug)]
+struct ConsensusArgs {
+    /// The path to the aligned multi-FASTA file, or to a directory of them, see --fasta-glob. Repeat to combine
+    /// several files of the same alignment.
+    #[arg(short, long, value_name = "FILE", required = true)]
+    input_fasta: Vec<PathBuf>,
+
+    /// The path to write the consensus FASTA record to. Defaults to stdout.
+    #[arg(short, long, value_name = "FILE", required = false)]
+    out_path: Option<PathBuf>,
+
+    /// An optional text file, listing out fasta record IDs -- one per line.
+    /// If provided, only these records contribute to the consensus.
+    #[arg(long, value_name = "FILE", required = false)]
+    id_file: Option<PathBuf>,
+
+    /// The record ID to give the consensus sequence.
+    #[arg(long, value_name = "NAME", required = false, default_value = "consensus")]
+    name: String,
+}
+
+
+#[derive(Args, Debug)]
+struct PairsArgs {
+    /// The path to the aligned multi-FASTA file, or to a directory of them, see --fasta-glob. Repeat to combine
+    /// several files of the same alignment.
+    #[arg(short, long, value_name = "FILE", required = true)]
+    input_fasta: Vec<PathBuf>,
+
+    /// A two-column TSV file of `query_id<TAB>db_id` pairs to compare.
+    #[arg(short, long, value_name = "FILE", required = true)]
+    pairs_file: PathBuf,
+
+    /// The path to output the result to. The result is a TSV-formatted table.
+    #[arg(short, long, value_name = "FILE", required = true)]
+    out_path: PathBuf,
+
+    /// The number of worker threads to use.
+    #[arg(short, long, value_name = "NUMBER", required = false, default_value_t = 1)]
+    num_workers: usize,
+
+    /// Write `NA` for pairs whose IDs are missing from the FASTA file, instead of failing.
+    #[arg(long, required = false)]
+    skip_missing: bool,
+
+    /// The number of decimal places of the identities.
+    #[arg(long, value_name = "DIGITS", required = false, default_value_t = DEFAULT_PRECISION)]
+    precision: usize,
+}
+
+
+#[derive(Args, Debug)]
+struct StatsArgs {
+    /// The path to the aligned multi-FASTA file, or to a directory of them, see --fasta-glob. Repeat to combine
+    /// several files of the same alignment.
+    #[arg(short, long, value_name = "FILE", required = true)]
+    input_fasta: Vec<PathBuf>,
+
+    /// The path to output the result to. Defaults to stdout.
+    #[arg(short, long, value_name = "FILE", required = false)]
+    out_path: Option<PathBuf>,
+
+    /// The number of worker threads to use.
+    #[arg(short, long, value_name = "NUMBER", required = false, default_value_t = 1)]
+    num_workers: usize,
+
+    /// An optional text file, listing out fasta record IDs -- one per line.
+    /// If provided, restricts the statistics to these records.
+    #[arg(long, value_name = "FILE", required = false)]
+    id_file: Option<PathBuf>,
+
+    /// Report a TSV of per-record statistics: ungapped length, gap fraction,
+    /// ambiguous base count and longest gap run.
+    #[arg(long, required = false, conflicts_with = "per_column")]
+    per_record: bool,
+
+    /// Report a TSV of per-column statistics: gap fraction, majority residue,
+    /// majority frequency and Shannon entropy.
+    #[arg(long, required = false, conflicts_with = "conservation")]
+    per_column: bool,
+
+    /// Report a TSV of per-column conservation scores, one minus the normalized entropy:
+    /// 1 for a fully conserved column, 0 for a column of equally frequent residues or only gaps.
+    #[arg(long, required = false, conflicts_with = "per_record")]
+    conservation: bool,
+
+    /// Write the consensus sequence of the selected records to this FASTA file.
+    #[arg(long, value_name = "FILE", required = false)]
+    consensus_out: Option<PathBuf>,
+
+    /// Columns whose majority residue has a lower frequency (among non-gap characters)
+    /// are written as `N` in the consensus.
+    #[arg(long, value_name = "FRACTION", required = false, default_value_t = 0.0)]
+    consensus_threshold: f32,
+
+    /// The number of random pairs behind the mean pairwise identity of the summary report.
+    #[arg(long, value_name = "N", required = false, default_value_t = DEFAULT_SAMPLE_PAIRS)]
+    sample_pairs: usize,
+}
+
+
+#[derive(Args, Debug)]
+struct TrimArgs {
+    /// The path to the aligned multi-FASTA file, or to a directory of them, see --fasta-glob. Repeat to combine
+    /// several files of the same alignment.
+    #[arg(short, long, value_name = "FILE", required = true)]
+    input_fasta: Vec<PathBuf>,
+
+    /// The path to write the trimmed FASTA file to.
+    #[arg(short, long, value_name = "FILE", required = true)]
+    out_path: PathBuf,
+
+    /// Columns with a larger fraction of gaps are dropped. All-gap columns are always dropped.
+    #[arg(long, value_name = "FRACTION", required = false, default_value_t = 1.0)]
+    max_gap_fraction: f32,
+
+    /// An optional path to write a TSV mapping of old to new (1-based) column indices.
+    /// Dropped columns are mapped to `NA`.
+    #[arg(long, value_name = "FILE", required = false)]
+    column_map: Option<PathBuf>,
+
+    /// The number of sequence characters per line. 0 writes each sequence on one line.
+    #[arg(long, value_name = "NUMBER", required = false, default_value_t = 60)]
+    line_width: usize,
+
+    /// The number of worker threads to use.
+    #[arg(short, long, value_name = "NUMBER", required = false, default_value_t = 1)]
+    num_workers: usize,
+}
+
+
+#[derive(Args, Debug)]
+struct MergeArgs {
+    /// The output of every shard, in shard order (shard 0 first).
+    #[arg(value_name = "SHARD_FILE", required = true)]
+    shard_files: Vec<PathBuf>,
+
+    /// The path to write the merged result to.
+    #[arg(short, long, value_name = "FILE", required = true)]
+    out_path: PathBuf,
+}
+
+
+#[derive(Args, Debug)]
+struct MatrixArgs {
+    /// The path to the aligned multi-FASTA file, or to a directory of them, see --fasta-glob. Repeat to combine
+    /// several files of the same alignment.
+    #[arg(short, long, value_name = "FILE", required_unless_present = "from_matrix")]
+    input_fasta: Vec<PathBuf>,
+
+    /// Load the identity matrix from this TSV file, as written by an earlier `matrix` run with or without
+    /// --upper-triangle, instead of computing it from --input-fasta.
+    #[arg(long, value_name = "FILE", required = false, conflicts_with_all = ["input_fasta", "id_file"])]
+    from_matrix: Option<PathBuf>,
+
+    /// The path to write the square TSV matrix to. Defaults to stdout.
+    #[arg(short, long, value_name = "FILE", required = false)]
+    out_path: Option<PathBuf>,
+
+    /// The number of worker threads to use.
+    #[arg(short, long, value_name = "NUMBER", required = false, default_value_t = 1)]
+    num_workers: usize,
+
+    /// An optional text file, listing out fasta record IDs -- one per line.
+    /// If provided, restricts the matrix to these records.
+    #[arg(long, value_name = "FILE", required = false)]
+    id_file: Option<PathBuf>,
+
+    /// Also build the neighbor-joining tree of the `1 - identity` distances, and write it to this file
+    /// in Newick format. The O(n³) joins are practical up to a few thousand records.
+    #[arg(long, value_name = "FILE", required = false)]
+    newick_out: Option<PathBuf>,
+
+    /// A two-column TSV of `record_id<TAB>group`, assigning records to groups for `--group-stats-out`.
+    #[arg(long, value_name = "FILE", required = false, requires = "group_stats_out")]
+    group_file: Option<PathBuf>,
+
+    /// Write the mean within-group and between-group identities of each pair of groups of `--group-file`,
+    /// and the ratio of the within-group means to the between-group mean, to this TSV file.
+    #[arg(long, value_name = "FILE", required = false, requires = "group_file")]
+    group_stats_out: Option<PathBuf>,
+
+    /// The number of decimal places of the identities in the matrix.
+    #[arg(long, value_name = "DIGITS", required = false, default_value_t = DEFAULT_PRECISION)]
+    precision: usize,
+
+    /// Only write the upper triangle of the matrix, without the diagonal, leaving the other cells empty.
+    #[arg(long, required = false)]
+    upper_triangle: bool,
+}
+
+
+#[derive(Args, Debug)]
+struct ValidateArgs {
+    /// The path to the aligned multi-FASTA file, or to a directory of them, see --fasta-glob. Repeat to combine
+    /// several files of the same alignment.
+    #[arg(short, long, value_name = "FILE", required = true)]
+    input_fasta: Vec<PathBuf>,
+
+    /// An optional text file of query record IDs, checked against the FASTA file.
+    #[arg(short, long, value_name = "FILE", required = false)]
+    query_id_file: Option<PathBuf>,
+
+    /// An optional text file of database record IDs, checked against the FASTA file.
+    #[arg(short, long, value_name = "FILE", required = false)]
+    database_id_file: Option<PathBuf>,
+
+    /// If given, check that every residue belongs to this alphabet (gaps are always allowed).
+    #[arg(long, value_enum)]
+    alphabet: Option<Alphabet>,
+}
+
+
+/// Insert the default `nn` subcommand when the first argument isn't a subcommand,
+/// so that invocations predating the subcommands keep working.
+fn cli_args() -> Vec<OsString> {
+    let mut args: Vec<OsString> = std::env::args_os().collect();
+    let needs_default = match args.get(1).and_then(|arg| arg.to_str()) {
+        None => true,
+        Some("-h" | "--help" | "-V" | "--version" | "help") => false,
+        Some(first) => !Cli::command()
+            .get_subcommands()
+            .any(|subcommand| subcommand.get_name() == first),
+    };
+    if needs_default {
+        args.insert(1, OsString::from("nn"));
+    }
+    with_config_file(args)
+}
+
+
+/// Insert the options of the `--config` file that the command line doesn't set right after the subcommand,
+/// so that the command line overrides the file.
+fn with_config_file(mut args: Vec<OsString>) -> Vec<OsString> {
+    // A lenient first parse finds the file, and the options set on the command line, even if the required
+    // options are only set by the file.
+    let Ok(matches) = Cli::command().ignore_errors(true).try_get_matches_from(&args) else {
+        return args;
+    };
+    let Some((name, sub_matches)) = matches.subcommand() else {
+        return args;
+    };
+    let Some(config_path) = sub_matches.get_one::<PathBuf>("config") else {
+        return args;
+    };
+    let mut command = Cli::command();
+    command.build();
+    let subcommand = command.find_subcommand(name).expect("The subcommand was just parsed");
+    let config_args = config_file_args(config_path, subcommand, sub_matches).unwrap_or_else(|err| {
+        Cli::command()
+            .error(ErrorKind::InvalidValue, format!("Invalid config file {}: {}", config_path.display(), err))
+            .exit()
+    });
+    args.splice(2..2, config_args);
+    args
+}
+
+
+/// The options of a TOML config file, as command-line arguments of `subcommand`, leaving out those set
+/// on the command line, as found in `matches`.
+fn config_file_args(config_path: &Path, subcommand: &clap::Command, matches: &ArgMatches) -> Result<Vec<OsString>, String> {
+    let text = std::fs::read_to_string(config_path).map_err(|err| err.to_string())?;
+    let table: toml::Table = text.parse().map_err(|err: toml::de::Error| err.to_string())?;
+    let mut args: Vec<OsString> = vec![];
+    for (key, value) in table {
+        let arg = subcommand.get_arguments()
+            .find(|arg| arg.get_id() == key.as_str() && arg.get_long().is_some())
+            .ok_or_else(|| format!("unknown option `{}` for `{}`", key, subcommand.get_name()))?;
+        if matches.value_source(&key) == Some(ValueSource::CommandLine) {
+            continue;
+        }
+        let flag = format!("--{}", arg.get_long().unwrap_or_default());
+        let values = match value {
+            toml::Value::Array(values) => values,
+            value => vec![value],
+        };
+        for value in values {
+            match (arg.get_action(), value) {
+                (ArgAction::SetTrue, toml::Value::Boolean(set)) => {
+                    if set {
+                        args.push(OsString::from(&flag));
+                    }
+                }
+                (ArgAction::Count, toml::Value::Integer(count)) => {
+                    args.extend((0..count).map(|_| OsString::from(&flag)));
+                }
+                (ArgAction::SetTrue | ArgAction::Count, value) => {
+                    return Err(format!("expected a boolean or a count for `{}`, got {}", key, value));
+                }
+                (_, toml::Value::String(value)) => args.push(OsString::from(format!("{}={}", flag, value))),
+                (_, value @ (toml::Value::Integer(_) | toml::Value::Float(_) | toml::Value::Boolean(_))) => {
+                    args.push(OsString::from(format!("{}={}", flag, value)));
+                }
+                (_, value) => return Err(format!("unsupported value for `{}`: {}", key, value)),
+            }
+        }
+    }
+    Ok(args)
+}
+
+
+/// The IDs of the records selected by `ids`, all of them if `None`, less those listed in `exclude_file`, see
+/// [`filter_records_excluding`]. Without an `exclude_file`, `ids` as they are.
+fn exclude_ids(
+    records: &[Record],
+    ids: Option<Vec<String>>,
+    exclude_file: Option<PathBuf>,
+    arg_name: &str,
+) -> Option<Vec<String>> {
+    let Some(fpath) = exclude_file else {
+        return ids;
+    };
+    let exclude = parse_record_ids(&fpath).unwrap_or_else(|e| {
+        error!("Error reading file {}: {}", fpath.display(), e);
+        exit(1);
+    });
+    let selected: Vec<String> = filter_records_excluding(records, ids.as_deref(), &exclude).iter()
+        .map(|record| record.id().to_owned())
+        .collect();
+    info!("Excluding {} from file: {} ({} entries, {} records left)", arg_name, fpath.display(), exclude.len(), selected.len());
+    Some(selected)
+}
+
+
+/// The filters of the queries or the database records beyond their IDs: an ID regex and a maximum gap fraction.
+fn record_filters(regex: Option<&Regex>, max_gap_fraction: Option<f32>) -> RecordSelector<'_> {
+    let mut selector = RecordSelector::All;
+    if let Some(regex) = regex {
+        selector = selector.and(RecordSelector::Regex(regex));
+    }
+    if let Some(max_gap_fraction) = max_gap_fraction {
+        selector = selector.and(RecordSelector::MaxGapFraction(max_gap_fraction));
+    }
+    selector
+}
+
+
+/// The IDs of the records selected by `ids`, all of them if `None`, that pass the `filters`, see
+/// [`record_filters`]. Without any filter, `ids` as they are.
+fn select_ids(records: &[Record], ids: Option<Vec<String>>, filters: RecordSelector<'_>, arg_name: &str) -> Option<Vec<String>> {
+    if matches!(filters, RecordSelector::All) {
+        return ids;
+    }
+    let selector = RecordSelector::ids(ids.as_deref()).and(filters);
+    let selected: Vec<String> = filter_records(records, &selector).iter()
+        .map(|record| record.id().to_owned())
+        .collect();
+    info!("Selected {} {} records passing the filters", selected.len(), arg_name);
+    Some(selected)
+}
+
+
+/// Exit with an error if no query, or no database record, is selected.
+fn check_selection_or_exit(records: &[Record], query_ids: Option<&[String]>, db_ids: Option<&[String]>) {
+    let query_records = filter_records(records, &RecordSelector::ids(query_ids));
+    let err = match query_records.first() {
+        None => NearestNeighborError::EmptyQuery,
+        Some(query) if filter_records(records, &RecordSelector::ids(db_ids)).is_empty() => {
+            NearestNeighborError::EmptyDatabase(query.id().to_owned())
+        }
+        Some(_) => return,
+    };
+    error!("Error while selecting the records. Reason: {}", err);
+    exit(1);
 }
 
 
 fn parse_id_file(id_file_path: Option<PathBuf>, arg_name: &str) -> Option<Vec<String>> {
     match id_file_path {
         None => {
-            println!("No file specified for {} -- the entire collection will be used.", arg_name);
+            info!("No file specified for {} -- the entire collection will be used.", arg_name);
             None
         },
         Some(fpath) => {
             let ids = parse_record_ids(&fpath).unwrap_or_else(|e| {
-                eprintln!("Error reading file {}: {}", fpath.display(), e);
+                error!("Error reading file {}: {}", fpath.display(), e);
                 exit(1);
             });
 
-            println!("Parsing {} from file: {} ({} entries)", arg_name, fpath.display(), ids.len());
+            info!("Parsing {} from file: {} ({} entries)", arg_name, fpath.display(), ids.len());
             Some(ids)
         }
     }
 }
 
 
-/// Read a multi-FASTA file, where all sequences have been pre-aligned (possibly with gaps).
-/// For each sequence, report the hamming-distance nearest neighbor, as well as statistics for each entry.
-fn main() {
-    let args = Args::parse();
-    let records = parse_all_records(args.input_fasta)
-        .unwrap_or_else(|err| {
-            eprintln!("Unable to parse FASTA file. Reason: {}", err.message);
-            exit(1)
-        });
-    if records.len() < 2 {
-        eprintln!("There must be at least two Fasta records.");
-        exit(1);
+/// Open the given output file, or stdout when no file is given.
+fn open_output_or_stdout(out_path: Option<&Path>) -> Box<dyn Write> {
+    match out_path {
+        None => Box::new(io::stdout().lock()),
+        Some(fpath) => {
+            let file = File::create(fpath).unwrap_or_else(|e| {
+                error!("Unable to create file {}: {}", fpath.display(), e);
+                exit(1);
+            });
+            Box::new(BufWriter::new(file))
+        }
     }
+}
 
-    let num_workers = args.num_workers;
-    println!("Number of workers = {}", num_workers);
-    // Set number of threads globally at the start of your program
-    rayon::ThreadPoolBuilder::new()
-        .num_threads(num_workers)
-        .build_global()
-        .unwrap_or_else(|err| {
-            eprintln!("Failed to build global thread pool. Reason: {}", err);
-            exit(1);
-        });
 
-    let query_record_ids: Option<Vec<String>> = parse_id_file(args.query_id_file, "query");
-    let db_record_ids: Option<Vec<String>> = parse_id_file(args.database_id_file, "database");
-    let out_tsv_path = args.out_path;
-    if out_tsv_path.exists() {
-        println!("The output file {} already exists. It will be overwritten!", out_tsv_path.display());
+/// The FASTA files of the --input-fasta paths, with the directories expanded, see [`expand_input_paths`].
+fn input_paths_or_exit(input_fasta: &[PathBuf], input: &InputOptions) -> Vec<PathBuf> {
+    let paths = expand_input_paths(input_fasta, &input.fasta_glob).unwrap_or_else(|err| {
+        error!("Unable to list the input FASTA files. Reason: {}", err);
+        exit(1);
+    });
+    if paths.len() != input_fasta.len() {
+        info!("Reading {} FASTA files", paths.len());
     }
-    let result = compute_store_nearest_neighbors(
-        records,
-        &out_tsv_path,
-        query_record_ids,
+    paths
+}
+
+
+fn parse_records_or_exit(input_fasta: &[PathBuf], input: InputOptions) -> Vec<Record> {
+    let input_fasta = input_paths_or_exit(input_fasta, &input);
+    parse_all_records_multi(&input_fasta, input.format, input.on_duplicate, input.allow_ragged, &input.allowed_chars)
+        .and_then(|records| match &input.id_normalizer {
+            Some(normalizer) => normalize_record_ids(records, normalizer),
+            None => Ok(records),
+        })
+        .and_then(|records| sanitize_record_headers(records, input.on_bad_id))
+        .unwrap_or_else(|err| {
+            error!("Unable to parse FASTA file. Reason: {}", err.message);
+            exit(1)
+        })
+}
+
+
+/// Read a multi-FASTA file, where all sequences have been pre-aligned (possibly with gaps), and run
+/// one of the subcommands listed in [`Cli`]. Without a subcommand, report the percent-identity
+/// nearest neighbor of each sequence, as `nn` does.
+fn main() {
+    let cli = Cli::parse_from(cli_args());
+    init_logging(log_level(&cli), cli.log_format);
+    if let Some(config_path) = &cli.config {
+        info!("Reading the options not given on the command line from {}", config_path.display());
+    }
+    let input = InputOptions {
+        fasta_glob: cli.fasta_glob.clone(),
+        format: cli.input_format,
+        on_duplicate: if cli.allow_duplicate_ids { DuplicateIdPolicy::Allow } else { cli.on_duplicate_id },
+        allow_ragged: cli.allow_ragged,
+        on_bad_id: cli.on_bad_id,
+        allowed_chars: cli.allow_chars.clone().into_bytes(),
+        id_normalizer: id_normalizer(&cli),
+    };
+    match cli.command {
+        Command::Nn(args) => run_nn(*args, input, install_cancel_handler()),
+        Command::Consensus(args) => run_consensus(args, input),
+        Command::Pairs(args) => run_pairs(args, input),
+        Command::Stats(args) => run_stats(args, input),
+        Command::Trim(args) => run_trim(args, input),
+        Command::Merge(args) => run_merge(args),
+        Command::Matrix(args) => run_matrix(args, input),
+        Command::Validate(args) => run_validate(args, input),
+    }
+}
+
+
+fn progress_mode(args: &NnArgs) -> ProgressMode {
+    if let Some(mode) = args.progress {
+        mode
+    } else if args.no_progress {
+        ProgressMode::Hidden
+    } else if args.progress_json {
+        ProgressMode::Json
+    } else {
+        ProgressMode::Bar
+    }
+}
+
+
+fn comparison_config(args: &NnArgs) -> ComparisonConfig {
+    let score_matrix = (args.seq_type == Some(Alphabet::Protein)).then(|| match &args.score_matrix_file {
+        Some(fpath) => ScoreMatrix::from_file(fpath).unwrap_or_else(|err| {
+            error!("Unable to read the score matrix. Reason: {}", err);
+            exit(1);
+        }),
+        None => ScoreMatrix::builtin(args.score_matrix.unwrap_or(BuiltinMatrix::Blosum62)),
+    });
+    let match_matrix = args.match_matrix.as_ref().map(|fpath| {
+        MatchMatrix::from_file(fpath).unwrap_or_else(|err| {
+            error!("Unable to read the match matrix. Reason: {}", err);
+            exit(1);
+        })
+    });
+    ComparisonConfig {
+        metric: args.metric,
+        saturation_cap: args.saturation_cap,
+        ignore_ambiguous: args.ignore_ambiguous,
+        ignore_chars: ignore_chars(args),
+        gap_run_as_one: args.gap_run_as_one,
+        score_matrix: score_matrix.map(Arc::new),
+        match_matrix: match_matrix.map(Arc::new),
+        gap_chars: args.gap_chars,
+        min_overlap: args.min_overlap,
+        min_query_coverage: args.min_query_coverage,
+        ..Default::default()
+    }
+}
+
+
+/// The symbols of `--ignore-chars`, with letters in both cases.
+fn ignore_chars(args: &NnArgs) -> Vec<u8> {
+    if let Some(ch) = args.ignore_chars.iter().find(|ch| !ch.is_ascii()) {
+        error!("Invalid --ignore-chars symbol {:?}: symbols must be ASCII.", ch);
+        exit(1);
+    }
+    let mut chars: Vec<u8> = args.ignore_chars.iter()
+        .flat_map(|ch| [ch.to_ascii_uppercase() as u8, ch.to_ascii_lowercase() as u8])
+        .collect();
+    chars.sort_unstable();
+    chars.dedup();
+    chars
+}
+
+
+fn output_config(args: &NnArgs) -> OutputConfig {
+    OutputConfig {
+        format: args.format,
+        sort_by: args.sort_by,
+        with_rank: args.top_k > 1,
+        with_stats: args.with_stats,
+        with_similarity: args.seq_type == Some(Alphabet::Protein),
+        with_detailed_counts: args.detailed_counts,
+        with_runner_up: args.with_runner_up,
+        with_extended_output: args.extended_output,
+        with_ts_tv: args.ts_tv,
+        with_description: args.include_description,
+        with_gc_content: args.gc_content,
+        with_strand: args.reverse_complement,
+        with_db_rank: args.output_rank,
+        with_query_coverage: args.with_query_coverage,
+        normalize: args.normalize,
+        mismatch_positions: args.emit_mismatch_positions.clone().map(|path| MismatchOutput {
+            path,
+            max_positions: args.max_positions,
+        }),
+        graph: graph_output(args),
+        pairs_fasta: args.pairs_out.clone(),
+        identity_summary: args.summary_out.clone().map(|path| IdentitySummaryOutput {
+            path,
+            percentiles: args.percentiles.clone(),
+            bin_width: args.bin_width,
+        }),
+        precision: args.precision,
+        flush_interval: args.flush_interval,
+        row_group_size: args.row_group_size,
+        compression: compression(args),
+    }
+}
+
+
+/// The compression of the output: --compress, or for a text format, the one implied by the suffix of --out-path.
+fn compression(args: &NnArgs) -> Compression {
+    match args.compress {
+        Some(compression) => compression,
+        None if args.format.is_text() => Compression::from_path(&args.out_path),
+        None => Compression::None,
+    }
+}
+
+
+fn graph_output(args: &NnArgs) -> Option<GraphOutput> {
+    args.graph_out.clone().map(|path| GraphOutput { path, format: args.graph_format, mutual: args.mutual })
+}
+
+
+/// Exit if a record holds a residue outside `alphabet` that isn't a gap, naming the first such record.
+fn check_alphabet_or_exit(records: &[Record], alphabet: Alphabet, gap_chars: &GapChars) {
+    let invalid = records.iter().find_map(|record| {
+        let residue = record.seq().iter().find(|ch| !alphabet.is_valid(**ch) && !gap_chars.contains(**ch))?;
+        Some((record.id(), *residue as char))
+    });
+    if let Some((id, residue)) = invalid {
+        error!("Record {} has the residue {:?}, which is not valid for --seq-type {:?}.", id, residue, alphabet);
+        exit(1);
+    }
+}
+
+
+fn progress_interval(args: &NnArgs) -> Duration {
+    Duration::try_from_secs_f64(args.progress_interval).unwrap_or_else(|_| {
+        error!("Invalid --progress-interval: {}", args.progress_interval);
+        exit(1);
+    })
+}
+
+
+/// Log the end-of-run summary, and write it to `--metadata-out` if given.
+fn report_run_summary(args: &NnArgs, config: &NeighborConfig, normalizations: &[&'static str], start: Instant) {
+    let summary = RunSummary {
+        sort_by: args.sort_by,
+        seed: (config.query_sample.is_some() || config.db_sample.is_some()).then_some(args.seed),
+        seq_type: args.seq_type.map(|alphabet| alphabet.name()),
+        normalizations: normalizations.to_vec(),
+        ..RunSummary::new(&config.counters, start.elapsed())
+    };
+    info!("{}", summary);
+    if let Some(metadata_path) = &args.metadata_out {
+        std::fs::write(metadata_path, summary.to_json() + "\n").unwrap_or_else(|e| {
+            error!("Unable to write run metadata to {}: {}", metadata_path.display(), e);
+            exit(1);
+        });
+    }
+}
+
+
+/// The shortening of the record IDs set by --id-split-char or --id-regex, if any.
+fn id_normalizer(cli: &Cli) -> Option<IdNormalizer> {
+    if let Some(delimiter) = cli.id_split_char {
+        if cli.id_field == 0 {
+            error!("--id-field is 1-based: it must be at least 1.");
+            exit(1);
+        }
+        return Some(IdNormalizer::Split { delimiter, field: cli.id_field });
+    }
+    let pattern = cli.id_regex.as_ref()?;
+    match Regex::new(pattern) {
+        Ok(regex) if regex.captures_len() == 2 => Some(IdNormalizer::Regex(regex)),
+        Ok(_) => {
+            error!("--id-regex {} must have exactly one capture group.", pattern);
+            exit(1);
+        }
+        Err(err) => {
+            error!("Invalid --id-regex {}: {}", pattern, err);
+            exit(1);
+        }
+    }
+}
+
+
+/// The minimum level of the log messages, from --quiet, --verbose or --log-level.
+fn log_level(cli: &Cli) -> &str {
+    match cli.verbose {
+        _ if cli.quiet => "error",
+        0 => &cli.log_level,
+        1 => "debug",
+        _ => "trace",
+    }
+}
+
+
+/// Send log messages to stderr, so they never mix with results written to stdout.
+/// Colors are only used when stderr is a terminal.
+fn init_logging(log_level: &str, log_format: LogFormat) {
+    let filter = EnvFilter::try_from_default_env()
+        .or_else(|_| EnvFilter::try_new(log_level))
+        .unwrap_or_else(|err| {
+            eprintln!("Invalid log level {}: {}", log_level, err);
+            exit(1);
+        });
+    let subscriber = tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(std::io::stderr)
+        .with_span_events(FmtSpan::CLOSE);
+    match log_format {
+        LogFormat::Pretty => subscriber.with_ansi(io::stderr().is_terminal()).init(),
+        LogFormat::Json => subscriber.json().with_current_span(true).with_span_list(true).init(),
+    }
+}
+
+
+/// Cancel the search on the first Ctrl-C, so that the completed results can still be written.
+/// A second Ctrl-C exits immediately.
+fn install_cancel_handler() -> CancellationToken {
+    let cancel = CancellationToken::new();
+    let handler_cancel = cancel.clone();
+    ctrlc::set_handler(move || {
+        if handler_cancel.is_cancelled() {
+            exit(CANCELLED_EXIT_CODE);
+        }
+        warn!("Cancelling; writing the results of the completed queries (press Ctrl-C again to exit now).");
+        handler_cancel.cancel();
+    }).unwrap_or_else(|err| {
+        warn!("Unable to install the Ctrl-C handler. Reason: {}", err);
+    });
+    cancel
+}
+
+
+/// Exit after a cancelled search, with the number of queries whose results were written, if any.
+fn exit_cancelled(num_completed: usize, out_path: Option<&Path>) -> ! {
+    match out_path {
+        Some(out_path) => warn!(
+            "Cancelled after {} queries were completed; their results were written to {}",
+            num_completed, out_path.display(),
+        ),
+        None => warn!("Cancelled; no results were written."),
+    }
+    exit(CANCELLED_EXIT_CODE)
+}
+
+
+fn init_thread_pool(num_workers: usize) {
+    // Set number of threads globally at the start of your program
+    rayon::ThreadPoolBuilder::new()
+        .num_threads(num_workers)
+        .build_global()
+        .unwrap_or_else(|err| {
+            error!("Failed to build global thread pool. Reason: {}", err);
+            exit(1);
+        });
+}
+
+
+/// Handle an existing --out-path: refuse it with --no-overwrite, and warn that it will be overwritten unless
+/// --overwrite is set. Resuming from a checkpoint appends to the output, so it overwrites nothing, and neither
+/// does writing to stdout.
+fn check_overwrite(args: &NnArgs) {
+    let resuming = args.checkpoint_file.as_ref().is_some_and(|checkpoint_path| checkpoint_path.exists());
+    if is_stdout(&args.out_path) || !args.out_path.exists() || resuming {
+        return;
+    }
+    if args.no_overwrite {
+        error!("The output file {} already exists, and --no-overwrite is set.", args.out_path.display());
+        exit(1);
+    }
+    if !args.overwrite {
+        warn!("The output file {} already exists. It will be overwritten!", args.out_path.display());
+    }
+}
+
+
+/// Exit quietly when the output is a pipe closed by its reader, e.g. `nn -o - | head`: the reader has all the
+/// results it wants, so it is no failure.
+fn exit_if_broken_pipe(err: &NearestNeighborError) {
+    if matches!(err, NearestNeighborError::Io(io_err) if io_err.kind() == io::ErrorKind::BrokenPipe) {
+        exit(0);
+    }
+}
+
+
+fn run_nn(mut args: NnArgs, mut input: InputOptions, cancel: CancellationToken) {
+    let start = Instant::now();
+    let to_stdout = is_stdout(&args.out_path);
+    if to_stdout && (args.shard.is_some() || args.checkpoint_file.is_some() || !args.format.is_text()) {
+        error!("--out-path - writes to stdout, which requires --format tsv or blast6, without --shard or --checkpoint-file.");
+        exit(1);
+    }
+    if let Some(shard) = &args.shard {
+        args.out_path = shard.out_path(&args.out_path);
+    }
+    if args.top_k == 0 {
+        error!("--top-k must be at least 1.");
+        exit(1);
+    }
+    if args.bin_width <= 0.0 || args.percentiles.iter().any(|p| !(0.0..=100.0).contains(p)) {
+        error!("--bin-width must be positive, and --percentiles between 0 and 100.");
+        exit(1);
+    }
+    if args.checkpoint_file.is_some() && (args.format != OutputFormat::Tsv || args.sort_by != SortKey::QueryOrder) {
+        error!("--checkpoint-file appends to a TSV output in query order: it requires --format tsv and --sort-by query-order.");
+        exit(1);
+    }
+    if args.checkpoint_file.is_some() && compression(&args) != Compression::None {
+        error!("--checkpoint-file resumes by scanning the output, which must be uncompressed: use --compress none.");
+        exit(1);
+    }
+    if compression(&args) != Compression::None && !args.format.is_text() {
+        error!("--compress only applies to the tsv and blast6 formats.");
+        exit(1);
+    }
+    check_overwrite(&args);
+    if args.checkpoint_interval == 0 || args.flush_interval == 0 || args.row_group_size == 0 {
+        error!("--checkpoint-interval, --flush-interval and --row-group-size must be at least 1.");
+        exit(1);
+    }
+    let gap_fractions = [args.max_query_gap_fraction, args.max_db_gap_fraction];
+    if gap_fractions.iter().flatten().any(|fraction| !(0.0..=1.0).contains(fraction)) {
+        error!("--max-query-gap-fraction and --max-db-gap-fraction must be between 0 and 1.");
+        exit(1);
+    }
+    if !(0.0..=1.0).contains(&args.min_query_coverage) {
+        error!("--min-query-coverage must be between 0 and 1.");
+        exit(1);
+    }
+    if args.approx_candidates == Some(0) {
+        error!("--approx-candidates must be at least 1.");
+        exit(1);
+    }
+    if args.classify && args.metric.is_distance() {
+        error!("--classify weighs neighbors by identity, and can't be combined with a distance --metric.");
+        exit(1);
+    }
+    if args.mode == SearchMode::Farthest && (args.classify || args.approx_candidates.is_some()) {
+        error!("--mode farthest can't be combined with --classify or --approx-candidates.");
+        exit(1);
+    }
+    let protein = args.seq_type == Some(Alphabet::Protein);
+    if !protein && (args.score_matrix.is_some() || args.score_matrix_file.is_some() || args.metric.needs_score_matrix()) {
+        error!("--score-matrix, --score-matrix-file and --metric {:?} require --seq-type protein.", args.metric);
+        exit(1);
+    }
+    if args.format == OutputFormat::Blast6 && args.db_chunk_records.is_some() {
+        error!("--format blast6 needs the neighbors' sequences, and can't be combined with --db-chunk-records.");
+        exit(1);
+    }
+    if protein && args.db_chunk_records.is_some() {
+        error!("--seq-type protein can't be combined with --db-chunk-records.");
+        exit(1);
+    }
+    if args.strict_alphabet && args.db_chunk_records.is_some() {
+        error!("--strict-alphabet can't be combined with --db-chunk-records.");
+        exit(1);
+    }
+
+    // The pool is built before parsing, since the input files are parsed in parallel.
+    info!("Number of workers = {}", args.num_workers);
+    init_thread_pool(args.num_workers);
+
+    if args.db_chunk_records.is_some() && (input.allow_ragged || input.id_normalizer.is_some()) {
+        error!(
+            "--allow-ragged, --id-split-char and --id-regex apply to the whole input after parsing: drop --db-chunk-records."
+        );
+        exit(1);
+    }
+    if let Some(chunk_records) = args.db_chunk_records {
+        args.input_fasta = input_paths_or_exit(&args.input_fasta, &input);
+        if args.input_fasta.iter().any(|path| input.format.resolve(path) != InputFormat::Fasta) {
+            error!("--db-chunk-records only streams FASTA files: convert the other alignments, or drop --db-chunk-records.");
+            exit(1);
+        }
+        run_nn_streaming(&args, chunk_records, start, cancel);
+        return;
+    }
+
+    input.allowed_chars.extend(args.gap_chars.chars());
+    let mut records = parse_records_or_exit(&args.input_fasta, input);
+    let seq_type = args.seq_type.unwrap_or_else(|| detect_alphabet(&records, &args.gap_chars));
+    info!(
+        "Parsed {} records of {} columns, of sequence type {}{}",
+        records.len(), records.first().map_or(0, |record| record.seq().len()), seq_type.name(),
+        if args.seq_type.is_some() { "" } else { " (detected)" },
+    );
+    let mut normalizations: Vec<&'static str> = vec![];
+    // Once every gap is `-`, the comparisons can skip the lookup of other gap characters.
+    if args.gap_chars.contains(GAP) {
+        let num_changed = normalize_gaps(&mut records, &args.gap_chars);
+        if num_changed > 0 {
+            info!("Replaced the gap characters {} with - in {} records", args.gap_chars, num_changed);
+            normalizations.push("gaps");
+        }
+        args.gap_chars = GapChars::default();
+    }
+    if args.normalize_ut || seq_type != Alphabet::Protein {
+        let num_changed = normalize_uracil(&mut records, args.normalize_ut);
+        if num_changed > 0 {
+            info!("Replaced U with T in {} records", num_changed);
+            normalizations.push("u_to_t");
+        }
+    }
+    if args.deduplicate {
+        let num_records = records.len();
+        records = deduplicate_by_sequence(records);
+        info!("Kept {} of {} records after deduplicating by sequence", records.len(), num_records);
+    }
+    if records.len() < 2 {
+        error!("There must be at least two Fasta records.");
+        exit(1);
+    }
+    if let Some(alphabet) = args.seq_type {
+        check_alphabet_or_exit(&records, alphabet, &args.gap_chars);
+    }
+    // The detected type sets the defaults of --seq-type, such as the substitution scores of proteins.
+    args.seq_type = Some(seq_type);
+    let mut comparison = comparison_config(&args);
+    let column_weights = read_column_weights(args.column_mask.as_deref(), args.column_weights.as_deref(), records[0].seq().len());
+    match column_weights {
+        Ok(column_weights) => comparison.column_weights = column_weights.map(Arc::new),
+        Err(err) => {
+            error!("Unable to read the column mask or weights. Reason: {}", err);
+            exit(1);
+        }
+    }
+    if args.strict_alphabet {
+        let match_matrix = comparison.match_matrix.as_ref().expect("--strict-alphabet requires --match-matrix");
+        if let Err(err) = match_matrix.check_records(&records, &comparison.gap_chars) {
+            error!("{}", err);
+            exit(1);
+        }
+    }
+
+    if let Some(max_gap_fraction) = args.auto_trim {
+        let (trimmed, num_columns) = trim_alignment(&records, max_gap_fraction);
+        info!("Auto-trim kept {} of {} columns.", num_columns, records[0].seq().len());
+        records = trimmed;
+    }
+
+    let query_record_ids: Option<Vec<String>> = parse_id_file(args.query_id_file.clone(), "query");
+    let query_record_ids = exclude_ids(&records, query_record_ids, args.query_exclude_file.clone(), "query");
+    let db_record_ids: Option<Vec<String>> = parse_id_file(args.database_id_file.clone(), "database");
+    let db_record_ids = exclude_ids(&records, db_record_ids, args.database_exclude_file.clone(), "database");
+    let query_filters = record_filters(args.query_id_regex.as_ref(), args.max_query_gap_fraction);
+    let db_filters = record_filters(args.db_id_regex.as_ref(), args.max_db_gap_fraction);
+    let filtered = !matches!(query_filters, RecordSelector::All) || !matches!(db_filters, RecordSelector::All);
+    let query_record_ids = select_ids(&records, query_record_ids, query_filters, "query");
+    let db_record_ids = select_ids(&records, db_record_ids, db_filters, "database");
+    if filtered {
+        check_selection_or_exit(&records, query_record_ids.as_deref(), db_record_ids.as_deref());
+    }
+    let allowed_neighbors: Option<HashSet<String>> = args.allowed_neighbor_ids.as_ref().map(|fpath| {
+        let ids = parse_record_ids(fpath).unwrap_or_else(|e| {
+            error!("Error reading file {}: {}", fpath.display(), e);
+            exit(1);
+        });
+        info!("Parsed allowed neighbor IDs from file: {} ({} entries)", fpath.display(), ids.len());
+        ids.into_iter().collect()
+    });
+    let out_tsv_path = &args.out_path;
+    let config = NeighborConfig {
+        comparison,
+        exclude_self: args.leave_one_out,
+        collapse_duplicates: args.collapse_duplicates,
+        top_k: args.top_k,
+        include_consensus: args.include_consensus,
+        compress_invariant: args.compress_invariant,
+        approx_candidates: args.approx_candidates,
+        progress: progress_mode(&args),
+        progress_interval: progress_interval(&args),
+        query_sample: args.sample_queries.map(|size| RecordSample { size, seed: args.seed }),
+        db_sample: args.sample_db.map(|size| RecordSample { size, seed: args.seed }),
+        allowed_neighbors: allowed_neighbors.map(Arc::new),
+        shard: args.shard,
+        cancel,
+        mode: args.mode,
+        reverse_complement: args.reverse_complement,
+        ..Default::default()
+    };
+
+    if args.labels.is_some() {
+        run_nn_labeled(&args, records, query_record_ids, db_record_ids, &config);
+        report_run_summary(&args, &config, &normalizations, start);
+        return;
+    }
+    if args.query_pfm.is_some() {
+        run_nn_pfm(&args, records, db_record_ids, &config);
+        report_run_summary(&args, &config, &normalizations, start);
+        return;
+    }
+
+    let result = match &args.checkpoint_file {
+        Some(checkpoint_path) => compute_store_nearest_neighbors_checkpointed(
+            records,
+            out_tsv_path,
+            query_record_ids,
+            db_record_ids,
+            &config,
+            &output_config(&args),
+            checkpoint_path,
+            args.checkpoint_interval,
+        ),
+        None => compute_store_nearest_neighbors(
+            records,
+            out_tsv_path,
+            query_record_ids,
+            db_record_ids,
+            &config,
+            &output_config(&args),
+        ),
+    };
+    match result {
+        Ok(()) => {
+            info!("Successfully computed nearest neighbors to: {}", out_tsv_path.display());
+            report_run_summary(&args, &config, &normalizations, start);
+        }
+        Err(NearestNeighborError::Cancelled(num_completed)) => {
+            report_run_summary(&args, &config, &normalizations, start);
+            exit_cancelled(num_completed, Some(out_tsv_path));
+        }
+        Err(err) => {
+            exit_if_broken_pipe(&err);
+            error!("Error while performing nearest neighbors. Reason: {}", err);
+            exit(1);
+        }
+    }
+}
+
+
+/// Run the nearest-neighbor search without loading the database, see `--db-chunk-records`.
+fn run_nn_streaming(args: &NnArgs, chunk_records: usize, start: Instant, cancel: CancellationToken) {
+    if chunk_records == 0 {
+        error!("--db-chunk-records must be at least 1.");
+        exit(1);
+    }
+    let query_record_ids: Option<Vec<String>> = parse_id_file(args.query_id_file.clone(), "query");
+    let db_record_ids: Option<Vec<String>> = parse_id_file(args.database_id_file.clone(), "database");
+    let out_tsv_path = &args.out_path;
+    let config = NeighborConfig {
+        comparison: comparison_config(args),
+        exclude_self: args.leave_one_out,
+        top_k: args.top_k,
+        progress: progress_mode(args),
+        progress_interval: progress_interval(args),
+        query_sample: args.sample_queries.map(|size| RecordSample { size, seed: args.seed }),
+        db_sample: args.sample_db.map(|size| RecordSample { size, seed: args.seed }),
+        shard: args.shard,
+        cancel,
+        mode: args.mode,
+        ..Default::default()
+    };
+
+    let result = compute_store_nearest_neighbors_streaming(
+        &args.input_fasta,
+        out_tsv_path,
+        query_record_ids,
         db_record_ids,
+        chunk_records,
+        &config,
+        &output_config(args),
     );
     match result {
         Ok(()) => {
-            println!("Successfully computed nearest neighbors to: {}", out_tsv_path.display());
+            info!("Successfully computed nearest neighbors to: {}", out_tsv_path.display());
+            report_run_summary(args, &config, &[], start);
+        }
+        Err(NearestNeighborError::Cancelled(_)) => {
+            report_run_summary(args, &config, &[], start);
+            exit_cancelled(0, None);
+        }
+        Err(err) => {
+            exit_if_broken_pipe(&err);
+            error!("Error while performing nearest neighbors. Reason: {}", err);
+            exit(1);
+        }
+    }
+}
+
+
+/// Search the database records nearest to the --query-pfm profile.
+fn run_nn_pfm(args: &NnArgs, records: Vec<Record>, db_record_ids: Option<Vec<String>>, config: &NeighborConfig) {
+    if args.format == OutputFormat::Blast6 {
+        error!("--format blast6 describes pairs of sequences, and can't be combined with --query-pfm.");
+        exit(1);
+    }
+    if args.metric != Metric::Identity {
+        error!("--query-pfm reports an expected identity, and can't be combined with another --metric.");
+        exit(1);
+    }
+    let pfm_path = args.query_pfm.as_ref().unwrap();
+    let pfm = parse_pfm_from_jaspar(pfm_path).unwrap_or_else(|e| {
+        error!("Error reading file {}: {}", pfm_path.display(), e);
+        exit(1);
+    });
+    info!("Parsed a PFM of {} positions from file: {}", pfm.len(), pfm_path.display());
+    let query_id: String = pfm_path.file_stem().map_or("pfm".to_owned(), |stem| stem.to_string_lossy().into_owned());
+
+    let db_records: Vec<&Record> =
+        config.select_database(filter_records(&records, &RecordSelector::ids(db_record_ids.as_deref())));
+    let neighbors = match compute_pfm_neighbors(&pfm, &db_records, config) {
+        Ok(neighbors) => neighbors,
+        Err(NearestNeighborError::Cancelled(_)) => exit_cancelled(0, None),
+        Err(err) => {
+            error!("Error while performing nearest neighbors. Reason: {}", err);
+            exit(1);
+        }
+    };
+    let rows: Vec<NearestNeighborResultRow> = neighbors.iter()
+        .enumerate()
+        .map(|(rank_idx, (neighbor, identity))| NearestNeighborResultRow {
+            query_id: query_id.clone(),
+            neighbor_id: neighbor.id().to_owned(),
+            rank: rank_idx + 1,
+            identity: *identity,
+            ..Default::default()
+        })
+        .collect();
+    let out_tsv_path = &args.out_path;
+    if let Err(err) = write_results(&rows, out_tsv_path, &output_config(args), &config.run_metadata(1, db_records.len())) {
+        exit_if_broken_pipe(&err);
+        error!("Error while writing the PFM neighbors. Reason: {}", err);
+        exit(1);
+    }
+    info!("Successfully computed nearest neighbors to: {}", out_tsv_path.display());
+}
+
+
+fn run_nn_labeled(
+    args: &NnArgs,
+    records: Vec<Record>,
+    query_record_ids: Option<Vec<String>>,
+    db_record_ids: Option<Vec<String>>,
+    config: &NeighborConfig,
+) {
+    let labels_path = args.labels.as_ref().unwrap();
+    let labels = parse_labels(labels_path).unwrap_or_else(|e| {
+        error!("Error reading file {}: {}", labels_path.display(), e);
+        exit(1);
+    });
+    info!("Parsed labels from file: {} ({} entries)", labels_path.display(), labels.len());
+
+    let query_records: Vec<&Record> = filter_records(&records, &RecordSelector::ids(query_record_ids.as_deref()));
+    let query_records: Vec<&Record> = config.select_queries(query_records);
+    let mut db_records: Vec<&Record> =
+        config.select_database(filter_records(&records, &RecordSelector::ids(db_record_ids.as_deref())));
+    let consensus: Option<Record> = config.include_consensus.then(|| consensus_record(&db_records));
+    if let Some(consensus) = &consensus {
+        db_records.push(consensus);
+    }
+    let out_tsv_path = &args.out_path;
+
+    if args.classify {
+        let results = compute_top_k_neighbors(&query_records, &db_records, config)
+            .unwrap_or_else(|err| {
+                error!("Error while performing nearest neighbors. Reason: {}", err);
+                exit(1);
+            });
+        let classifications: Vec<Classification> = query_records.iter()
+            .zip(results.iter())
+            .map(|(query, neighbors)| classify_query(query, neighbors, &labels, args.min_identity))
+            .collect();
+        let write_result = create_output(out_tsv_path)
+            .map_err(NearestNeighborError::from)
+            .and_then(|output| {
+                let mut writer = BufWriter::new(output);
+                write_classifications(&classifications, &mut writer, args.precision)?;
+                Ok(writer.flush()?)
+            });
+        match write_result {
+            Ok(()) if results.len() < query_records.len() => {
+                exit_cancelled(results.len(), Some(out_tsv_path));
+            }
+            Ok(()) => {
+                info!("Successfully classified queries to: {}", out_tsv_path.display());
+            }
+            Err(err) => {
+                exit_if_broken_pipe(&err);
+                error!("Error while writing classifications. Reason: {}", err);
+                exit(1);
+            }
+        }
+        return;
+    }
+
+    let results = compute_nearest_neighbors(&query_records, &db_records, config)
+        .unwrap_or_else(|err| {
+            error!("Error while performing nearest neighbors. Reason: {}", err);
+            exit(1);
+        });
+    let labeled = annotate_labels(&query_records, &results, &labels);
+    let summary = summarize_labels(&labeled);
+    if let Some(graph_output) = graph_output(args) {
+        let rows: Vec<NearestNeighborResultRow> = labeled.iter()
+            .map(|hit| NearestNeighborResultRow {
+                query_id: hit.query_id.clone(),
+                neighbor_id: hit.neighbor_id.clone(),
+                rank: 1,
+                identity: hit.identity,
+                ..Default::default()
+            })
+            .collect();
+        let graph_records: Vec<&Record> = query_records.iter().chain(db_records.iter()).copied().collect();
+        if let Err(err) = write_graph_file(&rows, &graph_records, Some(&labels), &graph_output, args.precision) {
+            error!("Error while writing the neighbor graph. Reason: {}", err);
+            exit(1);
+        }
+    }
+
+    let write_result = create_output(out_tsv_path)
+        .map_err(NearestNeighborError::from)
+        .and_then(|output| {
+            let mut writer = BufWriter::new(output);
+            write_labeled_results(&labeled, &mut writer, args.precision)?;
+            Ok(writer.flush()?)
+        })
+        .and_then(|_| match &args.summary_path {
+            None => write_label_summary(&summary, &mut io::stderr()),
+            Some(fpath) => {
+                let mut writer = BufWriter::new(File::create(fpath)?);
+                write_label_summary(&summary, &mut writer)?;
+                Ok(writer.flush()?)
+            }
+        });
+    match write_result {
+        Ok(()) if results.len() < query_records.len() => {
+            exit_cancelled(results.len(), Some(out_tsv_path));
+        }
+        Ok(()) => {
+            info!("Successfully computed nearest neighbors to: {}", out_tsv_path.display());
         }
         Err(err) => {
-            println!("Error while performing nearest neighbors. Reason: {}", err);
+            exit_if_broken_pipe(&err);
+            error!("Error while writing labeled nearest neighbors. Reason: {}", err);
             exit(1);
         }
     }
 }
+
+
+fn run_consensus(args: ConsensusArgs, input: InputOptions) {
+    let records = parse_records_or_exit(&args.input_fasta, input);
+    let ids: Option<Vec<String>> = args.id_file.map(|fpath| {
+        parse_record_ids(&fpath).unwrap_or_else(|e| {
+            error!("Error reading file {}: {}", fpath.display(), e);
+            exit(1);
+        })
+    });
+    let selected: Vec<&Record> = filter_records(&records, &RecordSelector::ids(ids.as_deref()));
+    if selected.is_empty() {
+        error!("No records selected for the consensus.");
+        exit(1);
+    }
+    let consensus = compute_consensus(&selected);
+
+    let mut writer = FastaWriter::new(open_output_or_stdout(args.out_path.as_deref()));
+    writer.write(&args.name, None, &consensus)
+        .and_then(|_| writer.flush())
+        .unwrap_or_else(|e| {
+            error!("Unable to write consensus. Reason: {}", e);
+            exit(1);
+        });
+}
+
+
+fn run_pairs(args: PairsArgs, input: InputOptions) {
+    info!("Number of workers = {}", args.num_workers);
+    init_thread_pool(args.num_workers);
+    let records = parse_records_or_exit(&args.input_fasta, input);
+
+    let pairs = parse_pairs_file(&args.pairs_file).unwrap_or_else(|e| {
+        error!("Error reading file {}: {}", args.pairs_file.display(), e);
+        exit(1);
+    });
+    info!("Parsed {} pairs from file: {}", pairs.len(), args.pairs_file.display());
+
+    let results = compute_pairs(&records, &pairs, &ComparisonConfig::default())
+        .unwrap_or_else(|err| {
+            error!("Error while computing pair identities. Reason: {}", err);
+            exit(1);
+        });
+
+    let mut num_missing: usize = 0;
+    for (line_idx, result) in results.iter().enumerate() {
+        if result.counts.is_none() {
+            num_missing += 1;
+            warn!(
+                "Pair on line {} ({}, {}) refers to an ID not found in the FASTA file.",
+                line_idx + 1, result.query_id, result.db_id
+            );
+        }
+    }
+    if num_missing > 0 && !args.skip_missing {
+        error!("{} pairs have missing IDs. Use --skip-missing to report them as NA.", num_missing);
+        exit(1);
+    }
+
+    let file = File::create(&args.out_path).unwrap_or_else(|e| {
+        error!("Unable to create file {}: {}", args.out_path.display(), e);
+        exit(1);
+    });
+    let mut writer = BufWriter::new(file);
+    match write_pairs(&results, &mut writer, args.precision).and_then(|_| Ok(writer.flush()?)) {
+        Ok(()) => {
+            info!("Successfully computed pair identities to: {}", args.out_path.display());
+        }
+        Err(err) => {
+            error!("Error while writing pair identities. Reason: {}", err);
+            exit(1);
+        }
+    }
+}
+
+
+fn run_stats(args: StatsArgs, input: InputOptions) {
+    init_thread_pool(args.num_workers);
+    let records = parse_records_or_exit(&args.input_fasta, input);
+    let ids: Option<Vec<String>> = args.id_file.map(|fpath| {
+        parse_record_ids(&fpath).unwrap_or_else(|e| {
+            error!("Error reading file {}: {}", fpath.display(), e);
+            exit(1);
+        })
+    });
+    let selected: Vec<&Record> = filter_records(&records, &RecordSelector::ids(ids.as_deref()));
+
+    if !args.per_record && !args.per_column && !args.conservation && args.consensus_out.is_none() {
+        let summary = summarize_alignment(&selected, args.sample_pairs).unwrap_or_else(|err| {
+            error!("Unable to summarize the alignment. Reason: {}", err);
+            exit(1);
+        });
+        let write_result = match &args.out_path {
+            None => write!(io::stdout(), "{}", summary),
+            Some(out_path) => std::fs::write(out_path, summary.to_json() + "\n"),
+        };
+        write_result.unwrap_or_else(|e| {
+            error!("Unable to write statistics. Reason: {}", e);
+            exit(1);
+        });
+        return;
+    }
+
+    if args.per_record {
+        let stats = compute_record_stats(&selected);
+        let mut writer = open_output_or_stdout(args.out_path.as_deref());
+        write_record_stats(&selected, &stats, &mut writer)
+            .and_then(|_| Ok(writer.flush()?))
+            .unwrap_or_else(|err| {
+                error!("Unable to write statistics. Reason: {}", err);
+                exit(1);
+            });
+    }
+
+    if args.conservation {
+        let scores = compute_conservation_scores(&selected);
+        let mut writer = open_output_or_stdout(args.out_path.as_deref());
+        write_conservation_scores(&scores, &mut writer)
+            .and_then(|_| Ok(writer.flush()?))
+            .unwrap_or_else(|err| {
+                error!("Unable to write statistics. Reason: {}", err);
+                exit(1);
+            });
+    }
+
+    if args.per_column || args.consensus_out.is_some() {
+        let column_stats = compute_column_stats(&selected);
+        if args.per_column {
+            let mut writer = open_output_or_stdout(args.out_path.as_deref());
+            write_column_stats(&column_stats, &mut writer)
+                .and_then(|_| Ok(writer.flush()?))
+                .unwrap_or_else(|err| {
+                    error!("Unable to write statistics. Reason: {}", err);
+                    exit(1);
+                });
+        }
+        if let Some(consensus_path) = &args.consensus_out {
+            let consensus: Vec<u8> = column_stats.iter()
+                .map(|stats| stats.consensus(args.consensus_threshold))
+                .collect();
+            let mut writer = FastaWriter::new(open_output_or_stdout(Some(consensus_path.as_path())));
+            writer.write(CONSENSUS_ID, None, &consensus)
+                .and_then(|_| writer.flush())
+                .unwrap_or_else(|e| {
+                    error!("Unable to write consensus. Reason: {}", e);
+                    exit(1);
+                });
+        }
+    }
+}
+
+
+/// Trim the columns of `records` whose gap fraction exceeds `max_gap_fraction`.
+/// Returns the trimmed records and their number of columns.
+fn trim_alignment(records: &[Record], max_gap_fraction: f32) -> (Vec<Record>, usize) {
+    let record_refs: Vec<&Record> = records.iter().collect();
+    let columns = trimmed_columns(&compute_column_stats(&record_refs), max_gap_fraction);
+    (trim_records(records, &columns), columns.len())
+}
+
+
+fn run_trim(args: TrimArgs, input: InputOptions) {
+    init_thread_pool(args.num_workers);
+    let records = parse_records_or_exit(&args.input_fasta, input);
+    let aln_len = records[0].seq().len();
+
+    let record_refs: Vec<&Record> = records.iter().collect();
+    let columns = trimmed_columns(&compute_column_stats(&record_refs), args.max_gap_fraction);
+    info!("Keeping {} of {} columns.", columns.len(), aln_len);
+    let trimmed = trim_records(&records, &columns);
+
+    let mut writer = open_output_or_stdout(Some(args.out_path.as_path()));
+    write_fasta_wrapped(&trimmed, &mut writer, args.line_width)
+        .and_then(|_| Ok(writer.flush()?))
+        .unwrap_or_else(|err| {
+            error!("Unable to write trimmed alignment. Reason: {}", err);
+            exit(1);
+        });
+
+    if let Some(map_path) = &args.column_map {
+        let mut writer = open_output_or_stdout(Some(map_path.as_path()));
+        write_column_map(aln_len, &columns, &mut writer)
+            .and_then(|_| Ok(writer.flush()?))
+            .unwrap_or_else(|err| {
+                error!("Unable to write column map. Reason: {}", err);
+                exit(1);
+            });
+    }
+    info!("Successfully wrote trimmed alignment to: {}", args.out_path.display());
+}
+
+
+fn run_merge(args: MergeArgs) {
+    let mut writer = open_output_or_stdout(Some(args.out_path.as_path()));
+    match merge_shards(&args.shard_files, &mut writer).and_then(|num_queries| Ok((num_queries, writer.flush()?))) {
+        Ok((num_queries, ())) => {
+            info!("Merged {} queries from {} shards to: {}", num_queries, args.shard_files.len(), args.out_path.display());
+        }
+        Err(err) => {
+            error!("Unable to merge shard outputs. Reason: {}", err);
+            exit(1);
+        }
+    }
+}
+
+
+fn run_matrix(args: MatrixArgs, input: InputOptions) {
+    init_thread_pool(args.num_workers);
+    let (ids, matrix): (Vec<String>, Vec<Vec<f64>>) = match &args.from_matrix {
+        Some(matrix_path) => {
+            let (ids, matrix) = load_identity_matrix(matrix_path).unwrap_or_else(|err| {
+                error!("Unable to load the identity matrix {}. Reason: {}", matrix_path.display(), err.message);
+                exit(1);
+            });
+            info!("Loaded the identity matrix of {} records from: {}", ids.len(), matrix_path.display());
+            (ids, matrix)
+        }
+        None => {
+            let records = parse_records_or_exit(&args.input_fasta, input);
+            let ids: Option<Vec<String>> = args.id_file.map(|fpath| {
+                parse_record_ids(&fpath).unwrap_or_else(|e| {
+                    error!("Error reading file {}: {}", fpath.display(), e);
+                    exit(1);
+                })
+            });
+            let selected: Vec<&Record> = filter_records(&records, &RecordSelector::ids(ids.as_deref()));
+            info!("Computing the identity matrix of {} records", selected.len());
+            let matrix = compute_identity_matrix(&selected, &ComparisonConfig::default()).unwrap_or_else(|err| {
+                error!("Unable to compute the identity matrix. Reason: {}", err);
+                exit(1);
+            });
+            (selected.iter().map(|record| record.id().to_owned()).collect(), matrix)
+        }
+    };
+    let ids: Vec<&str> = ids.iter().map(String::as_str).collect();
+
+    let mut writer = open_output_or_stdout(args.out_path.as_deref());
+    let written = if args.upper_triangle {
+        export_upper_triangle(&matrix, &ids, args.precision, &mut writer)
+    } else {
+        write_identity_matrix(&ids, &matrix, args.precision, &mut writer)
+    };
+    written
+        .and_then(|_| Ok(writer.flush()?))
+        .unwrap_or_else(|err| {
+            error!("Unable to write the identity matrix. Reason: {}", err);
+            exit(1);
+        });
+
+    if let Some(newick_path) = &args.newick_out {
+        info!("Building the neighbor-joining tree of {} records", ids.len());
+        let tree = identity_distances(&ids, &matrix)
+            .and_then(|distances| neighbor_joining(&ids, &distances))
+            .and_then(|tree| {
+                let mut writer = BufWriter::new(File::create(newick_path)?);
+                write_newick(&tree, &mut writer)?;
+                writer.flush()?;
+                Ok(tree)
+            })
+            .unwrap_or_else(|err| {
+                error!("Unable to build the neighbor-joining tree. Reason: {}", err);
+                exit(1);
+            });
+        if tree.clamped_branches > 0 {
+            warn!("Clamped {} negative branch lengths to 0", tree.clamped_branches);
+        }
+        info!("Wrote the neighbor-joining tree to: {}", newick_path.display());
+    }
+
+    if let (Some(group_path), Some(stats_path)) = (&args.group_file, &args.group_stats_out) {
+        let groups = parse_labels(group_path).unwrap_or_else(|e| {
+            error!("Error reading file {}: {}", group_path.display(), e);
+            exit(1);
+        });
+        let ungrouped = ids.iter().filter(|id| !groups.contains_key(**id)).count();
+        if ungrouped > 0 {
+            warn!("{} records have no group in {}, and are left out of the group statistics", ungrouped, group_path.display());
+        }
+        let statistics = group_statistics_from_matrix(&ids, &matrix, &groups);
+        write_atomically(stats_path, |tmp_path| {
+            let mut writer = BufWriter::new(File::create(tmp_path)?);
+            write_group_statistics(&statistics, args.precision, &mut writer)?;
+            writer.flush()?;
+            Ok(())
+        }).unwrap_or_else(|err| {
+            error!("Unable to write the group statistics. Reason: {}", err);
+            exit(1);
+        });
+        info!("Wrote the group statistics to: {}", stats_path.display());
+    }
+}
+
+
+/// Print the validation report to stdout: `OK`, or the number of failed checks followed by one line per failure.
+/// Exits with code 1 if any check failed.
+fn run_validate(args: ValidateArgs, input: InputOptions) {
+    let input_fasta = input_paths_or_exit(&args.input_fasta, &input);
+    let id_files: Vec<PathBuf> = args.query_id_file.into_iter().chain(args.database_id_file).collect();
+    let issues = validate_inputs(&input_fasta, &id_files, args.alphabet);
+    if issues.is_empty() {
+        println!("OK");
+        return;
+    }
+    println!("FAILED ({} errors)", issues.len());
+    for issue in issues.iter() {
+        println!("- {}", issue);
+    }
+    exit(1);
+}
@@ -0,0 +1,209 @@
+use std::{
+    fmt::{Debug, Formatter},
+    path::Path,
+};
+use bio::io::fasta::Record;
+
+use crate::{gaps::GapChars, nearest_neighbor::NearestNeighborError};
+
+
+/// Symbol equivalences replacing the exact-match test of the identity, e.g. to treat `T` and `U` as
+/// identical, or `N` against anything as a half match.
+///
+/// Each compared column adds the score of its pair of symbols to the matches, instead of 1 for equal
+/// symbols and 0 otherwise. Pairs involving a symbol missing from the matrix fall back to the exact-match
+/// test. Symbols are case-sensitive, as in the exact-match test.
+#[derive(Clone)]
+pub struct MatchMatrix {
+    name: String,
+    symbols: Vec<u8>,
+    /// The score of `(x, y)` at index `x * 256 + y`.
+    scores: Vec<f32>,
+}
+
+
+impl Debug for MatchMatrix {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "MatchMatrix({})", self.name)
+    }
+}
+
+
+impl MatchMatrix {
+    pub fn score(&self, x: u8, y: u8) -> f32 {
+        self.scores[((x as usize) << 8) | (y as usize)]
+    }
+
+    /// The symbols of the matrix, in header order.
+    pub fn symbols(&self) -> &[u8] {
+        &self.symbols
+    }
+
+    /// Parse a whitespace-delimited square matrix: a header line of single-character symbols, then one
+    /// line per symbol, starting with the symbol and followed by its scores in header order. Scores must
+    /// be between 0 and 1. Blank lines and lines starting with `#` are ignored.
+    ///
+    /// ```text
+    /// #  T and U are identical, N is a half match
+    ///    A    C    G    T    U    N
+    /// A  1    0    0    0    0    0.5
+    /// C  0    1    0    0    0    0.5
+    /// G  0    0    1    0    0    0.5
+    /// T  0    0    0    1    1    0.5
+    /// U  0    0    0    1    1    0.5
+    /// N  0.5  0.5  0.5  0.5  0.5  0.5
+    /// ```
+    pub fn parse(name: &str, text: &str) -> Result<MatchMatrix, String> {
+        let mut lines = text.lines()
+            .enumerate()
+            .map(|(line_idx, line)| (line_idx + 1, line.trim()))
+            .filter(|(_, line)| !line.is_empty() && !line.starts_with('#'));
+        let (header_line, header) = lines.next().ok_or_else(|| "No header line of symbols".to_owned())?;
+        let symbols: Vec<u8> = header.split_whitespace()
+            .map(|field| single_symbol(field, header_line))
+            .collect::<Result<_, _>>()?;
+        for (idx, symbol) in symbols.iter().enumerate() {
+            if symbols[..idx].contains(symbol) {
+                return Err(format!("Line {}: duplicate symbol {:?} in the header", header_line, *symbol as char));
+            }
+        }
+
+        // Exact matching for every pair involving a symbol missing from the matrix.
+        let mut scores: Vec<f32> = (0..256 * 256)
+            .map(|idx: usize| if idx >> 8 == idx & 0xff { 1.0 } else { 0.0 })
+            .collect();
+        let mut seen_rows: Vec<u8> = vec![];
+        for (line_num, line) in lines {
+            let mut fields = line.split_whitespace();
+            let x = single_symbol(fields.next().unwrap_or_default(), line_num)?;
+            if !symbols.contains(&x) {
+                return Err(format!("Line {}: row symbol {:?} is not in the header", line_num, x as char));
+            }
+            if seen_rows.contains(&x) {
+                return Err(format!("Line {}: duplicate row for symbol {:?}", line_num, x as char));
+            }
+            seen_rows.push(x);
+
+            let values: Vec<&str> = fields.collect();
+            if values.len() != symbols.len() {
+                return Err(format!(
+                    "Line {}: row {:?} has {} scores, expected {}", line_num, x as char, values.len(), symbols.len(),
+                ));
+            }
+            for (y, value) in symbols.iter().zip(values) {
+                let score: f32 = value.parse()
+                    .map_err(|_| format!("Line {}: invalid score {:?} for ({}, {})", line_num, value, x as char, *y as char))?;
+                if !(0.0..=1.0).contains(&score) {
+                    return Err(format!(
+                        "Line {}: score {} for ({}, {}) is not between 0 and 1", line_num, score, x as char, *y as char,
+                    ));
+                }
+                scores[((x as usize) << 8) | (*y as usize)] = score;
+            }
+        }
+        if let Some(missing) = symbols.iter().find(|symbol| !seen_rows.contains(symbol)) {
+            return Err(format!("No row for symbol {:?}", *missing as char));
+        }
+        Ok(MatchMatrix { name: name.to_owned(), symbols, scores })
+    }
+
+    /// Read a matrix in the format of [`MatchMatrix::parse`] from a file, named after the file.
+    pub fn from_file(path: &Path) -> Result<MatchMatrix, NearestNeighborError> {
+        let text = std::fs::read_to_string(path)?;
+        let name = path.file_name().map(|name| name.to_string_lossy().into_owned()).unwrap_or_default();
+        MatchMatrix::parse(&name, &text)
+            .map_err(|message| NearestNeighborError::InvalidInput(format!("{}: {}", path.display(), message)))
+    }
+
+    /// Check that every non-gap symbol of the records is in the matrix, for a strict alphabet.
+    pub fn check_records(&self, records: &[Record], gap_chars: &GapChars) -> Result<(), NearestNeighborError> {
+        for record in records.iter() {
+            let missing = record.seq().iter().find(|ch| !gap_chars.contains(**ch) && !self.symbols.contains(ch));
+            if let Some(symbol) = missing {
+                return Err(NearestNeighborError::InvalidInput(format!(
+                    "Record {} has the symbol {:?}, which is not in the match matrix {}",
+                    record.id(), *symbol as char, self.name,
+                )));
+            }
+        }
+        Ok(())
+    }
+}
+
+
+fn single_symbol(field: &str, line_num: usize) -> Result<u8, String> {
+    match field.as_bytes() {
+        [symbol] => Ok(*symbol),
+        _ => Err(format!("Line {}: expected a single-character symbol, got {:?}", line_num, field)),
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use bio::io::fasta::Record;
+    use crate::{gaps::GapChars, nearest_neighbor::{compare_records, ComparisonConfig}};
+    use super::MatchMatrix;
+
+    const TU_MATRIX: &str = "\
+# T and U are identical, N is a half match
+   A    C    G    T    U    N
+A  1    0    0    0    0    0.5
+C  0    1    0    0    0    0.5
+G  0    0    1    0    0    0.5
+T  0    0    0    1    1    0.5
+U  0    0    0    1    1    0.5
+N  0.5  0.5  0.5  0.5  0.5  0.5
+";
+
+    #[test]
+    fn test_parse() {
+        let matrix = MatchMatrix::parse("tu", TU_MATRIX).unwrap();
+        assert_eq!(matrix.symbols(), b"ACGTUN");
+        assert_eq!(matrix.score(b'T', b'U'), 1.0);
+        assert_eq!(matrix.score(b'N', b'G'), 0.5);
+        assert_eq!(matrix.score(b'A', b'C'), 0.0);
+        // Symbols missing from the matrix match exactly.
+        assert_eq!(matrix.score(b'R', b'R'), 1.0);
+        assert_eq!(matrix.score(b'R', b'A'), 0.0);
+        assert_eq!(matrix.score(b'a', b'A'), 0.0);
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        let error = |text: &str| MatchMatrix::parse("test", text).unwrap_err();
+        assert_eq!(error("# only a comment\n"), "No header line of symbols");
+        assert_eq!(error("A AB\nA 1 0\n"), "Line 1: expected a single-character symbol, got \"AB\"");
+        assert_eq!(error("A A\nA 1 1\n"), "Line 1: duplicate symbol 'A' in the header");
+        assert_eq!(error("A C\nA 1 0\nG 0 1\n"), "Line 3: row symbol 'G' is not in the header");
+        assert_eq!(error("A C\nA 1 0\nA 1 0\n"), "Line 3: duplicate row for symbol 'A'");
+        assert_eq!(error("A C\nA 1\n"), "Line 2: row 'A' has 1 scores, expected 2");
+        assert_eq!(error("A C\nA 1 x\n"), "Line 2: invalid score \"x\" for (A, C)");
+        assert_eq!(error("A C\nA 1 2\n"), "Line 2: score 2 for (A, C) is not between 0 and 1");
+        assert_eq!(error("A C\n\nA 1 0\n"), "No row for symbol 'C'");
+    }
+
+    #[test]
+    fn test_match_matrix_identity() {
+        let comparison = ComparisonConfig {
+            match_matrix: Some(Arc::new(MatchMatrix::parse("tu", TU_MATRIX).unwrap())),
+            ..Default::default()
+        };
+        // T/U and A/A match, N/G is a half match, and C/A doesn't match: 2.5 of 4 columns.
+        let x = Record::with_attrs("x", None, b"TANC-");
+        let y = Record::with_attrs("y", None, b"UAGA-");
+        let counts = compare_records(&x, &y, &comparison).unwrap();
+        assert_eq!((counts.matches, counts.compared), (2.5, 4.0));
+        assert_eq!(counts.identity(), 0.625);
+
+        let matrix = MatchMatrix::parse("tu", TU_MATRIX).unwrap();
+        let gap_chars = GapChars::default();
+        assert!(matrix.check_records(&[x.clone(), y.clone()], &gap_chars).is_ok());
+        let z = Record::with_attrs("z", None, b"TARC.");
+        assert!(matrix.check_records(&[x.clone(), z.clone()], &gap_chars).is_err());
+        let z = Record::with_attrs("z", None, b"TAC.-");
+        assert!(matrix.check_records(&[x.clone(), z.clone()], &gap_chars).is_err());
+        assert!(matrix.check_records(&[x, z], &"-.".parse().unwrap()).is_ok());
+    }
+}
@@ -0,0 +1,500 @@
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{BufRead, BufReader, Write},
+    ops::Index,
+    path::Path,
+};
+use rayon::prelude::*;
+use bio::io::fasta::Record;
+
+use crate::{
+    nearest_neighbor::{compare_records, ComparisonConfig, NearestNeighborError},
+    FastaParseError, FastaParseErrorKind,
+};
+
+
+/// Compute the all-vs-all identity matrix of the records using multiple worker threads.
+/// Only the upper triangle is computed, since identity is symmetric.
+/// The diagonal holds each record's identity to itself: 1, or NaN for an all-gap record.
+pub fn compute_identity_matrix(records: &[&Record], config: &ComparisonConfig) -> Result<Vec<Vec<f64>>, NearestNeighborError> {
+    let upper: Vec<Vec<f64>> = (0..records.len())
+        .into_par_iter()
+        .map(|row| {
+            records[row..].iter()
+                .map(|other| compare_records(records[row], other, config).map(|counts| counts.identity()))
+                .collect::<Result<Vec<f64>, NearestNeighborError>>()
+        })
+        .collect::<Result<Vec<Vec<f64>>, NearestNeighborError>>()?;
+
+    let n = records.len();
+    let mut matrix: Vec<Vec<f64>> = vec![vec![0.0; n]; n];
+    for (row, row_values) in upper.iter().enumerate() {
+        for (offset, idty) in row_values.iter().enumerate() {
+            matrix[row][row + offset] = *idty;
+            matrix[row + offset][row] = *idty;
+        }
+    }
+    Ok(matrix)
+}
+
+
+/// A square matrix of the pairwise values of records, such as the identities of [`compute_identity_matrix`],
+/// looked up by record ID. The values are stored flat, row after row.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DistanceMatrix {
+    ids: Vec<String>,
+    data: Vec<f64>,
+    /// The row of each ID. A repeated ID stands for its first row, as in the ID files.
+    rows_by_id: HashMap<String, usize>,
+}
+
+
+impl DistanceMatrix {
+    /// The matrix of the `rows` of values, in the order of `ids`. Fails unless there are as many rows as IDs,
+    /// each with a value per ID.
+    pub fn new(ids: Vec<String>, rows: Vec<Vec<f64>>) -> Result<DistanceMatrix, NearestNeighborError> {
+        if rows.len() != ids.len() || rows.iter().any(|row| row.len() != ids.len()) {
+            return Err(NearestNeighborError::InvalidInput(format!(
+                "A matrix of {} IDs needs {} rows of {} values", ids.len(), ids.len(), ids.len(),
+            )));
+        }
+        let mut rows_by_id: HashMap<String, usize> = HashMap::with_capacity(ids.len());
+        for (row, id) in ids.iter().enumerate() {
+            rows_by_id.entry(id.clone()).or_insert(row);
+        }
+        Ok(DistanceMatrix { ids, data: rows.concat(), rows_by_id })
+    }
+
+    /// The identity matrix of the records, see [`compute_identity_matrix`].
+    pub fn from_records(records: &[&Record], config: &ComparisonConfig) -> Result<DistanceMatrix, NearestNeighborError> {
+        let ids: Vec<String> = records.iter().map(|record| record.id().to_owned()).collect();
+        DistanceMatrix::new(ids, compute_identity_matrix(records, config)?)
+    }
+
+    pub fn ids(&self) -> &[String] {
+        &self.ids
+    }
+
+    /// The number of records, i.e. of rows and of columns.
+    pub fn len(&self) -> usize {
+        self.ids.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ids.is_empty()
+    }
+
+    /// The value of the records `id1` and `id2`, or `None` if either ID isn't in the matrix.
+    pub fn get(&self, id1: &str, id2: &str) -> Option<f64> {
+        Some(self[(*self.rows_by_id.get(id1)?, *self.rows_by_id.get(id2)?)])
+    }
+
+    /// The values of the record `id` to all the records, in the order of [`DistanceMatrix::ids`].
+    pub fn row(&self, id: &str) -> Option<&[f64]> {
+        let row = *self.rows_by_id.get(id)?;
+        Some(&self.data[row * self.len()..(row + 1) * self.len()])
+    }
+
+    /// Each pair of distinct records once, with its value: the upper triangle, row after row.
+    pub fn iter_pairs(&self) -> impl Iterator<Item = (&str, &str, f64)> + '_ {
+        (0..self.len()).flat_map(move |row| {
+            (row + 1..self.len()).map(move |col| (self.ids[row].as_str(), self.ids[col].as_str(), self[(row, col)]))
+        })
+    }
+}
+
+
+impl Index<(usize, usize)> for DistanceMatrix {
+    type Output = f64;
+
+    /// The value at a 0-based `(row, column)`. Panics out of bounds.
+    fn index(&self, (row, col): (usize, usize)) -> &f64 {
+        assert!(row < self.len() && col < self.len(), "({}, {}) is out of a matrix of {} records", row, col, self.len());
+        &self.data[row * self.len() + col]
+    }
+}
+
+
+/// Write an identity matrix as a square TSV table, with the record IDs as header row and first column,
+/// and the identities with `precision` decimal places.
+pub fn write_identity_matrix<W: Write>(
+    ids: &[&str],
+    matrix: &[Vec<f64>],
+    precision: usize,
+    writer: &mut W,
+) -> Result<(), NearestNeighborError> {
+    writeln!(writer, "\t{}", ids.join("\t"))?;
+    for (id, row) in ids.iter().zip(matrix.iter()) {
+        let values: Vec<String> = row.iter().map(|idty| format!("{:.*}", precision, idty)).collect();
+        writeln!(writer, "{}\t{}", id, values.join("\t"))?;
+    }
+    Ok(())
+}
+
+
+/// Make a square matrix exactly symmetric by replacing `m[i][j]` and `m[j][i]` with their average, for a
+/// matrix computed in both directions where floating-point rounding makes the two halves differ slightly.
+pub fn symmetrize(matrix: &mut [Vec<f64>]) {
+    for row in 0..matrix.len() {
+        let (head, tail) = matrix.split_at_mut(row + 1);
+        let upper = &mut head[row];
+        for (offset, lower) in tail.iter_mut().enumerate() {
+            let col = row + 1 + offset;
+            let mean = (upper[col] + lower[row]) / 2.0;
+            upper[col] = mean;
+            lower[row] = mean;
+        }
+    }
+}
+
+
+/// Write the upper triangle of a symmetric matrix as a TSV table, with the IDs as header row and first column,
+/// and the values with `precision` decimal places. The diagonal and the lower triangle are left as empty cells,
+/// so that the table reads back as a matrix of missing values below the diagonal, as for `ape`.
+pub fn export_upper_triangle<W: Write>(
+    matrix: &[Vec<f64>],
+    ids: &[&str],
+    precision: usize,
+    writer: &mut W,
+) -> Result<(), NearestNeighborError> {
+    writeln!(writer, "\t{}", ids.join("\t"))?;
+    for (row, (id, values)) in ids.iter().zip(matrix.iter()).enumerate() {
+        let cells: Vec<String> = values.iter()
+            .enumerate()
+            .map(|(col, value)| if col > row { format!("{:.*}", precision, value) } else { String::new() })
+            .collect();
+        writeln!(writer, "{}\t{}", id, cells.join("\t"))?;
+    }
+    Ok(())
+}
+
+
+/// Load a matrix written by [`write_identity_matrix`] or [`export_upper_triangle`], to reuse it without computing
+/// it again. Returns the IDs of the header row and the square matrix. The empty cells of an upper triangle are filled
+/// from their mirrored cell, and an empty diagonal cell with 1. The row IDs must follow the order of the header row.
+pub fn load_identity_matrix(path: &Path) -> Result<(Vec<String>, Vec<Vec<f64>>), FastaParseError> {
+    let reader = BufReader::new(File::open(path)?);
+    let mut ids: Option<Vec<String>> = None;
+    let mut cells: Vec<Vec<Option<f64>>> = vec![];
+    for (line_idx, line) in reader.lines().enumerate() {
+        let line = line?;
+        let line = line.trim_end_matches('\r');
+        if line.trim().is_empty() {
+            continue;
+        }
+        let Some(ids) = &ids else {
+            ids = Some(line.split('\t').skip(1).map(str::to_owned).collect());
+            continue;
+        };
+        let fields: Vec<&str> = line.split('\t').collect();
+        let row = cells.len();
+        if row == ids.len() {
+            return Err(format_error(format!("Line {} is an extra row: the header has {} IDs", line_idx + 1, ids.len())));
+        }
+        if fields[0] != ids[row] {
+            return Err(format_error(format!(
+                "Row {} on line {} has the ID {}, but the header has {} in this position",
+                row + 1, line_idx + 1, fields[0], ids[row],
+            )));
+        }
+        if fields.len() != ids.len() + 1 {
+            return Err(FastaParseError {
+                message: format!("Line {} has {} values, but the header has {} IDs", line_idx + 1, fields.len() - 1, ids.len()),
+                kind: FastaParseErrorKind::LengthMismatch,
+                source: None,
+            });
+        }
+        let values = fields[1..].iter()
+            .map(|field| match field.trim() {
+                "" => Ok(None),
+                field => field.parse::<f64>()
+                    .map(Some)
+                    .map_err(|_| format_error(format!("Invalid value {:?} on line {}", field, line_idx + 1))),
+            })
+            .collect::<Result<Vec<Option<f64>>, FastaParseError>>()?;
+        cells.push(values);
+    }
+    let ids = ids.ok_or_else(|| FastaParseError {
+        message: "No matrix found.".to_owned(),
+        kind: FastaParseErrorKind::EmptyFile,
+        source: None,
+    })?;
+    if cells.len() != ids.len() {
+        return Err(FastaParseError {
+            message: format!("The matrix has {} rows, but the header has {} IDs", cells.len(), ids.len()),
+            kind: FastaParseErrorKind::LengthMismatch,
+            source: None,
+        });
+    }
+
+    let mut matrix: Vec<Vec<f64>> = vec![vec![0.0; ids.len()]; ids.len()];
+    for (row, row_cells) in cells.iter().enumerate() {
+        for (col, cell) in row_cells.iter().enumerate() {
+            matrix[row][col] = match (*cell, cells[col][row]) {
+                (Some(value), _) | (None, Some(value)) => value,
+                (None, None) if row == col => 1.0,
+                (None, None) => return Err(format_error(format!("The value of {} and {} is missing", ids[row], ids[col]))),
+            };
+        }
+    }
+    Ok((ids, matrix))
+}
+
+
+fn format_error(message: String) -> FastaParseError {
+    FastaParseError { message, kind: FastaParseErrorKind::FormatError, source: None }
+}
+
+
+/// The mean identities of record groups, keyed by ordered pairs of group names: `(a, a)` holds the mean identity
+/// of the pairs of distinct records within group `a`, and `(a, b)` with `a < b` the mean identity of the pairs
+/// with one record in each group. Means without any pair, as for a group of a single record, are NaN.
+pub type GroupStatistics = HashMap<(String, String), f64>;
+
+
+/// Compute the within- and between-group mean identities of the grouped records, see [`GroupStatistics`].
+/// Records without a group are left out, and only the identities between grouped records are computed.
+pub fn compute_group_statistics(
+    records: &[&Record],
+    groups: &HashMap<String, String>,
+    config: &ComparisonConfig,
+) -> Result<GroupStatistics, NearestNeighborError> {
+    let grouped: Vec<&Record> = records.iter()
+        .filter(|record| groups.contains_key(record.id()))
+        .copied()
+        .collect();
+    let matrix = compute_identity_matrix(&grouped, config)?;
+    let ids: Vec<&str> = grouped.iter().map(|record| record.id()).collect();
+    Ok(group_statistics_from_matrix(&ids, &matrix, groups))
+}
+
+
+/// Compute the [`GroupStatistics`] of an identity matrix of the records with these IDs, as computed by
+/// [`compute_identity_matrix`]. NaN identities, of all-gap pairs, are left out of the means.
+pub fn group_statistics_from_matrix(
+    ids: &[&str],
+    matrix: &[Vec<f64>],
+    groups: &HashMap<String, String>,
+) -> GroupStatistics {
+    let record_groups: Vec<Option<&String>> = ids.iter().map(|id| groups.get(*id)).collect();
+    let mut sums: HashMap<(String, String), (f64, usize)> = HashMap::new();
+    for group in record_groups.iter().flatten() {
+        sums.entry(((*group).clone(), (*group).clone())).or_insert((0.0, 0));
+    }
+    for (row, row_group) in record_groups.iter().enumerate() {
+        let Some(row_group) = row_group else { continue };
+        for (col, col_group) in record_groups.iter().enumerate().skip(row + 1) {
+            let Some(col_group) = col_group else { continue };
+            let idty = matrix[row][col];
+            if idty.is_nan() {
+                continue;
+            }
+            let key = if row_group <= col_group {
+                ((*row_group).clone(), (*col_group).clone())
+            } else {
+                ((*col_group).clone(), (*row_group).clone())
+            };
+            let (sum, count) = sums.entry(key).or_insert((0.0, 0));
+            *sum += idty;
+            *count += 1;
+        }
+    }
+    sums.into_iter()
+        .map(|(key, (sum, count))| (key, if count == 0 { f64::NAN } else { sum / count as f64 }))
+        .collect()
+}
+
+
+/// Write the group statistics as a TSV table with one row per pair of distinct groups, sorted by name:
+/// `group_a`, `group_b`, the within-group means of both groups, their between-group mean, and the ratio of the
+/// average of the two within-group means to the between-group mean. Values are written with `precision` decimal
+/// places, and missing means as `NA`, as is the ratio of groups whose between-group mean is 0.
+pub fn write_group_statistics<W: Write>(
+    statistics: &GroupStatistics,
+    precision: usize,
+    writer: &mut W,
+) -> Result<(), NearestNeighborError> {
+    let fmt = |value: f64| if !value.is_finite() { "NA".to_owned() } else { format!("{:.*}", precision, value) };
+    let within = |group: &str| statistics.get(&(group.to_owned(), group.to_owned())).copied().unwrap_or(f64::NAN);
+    let mut pairs: Vec<(&(String, String), f64)> = statistics.iter()
+        .filter(|((group_a, group_b), _)| group_a != group_b)
+        .map(|(key, between)| (key, *between))
+        .collect();
+    pairs.sort_by(|a, b| a.0.cmp(b.0));
+
+    writeln!(writer, "group_a\tgroup_b\twithin_a\twithin_b\tbetween\tratio")?;
+    for ((group_a, group_b), between) in pairs {
+        let (within_a, within_b) = (within(group_a), within(group_b));
+        let ratio = (within_a + within_b) / 2.0 / between;
+        writeln!(
+            writer, "{}\t{}\t{}\t{}\t{}\t{}",
+            group_a, group_b, fmt(within_a), fmt(within_b), fmt(between), fmt(ratio),
+        )?;
+    }
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use bio::io::fasta::Record;
+    use crate::{nearest_neighbor::{ComparisonConfig, NearestNeighborError}, FastaParseErrorKind};
+    use super::{
+        compute_group_statistics, compute_identity_matrix, export_upper_triangle, load_identity_matrix, symmetrize,
+        write_group_statistics, write_identity_matrix, DistanceMatrix,
+    };
+
+    #[test]
+    fn test_identity_matrix() {
+        let records = [
+            Record::with_attrs("x", None, b"AAAA"),
+            Record::with_attrs("y", None, b"AACC"),
+            Record::with_attrs("z", None, b"A-C-"),
+        ];
+        let record_refs: Vec<&Record> = records.iter().collect();
+        let matrix = compute_identity_matrix(&record_refs, &ComparisonConfig::default()).unwrap();
+        assert_eq!(matrix[0], vec![1.0, 0.5, 0.25]);
+        assert_eq!(matrix[1], vec![0.5, 1.0, 0.5]);
+        assert_eq!(matrix[2], vec![0.25, 0.5, 1.0]);
+
+        let mut buf: Vec<u8> = vec![];
+        write_identity_matrix(&["x", "y", "z"], &matrix, 2, &mut buf).unwrap();
+        assert_eq!(
+            String::from_utf8(buf).unwrap(),
+            "\tx\ty\tz\nx\t1.00\t0.50\t0.25\ny\t0.50\t1.00\t0.50\nz\t0.25\t0.50\t1.00\n",
+        );
+    }
+
+    #[test]
+    fn test_distance_matrix() {
+        let records = [
+            Record::with_attrs("x", None, b"AAAA"),
+            Record::with_attrs("y", None, b"AACC"),
+            Record::with_attrs("z", None, b"A-C-"),
+        ];
+        let record_refs: Vec<&Record> = records.iter().collect();
+        let matrix = DistanceMatrix::from_records(&record_refs, &ComparisonConfig::default()).unwrap();
+        assert_eq!((matrix.len(), matrix.ids()), (3, ["x".to_owned(), "y".to_owned(), "z".to_owned()].as_slice()));
+        for (id1, id2, expected) in [
+            ("x", "y", 0.5), ("y", "x", 0.5), ("x", "z", 0.25), ("z", "x", 0.25), ("y", "z", 0.5), ("z", "y", 0.5),
+        ] {
+            assert_eq!(matrix.get(id1, id2), Some(expected), "{} {}", id1, id2);
+        }
+        assert_eq!(matrix.get("x", "x"), Some(1.0));
+        assert_eq!(matrix.get("x", "missing"), None);
+        assert_eq!(matrix.row("z"), Some([0.25, 0.5, 1.0].as_slice()));
+        assert_eq!(matrix.row("missing"), None);
+        assert_eq!((matrix[(0, 2)], matrix[(2, 1)]), (0.25, 0.5));
+        assert_eq!(
+            matrix.iter_pairs().collect::<Vec<(&str, &str, f64)>>(),
+            [("x", "y", 0.5), ("x", "z", 0.25), ("y", "z", 0.5)],
+        );
+
+        let ragged = DistanceMatrix::new(vec!["x".to_owned(), "y".to_owned()], vec![vec![1.0, 0.5], vec![0.5]]);
+        assert!(matches!(ragged, Err(NearestNeighborError::InvalidInput(_))));
+    }
+
+    #[test]
+    fn test_symmetrize() {
+        let mut matrix = vec![
+            vec![1.0, 0.5000001, 0.25],
+            vec![0.4999999, 1.0, 0.75],
+            vec![0.25, 0.25, 1.0],
+        ];
+        symmetrize(&mut matrix);
+        for (row, values) in matrix.iter().enumerate() {
+            for (col, value) in values.iter().enumerate() {
+                assert_eq!(*value, matrix[col][row]);
+            }
+        }
+        assert!((matrix[0][1] - 0.5).abs() < 1e-12);
+        assert_eq!(matrix[1][2], 0.5);
+        assert_eq!(matrix[0][2], 0.25);
+        assert_eq!(matrix[1][1], 1.0);
+
+        let mut buf: Vec<u8> = vec![];
+        export_upper_triangle(&matrix, &["x", "y", "z"], 2, &mut buf).unwrap();
+        assert_eq!(String::from_utf8(buf).unwrap(), "\tx\ty\tz\nx\t\t0.50\t0.25\ny\t\t\t0.50\nz\t\t\t\n");
+    }
+
+    #[test]
+    fn test_load_identity_matrix() {
+        let ids = ["a", "b", "c", "d"];
+        let matrix = vec![
+            vec![1.0, 0.123456789, 0.5, f64::NAN],
+            vec![0.123456789, 1.0, 0.987654321, 0.25],
+            vec![0.5, 0.987654321, 1.0, 1.0 / 3.0],
+            vec![f64::NAN, 0.25, 1.0 / 3.0, 1.0],
+        ];
+        let path = std::env::temp_dir().join("aligned_nearest_neighbor_test_load_identity_matrix.tsv");
+        for upper_triangle in [false, true] {
+            let mut writer = std::fs::File::create(&path).unwrap();
+            if upper_triangle {
+                export_upper_triangle(&matrix, &ids, 8, &mut writer).unwrap();
+            } else {
+                write_identity_matrix(&ids, &matrix, 8, &mut writer).unwrap();
+            }
+            drop(writer);
+
+            let (loaded_ids, loaded) = load_identity_matrix(&path).unwrap();
+            assert_eq!(loaded_ids, ids);
+            for (row, values) in matrix.iter().enumerate() {
+                for (col, value) in values.iter().enumerate() {
+                    let loaded_value = loaded[row][col];
+                    if row == col && upper_triangle {
+                        assert_eq!(loaded_value, 1.0);
+                    } else if value.is_nan() {
+                        assert!(loaded_value.is_nan());
+                    } else {
+                        assert!((loaded_value - value).abs() <= 1e-8, "{} != {}", loaded_value, value);
+                    }
+                }
+            }
+        }
+
+        std::fs::write(&path, "\ta\tb\nb\t1\t0.5\na\t0.5\t1\n").unwrap();
+        assert!(matches!(load_identity_matrix(&path).unwrap_err().kind, FastaParseErrorKind::FormatError));
+        std::fs::write(&path, "\ta\tb\na\t1\t\nb\t\t1\n").unwrap();
+        assert_eq!(load_identity_matrix(&path).unwrap_err().message, "The value of a and b is missing");
+        std::fs::write(&path, "\ta\tb\na\t1\t0.5\n").unwrap();
+        assert!(matches!(load_identity_matrix(&path).unwrap_err().kind, FastaParseErrorKind::LengthMismatch));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_group_statistics() {
+        let records = [
+            Record::with_attrs("a1", None, b"AAAA"),
+            Record::with_attrs("a2", None, b"AAAA"),
+            Record::with_attrs("b1", None, b"CCCC"),
+            Record::with_attrs("b2", None, b"CCCC"),
+            Record::with_attrs("c1", None, b"AACC"),
+            Record::with_attrs("ungrouped", None, b"AAAA"),
+        ];
+        let record_refs: Vec<&Record> = records.iter().collect();
+        let groups: HashMap<String, String> = [("a1", "A"), ("a2", "A"), ("b1", "B"), ("b2", "B"), ("c1", "C")]
+            .into_iter()
+            .map(|(id, group)| (id.to_owned(), group.to_owned()))
+            .collect();
+        let statistics = compute_group_statistics(&record_refs, &groups, &ComparisonConfig::default()).unwrap();
+        let key = |a: &str, b: &str| (a.to_owned(), b.to_owned());
+        assert_eq!(statistics[&key("A", "A")], 1.0);
+        assert_eq!(statistics[&key("B", "B")], 1.0);
+        assert_eq!(statistics[&key("A", "B")], 0.0);
+        assert_eq!(statistics[&key("A", "C")], 0.5);
+        assert!(statistics[&key("C", "C")].is_nan());
+        assert_eq!(statistics.len(), 6);
+
+        let mut buf: Vec<u8> = vec![];
+        write_group_statistics(&statistics, 2, &mut buf).unwrap();
+        assert_eq!(
+            String::from_utf8(buf).unwrap(),
+            "group_a\tgroup_b\twithin_a\twithin_b\tbetween\tratio\n\
+             A\tB\t1.00\t1.00\t0.00\tNA\n\
+             A\tC\t1.00\tNA\t0.50\tNA\n\
+             B\tC\t1.00\tNA\t0.50\tNA\n",
+        );
+    }
+}
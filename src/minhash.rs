@@ -0,0 +1,225 @@
+use rayon::prelude::*;
+use bio::io::fasta::Record;
+
+use crate::{nearest_neighbor::GAP, sample::mix64};
+
+
+/// The k-mer length used by the approximate search.
+pub const MINHASH_K: usize = 5;
+
+/// The number of hash functions, i.e. the sketch size, used by the approximate search.
+pub const MINHASH_NUM_HASHES: usize = 128;
+
+
+/// The MinHash sketch of the ungapped k-mer set of a sequence: the minimum of each of `num_hashes` hash
+/// functions over the k-mers.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MinHashSketch {
+    pub mins: Vec<u64>,
+}
+
+
+/// MinHash sketches of the ungapped k-mer sets of a collection of database records.
+#[derive(Debug, Clone)]
+pub struct MinHashIndex {
+    pub k: usize,
+    pub num_hashes: usize,
+    /// One sketch per database record, in the order of the records.
+    pub sketches: Vec<MinHashSketch>,
+}
+
+
+/// FNV-1a hash of a k-mer.
+fn hash_kmer(kmer: &[u8]) -> u64 {
+    let mut h: u64 = 0xcbf29ce484222325;
+    for ch in kmer.iter() {
+        h ^= *ch as u64;
+        h = h.wrapping_mul(0x100000001b3);
+    }
+    h
+}
+
+
+impl MinHashSketch {
+    /// Sketch the k-mers of a sequence, ignoring gaps and case. Sequences shorter than `k` residues get
+    /// a sketch of `u64::MAX`, which matches no other sketch.
+    pub fn new(seq: &[u8], k: usize, num_hashes: usize) -> MinHashSketch {
+        let residues: Vec<u8> = seq.iter()
+            .filter(|ch| **ch != GAP)
+            .map(|ch| ch.to_ascii_uppercase())
+            .collect();
+        let mut mins: Vec<u64> = vec![u64::MAX; num_hashes];
+        if residues.len() < k {
+            return MinHashSketch { mins };
+        }
+        for kmer in residues.windows(k) {
+            let kmer_hash = hash_kmer(kmer);
+            for (seed, min) in mins.iter_mut().enumerate() {
+                let h = mix64(kmer_hash ^ mix64(seed as u64));
+                if h < *min {
+                    *min = h;
+                }
+            }
+        }
+        MinHashSketch { mins }
+    }
+
+    /// The estimated Jaccard similarity of the k-mer sets behind two sketches of the same size: the fraction
+    /// of hash functions with the same minimum.
+    pub fn estimate_jaccard(&self, other: &MinHashSketch) -> f32 {
+        let agree = self.mins.iter()
+            .zip(other.mins.iter())
+            .filter(|(x, y)| x == y && **x != u64::MAX)
+            .count();
+        (agree as f32) / (self.mins.len() as f32)
+    }
+}
+
+
+/// Sketch each database record using multiple worker threads.
+pub fn build_minhash_index(db: &[&Record], k: usize, num_hashes: usize) -> MinHashIndex {
+    let sketches: Vec<MinHashSketch> = db.par_iter()
+        .map(|record| MinHashSketch::new(record.seq(), k, num_hashes))
+        .collect();
+    MinHashIndex { k, num_hashes, sketches }
+}
+
+
+/// Select the `top_c` database records whose k-mer sets are most similar to the query's.
+/// Ties go to the later record, as in the exact search. The returned indices are in increasing
+/// order, so that comparing the candidates in order keeps the exact search's tie-breaking.
+pub fn query_candidates(index: &MinHashIndex, query: &Record, top_c: usize) -> Vec<usize> {
+    let query_sketch = MinHashSketch::new(query.seq(), index.k, index.num_hashes);
+    let mut scored: Vec<(usize, f32)> = index.sketches.iter()
+        .enumerate()
+        .map(|(db_idx, db_sketch)| (db_idx, query_sketch.estimate_jaccard(db_sketch)))
+        .collect();
+    scored.sort_unstable_by(|a, b| b.1.total_cmp(&a.1).then(b.0.cmp(&a.0)));
+    scored.truncate(top_c);
+
+    let mut candidates: Vec<usize> = scored.into_iter().map(|(db_idx, _)| db_idx).collect();
+    candidates.sort_unstable();
+    candidates
+}
+
+
+#[cfg(test)]
+mod tests {
+    use bio::io::fasta::Record;
+    use crate::nearest_neighbor::{compute_nearest_neighbors, compute_top_k_neighbors, NeighborConfig};
+    use super::{build_minhash_index, query_candidates, MinHashSketch};
+
+    /// A deterministic pseudo-random nucleotide sequence.
+    fn random_seq(state: &mut u64, len: usize) -> Vec<u8> {
+        (0..len)
+            .map(|_| {
+                *state = state.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+                b"ACGT"[(*state >> 62) as usize]
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_sketch_ignores_gaps() {
+        assert_eq!(MinHashSketch::new(b"AC-GTA--CG", 5, 16), MinHashSketch::new(b"acgtacg---", 5, 16));
+        let short = MinHashSketch::new(b"AC--", 5, 16);
+        assert!(short.mins.iter().all(|h| *h == u64::MAX));
+        assert_eq!(short.estimate_jaccard(&short), 0.0);
+    }
+
+    #[test]
+    fn test_estimate_jaccard() {
+        let mut state: u64 = 7;
+        let seq = random_seq(&mut state, 200);
+        let x = MinHashSketch::new(&seq, 5, 256);
+        assert_eq!(x.estimate_jaccard(&x), 1.0);
+        // Sharing the first half of their k-mers, the sequences have a Jaccard similarity of about 1/3.
+        let mut other = seq[..100].to_vec();
+        other.extend(random_seq(&mut state, 100));
+        let jaccard = x.estimate_jaccard(&MinHashSketch::new(&other, 5, 256));
+        assert!((0.2..0.5).contains(&jaccard), "{}", jaccard);
+    }
+
+    #[test]
+    fn test_approx_matches_exact() {
+        let mut state: u64 = 42;
+        let db: Vec<Record> = (0..40)
+            .map(|i| Record::with_attrs(&format!("db{}", i), None, &random_seq(&mut state, 80)))
+            .collect();
+        // Each query is a db record with a few substitutions.
+        let queries: Vec<Record> = (0..10)
+            .map(|i| {
+                let mut seq = db[i * 4].seq().to_vec();
+                seq[10] = b'-';
+                seq[40] = if seq[40] == b'A' { b'C' } else { b'A' };
+                seq[70] = b'-';
+                Record::with_attrs(&format!("q{}", i), None, &seq)
+            })
+            .collect();
+        let db_refs: Vec<&Record> = db.iter().collect();
+        let query_refs: Vec<&Record> = queries.iter().collect();
+
+        let index = build_minhash_index(&db_refs, 5, 64);
+        assert!(query_candidates(&index, &queries[0], 5).contains(&0));
+
+        let exact = compute_nearest_neighbors(&query_refs, &db_refs, &NeighborConfig::default()).unwrap();
+        let config = NeighborConfig { approx_candidates: Some(5), ..Default::default() };
+        let approx = compute_nearest_neighbors(&query_refs, &db_refs, &config).unwrap();
+        for ((exact_hit, exact_idty), (approx_hit, approx_idty)) in exact.iter().zip(approx.iter()) {
+            assert_eq!(exact_hit.id(), approx_hit.id());
+            assert_eq!(exact_idty, approx_idty);
+        }
+
+        let config = NeighborConfig { approx_candidates: Some(5), top_k: 3, ..Default::default() };
+        let approx = compute_top_k_neighbors(&query_refs, &db_refs, &config).unwrap();
+        for ((exact_hit, _), approx_hits) in exact.iter().zip(approx.iter()) {
+            assert_eq!(approx_hits.len(), 3);
+            assert_eq!(exact_hit.id(), approx_hits[0].0.id());
+        }
+    }
+
+    #[test]
+    fn test_approx_matches_exact_with_50_candidates() {
+        let mut state: u64 = 1234;
+        // Families of related records: a random founder and variants with substitutions, so that each query
+        // has close but distinct relatives.
+        let mut db: Vec<Record> = vec![];
+        for family in 0..20 {
+            let founder = random_seq(&mut state, 150);
+            for variant in 0..10 {
+                let mut seq = founder.clone();
+                for pos in (variant..seq.len()).step_by(11).take(variant * 3) {
+                    seq[pos] = if seq[pos] == b'A' { b'C' } else { b'A' };
+                }
+                db.push(Record::with_attrs(&format!("db{}_{}", family, variant), None, &seq));
+            }
+        }
+        let queries: Vec<Record> = (0..30)
+            .map(|i| {
+                let mut seq = db[i * 6].seq().to_vec();
+                for pos in [5, 33, 71, 102, 140] {
+                    seq[pos] = if seq[pos] == b'G' { b'T' } else { b'G' };
+                }
+                Record::with_attrs(&format!("q{}", i), None, &seq)
+            })
+            .collect();
+        let db_refs: Vec<&Record> = db.iter().collect();
+        let query_refs: Vec<&Record> = queries.iter().collect();
+
+        let exact = compute_nearest_neighbors(&query_refs, &db_refs, &NeighborConfig::default()).unwrap();
+        let config = NeighborConfig { approx_candidates: Some(50), ..Default::default() };
+        let approx = compute_nearest_neighbors(&query_refs, &db_refs, &config).unwrap();
+        for ((exact_hit, exact_idty), (approx_hit, approx_idty)) in exact.iter().zip(approx.iter()) {
+            assert_eq!((exact_hit.id(), exact_idty), (approx_hit.id(), approx_idty));
+        }
+
+        let config = NeighborConfig { top_k: 5, ..Default::default() };
+        let exact = compute_top_k_neighbors(&query_refs, &db_refs, &config).unwrap();
+        let config = NeighborConfig { approx_candidates: Some(50), top_k: 5, ..Default::default() };
+        let approx = compute_top_k_neighbors(&query_refs, &db_refs, &config).unwrap();
+        let ids = |hits: &[(&Record, f64)]| hits.iter().map(|(hit, idty)| (hit.id().to_owned(), *idty)).collect::<Vec<_>>();
+        for (exact_hits, approx_hits) in exact.iter().zip(approx.iter()) {
+            assert_eq!(ids(exact_hits), ids(approx_hits));
+        }
+    }
+}
@@ -1,217 +1,2708 @@
 use std::{
-    path::Path,
+    borrow::Cow,
+    cell::Cell,
     fs::File,
-    io::{Write, BufWriter},
-    sync::Arc,
-    collections::HashSet,
+    io::{BufWriter, Write},
+    path::Path,
+    sync::{mpsc, Arc},
+    time::Duration,
+    collections::{HashMap, HashSet},
     fmt::{Debug, Display, Formatter},
 };
 use rayon::{
     prelude::*,
 };
-use indicatif::{ProgressBar, ProgressStyle, ParallelProgressIterator};
 use bio::io::fasta::Record;
+use regex::Regex;
+
+use crate::{
+    blast::blast_hit,
+    graph::write_graph_file,
+    output::{
+        create_output_writer, header_line, sort_rows, write_atomically, write_identity_summary, write_mismatch_positions,
+        write_output_span, NearestNeighborResultRow, OutputConfig, OutputFormat, OutputWriter, RunnerUp, SortKey,
+    },
+    stats::{gap_fraction, gc_content, record_stats},
+    strand::{reverse_complement_record, Strand},
+    columns::{compute_column_stats, consensus_record, informative_columns, trim_record},
+    progress::{Progress, ProgressMode, DEFAULT_PROGRESS_INTERVAL},
+    telemetry::SearchCounters,
+    sample::RecordSample,
+    shard::Shard,
+    cancel::{completed_prefix, CancellationToken},
+    distance::{is_transition, is_transversion, jc69_distance, k2p_distance, Metric, PairwiseMetric, Ranking, SearchMode},
+    duplicates::DuplicateGroups,
+    score_matrix::ScoreMatrix,
+    match_matrix::MatchMatrix,
+    gaps::GapChars,
+};
+#[cfg(feature = "minhash")]
+use crate::minhash::{build_minhash_index, query_candidates, MinHashIndex, MINHASH_K, MINHASH_NUM_HASHES};
+
+/// Without the `minhash` feature, the pre-filter can't be built, so there is never an index.
+#[cfg(not(feature = "minhash"))]
+type MinHashIndex = std::convert::Infallible;
 
 // ======== boilerplate code START
-type NeighborResult<'a> = Vec<(&'a Record, f32)>;
+type NeighborResult<'a> = Vec<(&'a Record, f64)>;
+type TopKNeighborResult<'a> = Vec<Vec<(&'a Record, f64)>>;
 
 
-#[derive(Debug, PartialEq)]  // Add PartialEq here
+#[derive(Debug)]
 pub enum NearestNeighborError {
-    IOError(String),
+    /// An I/O failure, keeping the underlying error as the source.
+    Io(std::io::Error),
+    /// A failure of the output backend, e.g. a SQLite error.
+    Output(String),
+    /// An option that isn't available in this build, e.g. an output format behind a disabled feature.
+    Unsupported(String),
+    /// An input file that is malformed, or inconsistent with the other inputs.
+    InvalidInput(String),
     HammingDistanceError(String, String),
+    EmptyDatabase(String),
+    /// No query record was selected.
+    EmptyQuery,
+    /// No database record was compared against the query on at least the given number of columns,
+    /// see [`ComparisonConfig::min_overlap`].
+    InsufficientOverlap(String, usize),
+    /// No database record covered at least the given fraction of the query's residues,
+    /// see [`ComparisonConfig::min_query_coverage`].
+    InsufficientCoverage(String, f64),
+    /// The search was cancelled after the given number of queries were completed.
+    Cancelled(usize),
 }
 
 
 impl Display for NearestNeighborError {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         match self {
-            NearestNeighborError::IOError(msg) => { write!(f, "{}", msg) }
+            NearestNeighborError::Io(err) => { write!(f, "{}", err) }
+            NearestNeighborError::Output(msg) => { write!(f, "{}", msg) }
+            NearestNeighborError::Unsupported(msg) => { write!(f, "{}", msg) }
+            NearestNeighborError::InvalidInput(msg) => { write!(f, "{}", msg) }
             NearestNeighborError::HammingDistanceError(id1, id2) => {
                 write!(f, "Hamming distance computation error between: {} and {}", id1, id2)
             }
+            NearestNeighborError::EmptyDatabase(id) => {
+                write!(f, "No database record could be compared against query: {}", id)
+            }
+            NearestNeighborError::EmptyQuery => { write!(f, "No query record was selected") }
+            NearestNeighborError::InsufficientOverlap(id, min_overlap) => {
+                write!(f, "No database record overlaps query {} on at least {} compared columns", id, min_overlap)
+            }
+            NearestNeighborError::InsufficientCoverage(id, min_coverage) => {
+                write!(f, "No database record covers at least a fraction {} of the residues of query {}", min_coverage, id)
+            }
+            NearestNeighborError::Cancelled(completed) => {
+                write!(f, "Cancelled after {} queries were completed", completed)
+            }
+        }
+    }
+}
+
+impl std::error::Error for NearestNeighborError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            NearestNeighborError::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+// io::Error has no PartialEq, so I/O errors are compared by kind and message.
+impl PartialEq for NearestNeighborError {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (NearestNeighborError::Io(a), NearestNeighborError::Io(b)) => {
+                a.kind() == b.kind() && a.to_string() == b.to_string()
+            }
+            (NearestNeighborError::Output(a), NearestNeighborError::Output(b)) => a == b,
+            (NearestNeighborError::Unsupported(a), NearestNeighborError::Unsupported(b)) => a == b,
+            (NearestNeighborError::InvalidInput(a), NearestNeighborError::InvalidInput(b)) => a == b,
+            (NearestNeighborError::HammingDistanceError(a1, a2), NearestNeighborError::HammingDistanceError(b1, b2)) => {
+                a1 == b1 && a2 == b2
+            }
+            (NearestNeighborError::EmptyDatabase(a), NearestNeighborError::EmptyDatabase(b)) => a == b,
+            (NearestNeighborError::EmptyQuery, NearestNeighborError::EmptyQuery) => true,
+            (NearestNeighborError::InsufficientOverlap(a, n), NearestNeighborError::InsufficientOverlap(b, m)) => {
+                a == b && n == m
+            }
+            (NearestNeighborError::InsufficientCoverage(a, x), NearestNeighborError::InsufficientCoverage(b, y)) => {
+                a == b && x == y
+            }
+            (NearestNeighborError::Cancelled(a), NearestNeighborError::Cancelled(b)) => a == b,
+            _ => false,
         }
     }
 }
 
 impl From<std::io::Error> for NearestNeighborError {
     fn from(err: std::io::Error) -> NearestNeighborError {
-        NearestNeighborError::IOError(format!("{}", err))
+        NearestNeighborError::Io(err)
     }
 }
 
 // ======== boilerplate code END
-pub(super) fn filter_records(records: &[Record], id_arr: Option<Vec<String>>) -> Vec<&Record> {
-    match id_arr {
-        None => records.iter().collect(),
-        Some(id_list) => {
-            let id_subset: HashSet<String> = HashSet::from_iter(id_list);
-            records.iter()
-                .filter(|record| id_subset.contains(record.id()))
-                .collect()
+/// Which records [`filter_records`] selects, by their IDs. The IDs can be of any string type.
+#[derive(Debug, Clone)]
+pub enum RecordSelector<'s, S = String> {
+    /// Every record.
+    All,
+    /// The records whose ID is in the list.
+    Ids(&'s [S]),
+    /// The records whose ID matches the regex, anywhere in the ID unless the regex is anchored.
+    Regex(&'s Regex),
+    /// The records whose fraction of gap columns is at most this fraction, see [`gap_fraction`]. The others are
+    /// dropped with a warning.
+    MaxGapFraction(f32),
+    /// The records selected by every one of the selectors.
+    And(Vec<RecordSelector<'s, S>>),
+}
+
+
+impl<'s, S: AsRef<str>> RecordSelector<'s, S> {
+    /// The records whose ID is in `ids`, or all of them if `ids` is `None`.
+    pub fn ids(ids: Option<&'s [S]>) -> Self {
+        ids.map_or(RecordSelector::All, RecordSelector::Ids)
+    }
+
+    /// The records selected by both `self` and `other`.
+    pub fn and(self, other: RecordSelector<'s, S>) -> Self {
+        match (self, other) {
+            (RecordSelector::All, selector) | (selector, RecordSelector::All) => selector,
+            (RecordSelector::And(mut selectors), other) => {
+                selectors.push(other);
+                RecordSelector::And(selectors)
+            }
+            (selector, other) => RecordSelector::And(vec![selector, other]),
+        }
+    }
+
+    /// The `records` selected, keeping their order.
+    fn select<'r>(&self, records: Vec<&'r Record>) -> Vec<&'r Record> {
+        let num_records = records.len();
+        match self {
+            RecordSelector::All => records,
+            RecordSelector::Ids(id_list) => {
+                let id_subset: HashSet<&str> = id_list.iter().map(|id| id.as_ref()).collect();
+                let selected: Vec<&Record> = records.into_iter()
+                    .filter(|record| id_subset.contains(record.id()))
+                    .collect();
+                tracing::debug!("Selected {} of {} records by {} IDs", selected.len(), num_records, id_subset.len());
+                selected
+            }
+            RecordSelector::Regex(regex) => {
+                let selected: Vec<&Record> = records.into_iter()
+                    .filter(|record| regex.is_match(record.id()))
+                    .collect();
+                tracing::debug!("Selected {} of {} records by the regex {}", selected.len(), num_records, regex);
+                selected
+            }
+            RecordSelector::MaxGapFraction(max_gap_fraction) => {
+                let selected: Vec<&Record> = records.into_iter()
+                    .filter(|record| gap_fraction(record) <= *max_gap_fraction)
+                    .collect();
+                if selected.len() < num_records {
+                    tracing::warn!(
+                        "Filtered out {} of {} records with a gap fraction above {}",
+                        num_records - selected.len(), num_records, max_gap_fraction,
+                    );
+                }
+                selected
+            }
+            RecordSelector::And(selectors) => {
+                selectors.iter().fold(records, |records, selector| selector.select(records))
+            }
+        }
+    }
+}
+
+
+/// The records picked by `selector`, in the order of `records`, e.g.
+/// `filter_records(&records, &RecordSelector::Ids(&["seq_1", "seq_2"]))`.
+pub fn filter_records<'r, S: AsRef<str>>(records: &'r [Record], selector: &RecordSelector<'_, S>) -> Vec<&'r Record> {
+    match selector {
+        RecordSelector::All => records.iter().collect(),
+        _ => {
+            let _span = tracing::info_span!("filter_records", record_count = records.len()).entered();
+            selector.select(records.iter().collect())
+        }
+    }
+}
+
+
+/// The records selected by `include` as [`filter_records`] does, less those whose ID is in `exclude`: the
+/// exclusion takes precedence. Logs a warning listing the IDs both included and excluded.
+pub fn filter_records_excluding<'r, S: AsRef<str>, T: AsRef<str>>(
+    records: &'r [Record],
+    include: Option<&[S]>,
+    exclude: &[T],
+) -> Vec<&'r Record> {
+    let exclude_ids: HashSet<&str> = exclude.iter().map(|id| id.as_ref()).collect();
+    if let Some(include) = include {
+        let conflicting_ids: Vec<&str> = include.iter().map(|id| id.as_ref()).filter(|id| exclude_ids.contains(id)).collect();
+        if !conflicting_ids.is_empty() {
+            tracing::warn!("Excluding the IDs both included and excluded: {}", conflicting_ids.join(", "));
+        }
+    }
+    filter_records(records, &RecordSelector::ids(include)).into_iter()
+        .filter(|record| !exclude_ids.contains(record.id()))
+        .collect()
+}
+
+
+#[derive(Debug, Clone)]
+pub struct NeighborConfig {
+    /// Options for each pairwise comparison.
+    pub comparison: ComparisonConfig,
+    /// If set, the measure of each pair instead of the metric of `comparison`, whose options then don't apply
+    /// to the search. The reported rows still describe each pair with the options of `comparison`.
+    pub custom_metric: Option<Arc<dyn PairwiseMetric>>,
+    /// Skip database records with the same ID as the query, e.g. for leave-one-out evaluation.
+    /// With `collapse_duplicates`, also skip those with the same sequence, see [`NeighborConfig::is_self`].
+    pub exclude_self: bool,
+    /// Search the neighbors of each distinct query sequence once, on its first query, and report them for
+    /// each query of that sequence.
+    pub collapse_duplicates: bool,
+    /// The number of neighbors to report per query.
+    pub top_k: usize,
+    /// Append the consensus of the database records to the database, as a synthetic centroid entry.
+    pub include_consensus: bool,
+    /// Also drop the columns holding the same residue in every record before the search,
+    /// correcting the counts so that identities are unchanged.
+    pub compress_invariant: bool,
+    /// If set, only compare each query against this many database records, pre-selected by the
+    /// MinHash similarity of their k-mers. The result is approximate. Requires the `minhash` feature.
+    pub approx_candidates: Option<usize>,
+    /// How to report the progress of the search.
+    pub progress: ProgressMode,
+    /// How often JSON progress lines are written.
+    pub progress_interval: Duration,
+    /// Counters of the work done, shared by all clones of this config.
+    pub counters: Arc<SearchCounters>,
+    /// If set, only search the neighbors of a random sample of the queries.
+    pub query_sample: Option<RecordSample>,
+    /// If set, only search a random sample of the database records.
+    pub db_sample: Option<RecordSample>,
+    /// If set, only the database records with these IDs, and the consensus, may be reported as neighbors.
+    /// The other database records still count towards the consensus and the invariant columns.
+    pub allowed_neighbors: Option<Arc<HashSet<String>>>,
+    /// If set, only search the neighbors of the queries assigned to this shard.
+    pub shard: Option<Shard>,
+    /// Stops the search early when cancelled. The results of the completed queries are kept.
+    pub cancel: CancellationToken,
+    /// Whether to report the nearest or the farthest records.
+    pub mode: SearchMode,
+    /// Also compare the reverse complement of each query, and keep the better of the two strands' values
+    /// for each pair, see [`Strand`]. The records are compared untrimmed, as reversing a query reverses
+    /// its columns.
+    pub reverse_complement: bool,
+}
+
+
+impl Default for NeighborConfig {
+    fn default() -> Self {
+        NeighborConfig {
+            comparison: ComparisonConfig::default(),
+            custom_metric: None,
+            exclude_self: false,
+            collapse_duplicates: false,
+            top_k: 1,
+            include_consensus: false,
+            compress_invariant: false,
+            approx_candidates: None,
+            progress: ProgressMode::Bar,
+            progress_interval: DEFAULT_PROGRESS_INTERVAL,
+            counters: Arc::new(SearchCounters::default()),
+            shard: None,
+            cancel: CancellationToken::new(),
+            mode: SearchMode::Nearest,
+            query_sample: None,
+            db_sample: None,
+            allowed_neighbors: None,
+            reverse_complement: false,
+        }
+    }
+}
+
+
+impl NeighborConfig {
+    /// The measure of each pair: the custom metric if set, the metric of the comparison options otherwise.
+    pub fn pair_metric(&self) -> &dyn PairwiseMetric {
+        match &self.custom_metric {
+            Some(metric) => metric.as_ref(),
+            None => &self.comparison,
+        }
+    }
+
+    /// How the search ranks the values of the pair metric.
+    pub fn ranking(&self) -> Ranking {
+        Ranking { distance: self.pair_metric().is_distance(), mode: self.mode }
+    }
+
+    /// The best value a pair can reach with the metric and the mode, if bounded: an identity of 1 for the nearest
+    /// neighbors, or a distance of 0. The search of the nearest neighbor of a query stops at the first record of
+    /// that value.
+    pub fn perfect_value(&self) -> Option<f64> {
+        if self.custom_metric.is_some() {
+            return None;
+        }
+        match (self.comparison.metric, self.mode) {
+            (Metric::Identity | Metric::Similarity, SearchMode::Nearest) => Some(1.0),
+            (Metric::Identity | Metric::Similarity, SearchMode::Farthest) => Some(0.0),
+            (Metric::Jc69 | Metric::K2p, SearchMode::Nearest) => Some(0.0),
+            _ => None,
+        }
+    }
+
+    /// Keep the queries to search among the filtered ones: the sample if set, then this shard's queries.
+    pub fn select_queries<'a>(&self, query_records: Vec<&'a Record>) -> Vec<&'a Record> {
+        let query_records = match &self.query_sample {
+            Some(sample) => {
+                let sampled = sample.select(query_records);
+                self.counters.set_sampled_queries(sampled.len());
+                sampled
+            }
+            None => query_records,
+        };
+        match &self.shard {
+            Some(shard) => shard.select(query_records),
+            None => query_records,
+        }
+    }
+
+    /// Whether `other` is left out of the neighbors of `query` with [`NeighborConfig::exclude_self`]: a record
+    /// of the same ID, or with [`NeighborConfig::collapse_duplicates`], of the same sequence. The duplicates of a
+    /// query share its neighbors, so a copy of its sequence left in the database would be reported as
+    /// the neighbor of that very copy.
+    pub fn is_self(&self, query: &Record, other: &Record) -> bool {
+        self.exclude_self && (other.id() == query.id() || (self.collapse_duplicates && other.seq() == query.seq()))
+    }
+
+    /// Group the queries to search by sequence with [`NeighborConfig::collapse_duplicates`], or one group
+    /// per query otherwise.
+    pub fn query_groups(&self, query_records: &[&Record]) -> DuplicateGroups {
+        if !self.collapse_duplicates {
+            return DuplicateGroups::singletons(query_records.len());
+        }
+        let groups = DuplicateGroups::new(query_records);
+        tracing::info!("Searching {} unique sequences of {} queries", groups.num_unique(), query_records.len());
+        self.counters.add_collapsed_queries(groups.num_unique(), query_records.len());
+        groups
+    }
+
+    /// Keep the database records to search among the filtered ones: the sample if set.
+    pub fn select_database<'a>(&self, db_records: Vec<&'a Record>) -> Vec<&'a Record> {
+        match &self.db_sample {
+            Some(sample) => {
+                let sampled = sample.select(db_records);
+                self.counters.set_sampled_db_records(sampled.len());
+                sampled
+            }
+            None => db_records,
         }
     }
+
+    /// The settings of a search over `num_queries` queries and `num_db_records` database records, as
+    /// `(key, value)` pairs, for the output formats that record them.
+    pub fn run_metadata(&self, num_queries: usize, num_db_records: usize) -> Vec<(String, String)> {
+        vec![
+            ("version".to_owned(), env!("CARGO_PKG_VERSION").to_owned()),
+            ("metric".to_owned(), match self.custom_metric {
+                Some(_) => "custom".to_owned(),
+                None => format!("{:?}", self.comparison.metric).to_lowercase(),
+            }),
+            ("mode".to_owned(), format!("{:?}", self.mode).to_lowercase()),
+            ("top_k".to_owned(), self.top_k.to_string()),
+            ("num_queries".to_owned(), num_queries.to_string()),
+            ("num_db_records".to_owned(), num_db_records.to_string()),
+        ]
+    }
 }
 
 
-/// Compute all nearest neighbors, and write each result to a TSV file.
+/// Compute all nearest neighbors, and write each result to `out_path` in the configured format.
+/// If the search is cancelled, the results of the completed queries are written, and
+/// [`NearestNeighborError::Cancelled`] is returned. The output is written atomically, see [`write_atomically`].
 pub fn compute_store_nearest_neighbors(
     records: Vec<Record>,
     out_path: &Path,
     query_ids: Option<Vec<String>>,
     db_ids: Option<Vec<String>>,
+    config: &NeighborConfig,
+    output_config: &OutputConfig,
 ) -> Result<(), NearestNeighborError> {
-    let query_records: Vec<&Record> = filter_records(&records, query_ids);
-    let db_records: Vec<&Record> = filter_records(&records, db_ids);
+    let query_records: Vec<&Record> = filter_records(&records, &RecordSelector::ids(query_ids.as_deref()));
+    let query_records: Vec<&Record> = config.select_queries(query_records);
+    let mut db_records: Vec<&Record> =
+        config.select_database(filter_records(&records, &RecordSelector::ids(db_ids.as_deref())));
+    let consensus: Option<Record> = config.include_consensus.then(|| consensus_record(&db_records));
+    if let Some(consensus) = &consensus {
+        db_records.push(consensus);
+    }
 
-    let results = compute_nearest_neighbors(&query_records, &db_records)?;
-    let file = File::create(out_path)?;
-    let mut writer = BufWriter::new(file);
+    // Drop the columns that can't change any identity, so that each comparison scans fewer bytes.
+    let search_records: Vec<&Record> = query_records.iter().chain(db_records.iter()).copied().collect();
+    let column_stats = compute_column_stats(&search_records);
+    // Substitution scores differ between invariant columns, so they can't be added back as plain matches.
+    // Nor can invariant columns be counted as plain matches with a match matrix. The column stats only
+    // know of `-` gaps and `N`/`X` unknown residues, so a column of other gap characters or ignored
+    // symbols would pass for an invariant residue. Dropping an invariant column could also merge two gap runs.
+    // A custom metric gets no corrected counts, so it must see every column.
+    let compress_invariant = config.compress_invariant
+        && config.custom_metric.is_none()
+        && !config.comparison.gap_run_as_one
+        && config.comparison.score_matrix.is_none()
+        && config.comparison.match_matrix.is_none()
+        && config.comparison.gap_chars == GapChars::default()
+        && config.comparison.ignore_chars.is_empty();
+    let selection = informative_columns(&column_stats, compress_invariant);
+    if let Some(allowed) = &config.allowed_neighbors {
+        let num_db_records = db_records.len();
+        db_records.retain(|record| {
+            allowed.contains(record.id()) || consensus.as_ref().is_some_and(|consensus| std::ptr::eq(*record, consensus))
+        });
+        tracing::info!("Selecting the neighbors among {} of {} allowed database records", db_records.len(), num_db_records);
+    }
+    // Column weights are indexed by alignment column, and a custom metric gets no corrected counts for the
+    // all-gap columns, nor their positions, so the records are compared untrimmed.
+    let untrimmed = config.comparison.column_weights.is_some() || config.custom_metric.is_some()
+        || config.reverse_complement || selection.columns.len() == column_stats.len();
+    // A failed search leaves `out_path` untouched, while a cancelled one still writes the completed queries.
+    let (rows, num_completed) = write_atomically(out_path, |tmp_path| {
+        if untrimmed {
+            return search_write_rows(&query_records, &db_records, &query_records, &db_records, config, output_config, tmp_path);
+        }
+        tracing::info!(
+            "Comparing {} of {} columns ({} invariant columns dropped)",
+            selection.columns.len(), column_stats.len(), selection.invariant_matches,
+        );
+        let trimmed_query: Vec<Record> = query_records.par_iter().map(|record| trim_record(record, &selection.columns)).collect();
+        let trimmed_db: Vec<Record> = db_records.par_iter().map(|record| trim_record(record, &selection.columns)).collect();
+        let trimmed_query_refs: Vec<&Record> = trimmed_query.iter().collect();
+        let trimmed_db_refs: Vec<&Record> = trimmed_db.iter().collect();
+        let mut trimmed_config = config.clone();
+        trimmed_config.comparison.invariant_matches = selection.invariant_matches;
+        trimmed_config.comparison.gap_columns = selection.gap_columns;
+        search_write_rows(
+            &trimmed_query_refs, &trimmed_db_refs, &query_records, &db_records, &trimmed_config, output_config, tmp_path,
+        )
+    })?;
 
-    // Pre-computation is done. Now write the results to file.
-    assert_eq!(results.len(), query_records.len(), "Results length should always match query length!");
-    for (query_record, (neighbor_record, dist)) in query_records.iter().zip(results.iter()) {
-        writeln!(writer, "{}\t{}\t{}", query_record.id(), neighbor_record.id(), dist)?;
+    if let Some(mismatch_output) = &output_config.mismatch_positions {
+        write_atomically(&mismatch_output.path, |tmp_path| {
+            let mut writer = BufWriter::new(File::create(tmp_path)?);
+            write_mismatch_positions(&rows, &mut writer)?;
+            writer.flush()?;
+            Ok(())
+        })?;
+    }
+    if let Some(graph_output) = &output_config.graph {
+        write_graph_file(&rows, &search_records, None, graph_output, output_config.precision)?;
+    }
+    if let Some(summary_output) = &output_config.identity_summary {
+        write_identity_summary(&rows, num_completed, summary_output, output_config.precision)?;
+    }
+    if let Some(pairs_path) = &output_config.pairs_fasta {
+        // As for the ID files, an ID shared by several records stands for the first of them.
+        let mut records_by_id: HashMap<&str, &Record> = HashMap::new();
+        for record in search_records.iter() {
+            records_by_id.entry(record.id()).or_insert(record);
+        }
+        write_atomically(pairs_path, |tmp_path| {
+            let mut writer = BufWriter::new(File::create(tmp_path)?);
+            for row in rows.iter() {
+                write_pairs_fasta(records_by_id[row.query_id.as_str()], records_by_id[row.neighbor_id.as_str()], row.rank, &mut writer)?;
+            }
+            writer.flush()?;
+            Ok(())
+        })?;
+    }
+    if num_completed < query_records.len() {
+        return Err(NearestNeighborError::Cancelled(num_completed));
     }
     Ok(())
 }
 
 
-/// Compute nearest-neighbors using multiple worker threads.
-pub(super) fn compute_nearest_neighbors<'a>(
-    query_records: &'a Vec<&'a Record>,
-    db_records: &'a Vec<&'a Record>,
-) -> Result<NeighborResult<'a>, NearestNeighborError> {
-    // Setup the loop, including indicatif progress bar styling.
-    let db_records = Arc::new(db_records);
-    let pbar = ProgressBar::new(query_records.len() as u64);
-    pbar.set_style(
-        ProgressStyle::default_bar()
-            .template("{spinner:.green} [{elapsed_precise}] [{bar:40.cyan/blue}] {pos}/{len} ({eta})")
-            .unwrap()
-            .progress_chars("#>-")
-    );
-    // Enable steady tick to prevent multiple threads from causing line breaks
-    pbar.enable_steady_tick(std::time::Duration::from_millis(50));
-
-    // Do the calculation, using rayon's par_iter()'s map-reduce pattern.
-    let results: NeighborResult<'a> = query_records.par_iter()
-        .progress_with(pbar)
-        .map(|query_record| {
-            let data_ref = Arc::clone(&db_records);
-            compute_nearest_neighbors_single(query_record, data_ref)
-        })
+/// Write a query and its neighbor of 1-based `rank` as two consecutive FASTA records, with the IDs
+/// `<query_id>|<rank>|query` and `<query_id>|<rank>|neighbor`, described by their own record IDs, so that the
+/// pairs of a query's several neighbors keep distinct IDs. The aligned sequences are written unwrapped,
+/// gaps included, so that the two records can be compared column by column.
+pub fn write_pairs_fasta(query: &Record, neighbor: &Record, rank: usize, writer: &mut impl Write) -> Result<(), NearestNeighborError> {
+    for (record, role) in [(query, "query"), (neighbor, "neighbor")] {
+        writeln!(writer, ">{}|{}|{} {}", query.id(), rank, role, record.id())?;
+        writer.write_all(record.seq())?;
+        writeln!(writer)?;
+    }
+    Ok(())
+}
+
+
+/// Search the rows as [`neighbor_rows`] does, and write them to `out_path`: as they are found in query order,
+/// see [`write_neighbor_rows_incrementally`], or once they are all found and sorted. The output records
+/// whether all the queries were searched, see [`OutputWriter::finish`].
+fn search_write_rows(
+    search_query: &[&Record],
+    search_db: &[&Record],
+    query_records: &[&Record],
+    db_records: &[&Record],
+    config: &NeighborConfig,
+    output_config: &OutputConfig,
+    out_path: &Path,
+) -> Result<(Vec<NearestNeighborResultRow>, usize), NearestNeighborError> {
+    let metadata = config.run_metadata(query_records.len(), db_records.len());
+    let mut writer = create_output_writer(out_path, output_config, &metadata)?;
+    let (rows, num_completed) = if output_config.sort_by == SortKey::QueryOrder && output_config.normalize.is_none() {
+        write_neighbor_rows_incrementally(
+            search_query, search_db, query_records, db_records, config, output_config, writer.as_mut(),
+        )?
+    } else {
+        let (mut rows, num_completed) = neighbor_rows(search_query, search_db, query_records, db_records, config, output_config)?;
+        if let Some(normalization) = output_config.normalize {
+            normalization.apply(&mut rows);
+        }
+        sort_rows(&mut rows, output_config.sort_by);
+        let _span = write_output_span(output_config, Some(rows.len())).entered();
+        writer.write_rows(&rows)?;
+        (rows, num_completed)
+    };
+    writer.finish(num_completed == search_query.len())?;
+    Ok((rows, num_completed))
+}
+
+
+/// Search the neighbors of `search_query` among `search_db`, and report them as rows describing
+/// the corresponding records of `query_records` and `db_records`, which may differ from the
+/// searched records by their columns but not by their order.
+/// Also returns the number of completed queries, which is smaller than the number of queries if the
+/// search was cancelled.
+pub(crate) fn neighbor_rows(
+    search_query: &[&Record],
+    search_db: &[&Record],
+    query_records: &[&Record],
+    db_records: &[&Record],
+    config: &NeighborConfig,
+    output_config: &OutputConfig,
+) -> Result<(Vec<NearestNeighborResultRow>, usize), NearestNeighborError> {
+    let results = compute_top_k_neighbors(search_query, search_db, &row_search_config(config, output_config))?;
+    let row_builder = RowBuilder::new(search_db, db_records, config, output_config);
+
+    // Pre-computation is done. Now build the result rows.
+    assert!(results.len() <= query_records.len(), "Results length should never exceed the query length!");
+    let rows: Vec<NearestNeighborResultRow> = query_records.par_iter()
+        .zip(results.par_iter())
+        .flat_map_iter(|(query_record, neighbors)| row_builder.query_rows(query_record, neighbors))
         .collect();
-    Ok(results)
+    Ok((rows, results.len()))
 }
 
 
-/// Compute the nearest neighbor between query and the collection.
-/// Single-worker task, meant to be used for the map-reduce in [`compute_nearest_neighbors`].
-///
-/// # Arguments
+/// Search the rows as [`neighbor_rows`] does, but write the rows of each query to `writer` as soon as they and
+/// those of all previous queries are found, so that the output is in query order, and
+/// holds the rows of the completed queries if the search is cancelled.
 ///
-/// * `query` - The query Fasta record.
-/// * `collection` - An Arc-wrapped vector of Fasta Records.
+/// The workers send the rows of each query to a writer thread, which puts them back in query order and
+/// flushes `writer` every [`OutputConfig::flush_interval`] rows. The channel is bounded, so that the workers
+/// wait for a slow writer rather than buffer the rows. All rows are also returned, for the other outputs.
+pub(crate) fn write_neighbor_rows_incrementally<O: OutputWriter + Send + ?Sized>(
+    search_query: &[&Record],
+    search_db: &[&Record],
+    query_records: &[&Record],
+    db_records: &[&Record],
+    config: &NeighborConfig,
+    output_config: &OutputConfig,
+    writer: &mut O,
+) -> Result<(Vec<NearestNeighborResultRow>, usize), NearestNeighborError> {
+    let search_config = row_search_config(config, output_config);
+    let row_builder = RowBuilder::new(search_db, db_records, config, output_config);
+    let (sender, receiver) = mpsc::sync_channel::<(usize, Vec<NearestNeighborResultRow>)>(rayon::current_num_threads());
+
+    std::thread::scope(|scope| {
+        let writer_thread = scope.spawn(move || write_rows_in_query_order(receiver, writer, output_config));
+        let on_query = |query_idx: usize, neighbors: &[(&Record, f64)]| {
+            // The writer only stops receiving on an error, which it returns: the search is stopped then, e.g. as
+            // soon as the reader of a piped output exits.
+            if sender.send((query_idx, row_builder.query_rows(query_records[query_idx], neighbors))).is_err() {
+                config.cancel.cancel();
+            }
+        };
+        let results = NeighborSearcher::build(search_db, &search_config)
+            .and_then(|searcher| searcher.search_each(search_query, &on_query));
+        drop(sender);
+        let rows = writer_thread.join().unwrap_or_else(|panic| std::panic::resume_unwind(panic));
+        let num_completed = results?.len();
+        Ok((rows?, num_completed))
+    })
+}
+
+
+/// Receive the rows of each query from the workers, write them in query order, and return them all.
+/// The rows received ahead of those of an earlier query wait for them.
+fn write_rows_in_query_order<O: OutputWriter + ?Sized>(
+    receiver: mpsc::Receiver<(usize, Vec<NearestNeighborResultRow>)>,
+    writer: &mut O,
+    output_config: &OutputConfig,
+) -> Result<Vec<NearestNeighborResultRow>, NearestNeighborError> {
+    let span = write_output_span(output_config, None);
+    let _entered = span.enter();
+    let mut pending: HashMap<usize, Vec<NearestNeighborResultRow>> = HashMap::new();
+    let mut rows: Vec<NearestNeighborResultRow> = vec![];
+    let (mut num_written_queries, mut num_unflushed_rows) = (0usize, 0usize);
+    for (query_idx, query_rows) in receiver {
+        pending.insert(query_idx, query_rows);
+        while let Some(query_rows) = pending.remove(&num_written_queries) {
+            writer.write_rows(&query_rows)?;
+            num_written_queries += 1;
+            num_unflushed_rows += query_rows.len();
+            if num_unflushed_rows >= output_config.flush_interval {
+                writer.flush()?;
+                num_unflushed_rows = 0;
+            }
+            rows.extend(query_rows);
+        }
+    }
+    writer.flush()?;
+    span.record("row_count", rows.len());
+    Ok(rows)
+}
+
+
+/// The search behind the rows: with [`OutputConfig::with_runner_up`], at least the top 2, so that the runner-up
+/// is found with the same tie-breaking as the reported neighbors.
+fn row_search_config(config: &NeighborConfig, output_config: &OutputConfig) -> NeighborConfig {
+    if output_config.with_runner_up && config.top_k < 2 {
+        NeighborConfig { top_k: 2, ..config.clone() }
+    } else {
+        config.clone()
+    }
+}
+
+
+/// Builds the rows of each query's neighbors, describing the records of `db_records` at the positions of the
+/// searched neighbors in the searched database.
+struct RowBuilder<'r> {
+    /// The position of each searched database record, by address.
+    db_index: HashMap<usize, usize>,
+    db_records: &'r [&'r Record],
+    top_k: usize,
+    /// The rows describe the original records, so their counts need no correction for dropped columns.
+    row_comparison: ComparisonConfig,
+    /// With [`NeighborConfig::reverse_complement`], the search config, to find the strand of each pair.
+    strand_config: Option<NeighborConfig>,
+    /// With [`OutputConfig::with_db_rank`], the search config comparing the original records, to rank each
+    /// neighbor among all of `db_records`.
+    rank_config: Option<NeighborConfig>,
+    output_config: &'r OutputConfig,
+}
+
+
+impl<'r> RowBuilder<'r> {
+    fn new(
+        search_db: &[&Record],
+        db_records: &'r [&'r Record],
+        config: &NeighborConfig,
+        output_config: &'r OutputConfig,
+    ) -> RowBuilder<'r> {
+        RowBuilder {
+            db_index: search_db.iter().enumerate().map(|(db_idx, record)| (record_address(record), db_idx)).collect(),
+            db_records,
+            top_k: config.top_k,
+            row_comparison: ComparisonConfig { invariant_matches: 0, gap_columns: 0, ..config.comparison.clone() },
+            strand_config: config.reverse_complement.then(|| config.clone()),
+            rank_config: output_config.with_db_rank.then(|| NeighborConfig {
+                comparison: ComparisonConfig { invariant_matches: 0, gap_columns: 0, ..config.comparison.clone() },
+                ..config.clone()
+            }),
+            output_config,
+        }
+    }
+
+    /// The rank of `neighbor` among the `db_values` of the query, see [`NearestNeighborResultRow::db_rank`].
+    /// All the values are measured on the original records, so that the neighbor's value is one of them.
+    fn db_rank(
+        &self,
+        query_record: &Record,
+        reverse_query: Option<&Record>,
+        neighbor: &Record,
+        db_values: &[f64],
+        config: &NeighborConfig,
+    ) -> usize {
+        let ranking = config.ranking();
+        let value = stranded_value(query_record, reverse_query, neighbor, config).map_or(f64::NAN, |(value, _)| value);
+        1 + db_values.iter()
+            .filter(|other| !other.is_nan() && ranking.cmp_best_first(**other, value) == std::cmp::Ordering::Less)
+            .count()
+    }
+
+    /// The value of each record of `db_records` to the query, NaN for the query itself with
+    /// [`NeighborConfig::exclude_self`], see [`NeighborConfig::is_self`].
+    fn db_values(&self, query_record: &Record, reverse_query: Option<&Record>, config: &NeighborConfig) -> Vec<f64> {
+        self.db_records.iter()
+            .map(|other| {
+                if config.is_self(query_record, other) {
+                    return f64::NAN;
+                }
+                stranded_value(query_record, reverse_query, other, config).map_or(f64::NAN, |(value, _)| value)
+            })
+            .collect()
+    }
+
+    fn db_record(&self, neighbor: &Record) -> &'r Record {
+        self.db_records[self.db_index[&record_address(neighbor)]]
+    }
+
+    /// The rows of a query's neighbors, sorted from best to worst.
+    fn query_rows(&self, query_record: &Record, neighbors: &[(&Record, f64)]) -> Vec<NearestNeighborResultRow> {
+        let (output_config, row_comparison) = (self.output_config, &self.row_comparison);
+        let runner_up: Option<RunnerUp> = neighbors.get(1)
+            .filter(|_| output_config.with_runner_up)
+            .map(|(neighbor, dist)| RunnerUp {
+                neighbor_id: self.db_record(neighbor).id().to_owned(),
+                identity: *dist,
+                margin: (neighbors[0].1 - *dist).abs(),
+            });
+        let reverse_query: Option<Record> = self.strand_config.as_ref().map(|_| reverse_complement_record(query_record));
+        let db_values: Option<Vec<f64>> = self.rank_config.as_ref()
+            .map(|config| self.db_values(query_record, reverse_query.as_ref(), config));
+        neighbors.iter()
+            .take(self.top_k)
+            .enumerate()
+            .map(|(rank_idx, (neighbor, dist))| {
+                let neighbor_record = self.db_record(neighbor);
+                let db_rank: Option<usize> = self.rank_config.as_ref().zip(db_values.as_deref()).map(|(config, db_values)| {
+                    self.db_rank(query_record, reverse_query.as_ref(), neighbor_record, db_values, config)
+                });
+                // The search already compared the pair on both strands, so this comparison can't fail.
+                let strand: Option<Strand> = self.strand_config.as_ref().map(|config| {
+                    stranded_value(query_record, reverse_query.as_ref(), neighbor, config)
+                        .map_or(Strand::Forward, |(_, strand)| strand)
+                });
+                // The pair is described on the strand it was found on.
+                let query_record: &Record = match (&reverse_query, strand) {
+                    (Some(reverse_query), Some(Strand::Reverse)) => reverse_query,
+                    _ => query_record,
+                };
+                NearestNeighborResultRow {
+                    query_id: query_record.id().to_owned(),
+                    neighbor_id: neighbor_record.id().to_owned(),
+                    rank: rank_idx + 1,
+                    identity: *dist,
+                    query_stats: output_config.with_stats.then(|| record_stats(query_record)),
+                    neighbor_stats: output_config.with_stats.then(|| record_stats(neighbor_record)),
+                    counts: (output_config.with_similarity || output_config.with_detailed_counts
+                        || output_config.with_query_coverage || output_config.format.has_counts())
+                        .then(|| pair_counts(query_record.seq(), neighbor_record.seq(), row_comparison)),
+                    runner_up: runner_up.clone(),
+                    comparison: (output_config.with_extended_output || output_config.with_ts_tv).then(|| compare_sequences(
+                        query_record.seq(), neighbor_record.seq(), &row_comparison.gap_chars,
+                    )),
+                    mismatches: output_config.mismatch_positions.as_ref().map(|mismatch_output| mismatch_columns(
+                        query_record.seq(), neighbor_record.seq(), row_comparison, mismatch_output.max_positions,
+                    )),
+                    blast: (output_config.format == OutputFormat::Blast6)
+                        .then(|| blast_hit(query_record.seq(), neighbor_record.seq(), row_comparison)),
+                    query_header: output_config.with_description.then(|| header_line(query_record)),
+                    neighbor_header: output_config.with_description.then(|| header_line(neighbor_record)),
+                    query_gc: output_config.with_gc_content.then(|| gc_content(query_record)),
+                    neighbor_gc: output_config.with_gc_content.then(|| gc_content(neighbor_record)),
+                    strand,
+                    db_rank,
+                }
+            })
+            .collect()
+    }
+}
+
+
+/// The address of a record, to find it among records compared by reference. Unlike a raw pointer, it can
+/// be shared between threads.
+fn record_address(record: &Record) -> usize {
+    record as *const Record as usize
+}
+
+
+fn search_span(query_count: usize, db_count: usize) -> tracing::Span {
+    tracing::info_span!(
+        "compute",
+        num_workers = rayon::current_num_threads(),
+        query_count,
+        db_count,
+    )
+}
+
+
+/// A database prepared once for the nearest-neighbor searches of any number of queries, e.g. to serve queries
+/// as they arrive rather than in one batch. The one-shot functions, such as [`compute_nearest_neighbors`], build
+/// one for their queries.
 ///
-/// # Returns
+/// Building it packs the database records: the gap mask of the database, marking the columns gapped in every
+/// record, is computed once, those columns are dropped, and the rest of the records are copied one after the other
+/// into a single buffer. Each query is then compared on the kept columns only, its counts over the dropped columns
+/// being the same against every record. The records are searched whole when the comparison needs every column, or
+/// when they differ in length. The columns that are invariant across the records aren't dropped, since that
+/// depends on the queries too, see [`compute_store_nearest_neighbors`]. With
+/// [`NeighborConfig::approx_candidates`], the database records are also sketched for the MinHash pre-filter once,
+/// at build time.
 ///
-/// The nearest-neighbor Fasta record, and the hamming distance between it and the query.
-fn compute_nearest_neighbors_single<'a>(query: &'a Record, collection: Arc<&'a Vec<&'a Record>>) -> (&'a Record, f32) {
-    let mut best_idty: f32 = 0.0;
-    let mut best_neighbor: Option<&Record> = None;
-
-    // Note: this used to exclude self-matches via: .filter(|other| other.id() != query.id())
-    // but this is no longer necessary since the program explicitly asks for query & collection ID sets.
-    for other in collection.iter() {
-        // Honestly, panicking here is Ok!
-        let idty = pct_identity(query, other)
-            .unwrap_or_else(
-                |e| {
-                    println!("Unexpected fatal error during identity calculation: {}", e);
-                    panic!("calculation failed")
+/// The searcher holds references to the database records and a clone of the config, whose counters and
+/// cancellation token it shares. Packed, it also holds a byte per alignment column for the gap mask, and a copy
+/// of the kept columns of every record: at most the size of the database sequences. With the MinHash pre-filter,
+/// it also holds a sketch of 128 hashes per database record, 1 KiB each. The searcher is `Send + Sync`, so that
+/// it can be searched from several threads at once; each batch search also runs its queries in parallel.
+#[derive(Debug)]
+pub struct NeighborSearcher<'d> {
+    db_records: Vec<&'d Record>,
+    config: NeighborConfig,
+    index: Option<MinHashIndex>,
+    packed: Option<PackedDatabase>,
+}
+
+
+impl<'d> NeighborSearcher<'d> {
+    /// Prepare `db_records` for the searches with the options of `config`. Fails if the MinHash pre-filter is
+    /// requested without the `minhash` feature.
+    pub fn build(db_records: &[&'d Record], config: &NeighborConfig) -> Result<NeighborSearcher<'d>, NearestNeighborError> {
+        Ok(NeighborSearcher {
+            db_records: db_records.to_vec(),
+            config: config.clone(),
+            index: approx_index(db_records, config)?,
+            packed: PackedDatabase::build(db_records, config),
+        })
+    }
+
+    pub fn db_records(&self) -> &[&'d Record] {
+        &self.db_records
+    }
+
+    pub fn config(&self) -> &NeighborConfig {
+        &self.config
+    }
+
+    /// The nearest neighbor of a single query, and its value of the configured metric.
+    pub fn nearest(&self, query: &Record) -> Result<(&'d Record, f64), NearestNeighborError> {
+        self.nearest_among(query, &self.candidates(query, None))
+    }
+
+    /// The [`NeighborConfig::top_k`] nearest neighbors of a single query, from best to worst.
+    pub fn top_k(&self, query: &Record) -> Result<Vec<(&'d Record, f64)>, NearestNeighborError> {
+        self.top_k_among(query, &self.candidates(query, None))
+    }
+
+    /// The nearest neighbor of each query, searched in parallel, as [`compute_nearest_neighbors`] does.
+    pub fn nearest_batch(&self, query_records: &[&Record]) -> Result<NeighborResult<'d>, NearestNeighborError> {
+        let search = |query: &Record, candidates: &Candidates| self.nearest_among(query, candidates);
+        self.search_batch(query_records, search, &|_, _| {})
+    }
+
+    /// The [`NeighborConfig::top_k`] nearest neighbors of each query, searched in parallel, as
+    /// [`compute_top_k_neighbors`] does.
+    pub fn top_k_batch(&self, query_records: &[&Record]) -> Result<TopKNeighborResult<'d>, NearestNeighborError> {
+        if self.config.top_k == 1 {
+            return Ok(self.nearest_batch(query_records)?.into_iter().map(|hit| vec![hit]).collect());
+        }
+        self.search_each(query_records, &|_, _| {})
+    }
+
+    /// The search of [`NeighborSearcher::top_k_batch`], for any `top_k`, which also hands the neighbors of each
+    /// query to `on_query` with the query's index as soon as they are found, from the worker threads.
+    pub(crate) fn search_each(
+        &self,
+        query_records: &[&Record],
+        on_query: &(dyn Fn(usize, &[(&'d Record, f64)]) + Sync),
+    ) -> Result<TopKNeighborResult<'d>, NearestNeighborError> {
+        let search = |query: &Record, candidates: &Candidates| self.top_k_among(query, candidates);
+        self.search_batch(query_records, search, &|query_idx, neighbors: &Vec<(&'d Record, f64)>| on_query(query_idx, neighbors))
+    }
+
+    /// Run `search` on each query against its candidates, in parallel, once per group of duplicate queries, see
+    /// [`NeighborConfig::query_groups`]. If `config.cancel` is cancelled during the search, only the results of
+    /// the queries completed before the first skipped one are returned, so they always match a prefix of
+    /// `query_records`.
+    fn search_batch<T: Clone + Send>(
+        &self,
+        query_records: &[&Record],
+        search: impl Fn(&Record, &Candidates) -> Result<T, NearestNeighborError> + Sync,
+        on_query: &(dyn Fn(usize, &T) + Sync),
+    ) -> Result<Vec<T>, NearestNeighborError> {
+        let _span = search_span(query_records.len(), self.db_records.len()).entered();
+        tracing::info!("Computing nearest neighbors");
+        let groups = self.config.query_groups(query_records);
+        let progress = search_progress(groups.num_unique(), self.db_records.len(), &self.config);
+
+        // Queries skipped after a cancellation yield None.
+        let results: Vec<Option<T>> = (0..groups.num_unique()).into_par_iter()
+            .map(|group_idx| {
+                if self.config.cancel.is_cancelled() {
+                    return Ok(None);
                 }
-            );
-        if idty >= best_idty {
-            best_idty = idty;
+                let query_record = query_records[groups.representative(group_idx)];
+                let result = search(query_record, &self.candidates(query_record, Some(&progress)))?;
+                for query_idx in groups.members(group_idx) {
+                    on_query(*query_idx, &result);
+                }
+                Ok(Some(result))
+            })
+            .collect::<Result<Vec<Option<T>>, NearestNeighborError>>()?;
+        Ok(completed_prefix(groups.expand(results)))
+    }
+
+    /// The database records to compare `query` against: all of them, or those pre-selected by the MinHash index.
+    fn candidates(&self, query: &Record, progress: Option<&Progress>) -> Candidates {
+        let candidates = match &self.index {
+            None => Candidates::All(self.db_records.len()),
+            Some(index) => Candidates::Selected(approx_candidate_indices(index, query, self.db_records.len(), &self.config)),
+        };
+        if let Some(progress) = progress {
+            progress.inc(candidates.len() as u64);
+        }
+        candidates
+    }
+
+    /// The nearest neighbor of `query` among its candidates, and its value of the configured metric.
+    fn nearest_among(&self, query: &Record, candidates: &Candidates) -> Result<(&'d Record, f64), NearestNeighborError> {
+        let config = &self.config;
+        let ranking = config.ranking();
+        let perfect_value: Option<f64> = config.perfect_value();
+        let mut best_value: f64 = ranking.worst_value();
+        let mut best_neighbor: Option<&'d Record> = None;
+        let reverse_query: Option<Record> = config.reverse_complement.then(|| reverse_complement_record(query));
+        let (forward, reverse) = (self.prepare(query), reverse_query.as_ref().map(|record| self.prepare(record)));
+
+        // The records are scanned from the last one, and a value replaces the best one only if it is strictly better,
+        // so that ties go to the later record, as in the top-k search, and the scan can stop at the first record
+        // with the perfect value: none of the records left could be reported instead.
+        // The scanned pairs are counted once the scan ends, to keep atomics out of the inner loop.
+        let num_candidates = candidates.len();
+        let mut num_scanned: usize = num_candidates;
+        for scan_idx in 0..num_candidates {
+            let db_idx = candidates.db_idx(num_candidates - 1 - scan_idx);
+            let other = self.db_records[db_idx];
+            if config.is_self(query, other) {
+                continue;
+            }
+            let (value, _) = self.stranded_value(&forward, reverse.as_ref(), db_idx)?;
+            if value.is_nan() || (best_neighbor.is_some() && ranking.is_at_least_as_good(best_value, value)) {
+                continue;
+            }
+            best_value = value;
             best_neighbor = Some(other);
+            if perfect_value.is_some_and(|perfect_value| ranking.is_at_least_as_good(value, perfect_value)) {
+                num_scanned = scan_idx + 1;
+                config.counters.add_skipped_pairs((num_candidates - num_scanned) as u64);
+                break;
+            }
+        }
+        config.counters.add_pairs(num_scanned as u64);
+
+        match best_neighbor {
+            Some(neighbor) => Ok((neighbor, best_value)),
+            None => Err(no_neighbor_error(query, &config.comparison)),
+        }
+    }
+
+    /// The [`NeighborConfig::top_k`] nearest neighbors of `query` among its candidates, from best to worst.
+    fn top_k_among(&self, query: &Record, candidates: &Candidates) -> Result<Vec<(&'d Record, f64)>, NearestNeighborError> {
+        let config = &self.config;
+        let reverse_query: Option<Record> = config.reverse_complement.then(|| reverse_complement_record(query));
+        let (forward, reverse) = (self.prepare(query), reverse_query.as_ref().map(|record| self.prepare(record)));
+        with_scored_scratch(|scored| {
+            for candidate_idx in 0..candidates.len() {
+                let db_idx = candidates.db_idx(candidate_idx);
+                if config.is_self(query, self.db_records[db_idx]) {
+                    continue;
+                }
+                let (value, _) = self.stranded_value(&forward, reverse.as_ref(), db_idx)?;
+                if !value.is_nan() {
+                    scored.push((db_idx, value));
+                }
+            }
+            config.counters.add_pairs(candidates.len() as u64);
+            if scored.is_empty() {
+                return Err(no_neighbor_error(query, &config.comparison));
+            }
+
+            let ranking = config.ranking();
+            scored.sort_unstable_by(|a, b| ranking.cmp_best_first(a.1, b.1).then(b.0.cmp(&a.0)));
+            Ok(scored.iter().take(config.top_k).map(|(db_idx, idty)| (self.db_records[*db_idx], *idty)).collect())
+        })
+    }
+
+    /// Prepare `query` for its comparisons against the database records.
+    fn prepare<'q>(&self, query: &'q Record) -> PreparedQuery<'q> {
+        PreparedQuery {
+            record: query,
+            packed: self.packed.as_ref().map(|packed| packed.prepare(query.seq(), &self.config.comparison)),
+        }
+    }
+
+    /// The value of the pair metric between a prepared query and the database record of index `db_idx`, as
+    /// [`compare_records_with`] gives it for the whole records.
+    fn pair_value(&self, query: &PreparedQuery, db_idx: usize) -> Result<f64, NearestNeighborError> {
+        let other = self.db_records[db_idx];
+        match (&self.packed, &query.packed) {
+            (Some(packed), Some((residues, dropped_counts))) => {
+                check_lengths(query.record, other)?;
+                let comparison = &self.config.comparison;
+                Ok(comparison.value(&add_pair_counts(*dropped_counts, residues, packed.record(db_idx), comparison)))
+            }
+            _ => compare_records_with(query.record, other, self.config.pair_metric()),
         }
     }
 
-    // honestly, ok to panic here -- the collection ought to be non-empty.
-    (best_neighbor.unwrap(), best_idty)
+    /// The value of the pair metric between a prepared query and the database record of index `db_idx`, with
+    /// the reverse complement of the query as [`stranded_value`] does.
+    fn stranded_value(
+        &self,
+        query: &PreparedQuery,
+        reverse_query: Option<&PreparedQuery>,
+        db_idx: usize,
+    ) -> Result<(f64, Strand), NearestNeighborError> {
+        let forward = self.pair_value(query, db_idx)?;
+        let reverse = reverse_query.map(|reverse_query| self.pair_value(reverse_query, db_idx)).transpose()?;
+        Ok(better_strand(forward, reverse, &self.config))
+    }
 }
 
 
-const GAP: u8 = '-' as u8;
+/// The database records a query is compared against, by index among the records of a [`NeighborSearcher`], in
+/// database order.
+enum Candidates {
+    /// All the database records, of which there are this many.
+    All(usize),
+    /// The records pre-selected by the MinHash index.
+    Selected(Vec<usize>),
+}
 
-fn pct_identity(x: &Record, y: &Record) -> Result<f32, NearestNeighborError> {
-    if x.seq().len() != y.seq().len() {
-        return Err(NearestNeighborError::HammingDistanceError(x.id().to_owned(), y.id().to_owned()));
+
+impl Candidates {
+    fn len(&self) -> usize {
+        match self {
+            Candidates::All(num_records) => *num_records,
+            Candidates::Selected(db_indices) => db_indices.len(),
+        }
     }
 
-    let numer = x.seq()
-        .iter()
-        .zip(y.seq().iter())
-        .filter(|(xi, yi)| !(**xi == GAP && **yi == GAP))
-        .filter(|(xi, yi)| xi == yi)
-        .count() as u64;
-    let denom = x.seq()
-        .iter()
-        .zip(y.seq().iter())
-        .filter(|(xi, yi)| !(**xi == GAP && **yi == GAP))
-        .count() as u64;
-    let idty = (numer as f32) / (denom as f32);
-    Ok(idty)
-}
-
-
-// fn hamming_distance(x: &Record, y: &Record) -> Result<u64, NearestNeighborError> {
-//     if x.seq().len() != y.seq().len() {
-//         return Err(NearestNeighborError::HammingDistanceError(x.id().to_owned(), y.id().to_owned()));
-//     }
-//
-//     let dist = x.seq()
-//         .iter()
-//         .zip(y.seq().iter())
-//         .filter(|(xi, yi)| xi != yi)
-//         .count() as u64;
-//     Ok(dist)
-// }
-//
-//
-#[cfg(test)]
-mod tests {
-    use bio::io::fasta::Record;
-    use crate::nearest_neighbor::pct_identity;
+    /// The database index of the candidate of index `candidate_idx`.
+    fn db_idx(&self, candidate_idx: usize) -> usize {
+        match self {
+            Candidates::All(_) => candidate_idx,
+            Candidates::Selected(db_indices) => db_indices[candidate_idx],
+        }
+    }
+}
 
-    #[test]
-    fn test_pct_identity() {
-        let x = Record::with_attrs("input1", None, b"AAAAAAA");
-        let y = Record::with_attrs("input2", None, b"AAAACCA");
-        assert_eq!(pct_identity(&x, &y), Ok(5.0 / 7.0));
 
-        let x = Record::with_attrs("input1", None, b"AAAA");
-        let y = Record::with_attrs("input2", None, b"CCCC");
-        assert_eq!(pct_identity(&x, &y), Ok(0.0));
+/// The database records of a [`NeighborSearcher`], packed at build time: the columns gapped in every record are
+/// dropped, and the kept columns of the records are stored one after the other.
+#[derive(Debug)]
+struct PackedDatabase {
+    /// The gap mask of the database: whether each alignment column is gapped in every record.
+    gap_columns: Vec<bool>,
+    /// The number of kept columns, those holding a residue in at least one record.
+    num_kept: usize,
+    /// The kept columns of every record, `num_kept` bytes per record.
+    residues: Vec<u8>,
+}
 
-        let x = Record::with_attrs("input1", None, b"AAAA");
-        let y = Record::with_attrs("input2", None, b"AAAA");
-        assert_eq!(pct_identity(&x, &y), Ok(1.0));
 
-        let x = Record::with_attrs("input1", None, b"AAAA");
-        let y = Record::with_attrs("input2", None, b"AAA");
-        assert!(pct_identity(&x, &y).is_err());
+impl PackedDatabase {
+    /// Pack `db_records`, unless the comparison needs every column or the records differ in length.
+    fn build(db_records: &[&Record], config: &NeighborConfig) -> Option<PackedDatabase> {
+        let comparison = &config.comparison;
+        // A custom metric gets no corrected counts, gap runs span the dropped columns, and column weights are
+        // indexed by alignment column. The counts are summed in another order than the columns', which is exact
+        // unless fractional matches are added up. The dropped columns are compared as `-` gaps.
+        if config.custom_metric.is_some() || comparison.gap_run_as_one || comparison.column_weights.is_some()
+            || comparison.match_matrix.is_some() || comparison.gap_chars != GapChars::default()
+        {
+            return None;
+        }
+        let alignment_length = db_records.first()?.seq().len();
+        if db_records.iter().any(|record| record.seq().len() != alignment_length) {
+            return None;
+        }
 
-        let x = Record::with_attrs("input1", None, b"----AAAA----");
-        let y = Record::with_attrs("input2", None, b"----AAA-----");
-        assert_eq!(pct_identity(&x, &y), Ok(3.0 / 4.0));
+        let gap_columns: Vec<bool> = db_records.par_iter()
+            .fold(|| vec![true; alignment_length], |mut gap_columns, record| {
+                for (is_gap, ch) in gap_columns.iter_mut().zip(record.seq().iter()) {
+                    *is_gap &= *ch == GAP;
+                }
+                gap_columns
+            })
+            .reduce(|| vec![true; alignment_length], |mut x, y| {
+                x.iter_mut().zip(y.iter()).for_each(|(xi, yi)| *xi &= *yi);
+                x
+            });
+        let num_kept = gap_columns.iter().filter(|is_gap| !**is_gap).count();
+        tracing::info!("Packing {} of {} columns of {} database records", num_kept, alignment_length, db_records.len());
+        let mut residues: Vec<u8> = Vec::with_capacity(num_kept * db_records.len());
+        for record in db_records.iter() {
+            residues.extend(kept_columns(record.seq(), &gap_columns));
+        }
+        Some(PackedDatabase { gap_columns, num_kept, residues })
+    }
 
-        let x1 = Record::with_attrs("x1", None, b"-----------------------------------------AAAAAAAAAA---------------------");
-        let x2 = Record::with_attrs("x2", None, b"-------------------------CCCCCCCCCCCCCCCCCCCCCCCCCCCCCCCCCC-------------");
-        let y = Record::with_attrs("y", None, b"--------------------------------------------CCC-------------------------");
-        let id1 = pct_identity(&x1, &y).unwrap();
-        let id2 = pct_identity(&x2, &y).unwrap();
-        assert!(id2 > id1);
+    /// The kept columns of the database record of index `db_idx`.
+    fn record(&self, db_idx: usize) -> &[u8] {
+        &self.residues[db_idx * self.num_kept..(db_idx + 1) * self.num_kept]
+    }
+
+    /// The kept columns of a query, and its counts over the dropped columns, which hold a gap in every database
+    /// record. Without any dropped column, the query is compared as it is.
+    fn prepare<'q>(&self, query: &'q [u8], comparison: &ComparisonConfig) -> (Cow<'q, [u8]>, PairCounts) {
+        if self.num_kept == self.gap_columns.len() {
+            return (Cow::Borrowed(query), pair_counts(&[], &[], comparison));
+        }
+        let dropped: Vec<u8> = query.iter()
+            .zip(self.gap_columns.iter())
+            .filter(|(_, is_gap)| **is_gap)
+            .map(|(ch, _)| *ch)
+            .collect();
+        let residues: Vec<u8> = kept_columns(query, &self.gap_columns).collect();
+        (Cow::Owned(residues), pair_counts(&dropped, &vec![GAP; dropped.len()], comparison))
+    }
+}
+
+
+/// The bytes of `seq` in the columns not marked in `gap_columns`.
+fn kept_columns<'s>(seq: &'s [u8], gap_columns: &'s [bool]) -> impl Iterator<Item = u8> + 's {
+    seq.iter().zip(gap_columns.iter()).filter(|(_, is_gap)| !**is_gap).map(|(ch, _)| *ch)
+}
+
+
+/// A query prepared for the comparisons of a [`NeighborSearcher`]: against a packed database, its kept columns
+/// and its counts over the dropped columns, see [`PackedDatabase::prepare`].
+struct PreparedQuery<'q> {
+    record: &'q Record,
+    packed: Option<(Cow<'q, [u8]>, PairCounts)>,
+}
+
+
+/// A neighbor of a query, by ID. Unlike the record references of [`compute_nearest_neighbors`], it doesn't borrow
+/// from the records, so that the results can outlive them, e.g. to be returned by the function parsing them.
+#[derive(Debug, Clone, PartialEq)]
+pub struct NeighborHit {
+    pub query_id: String,
+    pub neighbor_id: String,
+    /// The value of the configured metric, the identity by default.
+    pub identity: f64,
+}
+
+
+impl NeighborHit {
+    /// The hit of a query to a neighbor found with `identity`.
+    pub fn new(query: &Record, (neighbor, identity): (&Record, f64)) -> NeighborHit {
+        NeighborHit { query_id: query.id().to_owned(), neighbor_id: neighbor.id().to_owned(), identity }
+    }
+}
+
+
+/// Compute nearest-neighbors using multiple worker threads.
+/// If `config.cancel` is cancelled during the search, only the results of the queries completed
+/// before the first skipped one are returned, so they always match a prefix of `query_records`.
+pub fn compute_nearest_neighbors<'a>(
+    query_records: &'a [&'a Record],
+    db_records: &'a [&'a Record],
+    config: &NeighborConfig,
+) -> Result<NeighborResult<'a>, NearestNeighborError> {
+    NeighborSearcher::build(db_records, config)?.nearest_batch(query_records)
+}
+
+
+/// The value of the pair metric between `query` and `other`, and the strand of the query it was measured on.
+/// With the reverse complement of the query, `reverse_query`, the better of the two strands' values, the forward
+/// strand winning ties.
+fn stranded_value(
+    query: &Record,
+    reverse_query: Option<&Record>,
+    other: &Record,
+    config: &NeighborConfig,
+) -> Result<(f64, Strand), NearestNeighborError> {
+    let forward = compare_records_with(query, other, config.pair_metric())?;
+    let reverse = reverse_query.map(|reverse_query| compare_records_with(reverse_query, other, config.pair_metric())).transpose()?;
+    Ok(better_strand(forward, reverse, config))
+}
+
+
+/// The better of the values of a pair on the forward strand of the query and, if measured, on its reverse
+/// complement, the forward strand winning ties.
+fn better_strand(forward: f64, reverse: Option<f64>, config: &NeighborConfig) -> (f64, Strand) {
+    match reverse {
+        Some(reverse) if !reverse.is_nan() && (forward.is_nan() || !config.ranking().is_at_least_as_good(forward, reverse)) => {
+            (reverse, Strand::Reverse)
+        }
+        _ => (forward, Strand::Forward),
+    }
+}
+
+
+/// Track the progress of a search in compared pairs, which reflects the work done better than
+/// completed queries do when queries are compared against different numbers of records.
+fn search_progress(query_count: usize, db_count: usize, config: &NeighborConfig) -> Progress {
+    let pairs_per_query = match config.approx_candidates {
+        Some(top_c) => top_c.min(db_count),
+        None => db_count,
+    };
+    Progress::new((query_count * pairs_per_query) as u64, config.progress, config.progress_interval)
+}
+
+
+/// With [`NeighborConfig::approx_candidates`], sketch the database records for the MinHash pre-filter.
+#[cfg(feature = "minhash")]
+fn approx_index(db_records: &[&Record], config: &NeighborConfig) -> Result<Option<MinHashIndex>, NearestNeighborError> {
+    Ok(config.approx_candidates.map(|_| {
+        tracing::info!("Building MinHash index of {} database records", db_records.len());
+        build_minhash_index(db_records, MINHASH_K, MINHASH_NUM_HASHES)
+    }))
+}
+
+
+#[cfg(not(feature = "minhash"))]
+fn approx_index(_db_records: &[&Record], config: &NeighborConfig) -> Result<Option<MinHashIndex>, NearestNeighborError> {
+    match config.approx_candidates {
+        Some(_) => Err(NearestNeighborError::Unsupported(
+            "The MinHash pre-filter is unavailable; rebuild with `--features minhash`.".to_owned()
+        )),
+        None => Ok(None),
+    }
+}
+
+
+/// The indices of the database records pre-selected by the MinHash index for the exact comparison, in database
+/// order.
+#[cfg(feature = "minhash")]
+fn approx_candidate_indices(index: &MinHashIndex, query: &Record, num_db_records: usize, config: &NeighborConfig) -> Vec<usize> {
+    query_candidates(index, query, config.approx_candidates.unwrap_or(num_db_records))
+}
+
+
+#[cfg(not(feature = "minhash"))]
+fn approx_candidate_indices(index: &MinHashIndex, _query: &Record, _num_db_records: usize, _config: &NeighborConfig) -> Vec<usize> {
+    match *index {}
+}
+
+
+/// Compute the `config.top_k` nearest neighbors of each query using multiple worker threads.
+/// Each query's neighbors are sorted from best to worst; ties are broken in favor of the record
+/// appearing later in `db_records`, consistent with [`compute_nearest_neighbors`].
+/// Pairs with an undefined identity (no compared columns) are never reported.
+/// Cancellation is handled as in [`compute_nearest_neighbors`].
+pub fn compute_top_k_neighbors<'a>(
+    query_records: &'a [&'a Record],
+    db_records: &'a [&'a Record],
+    config: &NeighborConfig,
+) -> Result<TopKNeighborResult<'a>, NearestNeighborError> {
+    NeighborSearcher::build(db_records, config)?.top_k_batch(query_records)
+}
+
+
+/// Compute the nearest neighbor of each query as [`compute_nearest_neighbors`] does, as owned [`NeighborHit`]s.
+pub fn compute_nearest_neighbors_owned(
+    query_records: &[&Record],
+    db_records: &[&Record],
+    config: &NeighborConfig,
+) -> Result<Vec<NeighborHit>, NearestNeighborError> {
+    let results = NeighborSearcher::build(db_records, config)?.nearest_batch(query_records)?;
+    Ok(query_records.iter().zip(results).map(|(query, hit)| NeighborHit::new(query, hit)).collect())
+}
+
+
+/// Compute the `config.top_k` nearest neighbors of each query as [`compute_top_k_neighbors`] does, as owned
+/// [`NeighborHit`]s.
+pub fn compute_top_k_neighbors_owned(
+    query_records: &[&Record],
+    db_records: &[&Record],
+    config: &NeighborConfig,
+) -> Result<Vec<Vec<NeighborHit>>, NearestNeighborError> {
+    let results = NeighborSearcher::build(db_records, config)?.top_k_batch(query_records)?;
+    Ok(query_records.iter()
+        .zip(results)
+        .map(|(query, neighbors)| neighbors.into_iter().map(|hit| NeighborHit::new(query, hit)).collect())
+        .collect())
+}
+
+
+thread_local! {
+    /// The `(db_idx, value)` pairs of the query searched on this thread, see [`with_scored_scratch`].
+    static SCORED_SCRATCH: Cell<Vec<(usize, f64)>> = const { Cell::new(Vec::new()) };
+}
+
+
+/// Run `f` with an empty buffer for the scored database records of a query. The buffer is kept between the
+/// queries searched on the same thread, so that each worker grows it to the database size once, 16 bytes per
+/// record, rather than allocating it for every query; see `benches/scratch_allocations.rs`. Each thread has
+/// its own buffer, and a nested call gets a new one.
+pub(crate) fn with_scored_scratch<T>(f: impl FnOnce(&mut Vec<(usize, f64)>) -> T) -> T {
+    let mut scored = SCORED_SCRATCH.take();
+    scored.clear();
+    let result = f(&mut scored);
+    SCORED_SCRATCH.set(scored);
+    result
+}
+
+
+/// The gap character that other gap characters are replaced with at parse time.
+pub const GAP: u8 = '-' as u8;
+
+
+/// Whether a residue is an unknown base (`N`) or amino acid (`X`), case-insensitive.
+pub fn is_unknown_residue(ch: u8) -> bool {
+    matches!(ch.to_ascii_uppercase(), b'N' | b'X')
+}
+
+
+/// Options controlling how a pair of aligned sequences is compared.
+#[derive(Debug, Clone, Default)]
+pub struct ComparisonConfig {
+    /// Number of matching columns removed from the sequences beforehand, added to both counts.
+    /// See [`crate::columns::informative_columns`].
+    pub invariant_matches: u64,
+    /// Number of all-gap columns removed from the sequences beforehand, added to the double gaps.
+    pub gap_columns: u64,
+    /// The measure reported for each pair and used to rank neighbors.
+    pub metric: Metric,
+    /// The distance reported for saturated pairs, whose corrected distance is undefined.
+    /// Infinite if unset.
+    pub saturation_cap: Option<f64>,
+    /// Exclude the columns where either sequence holds an unknown residue (`N` or `X`) from both
+    /// counts, as double gaps are, rather than counting them as mismatches.
+    pub ignore_ambiguous: bool,
+    /// Symbols excluded from both counts wherever either sequence holds one, as double gaps are.
+    /// Case-sensitive.
+    pub ignore_chars: Vec<u8>,
+    /// Count each maximal run of columns where the same one of the two sequences is gapped as a single
+    /// compared column, i.e. one difference whatever its length, as in the gap-compressed identity of
+    /// `esl-alipid`. Skipped columns, such as double gaps, don't interrupt a run.
+    pub gap_run_as_one: bool,
+    /// If set, also score the columns where both sequences hold a residue with this matrix, filling in
+    /// [`PairCounts::similar`] and [`PairCounts::score`].
+    pub score_matrix: Option<Arc<ScoreMatrix>>,
+    /// If set, symbol equivalences used instead of the exact-match test, making matches fractional.
+    pub match_matrix: Option<Arc<MatchMatrix>>,
+    /// If set, the weight of each alignment column in the counts, see [`crate::columns::read_column_weights`].
+    /// Columns of weight 0 are skipped, as double gaps are.
+    pub column_weights: Option<Arc<Vec<f32>>>,
+    /// The characters treated as gaps. A column where both sequences hold one, not necessarily the
+    /// same, is a double gap.
+    pub gap_chars: GapChars,
+    /// The pairs compared on fewer columns, as counted in [`PairCounts::compared`], have no value: a short overlap
+    /// can give a spuriously high identity. 0 keeps all pairs.
+    pub min_overlap: usize,
+    /// The pairs covering a smaller fraction of the query's residues, see [`PairCounts::query_coverage`], have
+    /// no value, so that a short neighbor can't win over one covering the whole query. 0 keeps all pairs.
+    pub min_query_coverage: f64,
+}
+
+
+impl ComparisonConfig {
+    /// The weight of a 0-based column in the counts, 0 for the columns masked out by the weights.
+    fn column_weight(&self, col: usize) -> f64 {
+        match &self.column_weights {
+            None => 1.0,
+            Some(weights) => weights.get(col).copied().unwrap_or(0.0) as f64,
+        }
+    }
+
+    /// Whether a column is excluded for an unknown residue or an ignored symbol in either sequence.
+    fn is_ignored(&self, x: u8, y: u8) -> bool {
+        (self.ignore_ambiguous && (is_unknown_residue(x) || is_unknown_residue(y)))
+            || (!self.ignore_chars.is_empty() && (self.ignore_chars.contains(&x) || self.ignore_chars.contains(&y)))
+    }
+
+    /// The match score of a column: 1 for equal symbols and 0 otherwise, unless set by the match matrix.
+    fn match_score(&self, x: u8, y: u8) -> f64 {
+        match &self.match_matrix {
+            None => (x == y) as u8 as f64,
+            Some(matrix) => matrix.score(x, y) as f64,
+        }
+    }
+
+    /// The value of the configured metric for a pair. Like the identity, it is NaN if no column was compared.
+    ///
+    /// The p-distance is `1 - identity`. For K2P, every mismatch that isn't a transition, including a
+    /// residue against a gap, counts as a transversion, so that both distances use the same p-distance.
+    pub fn value(&self, counts: &PairCounts) -> f64 {
+        let compared = counts.compared;
+        if compared < self.min_overlap as f64 {
+            return f64::NAN;
+        }
+        if self.min_query_coverage > 0.0 {
+            let coverage = counts.query_coverage();
+            if coverage.is_nan() || coverage < self.min_query_coverage {
+                return f64::NAN;
+            }
+        }
+        let distance = match self.metric {
+            Metric::Identity => return counts.identity(),
+            Metric::Similarity => return counts.similarity(),
+            _ if counts.compared == 0.0 => return f64::NAN,
+            Metric::Score => return counts.score as f64,
+            Metric::Jc69 => jc69_distance((counts.compared - counts.matches) / compared),
+            Metric::K2p => {
+                let transversions = counts.compared - counts.matches - counts.transitions;
+                k2p_distance(counts.transitions / compared, transversions / compared)
+            }
+        };
+        match distance {
+            Some(distance) => distance,
+            None => self.saturation_cap.unwrap_or(f64::INFINITY),
+        }
+    }
+}
+
+
+impl PairwiseMetric for ComparisonConfig {
+    /// The value of the configured metric, see [`ComparisonConfig::value`].
+    fn compare(&self, x: &[u8], y: &[u8]) -> Result<f64, NearestNeighborError> {
+        Ok(self.value(&pair_counts(x, y, self)))
+    }
+
+    fn is_distance(&self) -> bool {
+        self.metric.is_distance()
+    }
+}
+
+
+/// The error of a query without any database record to compare against: each pair had too short an overlap
+/// with [`ComparisonConfig::min_overlap`], too small a coverage with [`ComparisonConfig::min_query_coverage`],
+/// or no value.
+pub(crate) fn no_neighbor_error(query: &Record, config: &ComparisonConfig) -> NearestNeighborError {
+    match config.min_overlap {
+        0 if config.min_query_coverage > 0.0 => {
+            NearestNeighborError::InsufficientCoverage(query.id().to_owned(), config.min_query_coverage)
+        }
+        0 => NearestNeighborError::EmptyDatabase(query.id().to_owned()),
+        min_overlap => NearestNeighborError::InsufficientOverlap(query.id().to_owned(), min_overlap),
+    }
+}
+
+
+/// The raw counts behind a percent-identity value.
+///
+/// The counts are whole numbers unless a [`ComparisonConfig::match_matrix`] gives partial matches, or
+/// [`ComparisonConfig::column_weights`] weigh each column's contribution.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct PairCounts {
+    /// Number of compared columns where both sequences carry the same character, or with a
+    /// [`ComparisonConfig::match_matrix`], the summed match scores of the compared columns.
+    pub matches: f64,
+    /// Number of compared columns, i.e. those that are not gaps in both sequences (nor masked, see
+    /// [`ComparisonConfig::ignore_ambiguous`] and [`ComparisonConfig::ignore_chars`]).
+    pub compared: f64,
+    /// Number of compared columns holding a transition, see [`crate::distance::is_transition`],
+    /// less their partial match scores.
+    pub transitions: f64,
+    /// Number of compared columns holding any other substitution between two residues, less their
+    /// partial match scores. Unlike the transversions of [`Metric::K2p`], excludes the gapped columns.
+    pub transversions: f64,
+    /// Number of compared columns where exactly one of the sequences is gapped.
+    pub single_gaps: f64,
+    /// Number of columns excluded because both sequences are gapped, unweighted.
+    pub double_gaps: u64,
+    /// Number of columns where the first sequence, the query, holds a residue, skipping the same masked
+    /// columns as `compared`, but counting every column of a gap run in the other sequence.
+    pub query_residues: f64,
+    /// Number of compared columns with a positive substitution score. Only counted with a
+    /// [`ComparisonConfig::score_matrix`].
+    pub similar: f64,
+    /// The summed substitution score of the compared columns without gaps, unweighted. Only computed
+    /// with a [`ComparisonConfig::score_matrix`].
+    pub score: i64,
+}
+
+
+impl PairCounts {
+    pub fn identity(&self) -> f64 {
+        self.matches / self.compared
+    }
+
+    pub fn similarity(&self) -> f64 {
+        self.similar / self.compared
+    }
+
+    /// The fraction of the query's residues aligned against a residue of the other sequence. NaN for a query
+    /// without any residue.
+    pub fn query_coverage(&self) -> f64 {
+        (self.compared - self.single_gaps) / self.query_residues
+    }
+}
+
+
+/// Count the matching and compared columns of two aligned byte sequences of equal length.
+/// Columns where both sequences are gaps are excluded from both counts, as are those with an unknown
+/// residue in either sequence if [`ComparisonConfig::ignore_ambiguous`] is set, those with an ignored
+/// symbol in either sequence, and those of weight 0 in [`ComparisonConfig::column_weights`].
+/// With [`ComparisonConfig::gap_run_as_one`], only the first column of each gap run is compared.
+pub fn pair_counts(x: &[u8], y: &[u8], config: &ComparisonConfig) -> PairCounts {
+    let counts = PairCounts {
+        matches: config.invariant_matches as f64,
+        compared: config.invariant_matches as f64,
+        double_gaps: config.gap_columns,
+        query_residues: config.invariant_matches as f64,
+        ..Default::default()
+    };
+    add_pair_counts(counts, x, y, config)
+}
+
+
+/// Add the counts of two aligned byte sequences of equal length to `counts`, as [`pair_counts`] counts them.
+fn add_pair_counts(mut counts: PairCounts, x: &[u8], y: &[u8], config: &ComparisonConfig) -> PairCounts {
+    // Whether `x` (true) or `y` (false) is gapped in the current gap run, if any.
+    let mut gap_run: Option<bool> = None;
+    for (col, (xi, yi)) in x.iter().zip(y.iter()).enumerate() {
+        let weight = config.column_weight(col);
+        if weight == 0.0 {
+            continue;
+        }
+        let (x_gap, y_gap) = (config.gap_chars.contains(*xi), config.gap_chars.contains(*yi));
+        if x_gap && y_gap {
+            counts.double_gaps += 1;
+            continue;
+        }
+        if config.is_ignored(*xi, *yi) {
+            continue;
+        }
+        if !x_gap {
+            counts.query_residues += weight;
+        }
+        if config.gap_run_as_one {
+            let column_run = (x_gap != y_gap).then_some(x_gap);
+            let continues_run = column_run.is_some() && column_run == gap_run;
+            gap_run = column_run;
+            if continues_run {
+                continue;
+            }
+        }
+        counts.compared += weight;
+        let match_score = config.match_score(*xi, *yi);
+        counts.matches += weight * match_score;
+        if x_gap || y_gap {
+            counts.single_gaps += weight;
+        } else if match_score < 1.0 && is_transition(*xi, *yi) {
+            counts.transitions += weight * (1.0 - match_score);
+        } else if match_score < 1.0 {
+            counts.transversions += weight * (1.0 - match_score);
+        }
+        if let Some(matrix) = &config.score_matrix
+            && !x_gap && !y_gap
+        {
+            let score = matrix.score(*xi, *yi);
+            counts.score += score as i64;
+            if score > 0 {
+                counts.similar += weight;
+            }
+        }
+    }
+    counts
+}
+
+
+/// The raw column counts of an aligned pair, whatever the comparison options but the gap characters.
+/// Each column is counted in exactly one of `query_gaps`, `neighbor_gaps`, `shared_gaps`, `mismatches`,
+/// or the matches, which are the remaining informative sites.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SequenceComparison {
+    /// The number of alignment columns.
+    pub alignment_length: usize,
+    /// The columns that aren't gapped in both sequences.
+    pub informative_sites: usize,
+    /// The columns gapped in the query only.
+    pub query_gaps: usize,
+    /// The columns gapped in the neighbor only.
+    pub neighbor_gaps: usize,
+    /// The columns gapped in both sequences.
+    pub shared_gaps: usize,
+    /// The columns holding two different residues.
+    pub mismatches: usize,
+    /// The mismatches between two purines or two pyrimidines, see [`is_transition`].
+    pub transitions: usize,
+    /// The mismatches between a purine and a pyrimidine, see [`is_transversion`]. Together with the
+    /// transitions, they leave out the mismatches involving ambiguity codes, such as `N`.
+    pub transversions: usize,
+}
+
+
+impl SequenceComparison {
+    /// The fraction of the informative sites holding the same residue in both sequences, as
+    /// [`PairCounts::identity`] with the default comparison options. NaN without any informative site.
+    pub fn identity(&self) -> f64 {
+        let matches = self.informative_sites - self.query_gaps - self.neighbor_gaps - self.mismatches;
+        matches as f64 / self.informative_sites as f64
+    }
+
+    /// The transition/transversion ratio, or `None` without any transversion.
+    pub fn ts_tv_ratio(&self) -> Option<f64> {
+        (self.transversions > 0).then(|| self.transitions as f64 / self.transversions as f64)
+    }
+}
+
+
+/// Count the columns of two aligned byte sequences of equal length in a single pass, see [`SequenceComparison`].
+pub fn compare_sequences(query: &[u8], neighbor: &[u8], gap_chars: &GapChars) -> SequenceComparison {
+    let mut comparison = SequenceComparison { alignment_length: query.len(), ..Default::default() };
+    for (q, n) in query.iter().zip(neighbor.iter()) {
+        match (gap_chars.contains(*q), gap_chars.contains(*n)) {
+            (true, true) => comparison.shared_gaps += 1,
+            (true, false) => comparison.query_gaps += 1,
+            (false, true) => comparison.neighbor_gaps += 1,
+            (false, false) if q != n => {
+                comparison.mismatches += 1;
+                comparison.transitions += is_transition(*q, *n) as usize;
+                comparison.transversions += is_transversion(*q, *n) as usize;
+            }
+            (false, false) => {}
+        }
+    }
+    comparison.informative_sites = comparison.alignment_length - comparison.shared_gaps;
+    comparison
+}
+
+
+/// A compared column where two aligned sequences differ.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Mismatch {
+    /// The 0-based alignment column.
+    pub column: usize,
+    pub x: u8,
+    pub y: u8,
+    /// Whether exactly one of the sequences is gapped, rather than both holding different residues.
+    pub one_sided_gap: bool,
+}
+
+
+/// The mismatches of a pair, see [`mismatch_columns`].
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct MismatchList {
+    pub mismatches: Vec<Mismatch>,
+    /// Whether mismatches beyond the first `max_positions` were left out.
+    pub truncated: bool,
+}
+
+
+/// List the compared columns of two aligned byte sequences of equal length that aren't full matches,
+/// skipping the same columns as [`pair_counts`], but listing every column of a gap run.
+///
+/// # Arguments
+///
+/// * `x`, `y` - The aligned sequences.
+/// * `config` - The comparison options deciding which columns are compared and which are matches.
+/// * `max_positions` - The maximum number of mismatches to list, beyond which the list is truncated.
+pub fn mismatch_columns(x: &[u8], y: &[u8], config: &ComparisonConfig, max_positions: usize) -> MismatchList {
+    let mut mismatches = x.iter()
+        .zip(y.iter())
+        .enumerate()
+        .filter(|(col, (xi, yi))| {
+            let double_gap = config.gap_chars.contains(**xi) && config.gap_chars.contains(**yi);
+            config.column_weight(*col) != 0.0 && !double_gap && !config.is_ignored(**xi, **yi)
+                && config.match_score(**xi, **yi) < 1.0
+        })
+        .map(|(column, (xi, yi))| Mismatch {
+            column,
+            x: *xi,
+            y: *yi,
+            one_sided_gap: config.gap_chars.contains(*xi) || config.gap_chars.contains(*yi),
+        });
+    let listed: Vec<Mismatch> = mismatches.by_ref().take(max_positions).collect();
+    MismatchList { mismatches: listed, truncated: mismatches.next().is_some() }
+}
+
+
+/// The percent identity of two aligned byte sequences of equal length.
+pub fn pct_identity_bytes(x: &[u8], y: &[u8], config: &ComparisonConfig) -> f64 {
+    pair_counts(x, y, config).identity()
+}
+
+
+/// Compare two aligned Fasta records, checking that their lengths agree.
+pub fn compare_records(x: &Record, y: &Record, config: &ComparisonConfig) -> Result<PairCounts, NearestNeighborError> {
+    check_lengths(x, y)?;
+    Ok(pair_counts(x.seq(), y.seq(), config))
+}
+
+
+/// The value of `metric` between two aligned Fasta records, checking that their lengths agree.
+pub fn compare_records_with(x: &Record, y: &Record, metric: &dyn PairwiseMetric) -> Result<f64, NearestNeighborError> {
+    check_lengths(x, y)?;
+    metric.compare(x.seq(), y.seq())
+}
+
+
+fn check_lengths(x: &Record, y: &Record) -> Result<(), NearestNeighborError> {
+    if x.seq().len() != y.seq().len() {
+        return Err(NearestNeighborError::HammingDistanceError(x.id().to_owned(), y.id().to_owned()));
+    }
+    Ok(())
+}
+
+
+/// The percent identity of two aligned Fasta records with the default comparison options.
+/// NaN if no column was compared, e.g. if both records are all gaps.
+pub fn pct_identity(x: &Record, y: &Record) -> Result<f64, NearestNeighborError> {
+    check_lengths(x, y)?;
+    Ok(compare_sequences(x.seq(), y.seq(), &GapChars::default()).identity())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, fs::File, io::Write, sync::Arc};
+    use bio::io::fasta::Record;
+    use proptest::{collection::vec, prelude::*, sample::select};
+    use regex::Regex;
+    use crate::cancel::CancellationToken;
+    use crate::classify::classify_query;
+    use crate::columns::{compute_column_stats, informative_columns, trim_record};
+    use crate::nearest_neighbor::{
+        compare_records, compare_sequences, compute_nearest_neighbors, compute_store_nearest_neighbors, compute_top_k_neighbors,
+        compute_nearest_neighbors_owned, compute_top_k_neighbors_owned, filter_records, filter_records_excluding,
+        neighbor_rows, pair_counts, pct_identity, pct_identity_bytes, with_scored_scratch, write_neighbor_rows_incrementally,
+        ComparisonConfig, NearestNeighborError, NeighborConfig, NeighborHit, NeighborSearcher, RecordSelector,
+        SequenceComparison,
+    };
+    use crate::gaps::GapChars;
+    use crate::distance::{Metric, SearchMode};
+    use crate::output::{temp_path, FinishWrite, OutputConfig, TextWriter};
+    use crate::strand::reverse_complement;
+
+    #[test]
+    fn test_filter_records() {
+        let records = [
+            Record::with_attrs("a", None, b"A"),
+            Record::with_attrs("b", None, b"A"),
+            Record::with_attrs("c", None, b"A"),
+        ];
+        let ids = |selected: Vec<&Record>| -> Vec<String> { selected.iter().map(|record| record.id().to_owned()).collect() };
+        // The records keep their order, whatever the order of the IDs.
+        assert_eq!(ids(filter_records(&records, &RecordSelector::Ids(&["c", "a", "missing"]))), ["a", "c"]);
+        assert_eq!(ids(filter_records(&records, &RecordSelector::ids(Some(&["b".to_owned()][..])))), ["b"]);
+        assert_eq!(ids(filter_records::<&str>(&records, &RecordSelector::All)).len(), 3);
+    }
+
+    #[test]
+    fn test_filter_records_by_regex() {
+        let records = [
+            Record::with_attrs("REF_1", None, b"A"),
+            Record::with_attrs("QRY_1", None, b"A"),
+            Record::with_attrs("REF_2", None, b"A"),
+            Record::with_attrs("QRY_REF_3", None, b"A"),
+        ];
+        let ids = |selected: Vec<&Record>| -> Vec<String> { selected.iter().map(|record| record.id().to_owned()).collect() };
+        let anchored = Regex::new("^REF_").unwrap();
+        let unanchored = Regex::new("REF_").unwrap();
+        assert_eq!(ids(filter_records::<&str>(&records, &RecordSelector::Regex(&anchored))), ["REF_1", "REF_2"]);
+        assert_eq!(ids(filter_records::<&str>(&records, &RecordSelector::Regex(&unanchored))).len(), 3);
+        // Combined selectors select the intersection.
+        let selector = RecordSelector::ids(Some(&["REF_2", "QRY_1", "QRY_REF_3"][..])).and(RecordSelector::Regex(&unanchored));
+        assert_eq!(ids(filter_records(&records, &selector)), ["REF_2", "QRY_REF_3"]);
+        let selector = RecordSelector::All.and(RecordSelector::Regex(&anchored)).and(RecordSelector::Ids(&["REF_1", "QRY_1"]));
+        assert_eq!(ids(filter_records(&records, &selector)), ["REF_1"]);
+        let nothing = Regex::new("^XYZ").unwrap();
+        assert!(filter_records::<&str>(&records, &RecordSelector::Regex(&nothing)).is_empty());
+    }
+
+    #[test]
+    fn test_filter_records_by_gap_fraction() {
+        let records = [
+            Record::with_attrs("full", None, b"ACGTACGTAC"),
+            Record::with_attrs("half", None, b"ACGTA-----"),
+            Record::with_attrs("sparse", None, b"A---------"),
+        ];
+        let ids = |selected: Vec<&Record>| -> Vec<String> { selected.iter().map(|record| record.id().to_owned()).collect() };
+        let max_gap_fraction = |fraction: f32| filter_records::<&str>(&records, &RecordSelector::MaxGapFraction(fraction));
+        assert_eq!(ids(max_gap_fraction(0.5)), ["full", "half"]);
+        assert_eq!(ids(max_gap_fraction(0.0)), ["full"]);
+        assert_eq!(ids(max_gap_fraction(1.0)).len(), 3);
+        let selector = RecordSelector::Ids(&["half", "sparse"]).and(RecordSelector::MaxGapFraction(0.8));
+        assert_eq!(ids(filter_records(&records, &selector)), ["half"]);
+    }
+
+    #[test]
+    fn test_filter_records_excluding() {
+        let records = [
+            Record::with_attrs("a", None, b"A"),
+            Record::with_attrs("b", None, b"A"),
+            Record::with_attrs("c", None, b"A"),
+        ];
+        let ids = |selected: Vec<&Record>| -> Vec<String> { selected.iter().map(|record| record.id().to_owned()).collect() };
+        assert_eq!(ids(filter_records_excluding::<&str, &str>(&records, None, &["b"])), ["a", "c"]);
+        // The exclusion wins over the inclusion of the same ID.
+        assert_eq!(ids(filter_records_excluding(&records, Some(&["a", "b"][..]), &["b", "missing"])), ["a"]);
+        assert_eq!(ids(filter_records_excluding::<&str, &str>(&records, None, &[])).len(), 3);
+    }
+
+    #[test]
+    fn test_pct_identity() {
+        let x = Record::with_attrs("input1", None, b"AAAAAAA");
+        let y = Record::with_attrs("input2", None, b"AAAACCA");
+        assert_eq!(pct_identity(&x, &y), Ok(5.0 / 7.0));
+
+        let x = Record::with_attrs("input1", None, b"AAAA");
+        let y = Record::with_attrs("input2", None, b"CCCC");
+        assert_eq!(pct_identity(&x, &y), Ok(0.0));
+
+        let x = Record::with_attrs("input1", None, b"AAAA");
+        let y = Record::with_attrs("input2", None, b"AAAA");
+        assert_eq!(pct_identity(&x, &y), Ok(1.0));
+
+        let x = Record::with_attrs("input1", None, b"AAAA");
+        let y = Record::with_attrs("input2", None, b"AAA");
+        assert!(pct_identity(&x, &y).is_err());
+
+        let x = Record::with_attrs("input1", None, b"----AAAA----");
+        let y = Record::with_attrs("input2", None, b"----AAA-----");
+        assert_eq!(pct_identity(&x, &y), Ok(3.0 / 4.0));
+
+        let x1 = Record::with_attrs("x1", None, b"-----------------------------------------AAAAAAAAAA---------------------");
+        let x2 = Record::with_attrs("x2", None, b"-------------------------CCCCCCCCCCCCCCCCCCCCCCCCCCCCCCCCCC-------------");
+        let y = Record::with_attrs("y", None, b"--------------------------------------------CCC-------------------------");
+        let id1 = pct_identity(&x1, &y).unwrap();
+        let id2 = pct_identity(&x2, &y).unwrap();
+        assert!(id2 > id1);
+    }
+
+    #[test]
+    fn test_compressed_columns_identity() {
+        let records = [
+            Record::with_attrs("x1", None, b"-A-CGTA--TTAC"),
+            Record::with_attrs("x2", None, b"-A-CG-A--TCA-"),
+            Record::with_attrs("x3", None, b"-A--GAA--T-AG"),
+            Record::with_attrs("x4", None, b"-A-C-TA--TGAC"),
+            Record::with_attrs("x5", None, b"-A----A--T---"),
+        ];
+        let record_refs: Vec<&Record> = records.iter().collect();
+        let stats = compute_column_stats(&record_refs);
+
+        for drop_invariant in [false, true] {
+            let selection = informative_columns(&stats, drop_invariant);
+            let expected_invariant = if drop_invariant { 3 } else { 0 };
+            assert_eq!(selection.invariant_matches, expected_invariant);
+            assert_eq!(selection.gap_columns, 4);
+            assert_eq!(selection.columns.len(), 13 - 4 - expected_invariant as usize);
+
+            let config = ComparisonConfig {
+                invariant_matches: selection.invariant_matches,
+                gap_columns: selection.gap_columns,
+                ..Default::default()
+            };
+            let trimmed: Vec<Record> = records.iter().map(|record| trim_record(record, &selection.columns)).collect();
+            for (x, x_trimmed) in records.iter().zip(trimmed.iter()) {
+                for (y, y_trimmed) in records.iter().zip(trimmed.iter()) {
+                    let naive = compare_records(x, y, &ComparisonConfig::default()).unwrap();
+                    let compressed = compare_records(x_trimmed, y_trimmed, &config).unwrap();
+                    assert_eq!(naive, compressed);
+                    assert_eq!(naive.identity().to_bits(), compressed.identity().to_bits());
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_cancelled_search() {
+        let records = [
+            Record::with_attrs("x", None, b"AAAA"),
+            Record::with_attrs("y", None, b"AACC"),
+        ];
+        let record_refs: Vec<&Record> = records.iter().collect();
+        let cancel = CancellationToken::new();
+        cancel.cancel();
+        let config = NeighborConfig { cancel, ..Default::default() };
+        assert!(compute_nearest_neighbors(&record_refs, &record_refs, &config).unwrap().is_empty());
+        let config = NeighborConfig { top_k: 2, ..config };
+        assert!(compute_top_k_neighbors(&record_refs, &record_refs, &config).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_ignore_ambiguous() {
+        let config = ComparisonConfig { ignore_ambiguous: true, ..Default::default() };
+        // The N columns count as mismatches by default.
+        assert_eq!(pct_identity_bytes(b"ACGTNN", b"ACGAAC", &ComparisonConfig::default()), 0.5);
+        // When masked, they leave the denominator instead: 3 matches out of 4 columns.
+        assert_eq!(pct_identity_bytes(b"ACGTNN", b"ACGAAC", &config), 0.75);
+        assert_eq!(pct_identity_bytes(b"ACGTnA", b"ACGANx", &config), 0.75);
+        // Double gaps and masked columns are both excluded.
+        let x = Record::with_attrs("x", None, b"AC-GTX");
+        let y = Record::with_attrs("y", None, b"XC-GAA");
+        let counts = compare_records(&x, &y, &config).unwrap();
+        assert_eq!((counts.matches, counts.compared), (2.0, 3.0));
+        // All-masked pairs have an undefined identity.
+        assert!(pct_identity_bytes(b"NN", b"AC", &config).is_nan());
+    }
+
+    #[test]
+    fn test_runner_up() {
+        let records = [
+            Record::with_attrs("q", None, b"AAAA"),
+            Record::with_attrs("tied", None, b"AAAC"),
+            Record::with_attrs("far", None, b"AACC"),
+            Record::with_attrs("best", None, b"AAAG"),
+        ];
+        let record_refs: Vec<&Record> = records.iter().collect();
+        let config = NeighborConfig { exclude_self: true, ..Default::default() };
+        let output_config = OutputConfig { with_runner_up: true, ..Default::default() };
+        // Ties go to the later record, for the runner-up as for the best neighbor.
+        let (queries, db) = (&record_refs[..1], &record_refs[..]);
+        let (rows, _) = neighbor_rows(queries, db, queries, db, &config, &output_config).unwrap();
+        assert_eq!(rows.len(), 1);
+        assert_eq!(rows[0].neighbor_id, "best");
+        let runner_up = rows[0].runner_up.clone().unwrap();
+        assert_eq!((runner_up.neighbor_id.as_str(), runner_up.identity, runner_up.margin), ("tied", 0.75, 0.0));
+
+        // A single-record database has no runner-up.
+        let db = &record_refs[2..3];
+        let (rows, _) = neighbor_rows(queries, db, queries, db, &config, &output_config).unwrap();
+        assert_eq!((rows[0].neighbor_id.as_str(), rows[0].runner_up.clone()), ("far", None));
+    }
+
+    #[test]
+    fn test_db_rank() {
+        let records = [
+            Record::with_attrs("q", None, b"AAAA"),
+            Record::with_attrs("second", None, b"AACC"),
+            Record::with_attrs("best", None, b"AAAC"),
+            Record::with_attrs("far", None, b"CCCC"),
+        ];
+        let record_refs: Vec<&Record> = records.iter().collect();
+        let config = NeighborConfig { exclude_self: true, top_k: 2, ..Default::default() };
+        let output_config = OutputConfig { with_db_rank: true, ..Default::default() };
+        let (queries, db) = (&record_refs[..1], &record_refs[..]);
+        let (rows, _) = neighbor_rows(queries, db, queries, db, &config, &output_config).unwrap();
+        let ranks: Vec<(&str, Option<usize>)> = rows.iter().map(|row| (row.neighbor_id.as_str(), row.db_rank)).collect();
+        assert_eq!(ranks, [("best", Some(1)), ("second", Some(2))]);
+
+        // Tied records share a rank.
+        let tied = [
+            Record::with_attrs("q", None, b"AAAA"),
+            Record::with_attrs("t1", None, b"AAAC"),
+            Record::with_attrs("t2", None, b"AACA"),
+        ];
+        let tied_refs: Vec<&Record> = tied.iter().collect();
+        let (queries, db) = (&tied_refs[..1], &tied_refs[..]);
+        let (rows, _) = neighbor_rows(queries, db, queries, db, &config, &output_config).unwrap();
+        assert_eq!(rows.iter().map(|row| row.db_rank).collect::<Vec<_>>(), [Some(1), Some(1)]);
+    }
+
+    /// A writer that cancels a search once it has written `cancel_after` lines.
+    struct CancellingWriter<W: Write> {
+        inner: W,
+        num_lines: usize,
+        cancel_after: usize,
+        cancel: CancellationToken,
+    }
+
+    impl<W: Write> FinishWrite for CancellingWriter<W> {}
+
+    impl<W: Write> Write for CancellingWriter<W> {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            let written = self.inner.write(buf)?;
+            self.num_lines += buf[..written].iter().filter(|ch| **ch == b'\n').count();
+            if self.num_lines >= self.cancel_after {
+                self.cancel.cancel();
+            }
+            Ok(written)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            self.inner.flush()
+        }
+    }
+
+    #[test]
+    fn test_incremental_output() {
+        // The identity of records i and j is 1 - |i - j| / 20.
+        let records: Vec<Record> = (0..20)
+            .map(|i| {
+                let seq: Vec<u8> = (0..20).map(|col| if col < i { b'C' } else { b'A' }).collect();
+                Record::with_attrs(&format!("r{}", i), None, &seq)
+            })
+            .collect();
+        let record_refs: Vec<&Record> = records.iter().collect();
+        let config = NeighborConfig { exclude_self: true, ..Default::default() };
+        let output_config = OutputConfig { flush_interval: 1, ..Default::default() };
+        let out_path = std::env::temp_dir().join("aligned_nearest_neighbor_test_incremental.tsv");
+
+        // Cancel the search once the first half of the queries are written. With a single worker, the queries
+        // are searched in order, and the bounded channel holds back the worker until the writer catches up.
+        let mut writer = TextWriter::new(
+            CancellingWriter {
+                inner: File::create(&out_path).unwrap(),
+                num_lines: 0,
+                cancel_after: 10,
+                cancel: config.cancel.clone(),
+            },
+            &output_config,
+        );
+        let pool = rayon::ThreadPoolBuilder::new().num_threads(1).build().unwrap();
+        let (rows, num_completed) = pool.install(|| write_neighbor_rows_incrementally(
+            &record_refs, &record_refs, &record_refs, &record_refs, &config, &output_config, &mut writer,
+        )).unwrap();
+        assert!((10..20).contains(&num_completed), "{} queries were completed", num_completed);
+        assert_eq!(rows.len(), num_completed);
+
+        // The partial output holds a complete row for each completed query, in query order.
+        let output = std::fs::read_to_string(&out_path).unwrap();
+        let lines: Vec<&str> = output.lines().collect();
+        assert_eq!(lines.len(), num_completed);
+        assert!(output.ends_with('\n'));
+        for (line, query) in lines.iter().zip(records.iter()) {
+            let fields: Vec<&str> = line.split('\t').collect();
+            assert_eq!(fields.len(), 3);
+            assert_eq!(fields[0], query.id());
+            assert_eq!(fields[2].parse::<f64>().unwrap(), 0.95);
+        }
+        std::fs::remove_file(&out_path).unwrap();
+    }
+
+    #[test]
+    fn test_atomic_output() {
+        let records = vec![Record::with_attrs("q1", None, b"ACGT"), Record::with_attrs("q2", None, b"ACGA")];
+        let out_path = std::env::temp_dir().join("aligned_nearest_neighbor_test_atomic.tsv");
+        let tmp_path = temp_path(&out_path);
+        let config = NeighborConfig { exclude_self: true, ..Default::default() };
+        std::fs::write(&out_path, "original\n").unwrap();
+
+        // A search failing after the output was started leaves the original file, and no temporary file.
+        let result = compute_store_nearest_neighbors(
+            records.clone(), &out_path, None, Some(vec!["q1".to_owned()]), &config, &OutputConfig::default(),
+        );
+        assert_eq!(result, Err(NearestNeighborError::EmptyDatabase("q1".to_owned())));
+        assert_eq!(std::fs::read_to_string(&out_path).unwrap(), "original\n");
+        assert!(!tmp_path.exists());
+
+        // So does a failure to write the output, here because the temporary file can't be created.
+        std::fs::create_dir(&tmp_path).unwrap();
+        let result = compute_store_nearest_neighbors(records.clone(), &out_path, None, None, &config, &OutputConfig::default());
+        assert!(result.is_err());
+        assert_eq!(std::fs::read_to_string(&out_path).unwrap(), "original\n");
+        std::fs::remove_dir(&tmp_path).unwrap();
+
+        // A successful search replaces the file.
+        compute_store_nearest_neighbors(records, &out_path, None, None, &config, &OutputConfig::default()).unwrap();
+        assert_eq!(std::fs::read_to_string(&out_path).unwrap().lines().count(), 2);
+        assert!(!tmp_path.exists());
+        std::fs::remove_file(&out_path).unwrap();
+    }
+
+    #[test]
+    fn test_reverse_complement_search() {
+        let records = vec![
+            Record::with_attrs("query", None, b"AACGTTTG"),
+            Record::with_attrs("fwd", None, b"AACGTTTC"),
+            Record::with_attrs("rc", None, &reverse_complement(b"AACGTTTG")),
+        ];
+        let out_path = std::env::temp_dir().join("aligned_nearest_neighbor_test_reverse_complement.tsv");
+        let search = |reverse_complement: bool| -> String {
+            compute_store_nearest_neighbors(
+                records.clone(),
+                &out_path,
+                Some(vec!["query".to_owned()]),
+                Some(vec!["fwd".to_owned(), "rc".to_owned()]),
+                &NeighborConfig { top_k: 2, reverse_complement, ..Default::default() },
+                &OutputConfig { with_strand: true, with_ts_tv: true, ..Default::default() },
+            ).unwrap();
+            std::fs::read_to_string(&out_path).unwrap()
+        };
+        // The reverse-complemented query matches its complement, and the pair's substitutions are those of that strand.
+        assert_eq!(search(true), "query\trc\t1.000000\t0\t0\tNA\t-\nquery\tfwd\t0.875000\t0\t1\t0.000000\t+\n");
+        assert_eq!(search(false), "query\tfwd\t0.875000\t0\t1\t0.000000\tNA\nquery\trc\t0.250000\t2\t4\t0.500000\tNA\n");
+        std::fs::remove_file(&out_path).unwrap();
+    }
+
+    #[test]
+    fn test_min_overlap() {
+        let query = Record::with_attrs("query", None, b"A-----------A");
+        let sparse = Record::with_attrs("sparse", None, b"A-----------A");
+        let dense = Record::with_attrs("dense", None, b"ACAAAAAAAACAA");
+        let counts = compare_records(&query, &sparse, &ComparisonConfig::default()).unwrap();
+        assert_eq!((counts.identity(), counts.compared), (1.0, 2.0));
+
+        let search = |db: &[&Record], min_overlap: usize| {
+            let config = NeighborConfig {
+                comparison: ComparisonConfig { min_overlap, ..Default::default() },
+                ..Default::default()
+            };
+            compute_nearest_neighbors(&[&query], db, &config).map(|results| (results[0].0.id().to_owned(), results[0].1))
+        };
+        assert_eq!(search(&[&sparse, &dense], 0), Ok(("sparse".to_owned(), 1.0)));
+        assert_eq!(search(&[&sparse, &dense], 2), Ok(("sparse".to_owned(), 1.0)));
+        // The sparse pair's identity rests on 2 columns only, and is filtered out.
+        assert_eq!(search(&[&sparse, &dense], 3), Ok(("dense".to_owned(), 2.0 / 13.0)));
+        assert_eq!(search(&[&sparse], 3), Err(NearestNeighborError::InsufficientOverlap("query".to_owned(), 3)));
+        assert_eq!(search(&[&dense], 14), Err(NearestNeighborError::InsufficientOverlap("query".to_owned(), 14)));
+    }
+
+    #[test]
+    fn test_min_query_coverage() {
+        let query = Record::with_attrs("query", None, b"ACGTACGTAC--");
+        let short = Record::with_attrs("short", None, b"ACGTAC------");
+        let full = Record::with_attrs("full", None, b"ACGTTTTTAGGT");
+        let counts = compare_records(&query, &short, &ComparisonConfig::default()).unwrap();
+        assert_eq!((counts.identity(), counts.query_residues, counts.query_coverage()), (0.6, 10.0, 0.6));
+        let counts = compare_records(&query, &full, &ComparisonConfig::default()).unwrap();
+        assert_eq!((counts.identity(), counts.query_coverage()), (0.5, 1.0));
+
+        let search = |db: &[&Record], min_query_coverage: f64| {
+            let config = NeighborConfig {
+                comparison: ComparisonConfig { min_query_coverage, ..Default::default() },
+                ..Default::default()
+            };
+            compute_nearest_neighbors(&[&query], db, &config).map(|results| (results[0].0.id().to_owned(), results[0].1))
+        };
+        assert_eq!(search(&[&short, &full], 0.0), Ok(("short".to_owned(), 0.6)));
+        assert_eq!(search(&[&short, &full], 0.6), Ok(("short".to_owned(), 0.6)));
+        // The short neighbor covers 6 of the 10 query residues only, and is disqualified.
+        assert_eq!(search(&[&short, &full], 0.8), Ok(("full".to_owned(), 0.5)));
+        assert_eq!(search(&[&short], 0.8), Err(NearestNeighborError::InsufficientCoverage("query".to_owned(), 0.8)));
+    }
+
+    #[test]
+    fn test_collapse_duplicates() {
+        let records = vec![
+            Record::with_attrs("a1", None, b"ACGTACGT"),
+            Record::with_attrs("b", None, b"ACGTACGA"),
+            Record::with_attrs("a2", None, b"ACGTACGT"),
+            Record::with_attrs("c", None, b"TTGTACGA"),
+        ];
+        let record_refs: Vec<&Record> = records.iter().collect();
+        let neighbors = |config: &NeighborConfig| -> Vec<Vec<(String, f64)>> {
+            compute_top_k_neighbors(&record_refs, &record_refs, config).unwrap().iter()
+                .map(|hits| hits.iter().map(|(record, value)| (record.id().to_owned(), *value)).collect())
+                .collect()
+        };
+        let hits = |hits: &[(&str, f64)]| hits.iter().map(|(id, value)| (id.to_string(), *value)).collect::<Vec<_>>();
+
+        // Without self-exclusion, collapsing the duplicates doesn't change the neighbors.
+        for top_k in [1, 2] {
+            let config = NeighborConfig { top_k, ..Default::default() };
+            let collapsed = NeighborConfig { collapse_duplicates: true, counters: Arc::default(), ..config.clone() };
+            assert_eq!(neighbors(&collapsed), neighbors(&config));
+            assert_eq!(collapsed.counters.collapsed_queries(), Some((3, 4)));
+            assert_eq!(config.counters.collapsed_queries(), None);
+        }
+
+        // With self-exclusion, a copy of the query is left out too, so that no query is its own neighbor.
+        let config = NeighborConfig { exclude_self: true, collapse_duplicates: true, top_k: 2, ..Default::default() };
+        assert_eq!(neighbors(&config), [
+            hits(&[("b", 0.875), ("c", 0.625)]),
+            hits(&[("a2", 0.875), ("a1", 0.875)]),
+            hits(&[("b", 0.875), ("c", 0.625)]),
+            hits(&[("b", 0.75), ("a2", 0.625)]),
+        ]);
+        let config = NeighborConfig { top_k: 1, ..config };
+        let results = compute_nearest_neighbors(&record_refs, &record_refs, &config).unwrap();
+        assert_eq!(results.iter().map(|(record, _)| record.id()).collect::<Vec<&str>>(), ["b", "a2", "b", "b"]);
+    }
+
+    #[test]
+    fn test_scored_scratch() {
+        with_scored_scratch(|scored| {
+            scored.extend([(0, 1.0), (1, 0.5)]);
+            with_scored_scratch(|nested| assert!(nested.is_empty()));
+        });
+        // The buffer is cleared, but keeps its capacity for the next query.
+        let capacity = with_scored_scratch(|scored| {
+            assert!(scored.is_empty());
+            scored.capacity()
+        });
+        assert!(capacity >= 2);
+        // Another thread starts from a buffer of its own.
+        std::thread::spawn(|| with_scored_scratch(|scored| assert_eq!(scored.capacity(), 0))).join().unwrap();
+
+        // Each worker thread reuses its own buffer, with the same results as a single thread.
+        let records: Vec<Record> = (0..40usize)
+            .map(|idx| {
+                let seq: Vec<u8> = (0..16).map(|col| b"ACGT-"[(idx * 7 + col * (idx % 5 + 1)) % 5]).collect();
+                Record::with_attrs(&format!("r{}", idx), None, &seq)
+            })
+            .collect();
+        let record_refs: Vec<&Record> = records.iter().collect();
+        let config = NeighborConfig { exclude_self: true, top_k: 3, ..Default::default() };
+        let search = |num_threads: usize| -> Vec<Vec<(String, f64)>> {
+            let pool = rayon::ThreadPoolBuilder::new().num_threads(num_threads).build().unwrap();
+            pool.install(|| compute_top_k_neighbors(&record_refs, &record_refs, &config)).unwrap().iter()
+                .map(|hits| hits.iter().map(|(record, value)| (record.id().to_owned(), *value)).collect())
+                .collect()
+        };
+        let expected = search(1);
+        assert_eq!(expected.len(), 40);
+        assert!(expected.iter().all(|hits| hits.len() == 3));
+        assert_eq!(search(4), expected);
+    }
+
+    #[test]
+    fn test_neighbor_searcher() {
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<NeighborSearcher<'static>>();
+
+        let records = vec![
+            Record::with_attrs("q1", None, b"ACGTACGT"),
+            Record::with_attrs("q2", None, b"TTGTACGA"),
+            Record::with_attrs("d1", None, b"ACGTACGA"),
+            Record::with_attrs("d2", None, b"ACGAACGT"),
+            Record::with_attrs("d3", None, b"TTGTTCGA"),
+        ];
+        let record_refs: Vec<&Record> = records.iter().collect();
+        let (query_refs, db_refs) = record_refs.split_at(2);
+        let ids = |hits: &[(&Record, f64)]| hits.iter().map(|(record, value)| (record.id().to_owned(), *value)).collect::<Vec<_>>();
+        for top_k in [1, 2] {
+            let config = NeighborConfig { top_k, ..Default::default() };
+            let searcher = NeighborSearcher::build(db_refs, &config).unwrap();
+            // Building once and searching twice gives the results of two one-shot searches.
+            for _ in 0..2 {
+                let batch = searcher.top_k_batch(query_refs).unwrap();
+                let one_shot = compute_top_k_neighbors(query_refs, db_refs, &config).unwrap();
+                assert_eq!(batch.len(), one_shot.len());
+                for (batch_hits, one_shot_hits) in batch.iter().zip(one_shot.iter()) {
+                    assert_eq!(ids(batch_hits), ids(one_shot_hits));
+                }
+                for (query, hits) in query_refs.iter().zip(one_shot.iter()) {
+                    assert_eq!(ids(&searcher.top_k(query).unwrap()), ids(hits));
+                    assert_eq!(ids(&[searcher.nearest(query).unwrap()]), ids(&hits[..1]));
+                }
+            }
+        }
+
+        // The searcher can be shared between threads.
+        let searcher = NeighborSearcher::build(db_refs, &NeighborConfig::default()).unwrap();
+        let expected = ids(&searcher.nearest_batch(query_refs).unwrap());
+        std::thread::scope(|scope| {
+            let handles: Vec<_> = (0..2).map(|_| scope.spawn(|| ids(&searcher.nearest_batch(query_refs).unwrap()))).collect();
+            for handle in handles {
+                assert_eq!(handle.join().unwrap(), expected);
+            }
+        });
+        assert_eq!(expected, [("d2".to_owned(), 0.875), ("d3".to_owned(), 0.875)]);
+    }
+
+    #[test]
+    fn test_neighbor_searcher_packing() {
+        // The second and last columns are gapped in every database record, but not in the first query.
+        let records = vec![
+            Record::with_attrs("q1", None, b"AACGTA"),
+            Record::with_attrs("q2", None, b"A-NGT-"),
+            Record::with_attrs("d1", None, b"A-CGT-"),
+            Record::with_attrs("d2", None, b"A-CTT-"),
+            Record::with_attrs("d3", None, b"T-CGA-"),
+        ];
+        let record_refs: Vec<&Record> = records.iter().collect();
+        let (query_refs, db_refs) = record_refs.split_at(2);
+        let comparisons = [
+            ComparisonConfig::default(),
+            ComparisonConfig { ignore_ambiguous: true, ..Default::default() },
+            ComparisonConfig { metric: Metric::Jc69, ..Default::default() },
+            ComparisonConfig { min_query_coverage: 0.5, ..Default::default() },
+            ComparisonConfig { invariant_matches: 2, gap_columns: 1, ..Default::default() },
+        ];
+        for comparison in comparisons {
+            let config = NeighborConfig { comparison, top_k: 3, ..Default::default() };
+            let searcher = NeighborSearcher::build(db_refs, &config).unwrap();
+            assert_eq!(searcher.packed.as_ref().map(|packed| packed.num_kept), Some(4));
+            for query in query_refs.iter() {
+                // The values of the packed records are those of the whole records.
+                let whole_values: Vec<f64> = db_refs.iter()
+                    .map(|other| config.comparison.value(&compare_records(query, other, &config.comparison).unwrap()))
+                    .collect();
+                let hits = searcher.top_k(query).unwrap();
+                assert_eq!(hits.len(), whole_values.iter().filter(|value| !value.is_nan()).count());
+                for (neighbor, value) in hits.iter() {
+                    let db_idx = db_refs.iter().position(|other| other.id() == neighbor.id()).unwrap();
+                    assert_eq!(*value, whole_values[db_idx]);
+                }
+            }
+        }
+        let searcher = NeighborSearcher::build(db_refs, &NeighborConfig::default()).unwrap();
+        let (neighbor, value) = searcher.nearest(query_refs[0]).unwrap();
+        assert_eq!((neighbor.id(), value), ("d1", 4.0 / 6.0));
+        let short = Record::with_attrs("short", None, b"ACGTA");
+        assert_eq!(
+            searcher.nearest(&short).unwrap_err(),
+            NearestNeighborError::HammingDistanceError("short".to_owned(), "d3".to_owned()),
+        );
+
+        // Gap runs span the dropped columns, so the records are searched whole.
+        let config = NeighborConfig { comparison: ComparisonConfig { gap_run_as_one: true, ..Default::default() }, ..Default::default() };
+        assert!(NeighborSearcher::build(db_refs, &config).unwrap().packed.is_none());
+        let ragged = [db_refs[0], &short];
+        assert!(NeighborSearcher::build(&ragged, &NeighborConfig::default()).unwrap().packed.is_none());
+    }
+
+    #[test]
+    fn test_owned_neighbors() {
+        // The records are dropped on return, but not the results.
+        fn search(top_k: usize) -> Vec<Vec<NeighborHit>> {
+            let records = vec![
+                Record::with_attrs("a", None, b"ACGTACGT"),
+                Record::with_attrs("b", None, b"ACGTACGA"),
+                Record::with_attrs("c", None, b"TTGTACGA"),
+            ];
+            let record_refs: Vec<&Record> = records.iter().collect();
+            let config = NeighborConfig { exclude_self: true, top_k, ..Default::default() };
+            if top_k == 1 {
+                let results = compute_nearest_neighbors_owned(&record_refs[..1], &record_refs, &config).unwrap();
+                return results.into_iter().map(|hit| vec![hit]).collect();
+            }
+            compute_top_k_neighbors_owned(&record_refs[..1], &record_refs, &config).unwrap()
+        }
+        let hit = |neighbor_id: &str, identity: f64| NeighborHit {
+            query_id: "a".to_owned(), neighbor_id: neighbor_id.to_owned(), identity,
+        };
+        assert_eq!(search(1), [vec![hit("b", 0.875)]]);
+        assert_eq!(search(2), [vec![hit("b", 0.875), hit("c", 0.625)]]);
+    }
+
+    #[test]
+    fn test_perfect_match_early_exit() {
+        let query = Record::with_attrs("q", None, b"ACGTACGT");
+        let db = [
+            Record::with_attrs("a", None, b"ACGTACGA"),
+            Record::with_attrs("dup1", None, b"ACGTACGT"),
+            Record::with_attrs("b", None, b"ACGTTCGA"),
+            Record::with_attrs("dup2", None, b"ACGTACGT"),
+            Record::with_attrs("c", None, b"TCGTACGA"),
+        ];
+        let db_refs: Vec<&Record> = db.iter().collect();
+        let query_refs = [&query];
+
+        // The scan from the last record stops at the later duplicate, which wins the tie as in a full scan.
+        let config = NeighborConfig::default();
+        let results = compute_nearest_neighbors(&query_refs, &db_refs, &config).unwrap();
+        assert_eq!((results[0].0.id(), results[0].1), ("dup2", 1.0));
+        assert_eq!((config.counters.pairs_compared(), config.counters.pairs_skipped()), (2, 3));
+
+        // The top-k search compares every pair.
+        let config = NeighborConfig { top_k: 2, ..Default::default() };
+        let results = compute_top_k_neighbors(&query_refs, &db_refs, &config).unwrap();
+        let ids: Vec<&str> = results[0].iter().map(|(neighbor, _)| neighbor.id()).collect();
+        assert_eq!(ids, ["dup2", "dup1"]);
+        assert_eq!((config.counters.pairs_compared(), config.counters.pairs_skipped()), (5, 0));
+
+        // Without a perfect match, nor with an unbounded metric, nothing is skipped.
+        let config = NeighborConfig::default();
+        let results = compute_nearest_neighbors(&query_refs, &db_refs[..1], &config).unwrap();
+        assert_eq!(results[0].0.id(), "a");
+        assert_eq!(config.counters.pairs_skipped(), 0);
+        let config = NeighborConfig { mode: SearchMode::Farthest, ..Default::default() };
+        assert_eq!(config.perfect_value(), Some(0.0));
+        let config = NeighborConfig {
+            comparison: ComparisonConfig { metric: Metric::Jc69, ..Default::default() },
+            ..Default::default()
+        };
+        let results = compute_nearest_neighbors(&query_refs, &db_refs, &config).unwrap();
+        assert_eq!(results[0].0.id(), "dup2");
+        assert_eq!(config.counters.pairs_skipped(), 3);
+        let config = NeighborConfig { mode: SearchMode::Farthest, ..config };
+        assert_eq!(config.perfect_value(), None);
+    }
+
+    #[test]
+    fn test_allowed_neighbors() {
+        let records = vec![
+            Record::with_attrs("query", None, b"AAAAAAAA"),
+            Record::with_attrs("closest", None, b"AAAAAAAC"),
+            Record::with_attrs("second", None, b"AAAAAACC"),
+            Record::with_attrs("third", None, b"AAAAACCC"),
+        ];
+        let out_path = std::env::temp_dir().join("aligned_nearest_neighbor_test_allowed_neighbors.tsv");
+        let search = |allowed: Option<&[&str]>| -> String {
+            let config = NeighborConfig {
+                allowed_neighbors: allowed.map(|ids| Arc::new(ids.iter().map(|id| id.to_string()).collect())),
+                ..Default::default()
+            };
+            compute_store_nearest_neighbors(
+                records.clone(), &out_path, Some(vec!["query".to_owned()]), None, &config, &OutputConfig::default(),
+            ).unwrap();
+            std::fs::read_to_string(&out_path).unwrap()
+        };
+        assert_eq!(search(None), "query\tquery\t1.000000\n");
+        assert_eq!(search(Some(&["closest", "second", "third"])), "query\tclosest\t0.875000\n");
+        // Without the true nearest neighbor, the nearest allowed record is reported instead.
+        assert_eq!(search(Some(&["second", "third"])), "query\tsecond\t0.750000\n");
+        assert_eq!(search(Some(&["third", "missing"])), "query\tthird\t0.625000\n");
+        std::fs::remove_file(&out_path).unwrap();
+    }
+
+    #[test]
+    fn test_pairs_fasta() {
+        let records = crate::parse_all_records("tests/inputs/query_db/seqs.fasta".into()).unwrap();
+        let out_path = std::env::temp_dir().join("aligned_nearest_neighbor_test_pairs.tsv");
+        let pairs_path = std::env::temp_dir().join("aligned_nearest_neighbor_test_pairs.fasta");
+        let output_config = OutputConfig { pairs_fasta: Some(pairs_path.clone()), ..Default::default() };
+        let write_pairs = |top_k: usize| -> Vec<(String, Option<String>, Vec<u8>)> {
+            compute_store_nearest_neighbors(
+                records.clone(),
+                &out_path,
+                Some(vec!["query_1".to_owned(), "query_2".to_owned()]),
+                Some(vec!["db_1".to_owned(), "db_2".to_owned()]),
+                &NeighborConfig { top_k, ..Default::default() },
+                &output_config,
+            ).unwrap();
+            crate::parse_all_records(pairs_path.clone()).unwrap().iter()
+                .map(|record| (record.id().to_owned(), record.desc().map(str::to_owned), record.seq().to_vec()))
+                .collect()
+        };
+        let pair = |id: &str, desc: &str, seq: &[u8]| (id.to_owned(), Some(desc.to_owned()), seq.to_vec());
+
+        // Two records per query, holding the sequences of the query and of its neighbor.
+        assert_eq!(write_pairs(1), [
+            pair("query_1|1|query", "query_1", b"AAAAAAAAAAAAAAAA"),
+            pair("query_1|1|neighbor", "db_1", b"GGGGGGGGGGGGGAAA"),
+            pair("query_2|1|query", "query_2", b"AAAAAAAAAAATTTTT"),
+            pair("query_2|1|neighbor", "db_2", b"GGGGGGGGGGGGTTTT"),
+        ]);
+        // With several neighbors per query, the rank keeps the IDs of the pairs apart.
+        assert_eq!(write_pairs(2), [
+            pair("query_1|1|query", "query_1", b"AAAAAAAAAAAAAAAA"),
+            pair("query_1|1|neighbor", "db_1", b"GGGGGGGGGGGGGAAA"),
+            pair("query_1|2|query", "query_1", b"AAAAAAAAAAAAAAAA"),
+            pair("query_1|2|neighbor", "db_2", b"GGGGGGGGGGGGTTTT"),
+            pair("query_2|1|query", "query_2", b"AAAAAAAAAAATTTTT"),
+            pair("query_2|1|neighbor", "db_2", b"GGGGGGGGGGGGTTTT"),
+            pair("query_2|2|query", "query_2", b"AAAAAAAAAAATTTTT"),
+            pair("query_2|2|neighbor", "db_1", b"GGGGGGGGGGGGGAAA"),
+        ]);
+        std::fs::remove_file(&out_path).unwrap();
+        std::fs::remove_file(&pairs_path).unwrap();
+    }
+
+    #[test]
+    fn test_ties_at_full_precision() {
+        // Both pairs have an identity of 2/3: 4 matches in 6 compared columns, and 2 in 3 once the
+        // unknown residues are excluded.
+        let records = [
+            Record::with_attrs("q", None, b"AAAAAA"),
+            Record::with_attrs("a", None, b"AACAAC"),
+            Record::with_attrs("b", None, b"AACNNN"),
+        ];
+        let record_refs: Vec<&Record> = records.iter().collect();
+        let config = NeighborConfig {
+            comparison: ComparisonConfig { ignore_ambiguous: true, ..Default::default() },
+            exclude_self: true,
+            ..Default::default()
+        };
+        let counts_a = compare_records(&records[0], &records[1], &config.comparison).unwrap();
+        let counts_b = compare_records(&records[0], &records[2], &config.comparison).unwrap();
+        assert_eq!((counts_a.matches, counts_a.compared, counts_b.matches, counts_b.compared), (4.0, 6.0, 2.0, 3.0));
+        assert_eq!(config.comparison.value(&counts_a), config.comparison.value(&counts_b));
+
+        // The tie goes to the later record.
+        let results = compute_nearest_neighbors(&record_refs[..1], &record_refs, &config).unwrap();
+        assert_eq!((results[0].0.id(), results[0].1), ("b", 2.0 / 3.0));
+        let top_k_config = NeighborConfig { top_k: 2, ..config.clone() };
+        let results = compute_top_k_neighbors(&record_refs[..1], &record_refs, &top_k_config).unwrap();
+        let hits: Vec<(&str, f64)> = results[0].iter().map(|(neighbor, idty)| (neighbor.id(), *idty)).collect();
+        assert_eq!(hits, [("b", 2.0 / 3.0), ("a", 2.0 / 3.0)]);
+        // A threshold at the same identity keeps both neighbors.
+        let labels = HashMap::from([("a".to_owned(), "A".to_owned()), ("b".to_owned(), "A".to_owned())]);
+        let classification = classify_query(&records[0], &results[0], &labels, Some(2.0 / 3.0));
+        assert_eq!((classification.predicted_label.as_str(), classification.votes["A"]), ("A", 2));
+
+        let jc69_config = NeighborConfig {
+            comparison: ComparisonConfig { metric: Metric::Jc69, ..config.comparison.clone() },
+            ..config.clone()
+        };
+        let results = compute_nearest_neighbors(&record_refs[..1], &record_refs, &jc69_config).unwrap();
+        assert_eq!(results[0].0.id(), "b");
+    }
+
+    #[test]
+    fn test_farthest_mode() {
+        let records = [
+            Record::with_attrs("q", None, b"AAAA"),
+            Record::with_attrs("near", None, b"AAAC"),
+            Record::with_attrs("far", None, b"ACCC"),
+            // Only ignored unknown residues: no compared column, so never the farthest.
+            Record::with_attrs("empty", None, b"NNNN"),
+        ];
+        let record_refs: Vec<&Record> = records.iter().collect();
+        let (queries, db) = (&record_refs[..1], &record_refs[..]);
+        let comparison = ComparisonConfig { ignore_ambiguous: true, ..Default::default() };
+        let nearest = NeighborConfig { comparison: comparison.clone(), exclude_self: true, ..Default::default() };
+        let farthest = NeighborConfig { mode: SearchMode::Farthest, ..nearest.clone() };
+        let output_config = OutputConfig::default();
+
+        let (rows, _) = neighbor_rows(queries, db, queries, db, &nearest, &output_config).unwrap();
+        assert_eq!((rows[0].neighbor_id.as_str(), rows[0].identity), ("near", 0.75));
+        let (rows, _) = neighbor_rows(queries, db, queries, db, &farthest, &output_config).unwrap();
+        assert_eq!((rows[0].neighbor_id.as_str(), rows[0].identity), ("far", 0.25));
+
+        // With a distance, the farthest neighbor has the largest distance.
+        let comparison = ComparisonConfig { metric: Metric::Jc69, ..comparison };
+        let farthest_distance = NeighborConfig { comparison, ..farthest.clone() };
+        let (rows, _) = neighbor_rows(queries, db, queries, db, &farthest_distance, &output_config).unwrap();
+        assert_eq!(rows[0].neighbor_id, "far");
+
+        // Top-k lists the most divergent first.
+        let top_two = NeighborConfig { top_k: 2, ..farthest };
+        let (rows, _) = neighbor_rows(queries, db, queries, db, &top_two, &output_config).unwrap();
+        let ids: Vec<&str> = rows.iter().map(|row| row.neighbor_id.as_str()).collect();
+        assert_eq!(ids, ["far", "near"]);
+    }
+
+    #[test]
+    fn test_compare_sequences() {
+        // Columns: 4 matches, a mismatch, a query gap, two neighbor gaps and two shared gaps.
+        let comparison = compare_sequences(b"ACGTA-CG--", b"ACGAAT--.-", &GapChars::new(b"-."));
+        assert_eq!(comparison, SequenceComparison {
+            alignment_length: 10,
+            informative_sites: 8,
+            query_gaps: 1,
+            neighbor_gaps: 2,
+            shared_gaps: 2,
+            mismatches: 1,
+            transitions: 0,
+            transversions: 1,
+        });
+        assert_eq!(comparison.identity(), 0.5);
+
+        // The identity is that of the pair counts with the default options, NaN for two all-gap sequences.
+        for (x, y) in [(&b"ACGTA-CG--"[..], &b"ACGAAT--.-"[..]), (b"AcGATNA-", b"GTACGAAT"), (b"A-NN", b"ANNC")] {
+            let counts = pair_counts(x, y, &ComparisonConfig::default());
+            assert_eq!(compare_sequences(x, y, &GapChars::default()).identity(), counts.identity());
+        }
+        assert!(compare_sequences(b"--", b"--", &GapChars::default()).identity().is_nan());
+    }
+
+    #[test]
+    fn test_ts_tv() {
+        // Transitions A/G, c/T and G/A, transversions A/C and T/G. The N and gap columns count for neither.
+        let comparison = compare_sequences(b"AcGATNA-", b"GTACGAAT", &GapChars::default());
+        assert_eq!((comparison.mismatches, comparison.transitions, comparison.transversions), (6, 3, 2));
+        assert_eq!(comparison.ts_tv_ratio(), Some(1.5));
+
+        let comparison = compare_sequences(b"ACGT", b"GCAT", &GapChars::default());
+        assert_eq!((comparison.transitions, comparison.transversions, comparison.ts_tv_ratio()), (2, 0, None));
+    }
+
+    #[test]
+    fn test_detailed_counts() {
+        // Matches A/A, C/C and T/T, transitions A/G and G/A, a C/G transversion, two single gaps and
+        // a double gap.
+        let x = Record::with_attrs("x", None, b"AACGTC-A-");
+        let y = Record::with_attrs("y", None, b"AGCATG--C");
+        let counts = compare_records(&x, &y, &ComparisonConfig::default()).unwrap();
+        assert_eq!((counts.matches, counts.compared), (3.0, 8.0));
+        assert_eq!((counts.transitions, counts.transversions), (2.0, 1.0));
+        assert_eq!((counts.single_gaps, counts.double_gaps), (2.0, 1));
+    }
+
+    #[test]
+    fn test_gap_run_as_one() {
+        let config = ComparisonConfig { gap_run_as_one: true, ..Default::default() };
+        let counts = |x: &[u8], y: &[u8]| {
+            let counts = compare_records(&Record::with_attrs("x", None, x), &Record::with_attrs("y", None, y), &config).unwrap();
+            (counts.matches, counts.compared)
+        };
+        // A 5-column insertion is a single difference.
+        assert_eq!(counts(b"ACGT-----ACGT", b"ACGTAAAAAACGT"), (8.0, 9.0));
+        // Adjacent runs gapped in different sequences are two differences.
+        assert_eq!(counts(b"AC--GGTT", b"ACTT--TT"), (4.0, 6.0));
+        // Double gaps nested in a run don't split it, but a residue column does.
+        assert_eq!(counts(b"AC----GT", b"ACT--AGT"), (4.0, 5.0));
+        assert_eq!(counts(b"AC-A-GT", b"ACTATGT"), (5.0, 7.0));
+        // Terminal runs count once each.
+        assert_eq!(counts(b"--ACGT--", b"AAACGTAA"), (4.0, 6.0));
+        assert_eq!(counts(b"ACGT", b"----"), (0.0, 1.0));
+        // Without the option, every gapped column is a difference.
+        let x = Record::with_attrs("x", None, b"ACGT-----ACGT");
+        let y = Record::with_attrs("y", None, b"ACGTAAAAAACGT");
+        assert_eq!(compare_records(&x, &y, &ComparisonConfig::default()).unwrap().compared, 13.0);
+    }
+
+    #[test]
+    fn test_ignore_chars() {
+        let config = ComparisonConfig { ignore_chars: b"NX?".to_vec(), ..Default::default() };
+        assert_eq!(pct_identity_bytes(b"AC?TX", b"ACGAN", &config), 2.0 / 3.0);
+
+        // A query that is half Ns. By default, the neighbor sharing its N run wins on N/N matches,
+        // despite a mismatch in the sequenced half.
+        let records = [
+            Record::with_attrs("q", None, b"ACGTACGTNNNNNNNN"),
+            Record::with_attrs("correct", None, b"ACGTACGTAAAAAAAA"),
+            Record::with_attrs("shared_ns", None, b"ACGTACGANNNNNNNN"),
+        ];
+        let record_refs: Vec<&Record> = records.iter().collect();
+        for (ignore_chars, expected) in [(vec![], "shared_ns"), (b"N".to_vec(), "correct")] {
+            let config = NeighborConfig {
+                comparison: ComparisonConfig { ignore_chars, ..Default::default() },
+                exclude_self: true,
+                ..Default::default()
+            };
+            let results = compute_nearest_neighbors(&record_refs[..1], &record_refs, &config).unwrap();
+            assert_eq!(results[0].0.id(), expected);
+        }
+    }
+
+    #[test]
+    fn test_column_weights() {
+        // Column 2 is masked out, column 4 is both masked out and a double gap, column 5 is a double gap.
+        let config = ComparisonConfig {
+            column_weights: Some(Arc::new(vec![1.0, 0.0, 0.5, 0.0, 2.0, 1.0])),
+            ..Default::default()
+        };
+        let x = Record::with_attrs("x", None, b"AAC--G");
+        let y = Record::with_attrs("y", None, b"ACC--T");
+        let counts = compare_records(&x, &y, &config).unwrap();
+        // Weighted matches of columns 1 and 3, out of columns 1, 3 and 6.
+        assert_eq!((counts.matches, counts.compared), (1.5, 2.5));
+        assert_eq!(counts.identity(), 0.6);
+
+        // Only the last column counts, where the neighbor with the lower plain identity matches.
+        let records = [
+            Record::with_attrs("q", None, b"AAC"),
+            Record::with_attrs("unweighted_match", None, b"AAG"),
+            Record::with_attrs("weighted_match", None, b"CCC"),
+        ];
+        let record_refs: Vec<&Record> = records.iter().collect();
+        let config = NeighborConfig {
+            comparison: ComparisonConfig { column_weights: Some(Arc::new(vec![0.0, 0.0, 1.0])), ..Default::default() },
+            exclude_self: true,
+            ..Default::default()
+        };
+        let results = compute_nearest_neighbors(&record_refs[..1], &record_refs, &config).unwrap();
+        assert_eq!((results[0].0.id(), results[0].1), ("weighted_match", 1.0));
+    }
+
+    /// Aligned sequences over nucleotides, an unknown residue and gaps, so that matches are frequent,
+    /// with other printable ASCII symbols. Records must be valid UTF-8.
+    fn aligned_seq(len: usize) -> impl Strategy<Value = Vec<u8>> {
+        vec(prop_oneof![4 => select(b"ACGTN-".to_vec()), 1 => b'!'..=b'~'], len)
+    }
+
+    fn aligned_pair() -> impl Strategy<Value = (Vec<u8>, Vec<u8>)> {
+        (0usize..64).prop_flat_map(|len| (aligned_seq(len), aligned_seq(len)))
+    }
+
+    fn record(id: &str, seq: &[u8]) -> Record {
+        Record::with_attrs(id, None, seq)
+    }
+
+    proptest! {
+        #[test]
+        fn prop_self_identity(x in (1usize..64).prop_flat_map(aligned_seq)) {
+            prop_assume!(x.iter().any(|ch| *ch != b'-'));
+            prop_assert_eq!(pct_identity(&record("x", &x), &record("y", &x)).unwrap(), 1.0);
+        }
+
+        #[test]
+        fn prop_symmetric((x, y) in aligned_pair()) {
+            let xy = pct_identity(&record("x", &x), &record("y", &y)).unwrap();
+            let yx = pct_identity(&record("y", &y), &record("x", &x)).unwrap();
+            prop_assert_eq!(xy.to_bits(), yx.to_bits());
+        }
+
+        /// The identity is a fraction, or NaN if every column is a double gap.
+        #[test]
+        fn prop_bounded((x, y) in aligned_pair()) {
+            let identity = pct_identity(&record("x", &x), &record("y", &y)).unwrap();
+            let all_double_gaps = x.iter().zip(y.iter()).all(|(xi, yi)| *xi == b'-' && *yi == b'-');
+            prop_assert_eq!(identity.is_nan(), all_double_gaps);
+            prop_assert!(identity.is_nan() || (0.0..=1.0).contains(&identity));
+        }
+
+        #[test]
+        fn prop_length_mismatch(x in (0usize..64).prop_flat_map(aligned_seq), extra in aligned_seq(1)) {
+            let y = [x.as_slice(), extra.as_slice()].concat();
+            prop_assert!(pct_identity(&record("x", &x), &record("y", &y)).is_err());
+        }
     }
 }
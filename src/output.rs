@@ -0,0 +1,1014 @@
+use std::{
+    cmp::Ordering,
+    path::{Path, PathBuf},
+    fs::File,
+    io::{Write, BufWriter},
+};
+#[cfg(feature = "cli")]
+use clap::ValueEnum;
+use bio::io::fasta::Record;
+#[cfg(feature = "compress")]
+use flate2::write::GzEncoder;
+#[cfg(feature = "compress")]
+use zstd::stream::write::Encoder as ZstdEncoder;
+
+use crate::{
+    blast::{write_blast6, BlastHit},
+    graph::GraphOutput,
+    nearest_neighbor::{MismatchList, NearestNeighborError, PairCounts, SequenceComparison},
+    stats::{IdentitySummary, Normalization, RecordStats},
+    strand::Strand,
+};
+
+
+/// The file format of the nearest-neighbor results.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "cli", derive(ValueEnum))]
+pub enum OutputFormat {
+    /// A headerless `query_id, neighbor_id, identity` TSV table.
+    #[default]
+    Tsv,
+    /// A SQLite database with `hits` and `run_metadata` tables, see [`crate::sqlite::SqliteWriter`].
+    /// Requires the `sqlite` feature.
+    Sqlite,
+    /// A Parquet file, see [`crate::parquet_writer::ParquetWriter`]. Requires the `parquet` feature.
+    Parquet,
+    /// BLAST tabular output (`-outfmt 6`), with placeholder e-values and bit scores, see [`write_blast6`].
+    Blast6,
+}
+
+
+impl OutputFormat {
+    /// Whether the rows are written as lines of text, which can be appended as they are found.
+    pub fn is_text(&self) -> bool {
+        matches!(self, OutputFormat::Tsv | OutputFormat::Blast6)
+    }
+
+    /// Whether the format records the matches and compared columns of each pair.
+    pub fn has_counts(&self) -> bool {
+        matches!(self, OutputFormat::Sqlite | OutputFormat::Parquet)
+    }
+}
+
+
+/// The compression of a text output, see [`OutputFile`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "cli", derive(ValueEnum))]
+pub enum Compression {
+    #[default]
+    None,
+    Gzip,
+    Zstd,
+}
+
+
+impl Compression {
+    /// The compression implied by the suffix of `path`: gzip for `.gz`, zstd for `.zst`, and none otherwise.
+    pub fn from_path(path: &Path) -> Compression {
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some("gz") => Compression::Gzip,
+            Some("zst") => Compression::Zstd,
+            _ => Compression::None,
+        }
+    }
+}
+
+
+/// The order of the written result rows.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "cli", derive(ValueEnum))]
+pub enum SortKey {
+    /// The order of the queries in the input, then of the neighbors' ranks.
+    #[default]
+    QueryOrder,
+    QueryId,
+    /// Increasing identity, or distance for a distance [`crate::distance::Metric`].
+    IdentityAsc,
+    IdentityDesc,
+    NeighborId,
+}
+
+
+impl SortKey {
+    /// The name of the key, as given on the command line.
+    pub fn name(&self) -> &'static str {
+        match self {
+            SortKey::QueryOrder => "query-order",
+            SortKey::QueryId => "query-id",
+            SortKey::IdentityAsc => "identity-asc",
+            SortKey::IdentityDesc => "identity-desc",
+            SortKey::NeighborId => "neighbor-id",
+        }
+    }
+}
+
+
+/// Sort the rows by `sort_by`. The sort is stable, so rows with equal keys keep their query order.
+///
+/// The row indices are sorted rather than the rows, then the rows are permuted in place, so that the
+/// memory used on top of the rows is a few words per row.
+pub fn sort_rows(rows: &mut [NearestNeighborResultRow], sort_by: SortKey) {
+    let compare: fn(&NearestNeighborResultRow, &NearestNeighborResultRow) -> Ordering = match sort_by {
+        SortKey::QueryOrder => return,
+        SortKey::QueryId => |a, b| a.query_id.cmp(&b.query_id),
+        SortKey::IdentityAsc => |a, b| a.identity.total_cmp(&b.identity),
+        SortKey::IdentityDesc => |a, b| b.identity.total_cmp(&a.identity),
+        SortKey::NeighborId => |a, b| a.neighbor_id.cmp(&b.neighbor_id),
+    };
+    let mut order: Vec<usize> = (0..rows.len()).collect();
+    order.sort_by(|a, b| compare(&rows[*a], &rows[*b]));
+
+    // Position `pos` receives the row at `order[pos]`. Each cycle of the permutation is applied with swaps,
+    // and the placed positions are marked by `order[pos] == pos`.
+    for start in 0..rows.len() {
+        let mut pos = start;
+        loop {
+            let source = order[pos];
+            order[pos] = pos;
+            if source == start {
+                break;
+            }
+            rows.swap(pos, source);
+            pos = source;
+        }
+    }
+}
+
+
+/// The default number of decimal places of the written identities.
+pub const DEFAULT_PRECISION: usize = 6;
+
+
+/// The default number of rows written between two flushes of an output written as the rows are found.
+pub const DEFAULT_FLUSH_INTERVAL: usize = 1000;
+
+
+/// The default maximum number of rows of a Parquet row group.
+pub const DEFAULT_ROW_GROUP_SIZE: usize = 65_536;
+
+
+/// The metadata key recording whether the search completed, in the outputs holding the run metadata.
+pub const COMPLETED_KEY: &str = "completed";
+
+
+/// Options controlling how nearest-neighbor results are written.
+#[derive(Debug, Clone)]
+pub struct OutputConfig {
+    pub format: OutputFormat,
+    /// The order of the rows, in the main output and in the files derived from the rows.
+    pub sort_by: SortKey,
+    /// Add the neighbor's rank as a fourth TSV column, for top-k results.
+    pub with_rank: bool,
+    /// Add the query's and neighbor's ungapped lengths as TSV columns.
+    pub with_stats: bool,
+    /// Add the pair's identity and similarity as the last TSV columns, whatever the search metric.
+    /// The similarity needs a [`crate::nearest_neighbor::ComparisonConfig::score_matrix`].
+    pub with_similarity: bool,
+    /// Add the pair's transitions, transversions, single-gap and double-gap columns as the last columns,
+    /// see [`PairCounts`].
+    pub with_detailed_counts: bool,
+    /// Add the query's second-best neighbor, its identity and the margin from the best neighbor's as the
+    /// last TSV columns, or `NA` if the query has a single neighbor.
+    pub with_runner_up: bool,
+    /// If set, also write the mismatch positions of each reported pair to a separate file.
+    pub mismatch_positions: Option<MismatchOutput>,
+    /// Add the pair's alignment length, informative sites, query-only, neighbor-only and shared gap columns,
+    /// and mismatches as the last TSV columns, see [`SequenceComparison`].
+    pub with_extended_output: bool,
+    /// Add the pair's transitions, transversions and their ratio as the last TSV columns, counting only the
+    /// substitutions between unambiguous nucleotides. The ratio is `NA` without any transversion.
+    pub with_ts_tv: bool,
+    /// Add the query's and neighbor's full FASTA header lines, the ID and the description, as the last TSV
+    /// columns, see [`header_line`].
+    pub with_description: bool,
+    /// Add the query's and neighbor's GC content as the last TSV columns, see [`crate::stats::gc_content`].
+    pub with_gc_content: bool,
+    /// Add the strand of the query each pair was found on as the last TSV column, see
+    /// [`crate::nearest_neighbor::NeighborConfig::reverse_complement`].
+    pub with_strand: bool,
+    /// Add the neighbor's rank among all the database records as the last TSV column, see
+    /// [`NearestNeighborResultRow::db_rank`]. Each query is compared again against the whole database,
+    /// which doubles the cost of an exact search, and is much slower than an approximate one.
+    pub with_db_rank: bool,
+    /// Add the fraction of the query's residues covered by the neighbor as the last TSV column, see
+    /// [`PairCounts::query_coverage`].
+    pub with_query_coverage: bool,
+    /// If set, the identities of all the rows are normalized once the search completes, and the normalized
+    /// values are written and passed to the other outputs. The rows can't be written as they are found then.
+    pub normalize: Option<Normalization>,
+    /// If set, also write the query-neighbor relation as a graph file.
+    pub graph: Option<GraphOutput>,
+    /// If set, also write the sequences of each reported pair to this multi-FASTA file, see
+    /// [`crate::nearest_neighbor::write_pairs_fasta`].
+    pub pairs_fasta: Option<PathBuf>,
+    /// If set, also write the distribution of the best-hit identities to a separate file.
+    pub identity_summary: Option<IdentitySummaryOutput>,
+    /// The number of decimal places of the identities, distances and ratios in the text outputs.
+    /// Only the formatting is affected: the values are ranked and compared at full precision.
+    pub precision: usize,
+    /// The number of rows between two flushes of a text output written as the rows are found, so that the
+    /// rows of the completed queries reach the file during a long search.
+    pub flush_interval: usize,
+    /// The maximum number of rows of a Parquet row group. Only the rows of the current row group are
+    /// held by the Parquet writer.
+    pub row_group_size: usize,
+    /// The compression of a text output.
+    pub compression: Compression,
+}
+
+
+impl Default for OutputConfig {
+    fn default() -> Self {
+        OutputConfig {
+            format: OutputFormat::default(),
+            sort_by: SortKey::default(),
+            with_rank: false,
+            with_stats: false,
+            with_similarity: false,
+            with_detailed_counts: false,
+            with_runner_up: false,
+            mismatch_positions: None,
+            with_extended_output: false,
+            with_ts_tv: false,
+            with_description: false,
+            with_gc_content: false,
+            with_strand: false,
+            with_db_rank: false,
+            with_query_coverage: false,
+            normalize: None,
+            graph: None,
+            pairs_fasta: None,
+            identity_summary: None,
+            precision: DEFAULT_PRECISION,
+            flush_interval: DEFAULT_FLUSH_INTERVAL,
+            row_group_size: DEFAULT_ROW_GROUP_SIZE,
+            compression: Compression::None,
+        }
+    }
+}
+
+
+/// Where and how to write the mismatch positions of the reported pairs, see [`write_mismatch_positions`].
+#[derive(Debug, Clone)]
+pub struct MismatchOutput {
+    pub path: PathBuf,
+    /// The maximum number of positions listed per pair.
+    pub max_positions: usize,
+}
+
+
+/// Where and how to write the best-hit identity distribution, see [`write_identity_summary`].
+#[derive(Debug, Clone)]
+pub struct IdentitySummaryOutput {
+    pub path: PathBuf,
+    /// The percentiles to report, in percent.
+    pub percentiles: Vec<f32>,
+    /// The width of the histogram bins, as an identity fraction.
+    pub bin_width: f64,
+}
+
+
+/// One row of nearest-neighbor output, owning its data so it can outlive the records.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct NearestNeighborResultRow {
+    pub query_id: String,
+    pub neighbor_id: String,
+    /// The rank of this neighbor among the query's hits, starting at 1 for the nearest.
+    pub rank: usize,
+    /// The identity, or the distance for a distance [`crate::distance::Metric`].
+    pub identity: f64,
+    /// The query's and neighbor's statistics, filled in when [`OutputConfig::with_stats`] is set.
+    pub query_stats: Option<RecordStats>,
+    pub neighbor_stats: Option<RecordStats>,
+    /// The pair's counts, filled in when [`OutputConfig::with_similarity`] or
+    /// [`OutputConfig::with_detailed_counts`] is set, or the format [`OutputFormat::has_counts`].
+    pub counts: Option<PairCounts>,
+    /// The query's second-best neighbor, whatever the row's rank. Filled in when
+    /// [`OutputConfig::with_runner_up`] is set and the query has at least two neighbors.
+    pub runner_up: Option<RunnerUp>,
+    /// The pair's column counts, filled in when [`OutputConfig::with_extended_output`] or
+    /// [`OutputConfig::with_ts_tv`] is set.
+    pub comparison: Option<SequenceComparison>,
+    /// The pair's mismatches, filled in when [`OutputConfig::mismatch_positions`] is set.
+    pub mismatches: Option<MismatchList>,
+    /// The pair as a BLAST hit, filled in when [`OutputConfig::format`] is [`OutputFormat::Blast6`].
+    pub blast: Option<BlastHit>,
+    /// The query's and neighbor's header lines, filled in when [`OutputConfig::with_description`] is set.
+    pub query_header: Option<String>,
+    pub neighbor_header: Option<String>,
+    /// The query's and neighbor's GC content, filled in when [`OutputConfig::with_gc_content`] is set.
+    pub query_gc: Option<f32>,
+    pub neighbor_gc: Option<f32>,
+    /// The strand of the query the pair was found on, filled in when the search compares both strands.
+    pub strand: Option<Strand>,
+    /// The rank of the neighbor among all the database records compared to the query, 1 plus the number
+    /// of records strictly nearer, so that tied records share a rank. Unlike [`Self::rank`], it counts
+    /// the records an approximate search skipped. Filled in when [`OutputConfig::with_db_rank`] is set.
+    pub db_rank: Option<usize>,
+}
+
+
+/// The second-best neighbor of a query, to judge how confident its nearest-neighbor assignment is.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct RunnerUp {
+    pub neighbor_id: String,
+    /// The identity, or the distance for a distance [`crate::distance::Metric`].
+    pub identity: f64,
+    /// The absolute difference between the best neighbor's identity and the runner-up's.
+    pub margin: f64,
+}
+
+
+/// The output path standing for the standard output.
+pub const STDOUT_PATH: &str = "-";
+
+
+/// Whether the output `path` is [`STDOUT_PATH`], standing for the standard output.
+pub fn is_stdout(path: &Path) -> bool {
+    path.as_os_str() == STDOUT_PATH
+}
+
+
+/// Open an output for writing: the standard output for [`STDOUT_PATH`], or else a new file at `path`, replacing
+/// any existing file.
+pub fn create_output(path: &Path) -> std::io::Result<Box<dyn Write + Send>> {
+    if is_stdout(path) {
+        return Ok(Box::new(std::io::stdout()));
+    }
+    Ok(Box::new(File::create(path)?))
+}
+
+
+/// The temporary file an output is written to before it is renamed to `path`: `<path>.tmp`, in the same
+/// directory so that the rename is atomic.
+pub fn temp_path(path: &Path) -> PathBuf {
+    let mut tmp_path: PathBuf = path.to_owned();
+    tmp_path.as_mut_os_string().push(".tmp");
+    tmp_path
+}
+
+
+/// Write an output to `path` atomically: `write` writes it to the [`temp_path`] of `path`, which is renamed
+/// to `path` once `write` succeeds, so that a failed run never leaves a truncated file at `path`, nor alters
+/// the file already there. The temporary file is removed if `write` fails. The standard output, see
+/// [`is_stdout`], is written directly.
+pub fn write_atomically<T>(
+    path: &Path,
+    write: impl FnOnce(&Path) -> Result<T, NearestNeighborError>,
+) -> Result<T, NearestNeighborError> {
+    if is_stdout(path) {
+        return write(path);
+    }
+    let tmp_path = temp_path(path);
+    match write(&tmp_path) {
+        Ok(value) => {
+            std::fs::rename(&tmp_path, path)?;
+            Ok(value)
+        }
+        Err(err) => {
+            // Report the failure itself rather than any failure to clean up after it.
+            let _ = std::fs::remove_file(&tmp_path);
+            Err(err)
+        }
+    }
+}
+
+
+/// A destination of the result rows, written in batches as the queries complete, see [`create_output_writer`].
+pub trait OutputWriter {
+    /// Write the rows, which may be buffered until the next [`OutputWriter::flush`].
+    fn write_rows(&mut self, rows: &[NearestNeighborResultRow]) -> Result<(), NearestNeighborError>;
+
+    /// Make the rows written so far reach the output.
+    fn flush(&mut self) -> Result<(), NearestNeighborError>;
+
+    /// Complete the output once all the rows of a search are written, recording whether the search
+    /// `completed`, or was e.g. cancelled, in the formats holding the run metadata. No rows may be
+    /// written afterwards.
+    fn finish(&mut self, completed: bool) -> Result<(), NearestNeighborError>;
+}
+
+
+/// A writer needing a last step once everything is written, such as ending a compressed stream.
+pub trait FinishWrite: Write {
+    /// Complete and flush the output. Nothing may be written afterwards.
+    fn finish(&mut self) -> std::io::Result<()> {
+        self.flush()
+    }
+}
+
+
+impl<W: Write> FinishWrite for BufWriter<W> {}
+
+
+/// A text output, compressed as configured, see [`OutputConfig::compression`].
+pub enum OutputFile {
+    Plain(BufWriter<Box<dyn Write + Send>>),
+    #[cfg(feature = "compress")]
+    Gzip(GzEncoder<BufWriter<Box<dyn Write + Send>>>),
+    #[cfg(feature = "compress")]
+    Zstd(ZstdEncoder<'static, BufWriter<Box<dyn Write + Send>>>),
+}
+
+
+impl OutputFile {
+    /// Create the output at `path`, see [`create_output`].
+    pub fn create(path: &Path, compression: Compression) -> std::io::Result<OutputFile> {
+        let writer = BufWriter::new(create_output(path)?);
+        Ok(match compression {
+            Compression::None => OutputFile::Plain(writer),
+            #[cfg(feature = "compress")]
+            Compression::Gzip => OutputFile::Gzip(GzEncoder::new(writer, flate2::Compression::default())),
+            #[cfg(feature = "compress")]
+            Compression::Zstd => OutputFile::Zstd(ZstdEncoder::new(writer, zstd::DEFAULT_COMPRESSION_LEVEL)?),
+            #[cfg(not(feature = "compress"))]
+            Compression::Gzip | Compression::Zstd => return Err(std::io::Error::new(
+                std::io::ErrorKind::Unsupported,
+                "Compressed output is unavailable; rebuild with `--features compress`.",
+            )),
+        })
+    }
+}
+
+
+impl Write for OutputFile {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self {
+            OutputFile::Plain(writer) => writer.write(buf),
+            #[cfg(feature = "compress")]
+            OutputFile::Gzip(encoder) => encoder.write(buf),
+            #[cfg(feature = "compress")]
+            OutputFile::Zstd(encoder) => encoder.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            OutputFile::Plain(writer) => writer.flush(),
+            #[cfg(feature = "compress")]
+            OutputFile::Gzip(encoder) => encoder.flush(),
+            #[cfg(feature = "compress")]
+            OutputFile::Zstd(encoder) => encoder.flush(),
+        }
+    }
+}
+
+
+impl FinishWrite for OutputFile {
+    /// Write the end of the compressed stream, without which the file would read as truncated, and flush the file.
+    fn finish(&mut self) -> std::io::Result<()> {
+        match self {
+            OutputFile::Plain(writer) => writer.flush(),
+            #[cfg(feature = "compress")]
+            OutputFile::Gzip(encoder) => {
+                encoder.try_finish()?;
+                encoder.get_mut().flush()
+            }
+            #[cfg(feature = "compress")]
+            OutputFile::Zstd(encoder) => {
+                encoder.do_finish()?;
+                encoder.get_mut().flush()
+            }
+        }
+    }
+}
+
+
+/// Writes the rows to `writer` in a text format, see [`OutputFormat::is_text`].
+pub struct TextWriter<'c, W: FinishWrite> {
+    writer: W,
+    config: &'c OutputConfig,
+}
+
+
+impl<'c, W: FinishWrite> TextWriter<'c, W> {
+    pub fn new(writer: W, config: &'c OutputConfig) -> Self {
+        TextWriter { writer, config }
+    }
+}
+
+
+impl<W: FinishWrite> OutputWriter for TextWriter<'_, W> {
+    fn write_rows(&mut self, rows: &[NearestNeighborResultRow]) -> Result<(), NearestNeighborError> {
+        write_results_text(rows, &mut self.writer, self.config)
+    }
+
+    fn flush(&mut self) -> Result<(), NearestNeighborError> {
+        self.writer.flush()?;
+        Ok(())
+    }
+
+    /// The output is also finished if the search didn't complete, so that a compressed output can be read.
+    fn finish(&mut self, _completed: bool) -> Result<(), NearestNeighborError> {
+        self.writer.finish()?;
+        Ok(())
+    }
+}
+
+
+/// Create a writer of the result rows to `out_path` in the configured format, replacing any existing file.
+///
+/// # Arguments
+///
+/// * `out_path` - The output file.
+/// * `config` - The output options.
+/// * `metadata` - The `(key, value)` settings of the run, recorded by the formats that hold metadata,
+///   see [`crate::nearest_neighbor::NeighborConfig::run_metadata`].
+pub fn create_output_writer<'c>(
+    out_path: &Path,
+    config: &'c OutputConfig,
+    metadata: &[(String, String)],
+) -> Result<Box<dyn OutputWriter + Send + 'c>, NearestNeighborError> {
+    match config.format {
+        OutputFormat::Tsv | OutputFormat::Blast6 => {
+            Ok(Box::new(TextWriter::new(OutputFile::create(out_path, config.compression)?, config)))
+        }
+        OutputFormat::Sqlite => create_sqlite_writer(out_path, config, metadata),
+        OutputFormat::Parquet => create_parquet_writer(out_path, config, metadata),
+    }
+}
+
+
+#[cfg(feature = "sqlite")]
+fn create_sqlite_writer<'c>(
+    out_path: &Path,
+    config: &'c OutputConfig,
+    metadata: &[(String, String)],
+) -> Result<Box<dyn OutputWriter + Send + 'c>, NearestNeighborError> {
+    Ok(Box::new(crate::sqlite::SqliteWriter::create(out_path, config, metadata)?))
+}
+
+
+#[cfg(not(feature = "sqlite"))]
+fn create_sqlite_writer<'c>(
+    _out_path: &Path,
+    _config: &'c OutputConfig,
+    _metadata: &[(String, String)],
+) -> Result<Box<dyn OutputWriter + Send + 'c>, NearestNeighborError> {
+    Err(NearestNeighborError::Unsupported(
+        "SQLite output is unavailable; rebuild with `--features sqlite`.".to_owned()
+    ))
+}
+
+
+#[cfg(feature = "parquet")]
+fn create_parquet_writer<'c>(
+    out_path: &Path,
+    config: &'c OutputConfig,
+    metadata: &[(String, String)],
+) -> Result<Box<dyn OutputWriter + Send + 'c>, NearestNeighborError> {
+    Ok(Box::new(crate::parquet_writer::ParquetWriter::create(out_path, config, metadata)?))
+}
+
+
+#[cfg(not(feature = "parquet"))]
+fn create_parquet_writer<'c>(
+    _out_path: &Path,
+    _config: &'c OutputConfig,
+    _metadata: &[(String, String)],
+) -> Result<Box<dyn OutputWriter + Send + 'c>, NearestNeighborError> {
+    Err(NearestNeighborError::Unsupported(
+        "Parquet output is unavailable; rebuild with `--features parquet`.".to_owned()
+    ))
+}
+
+
+/// Write the result rows of a completed search to `out_path` in the configured format, with the run's
+/// `metadata`, see [`create_output_writer`]. The output is written atomically, see [`write_atomically`].
+pub fn write_results(
+    rows: &[NearestNeighborResultRow],
+    out_path: &Path,
+    config: &OutputConfig,
+    metadata: &[(String, String)],
+) -> Result<(), NearestNeighborError> {
+    let _span = write_output_span(config, Some(rows.len())).entered();
+    write_atomically(out_path, |tmp_path| {
+        let mut writer = create_output_writer(tmp_path, config, metadata)?;
+        writer.write_rows(rows)?;
+        writer.finish(true)
+    })
+}
+
+
+/// The span of writing the result rows, with their `row_count` if known upfront, or else to be recorded.
+pub(crate) fn write_output_span(config: &OutputConfig, row_count: Option<usize>) -> tracing::Span {
+    let span = tracing::info_span!("write_output", format = ?config.format, row_count = tracing::field::Empty);
+    if let Some(row_count) = row_count {
+        span.record("row_count", row_count);
+    }
+    span
+}
+
+
+/// Write the result rows in a text format, see [`OutputFormat::is_text`], without flushing `writer`.
+pub fn write_results_text<W: Write>(
+    rows: &[NearestNeighborResultRow],
+    writer: &mut W,
+    config: &OutputConfig,
+) -> Result<(), NearestNeighborError> {
+    match config.format {
+        OutputFormat::Tsv => write_results_tsv(rows, writer, config),
+        OutputFormat::Blast6 => write_blast6(rows, writer, config.precision),
+        format => Err(NearestNeighborError::Unsupported(format!("The {:?} output format isn't a text format", format))),
+    }
+}
+
+
+pub fn write_results_tsv<W: Write>(
+    rows: &[NearestNeighborResultRow],
+    writer: &mut W,
+    config: &OutputConfig,
+) -> Result<(), NearestNeighborError> {
+    let precision = config.precision;
+    for row in rows.iter() {
+        write!(writer, "{}\t{}\t{:.*}", row.query_id, row.neighbor_id, precision, row.identity)?;
+        if config.with_rank {
+            write!(writer, "\t{}", row.rank)?;
+        }
+        if config.with_stats {
+            write!(writer, "\t{}\t{}", ungapped_length_field(row.query_stats), ungapped_length_field(row.neighbor_stats))?;
+        }
+        if config.with_similarity {
+            match row.counts {
+                Some(counts) => write!(writer, "\t{:.*}\t{:.*}", precision, counts.identity(), precision, counts.similarity())?,
+                None => write!(writer, "\tNA\tNA")?,
+            }
+        }
+        if config.with_detailed_counts {
+            match detailed_counts(row) {
+                Some(values) => values.iter().try_for_each(|value| write!(writer, "\t{}", value))?,
+                None => DETAILED_COUNT_COLUMNS.iter().try_for_each(|_| write!(writer, "\tNA"))?,
+            }
+        }
+        if config.with_runner_up {
+            match &row.runner_up {
+                Some(runner_up) => write!(
+                    writer, "\t{}\t{:.*}\t{:.*}", runner_up.neighbor_id, precision, runner_up.identity, precision, runner_up.margin,
+                )?,
+                None => write!(writer, "\tNA\tNA\tNA")?,
+            }
+        }
+        if config.with_extended_output {
+            match row.comparison {
+                Some(comparison) => write!(
+                    writer, "\t{}\t{}\t{}\t{}\t{}\t{}",
+                    comparison.alignment_length, comparison.informative_sites, comparison.query_gaps,
+                    comparison.neighbor_gaps, comparison.shared_gaps, comparison.mismatches,
+                )?,
+                None => write!(writer, "\tNA\tNA\tNA\tNA\tNA\tNA")?,
+            }
+        }
+        if config.with_ts_tv {
+            match row.comparison {
+                Some(comparison) => {
+                    let ratio = comparison.ts_tv_ratio().map_or("NA".to_owned(), |ratio| format!("{:.*}", precision, ratio));
+                    write!(writer, "\t{}\t{}\t{}", comparison.transitions, comparison.transversions, ratio)?
+                }
+                None => write!(writer, "\tNA\tNA\tNA")?,
+            }
+        }
+        if config.with_description {
+            let header = |header: &Option<String>| header.clone().unwrap_or_else(|| "NA".to_owned());
+            write!(writer, "\t{}\t{}", header(&row.query_header), header(&row.neighbor_header))?;
+        }
+        if config.with_gc_content {
+            // All-gap records have a NaN GC content.
+            let gc = |gc: Option<f32>| gc.filter(|gc| !gc.is_nan()).map_or("NA".to_owned(), |gc| format!("{:.*}", precision, gc));
+            write!(writer, "\t{}\t{}", gc(row.query_gc), gc(row.neighbor_gc))?;
+        }
+        if config.with_strand {
+            write!(writer, "\t{}", row.strand.map_or("NA".to_owned(), |strand| strand.to_string()))?;
+        }
+        if config.with_db_rank {
+            write!(writer, "\t{}", row.db_rank.map_or("NA".to_owned(), |rank| rank.to_string()))?;
+        }
+        if config.with_query_coverage {
+            let coverage = row.counts.map(|counts| counts.query_coverage()).filter(|coverage| !coverage.is_nan());
+            write!(writer, "\t{}", coverage.map_or("NA".to_owned(), |coverage| format!("{:.*}", precision, coverage)))?;
+        }
+        writeln!(writer)?;
+    }
+    Ok(())
+}
+
+
+/// Write the best-hit identity distribution of the rows of a search over `num_queries` queries to the
+/// configured file, with `precision` decimal places, see [`IdentitySummary::write_tsv`]. The file is written
+/// atomically, see [`write_atomically`].
+pub fn write_identity_summary(
+    rows: &[NearestNeighborResultRow],
+    num_queries: usize,
+    summary_output: &IdentitySummaryOutput,
+    precision: usize,
+) -> Result<(), NearestNeighborError> {
+    let summary = IdentitySummary::new(rows, num_queries, &summary_output.percentiles, summary_output.bin_width);
+    write_atomically(&summary_output.path, |tmp_path| {
+        let mut writer = BufWriter::new(File::create(tmp_path)?);
+        summary.write_tsv(&mut writer, precision)?;
+        writer.flush()?;
+        Ok(())
+    })
+}
+
+
+/// Write the mismatch positions of each row as a long-format TSV table with a header row:
+/// `query_id, neighbor_id, column, query_char, neighbor_char, kind`, with 1-based columns, and a kind
+/// of `mismatch` for two different residues or `gap` for a one-sided gap. A truncated list ends with
+/// a row of kind `truncated`, whose column is `...`.
+pub fn write_mismatch_positions<W: Write>(rows: &[NearestNeighborResultRow], writer: &mut W) -> Result<(), NearestNeighborError> {
+    writeln!(writer, "query_id\tneighbor_id\tcolumn\tquery_char\tneighbor_char\tkind")?;
+    for row in rows.iter() {
+        let Some(mismatch_list) = &row.mismatches else {
+            continue;
+        };
+        for mismatch in mismatch_list.mismatches.iter() {
+            writeln!(
+                writer, "{}\t{}\t{}\t{}\t{}\t{}",
+                row.query_id,
+                row.neighbor_id,
+                mismatch.column + 1,
+                mismatch.x as char,
+                mismatch.y as char,
+                if mismatch.one_sided_gap { "gap" } else { "mismatch" },
+            )?;
+        }
+        if mismatch_list.truncated {
+            writeln!(writer, "{}\t{}\t...\tNA\tNA\ttruncated", row.query_id, row.neighbor_id)?;
+        }
+    }
+    Ok(())
+}
+
+
+/// The names of the detailed count columns, see [`OutputConfig::with_detailed_counts`].
+pub(crate) const DETAILED_COUNT_COLUMNS: [&str; 4] = ["transitions", "transversions", "single_gaps", "double_gaps"];
+
+
+/// The detailed counts of a row, in the order of [`DETAILED_COUNT_COLUMNS`].
+pub(crate) fn detailed_counts(row: &NearestNeighborResultRow) -> Option<[f64; 4]> {
+    row.counts.map(|counts| [counts.transitions, counts.transversions, counts.single_gaps, counts.double_gaps as f64])
+}
+
+
+fn ungapped_length_field(stats: Option<RecordStats>) -> String {
+    match stats {
+        Some(stats) => stats.ungapped_length.to_string(),
+        None => "NA".to_owned(),
+    }
+}
+
+
+/// The FASTA header line of a record, without the `>`: its ID, then its description if any, however long.
+/// Lookups use the ID alone, the part of the line before the first whitespace.
+pub fn header_line(record: &Record) -> String {
+    match record.desc() {
+        Some(desc) => format!("{} {}", record.id(), desc),
+        None => record.id().to_owned(),
+    }
+}
+
+
+/// Write records as FASTA, keeping their IDs and descriptions, with sequences wrapped
+/// every `line_width` characters. A `line_width` of 0 writes each sequence on a single line.
+pub fn write_fasta_wrapped<W: Write>(records: &[Record], writer: &mut W, line_width: usize) -> Result<(), NearestNeighborError> {
+    for record in records.iter() {
+        writeln!(writer, ">{}", header_line(record))?;
+        let seq = record.seq();
+        let width = if line_width == 0 { seq.len().max(1) } else { line_width };
+        for line in seq.chunks(width) {
+            writer.write_all(line)?;
+            writeln!(writer)?;
+        }
+    }
+    Ok(())
+}
+
+
+#[cfg(feature = "sqlite")]
+impl From<rusqlite::Error> for NearestNeighborError {
+    fn from(err: rusqlite::Error) -> NearestNeighborError {
+        NearestNeighborError::Output(format!("SQLite error: {}", err))
+    }
+}
+
+
+#[cfg(feature = "parquet")]
+impl From<parquet::errors::ParquetError> for NearestNeighborError {
+    fn from(err: parquet::errors::ParquetError) -> NearestNeighborError {
+        NearestNeighborError::Output(format!("Parquet error: {}", err))
+    }
+}
+
+
+#[cfg(feature = "parquet")]
+impl From<arrow_schema::ArrowError> for NearestNeighborError {
+    fn from(err: arrow_schema::ArrowError) -> NearestNeighborError {
+        NearestNeighborError::Output(format!("Arrow error: {}", err))
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use bio::io::fasta::Record;
+    use crate::nearest_neighbor::{mismatch_columns, ComparisonConfig, PairCounts};
+    use std::path::{Path, PathBuf};
+    use crate::{
+        nearest_neighbor::{compute_store_nearest_neighbors, NeighborConfig},
+        parse_all_records, parse_record_ids,
+    };
+    #[cfg(feature = "compress")]
+    use crate::nearest_neighbor::NearestNeighborError;
+    use super::{
+        header_line, sort_rows, write_fasta_wrapped, write_mismatch_positions, write_results_tsv, Compression,
+        NearestNeighborResultRow, OutputConfig, SortKey,
+    };
+
+    #[test]
+    fn test_sort_rows() {
+        let row = |query_id: &str, neighbor_id: &str, identity: f64| NearestNeighborResultRow {
+            query_id: query_id.to_owned(),
+            neighbor_id: neighbor_id.to_owned(),
+            identity,
+            ..Default::default()
+        };
+        let rows = vec![row("q2", "b", 0.9), row("q1", "c", 0.5), row("q3", "a", 0.9), row("q1", "a", 0.7)];
+        let sorted = |sort_by: SortKey| -> Vec<(String, String)> {
+            let mut rows = rows.clone();
+            sort_rows(&mut rows, sort_by);
+            rows.into_iter().map(|row| (row.query_id, row.neighbor_id)).collect()
+        };
+        let pairs = |expected: &[(&str, &str)]| -> Vec<(String, String)> {
+            expected.iter().map(|(query_id, neighbor_id)| (query_id.to_string(), neighbor_id.to_string())).collect()
+        };
+        assert_eq!(sorted(SortKey::QueryOrder), pairs(&[("q2", "b"), ("q1", "c"), ("q3", "a"), ("q1", "a")]));
+        // Ties keep the input order.
+        assert_eq!(sorted(SortKey::QueryId), pairs(&[("q1", "c"), ("q1", "a"), ("q2", "b"), ("q3", "a")]));
+        assert_eq!(sorted(SortKey::IdentityAsc), pairs(&[("q1", "c"), ("q1", "a"), ("q2", "b"), ("q3", "a")]));
+        assert_eq!(sorted(SortKey::IdentityDesc), pairs(&[("q2", "b"), ("q3", "a"), ("q1", "a"), ("q1", "c")]));
+        assert_eq!(sorted(SortKey::NeighborId), pairs(&[("q3", "a"), ("q1", "a"), ("q2", "b"), ("q1", "c")]));
+    }
+
+    #[test]
+    fn test_gc_content_columns() {
+        let row = |query_gc: f32, neighbor_gc: f32| NearestNeighborResultRow {
+            query_id: "q".to_owned(),
+            neighbor_id: "n".to_owned(),
+            identity: 0.5,
+            query_gc: Some(query_gc),
+            neighbor_gc: Some(neighbor_gc),
+            ..Default::default()
+        };
+        let mut buf: Vec<u8> = vec![];
+        let config = OutputConfig { with_gc_content: true, precision: 2, ..Default::default() };
+        write_results_tsv(&[row(0.5, 1.0), row(f32::NAN, 0.0)], &mut buf, &config).unwrap();
+        assert_eq!(String::from_utf8(buf).unwrap(), "q\tn\t0.50\t0.50\t1.00\nq\tn\t0.50\tNA\t0.00\n");
+    }
+
+    #[test]
+    fn test_write_fasta_wrapped() {
+        let records = [
+            Record::with_attrs("x", Some("some description"), b"ACGTACGTAC"),
+            Record::with_attrs("y", None, b"AC-T"),
+        ];
+        let mut buf: Vec<u8> = vec![];
+        write_fasta_wrapped(&records, &mut buf, 4).unwrap();
+        assert_eq!(String::from_utf8(buf).unwrap(), ">x some description\nACGT\nACGT\nAC\n>y\nAC-T\n");
+    }
+
+    #[test]
+    fn test_write_detailed_counts() {
+        let counts = PairCounts {
+            matches: 3.0, compared: 8.0, transitions: 2.0, transversions: 1.0, single_gaps: 2.0, double_gaps: 1,
+            ..Default::default()
+        };
+        let rows = [
+            NearestNeighborResultRow {
+                query_id: "q1".to_owned(), neighbor_id: "d1".to_owned(), rank: 1, identity: 0.375, counts: Some(counts),
+                ..Default::default()
+            },
+            NearestNeighborResultRow {
+                query_id: "q2".to_owned(), neighbor_id: "d2".to_owned(), rank: 1, identity: 0.5, ..Default::default()
+            },
+        ];
+        let config = OutputConfig { with_detailed_counts: true, ..Default::default() };
+        let mut buf: Vec<u8> = vec![];
+        write_results_tsv(&rows, &mut buf, &config).unwrap();
+        assert_eq!(
+            String::from_utf8(buf).unwrap(),
+            "q1\td1\t0.375000\t2\t1\t2\t1\nq2\td2\t0.500000\tNA\tNA\tNA\tNA\n",
+        );
+
+        let config = OutputConfig { precision: 2, ..Default::default() };
+        let mut buf: Vec<u8> = vec![];
+        write_results_tsv(&rows, &mut buf, &config).unwrap();
+        assert_eq!(String::from_utf8(buf).unwrap(), "q1\td1\t0.38\nq2\td2\t0.50\n");
+    }
+
+    /// Search the neighbors of the `query_db` fixture, and return the decompressed output.
+    #[cfg(feature = "compress")]
+    fn compressed_search(compression: Compression, config: &NeighborConfig) -> (Result<(), NearestNeighborError>, String) {
+        use std::io::Read;
+        let records = parse_all_records(PathBuf::from("tests/inputs/query_db/seqs.fasta")).unwrap();
+        let out_path = std::env::temp_dir().join(format!("aligned_nearest_neighbor_test_compress_{:?}", compression));
+        let output_config = OutputConfig { compression, ..Default::default() };
+        let result = compute_store_nearest_neighbors(records, &out_path, None, None, config, &output_config);
+        let file = std::fs::File::open(&out_path).unwrap();
+        let mut output = String::new();
+        match compression {
+            Compression::None => std::io::BufReader::new(file).read_to_string(&mut output).unwrap(),
+            Compression::Gzip => flate2::read::GzDecoder::new(file).read_to_string(&mut output).unwrap(),
+            Compression::Zstd => zstd::stream::read::Decoder::new(file).unwrap().read_to_string(&mut output).unwrap(),
+        };
+        std::fs::remove_file(&out_path).unwrap();
+        (result, output)
+    }
+
+    #[test]
+    #[cfg(feature = "compress")]
+    fn test_compressed_output() {
+        let config = NeighborConfig { exclude_self: true, ..Default::default() };
+        let (result, reference) = compressed_search(Compression::None, &config);
+        result.unwrap();
+        assert_eq!(reference.lines().count(), 4);
+        for compression in [Compression::Gzip, Compression::Zstd] {
+            let (result, output) = compressed_search(compression, &config);
+            result.unwrap();
+            assert_eq!(output, reference);
+        }
+
+        // The stream of a cancelled search is ended too, so that the file reads as a complete archive.
+        let config = NeighborConfig { exclude_self: true, ..Default::default() };
+        config.cancel.cancel();
+        for compression in [Compression::Gzip, Compression::Zstd] {
+            let (result, output) = compressed_search(compression, &config);
+            assert_eq!(result, Err(NearestNeighborError::Cancelled(0)));
+            assert_eq!(output, "");
+        }
+    }
+
+    #[test]
+    fn test_include_description() {
+        let records = parse_all_records(PathBuf::from("tests/inputs/descriptions/seqs.fasta")).unwrap();
+        // The ID is the part of the header line before the first space, and the description is kept whole.
+        assert_eq!(records[0].id(), "gi|12345678|gb|AY123456.1|");
+        assert!(records[0].desc().unwrap().starts_with("Influenza A virus (A/Puerto Rico/8/1934(H1N1)) segment 4"));
+        assert!(records[0].desc().unwrap().ends_with("submitted 2003"));
+        assert_eq!(header_line(&records[2]), "gi|11111111|gb|AY111111.1|");
+
+        // The queries are looked up by ID.
+        let query_ids = parse_record_ids(Path::new("tests/inputs/descriptions/query.txt")).unwrap();
+        let out_path = std::env::temp_dir().join("aligned_nearest_neighbor_test_description.tsv");
+        compute_store_nearest_neighbors(
+            records.clone(),
+            &out_path,
+            Some(query_ids),
+            None,
+            &NeighborConfig { exclude_self: true, ..Default::default() },
+            &OutputConfig { with_description: true, ..Default::default() },
+        ).unwrap();
+        let output = std::fs::read_to_string(&out_path).unwrap();
+        let (query_header, neighbor_header) = (header_line(&records[0]), header_line(&records[1]));
+        assert_eq!(
+            output.trim_end().split('\t').collect::<Vec<&str>>(),
+            [records[0].id(), records[1].id(), "0.900000", query_header.as_str(), neighbor_header.as_str()],
+        );
+        std::fs::remove_file(&out_path).unwrap();
+    }
+
+    #[test]
+    fn test_compression_from_path() {
+        assert_eq!(Compression::from_path(Path::new("out.tsv.gz")), Compression::Gzip);
+        assert_eq!(Compression::from_path(Path::new("out.tsv.zst")), Compression::Zstd);
+        assert_eq!(Compression::from_path(Path::new("out.tsv")), Compression::None);
+        assert_eq!(Compression::from_path(Path::new("out")), Compression::None);
+    }
+
+    #[test]
+    fn test_write_mismatch_positions() {
+        let x = b"ACGT-AC";
+        let y = b"AGGTTCC";
+        let rows = [
+            NearestNeighborResultRow {
+                query_id: "q1".to_owned(), neighbor_id: "d1".to_owned(), rank: 1,
+                mismatches: Some(mismatch_columns(x, y, &ComparisonConfig::default(), 10)),
+                ..Default::default()
+            },
+            NearestNeighborResultRow {
+                query_id: "q2".to_owned(), neighbor_id: "d2".to_owned(), rank: 1,
+                mismatches: Some(mismatch_columns(x, y, &ComparisonConfig::default(), 1)),
+                ..Default::default()
+            },
+        ];
+        let mut buf: Vec<u8> = vec![];
+        write_mismatch_positions(&rows, &mut buf).unwrap();
+        assert_eq!(String::from_utf8(buf).unwrap(), "\
+query_id\tneighbor_id\tcolumn\tquery_char\tneighbor_char\tkind
+q1\td1\t2\tC\tG\tmismatch
+q1\td1\t5\t-\tT\tgap
+q1\td1\t6\tA\tC\tmismatch
+q2\td2\t2\tC\tG\tmismatch
+q2\td2\t...\tNA\tNA\ttruncated
+");
+    }
+}
@@ -0,0 +1,121 @@
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{BufRead, BufReader, Write},
+    path::Path,
+};
+use rayon::prelude::*;
+use bio::io::fasta::Record;
+
+use crate::nearest_neighbor::{compare_records, ComparisonConfig, NearestNeighborError, PairCounts};
+
+
+/// The identity computed for one explicitly requested (query, db) pair.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PairIdentity {
+    pub query_id: String,
+    pub db_id: String,
+    /// `None` when either of the IDs was not found among the records.
+    pub counts: Option<PairCounts>,
+}
+
+
+/// Parse a two-column TSV of `query_id<TAB>db_id` pairs. Blank lines are skipped.
+pub fn parse_pairs_file(fpath: &Path) -> Result<Vec<(String, String)>, std::io::Error> {
+    let file = File::open(fpath)?;
+    let reader = BufReader::new(file);
+
+    let mut pairs: Vec<(String, String)> = vec![];
+    for (line_idx, line) in reader.lines().enumerate() {
+        let line = line?;
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        let fields: Vec<&str> = line.split('\t').collect();
+        if fields.len() != 2 {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("Expected 2 tab-separated columns on line {}, got {}", line_idx + 1, fields.len()),
+            ));
+        }
+        pairs.push((fields[0].to_owned(), fields[1].to_owned()));
+    }
+    Ok(pairs)
+}
+
+
+/// Compute the identity of each explicitly listed (query, db) pair, in parallel.
+/// Results are returned in the same order as `pairs`.
+pub fn compute_pairs(
+    records: &[Record],
+    pairs: &[(String, String)],
+    config: &ComparisonConfig,
+) -> Result<Vec<PairIdentity>, NearestNeighborError> {
+    let lookup: HashMap<&str, &Record> = records.iter()
+        .map(|record| (record.id(), record))
+        .collect();
+
+    pairs.par_iter()
+        .map(|(query_id, db_id)| -> Result<PairIdentity, NearestNeighborError> {
+            let counts = match (lookup.get(query_id.as_str()), lookup.get(db_id.as_str())) {
+                (Some(query), Some(db)) => Some(compare_records(query, db, config)?),
+                _ => None,
+            };
+            Ok(PairIdentity {
+                query_id: query_id.clone(),
+                db_id: db_id.clone(),
+                counts,
+            })
+        })
+        .collect()
+}
+
+
+/// Write pair identities as `query_id, db_id, identity, matches, compared` TSV rows.
+/// Pairs with a missing record are written with `NA` values, and identities with `precision` decimal places.
+pub fn write_pairs<W: Write>(results: &[PairIdentity], writer: &mut W, precision: usize) -> Result<(), NearestNeighborError> {
+    for result in results.iter() {
+        match result.counts {
+            Some(counts) => writeln!(
+                writer, "{}\t{}\t{:.*}\t{}\t{}",
+                result.query_id, result.db_id, precision, counts.identity(), counts.matches, counts.compared
+            )?,
+            None => writeln!(writer, "{}\t{}\tNA\tNA\tNA", result.query_id, result.db_id)?,
+        }
+    }
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use bio::io::fasta::Record;
+    use crate::nearest_neighbor::{ComparisonConfig, PairCounts};
+    use super::compute_pairs;
+
+    #[test]
+    fn test_compute_pairs() {
+        let records = [
+            Record::with_attrs("q", None, b"AAAA--"),
+            Record::with_attrs("d1", None, b"AACC--"),
+            Record::with_attrs("d2", None, b"AAAAA-"),
+        ];
+        let pairs = [
+            ("q".to_owned(), "d1".to_owned()),
+            ("q".to_owned(), "missing".to_owned()),
+            ("q".to_owned(), "d2".to_owned()),
+        ];
+        let results = compute_pairs(&records, &pairs, &ComparisonConfig::default()).unwrap();
+
+        assert_eq!(results.len(), 3);
+        assert_eq!(results[0].counts, Some(PairCounts {
+            matches: 2.0, compared: 4.0, transversions: 2.0, double_gaps: 2, query_residues: 4.0, ..Default::default()
+        }));
+        assert_eq!(results[1].db_id, "missing");
+        assert_eq!(results[1].counts, None);
+        assert_eq!(results[2].counts, Some(PairCounts {
+            matches: 4.0, compared: 5.0, single_gaps: 1.0, double_gaps: 1, query_residues: 4.0, ..Default::default()
+        }));
+    }
+}
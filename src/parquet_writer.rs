@@ -0,0 +1,176 @@
+use std::{fs::File, path::Path, sync::Arc};
+use arrow_array::{ArrayRef, Float64Array, Int32Array, Int64Array, RecordBatch, StringArray};
+use arrow_schema::{DataType, Field, Schema, SchemaRef};
+use parquet::{arrow::ArrowWriter, file::properties::WriterProperties, format::KeyValue};
+
+use crate::{
+    nearest_neighbor::{NearestNeighborError, PairCounts},
+    output::{detailed_counts, NearestNeighborResultRow, OutputConfig, OutputWriter, COMPLETED_KEY, DETAILED_COUNT_COLUMNS},
+};
+
+
+/// Writes the result rows to a Parquet file, in row groups of at most [`OutputConfig::row_group_size`] rows,
+/// so that only the rows of the current row group are held in memory.
+///
+/// The file has the columns `query_id` (Utf8), `neighbor_id` (Utf8), `rank` (Int32), `identity` (Float64), and
+/// `matches` and `compared` (Int64), the rounded counts of the pair, null for the rows without their
+/// [`NearestNeighborResultRow::counts`]. With [`OutputConfig::with_detailed_counts`], a nullable Float64 column
+/// follows per detailed count. The run metadata is stored as key-value file metadata, along with the
+/// [`COMPLETED_KEY`], `true` only if the search completed.
+pub struct ParquetWriter {
+    /// `None` once the file is closed.
+    writer: Option<ArrowWriter<File>>,
+    schema: SchemaRef,
+    with_detailed_counts: bool,
+}
+
+
+impl ParquetWriter {
+    /// Create a Parquet file at `path`, replacing any existing file, recording the run's `metadata`.
+    pub fn create(
+        path: &Path,
+        config: &OutputConfig,
+        metadata: &[(String, String)],
+    ) -> Result<ParquetWriter, NearestNeighborError> {
+        let mut fields = vec![
+            Field::new("query_id", DataType::Utf8, false),
+            Field::new("neighbor_id", DataType::Utf8, false),
+            Field::new("rank", DataType::Int32, false),
+            Field::new("identity", DataType::Float64, false),
+            Field::new("matches", DataType::Int64, true),
+            Field::new("compared", DataType::Int64, true),
+        ];
+        if config.with_detailed_counts {
+            fields.extend(DETAILED_COUNT_COLUMNS.iter().map(|name| Field::new(*name, DataType::Float64, true)));
+        }
+        let schema = Arc::new(Schema::new(fields));
+        let properties = WriterProperties::builder()
+            .set_max_row_group_size(config.row_group_size)
+            .set_key_value_metadata(Some(
+                metadata.iter().map(|(key, value)| KeyValue::new(key.clone(), value.clone())).collect()
+            ))
+            .build();
+        let writer = ArrowWriter::try_new(File::create(path)?, schema.clone(), Some(properties))?;
+        Ok(ParquetWriter { writer: Some(writer), schema, with_detailed_counts: config.with_detailed_counts })
+    }
+
+    fn writer(&mut self) -> Result<&mut ArrowWriter<File>, NearestNeighborError> {
+        self.writer.as_mut().ok_or_else(|| NearestNeighborError::Output("The Parquet file is already closed".to_owned()))
+    }
+}
+
+
+impl OutputWriter for ParquetWriter {
+    fn write_rows(&mut self, rows: &[NearestNeighborResultRow]) -> Result<(), NearestNeighborError> {
+        let counts = |count: fn(&PairCounts) -> f64| -> Int64Array {
+            rows.iter().map(|row| row.counts.as_ref().map(|counts| count(counts).round() as i64)).collect()
+        };
+        let mut columns: Vec<ArrayRef> = vec![
+            Arc::new(StringArray::from_iter_values(rows.iter().map(|row| row.query_id.as_str()))),
+            Arc::new(StringArray::from_iter_values(rows.iter().map(|row| row.neighbor_id.as_str()))),
+            Arc::new(Int32Array::from_iter_values(rows.iter().map(|row| row.rank as i32))),
+            Arc::new(Float64Array::from_iter_values(rows.iter().map(|row| row.identity))),
+            Arc::new(counts(|counts| counts.matches)),
+            Arc::new(counts(|counts| counts.compared)),
+        ];
+        if self.with_detailed_counts {
+            for idx in 0..DETAILED_COUNT_COLUMNS.len() {
+                let values: Float64Array = rows.iter().map(|row| detailed_counts(row).map(|values| values[idx])).collect();
+                columns.push(Arc::new(values));
+            }
+        }
+        let batch = RecordBatch::try_new(self.schema.clone(), columns)?;
+        self.writer()?.write(&batch)?;
+        Ok(())
+    }
+
+    /// The rows reach the file a row group at a time, as the row groups fill, so that the periodic flushes
+    /// don't cut the file into small row groups.
+    fn flush(&mut self) -> Result<(), NearestNeighborError> {
+        Ok(())
+    }
+
+    fn finish(&mut self, completed: bool) -> Result<(), NearestNeighborError> {
+        let mut writer = self.writer.take()
+            .ok_or_else(|| NearestNeighborError::Output("The Parquet file is already closed".to_owned()))?;
+        writer.append_key_value_metadata(KeyValue::new(COMPLETED_KEY.to_owned(), completed.to_string()));
+        writer.close()?;
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::{fs::File, path::{Path, PathBuf}};
+    use arrow_array::{Array, Float64Array, Int32Array, Int64Array, RecordBatch, StringArray};
+    use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+    use crate::{
+        nearest_neighbor::{compute_store_nearest_neighbors, NeighborConfig},
+        output::{OutputConfig, OutputFormat},
+        parse_all_records,
+    };
+
+    fn search(out_path: &Path, output_config: &OutputConfig) {
+        let records = parse_all_records(PathBuf::from("tests/inputs/query_db/seqs.fasta")).unwrap();
+        compute_store_nearest_neighbors(
+            records,
+            out_path,
+            Some(vec!["query_1".to_owned(), "query_2".to_owned()]),
+            Some(vec!["db_1".to_owned(), "db_2".to_owned()]),
+            &NeighborConfig { top_k: 2, ..Default::default() },
+            output_config,
+        ).unwrap();
+    }
+
+    fn column<'b, T: 'static>(batch: &'b RecordBatch, name: &str) -> &'b T {
+        batch.column_by_name(name).unwrap().as_any().downcast_ref::<T>().unwrap()
+    }
+
+    #[test]
+    fn test_parquet_matches_tsv() {
+        let tsv_path = std::env::temp_dir().join("aligned_nearest_neighbor_test_parquet.tsv");
+        let parquet_path = std::env::temp_dir().join("aligned_nearest_neighbor_test.parquet");
+        search(&tsv_path, &OutputConfig { with_rank: true, ..Default::default() });
+        search(&parquet_path, &OutputConfig { format: OutputFormat::Parquet, row_group_size: 3, ..Default::default() });
+
+        let builder = ParquetRecordBatchReaderBuilder::try_new(File::open(&parquet_path).unwrap()).unwrap();
+        // The 4 rows are split into row groups of at most 3 rows.
+        assert_eq!(builder.metadata().num_row_groups(), 2);
+        let metadata: Vec<(String, Option<String>)> = builder.metadata().file_metadata().key_value_metadata().unwrap()
+            .iter()
+            .map(|kv| (kv.key.clone(), kv.value.clone()))
+            .collect();
+        assert!(metadata.contains(&("top_k".to_owned(), Some("2".to_owned()))));
+        assert!(metadata.contains(&("completed".to_owned(), Some("true".to_owned()))));
+
+        // The Parquet rows, formatted as the TSV rows, are the TSV rows.
+        let mut lines: Vec<String> = vec![];
+        let (mut matches, mut compared): (Vec<i64>, Vec<i64>) = (vec![], vec![]);
+        for batch in builder.build().unwrap() {
+            let batch = batch.unwrap();
+            let query_ids: &StringArray = column(&batch, "query_id");
+            let neighbor_ids: &StringArray = column(&batch, "neighbor_id");
+            let ranks: &Int32Array = column(&batch, "rank");
+            let identities: &Float64Array = column(&batch, "identity");
+            for idx in 0..batch.num_rows() {
+                lines.push(format!(
+                    "{}\t{}\t{:.6}\t{}",
+                    query_ids.value(idx), neighbor_ids.value(idx), identities.value(idx), ranks.value(idx),
+                ));
+            }
+            let batch_matches: &Int64Array = column(&batch, "matches");
+            let batch_compared: &Int64Array = column(&batch, "compared");
+            assert_eq!(batch_matches.null_count(), 0);
+            matches.extend(batch_matches.values().iter());
+            compared.extend(batch_compared.values().iter());
+        }
+        let tsv = std::fs::read_to_string(&tsv_path).unwrap();
+        assert_eq!(lines, tsv.lines().collect::<Vec<&str>>());
+        assert_eq!(matches, [3, 0, 4, 0]);
+        assert_eq!(compared, [16; 4]);
+
+        std::fs::remove_file(&tsv_path).unwrap();
+        std::fs::remove_file(&parquet_path).unwrap();
+    }
+}
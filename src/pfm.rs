@@ -0,0 +1,302 @@
+use std::{
+    fs::File,
+    io::{BufRead, BufReader},
+    path::Path,
+};
+use bio::io::fasta::Record;
+use rayon::prelude::*;
+
+use crate::{
+    distance::Ranking,
+    nearest_neighbor::{ComparisonConfig, NearestNeighborError, NeighborConfig, GAP},
+    progress::Progress,
+    FastaParseError, FastaParseErrorKind,
+};
+
+
+/// The row of a base in a position frequency matrix: `A`, `C`, `G` and `T`, with `U` read as `T`.
+/// Case is ignored.
+fn base_index(ch: u8) -> Option<usize> {
+    match ch.to_ascii_uppercase() {
+        b'A' => Some(0),
+        b'C' => Some(1),
+        b'G' => Some(2),
+        b'T' | b'U' => Some(3),
+        _ => None,
+    }
+}
+
+
+/// The expected identity of an aligned sequence to a profile, given as a position frequency matrix (PFM) of
+/// the `A`, `C`, `G` and `T` frequencies of each alignment column, e.g. from [`parse_pfm_from_jaspar`]: the
+/// mean frequency, over the columns where the sequence holds a residue, of that residue. A residue other than
+/// a base, e.g. `N`, matches with frequency 0. NaN if the sequence is all gaps, as for [`crate::nearest_neighbor::pct_identity`].
+///
+/// A uniform PFM, of frequency 0.25 for each base, gives an identity of 0.25 to any sequence of bases.
+pub fn pct_identity_pfm(pfm: &[[f32; 4]], seq: &[u8]) -> Result<f64, NearestNeighborError> {
+    let (matches, compared) = pfm_counts(pfm, seq)?;
+    Ok(matches / compared as f64)
+}
+
+
+/// The summed frequencies of the residues of `seq` in the PFM, and the number of columns where `seq` holds a
+/// residue, see [`pct_identity_pfm`].
+fn pfm_counts(pfm: &[[f32; 4]], seq: &[u8]) -> Result<(f64, u64), NearestNeighborError> {
+    if pfm.len() != seq.len() {
+        return Err(NearestNeighborError::InvalidInput(format!(
+            "The PFM has {} positions, but the sequence has {} columns", pfm.len(), seq.len(),
+        )));
+    }
+    let mut matches: f64 = 0.0;
+    let mut compared: u64 = 0;
+    for (freqs, ch) in pfm.iter().zip(seq.iter()) {
+        if *ch == GAP {
+            continue;
+        }
+        compared += 1;
+        if let Some(base_idx) = base_index(*ch) {
+            matches += freqs[base_idx] as f64;
+        }
+    }
+    Ok((matches, compared))
+}
+
+
+/// The identity of a record to the PFM, from its [`pfm_counts`], or NaN if the record overlaps the profile on
+/// fewer than [`ComparisonConfig::min_overlap`] columns, or covers less than [`ComparisonConfig::min_query_coverage`]
+/// of its positions, each of which holds a residue.
+fn pfm_value(matches: f64, compared: u64, num_positions: usize, config: &ComparisonConfig) -> f64 {
+    if compared < config.min_overlap as u64 {
+        return f64::NAN;
+    }
+    if config.min_query_coverage > 0.0 && (compared as f64) < config.min_query_coverage * num_positions as f64 {
+        return f64::NAN;
+    }
+    matches / compared as f64
+}
+
+
+/// Parse a position frequency matrix from a JASPAR file: an optional `>ID name` header line, then one row of
+/// counts per base, each labeled, as in `A [ 4 19 0 ]`, or in the `A`, `C`, `G`, `T` order of the raw `.pfm`
+/// files, as in `4 19 0`. The counts of each position are normalized to frequencies summing to 1.
+pub fn parse_pfm_from_jaspar(path: &Path) -> Result<Vec<[f32; 4]>, FastaParseError> {
+    let reader = BufReader::new(File::open(path)?);
+    let mut rows: [Option<Vec<f32>>; 4] = Default::default();
+    let mut num_rows: usize = 0;
+    for (line_idx, line) in reader.lines().enumerate() {
+        let line = line?;
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('>') {
+            continue;
+        }
+        let format_error = |message: String| FastaParseError {
+            message: format!("Line {}: {}", line_idx + 1, message),
+            kind: FastaParseErrorKind::FormatError,
+            source: None,
+        };
+        let (row_idx, counts) = match line.as_bytes()[0] {
+            label if label.is_ascii_alphabetic() => {
+                let row_idx = base_index(label).ok_or_else(|| format_error(format!("unknown base {}", label as char)))?;
+                (row_idx, &line[1..])
+            }
+            _ => (num_rows.min(3), line),
+        };
+        if num_rows == 4 || rows[row_idx].is_some() {
+            return Err(format_error("expected one row per base, A, C, G and T".to_owned()));
+        }
+        let counts: Vec<f32> = counts.replace(['[', ']'], " ")
+            .split_whitespace()
+            .map(|count| match count.parse::<f32>() {
+                Ok(count) if count.is_finite() && count >= 0.0 => Ok(count),
+                _ => Err(format_error(format!("invalid count {}", count))),
+            })
+            .collect::<Result<Vec<f32>, FastaParseError>>()?;
+        rows[row_idx] = Some(counts);
+        num_rows += 1;
+    }
+    let rows: Vec<Vec<f32>> = rows.into_iter().flatten().collect();
+    if rows.len() < 4 {
+        return Err(FastaParseError {
+            message: format!("Expected one row per base, A, C, G and T, got {} rows", rows.len()),
+            kind: FastaParseErrorKind::FormatError,
+            source: None,
+        });
+    }
+    if let Some(row_idx) = rows.iter().position(|row| row.len() != rows[0].len()) {
+        return Err(FastaParseError {
+            message: format!("Row {} has {} positions, but row A has {}", b"ACGT"[row_idx] as char, rows[row_idx].len(), rows[0].len()),
+            kind: FastaParseErrorKind::LengthMismatch,
+            source: None,
+        });
+    }
+
+    (0..rows[0].len())
+        .map(|pos| {
+            let counts: [f32; 4] = [rows[0][pos], rows[1][pos], rows[2][pos], rows[3][pos]];
+            let total: f32 = counts.iter().sum();
+            if total <= 0.0 {
+                return Err(FastaParseError {
+                    message: format!("Position {} has no counts", pos + 1),
+                    kind: FastaParseErrorKind::FormatError,
+                    source: None,
+                });
+            }
+            Ok(counts.map(|count| count / total))
+        })
+        .collect()
+}
+
+
+/// The `config.top_k` database records nearest to a profile, by [`pct_identity_pfm`], or the farthest ones with
+/// [`crate::distance::SearchMode::Farthest`]. They are sorted from best to worst, ties broken in favor of the
+/// record appearing later in `db_records`, as in [`crate::nearest_neighbor::compute_top_k_neighbors`]. The
+/// all-gap records are never reported, nor are the records outside [`NeighborConfig::allowed_neighbors`], or
+/// those without a value by [`ComparisonConfig::min_overlap`] and [`ComparisonConfig::min_query_coverage`].
+///
+/// The profile is a single query, so a cancelled search has no completed query to keep, and returns
+/// [`NearestNeighborError::Cancelled`] with none.
+pub fn compute_pfm_neighbors<'a>(
+    pfm: &[[f32; 4]],
+    db_records: &[&'a Record],
+    config: &NeighborConfig,
+) -> Result<Vec<(&'a Record, f64)>, NearestNeighborError> {
+    let db_records: Vec<&'a Record> = match &config.allowed_neighbors {
+        Some(allowed) => db_records.iter().filter(|record| allowed.contains(record.id())).copied().collect(),
+        None => db_records.to_vec(),
+    };
+    let progress = Progress::new(db_records.len() as u64, config.progress, config.progress_interval);
+    let values: Vec<f64> = db_records.par_iter()
+        .map(|record| {
+            if config.cancel.is_cancelled() {
+                return Err(NearestNeighborError::Cancelled(0));
+            }
+            let (matches, compared) = pfm_counts(pfm, record.seq())?;
+            progress.inc(1);
+            Ok(pfm_value(matches, compared, pfm.len(), &config.comparison))
+        })
+        .collect::<Result<Vec<f64>, NearestNeighborError>>()?;
+    drop(progress);
+    config.counters.add_pairs(db_records.len() as u64);
+
+    let ranking = Ranking { distance: false, mode: config.mode };
+    let mut scored: Vec<(usize, f64)> = values.into_iter()
+        .enumerate()
+        .filter(|(_, value)| !value.is_nan())
+        .collect();
+    scored.sort_unstable_by(|a, b| ranking.cmp_best_first(a.1, b.1).then(b.0.cmp(&a.0)));
+    Ok(scored.into_iter().take(config.top_k).map(|(db_idx, value)| (db_records[db_idx], value)).collect())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use bio::io::fasta::Record;
+    use crate::{
+        distance::SearchMode,
+        nearest_neighbor::{ComparisonConfig, NearestNeighborError, NeighborConfig},
+        progress::ProgressMode,
+        FastaParseErrorKind,
+    };
+    use super::{compute_pfm_neighbors, parse_pfm_from_jaspar, pct_identity_pfm};
+
+    #[test]
+    fn test_pct_identity_pfm() {
+        let uniform = [[0.25; 4]; 6];
+        assert_eq!(pct_identity_pfm(&uniform, b"ACGTAC").unwrap(), 0.25);
+        assert_eq!(pct_identity_pfm(&uniform, b"ac-uN-").unwrap(), 0.75 / 4.0);
+        assert!(pct_identity_pfm(&uniform, b"------").unwrap().is_nan());
+        assert!(matches!(pct_identity_pfm(&uniform, b"ACGT"), Err(NearestNeighborError::InvalidInput(_))));
+
+        let pfm = [[1.0, 0.0, 0.0, 0.0], [0.5, 0.5, 0.0, 0.0]];
+        assert_eq!(pct_identity_pfm(&pfm, b"AA").unwrap(), 0.75);
+        assert_eq!(pct_identity_pfm(&pfm, b"AC").unwrap(), 0.75);
+        assert_eq!(pct_identity_pfm(&pfm, b"TG").unwrap(), 0.0);
+    }
+
+    #[test]
+    fn test_parse_pfm_from_jaspar() {
+        let pfm_path = std::env::temp_dir().join("aligned_nearest_neighbor_test_pfm.jaspar");
+        let parse = |text: &str| {
+            std::fs::write(&pfm_path, text).unwrap();
+            parse_pfm_from_jaspar(&pfm_path)
+        };
+        let expected = vec![[0.25; 4], [0.5, 0.0, 0.25, 0.25]];
+        let jaspar = ">MA0000.1 test\nA  [ 1  2 ]\nC  [ 1  0 ]\nG  [ 1  1 ]\nT  [ 1  1 ]\n";
+        assert_eq!(parse(jaspar).unwrap(), expected);
+        assert_eq!(parse("G [1 1]\nT [1 1]\nA [1 2]\nC [1 0]\n").unwrap(), expected);
+        assert_eq!(parse("1 2\n1 0\n\n1 1\n1 1\n").unwrap(), expected);
+
+        for text in [
+            "A [1 2]\nC [1 0]\nG [1 1]\n",
+            "A [1 2]\nA [1 0]\nG [1 1]\nT [1 1]\n",
+            "A [1 0]\nC [1 0]\nG [1 0]\nT [1 0]\n",
+            "A [1 x]\nC [1 0]\nG [1 1]\nT [1 1]\n",
+            "1 2\n1 0\n1 1\n1 1\n1 1\n",
+        ] {
+            assert!(matches!(parse(text).unwrap_err().kind, FastaParseErrorKind::FormatError), "{}", text);
+        }
+        let error = parse("A [1 2]\nC [1 0]\nG [1 1]\nT [1]\n").unwrap_err();
+        assert!(matches!(error.kind, FastaParseErrorKind::LengthMismatch));
+        assert_eq!(error.message, "Row T has 1 positions, but row A has 2");
+        std::fs::remove_file(&pfm_path).unwrap();
+    }
+
+    #[test]
+    fn test_pfm_neighbors() {
+        let records = [
+            Record::with_attrs("a", None, b"AAAA"),
+            Record::with_attrs("c", None, b"CCCC"),
+            Record::with_attrs("gaps", None, b"----"),
+            Record::with_attrs("mixed", None, b"AACC"),
+            Record::with_attrs("t", None, b"TTTT"),
+        ];
+        let record_refs: Vec<&Record> = records.iter().collect();
+        let pfm = [[0.7, 0.1, 0.1, 0.1]; 4];
+        let config = NeighborConfig { top_k: 3, ..Default::default() };
+        let ids = |neighbors: Vec<(&Record, f64)>| -> Vec<String> {
+            neighbors.iter().map(|(neighbor, _)| neighbor.id().to_owned()).collect()
+        };
+        let neighbors = compute_pfm_neighbors(&pfm, &record_refs, &config).unwrap();
+        assert_eq!((neighbors[0].1 * 10.0).round(), 7.0);
+        assert_eq!(ids(neighbors), ["a", "mixed", "t"]);
+        assert_eq!(config.counters.pairs_compared(), 5);
+
+        let config = NeighborConfig { top_k: 10, mode: SearchMode::Farthest, ..Default::default() };
+        assert_eq!(ids(compute_pfm_neighbors(&pfm, &record_refs, &config).unwrap()), ["t", "c", "mixed", "a"]);
+    }
+
+    #[test]
+    fn test_pfm_neighbor_options() {
+        let records = [
+            Record::with_attrs("a", None, b"AAAA"),
+            Record::with_attrs("short", None, b"AA--"),
+            Record::with_attrs("c", None, b"CCCC"),
+        ];
+        let record_refs: Vec<&Record> = records.iter().collect();
+        let pfm = [[0.5, 0.25, 0.125, 0.125]; 4];
+        let search = |config: NeighborConfig| -> Vec<String> {
+            let config = NeighborConfig { top_k: 10, progress: ProgressMode::Hidden, ..config };
+            compute_pfm_neighbors(&pfm, &record_refs, &config).unwrap()
+                .iter()
+                .map(|(neighbor, _)| neighbor.id().to_owned())
+                .collect()
+        };
+        assert_eq!(search(NeighborConfig::default()), ["short", "a", "c"]);
+
+        let allowed = Arc::new(["short", "c"].iter().map(|id| id.to_string()).collect());
+        assert_eq!(search(NeighborConfig { allowed_neighbors: Some(allowed), ..Default::default() }), ["short", "c"]);
+
+        // The short record overlaps the profile on 2 of its 4 positions.
+        let comparison = ComparisonConfig { min_overlap: 3, ..Default::default() };
+        assert_eq!(search(NeighborConfig { comparison, ..Default::default() }), ["a", "c"]);
+        let comparison = ComparisonConfig { min_query_coverage: 0.5, ..Default::default() };
+        assert_eq!(search(NeighborConfig { comparison, ..Default::default() }), ["short", "a", "c"]);
+        let comparison = ComparisonConfig { min_query_coverage: 0.75, ..Default::default() };
+        assert_eq!(search(NeighborConfig { comparison, ..Default::default() }), ["a", "c"]);
+
+        let config = NeighborConfig { progress: ProgressMode::Hidden, ..Default::default() };
+        config.cancel.cancel();
+        assert_eq!(compute_pfm_neighbors(&pfm, &record_refs, &config).unwrap_err(), NearestNeighborError::Cancelled(0));
+    }
+}
@@ -0,0 +1,149 @@
+use std::{
+    fs::File,
+    io::{BufRead, BufReader},
+    path::Path,
+};
+use bio::io::fasta::Record;
+
+use crate::{check_record_lengths, FastaParseError, FastaParseErrorKind};
+
+
+/// Parse an aligned relaxed PHYLIP file (`.phy`) into Fasta records.
+///
+/// The first non-empty line declares the number of records and the alignment length. Each record starts
+/// with its name, separated from its sequence by whitespace, so the names may be of any length and padded.
+/// Both layouts are read: sequential, where the sequence of a record may be wrapped over several lines
+/// before the next name, and interleaved, where the first block holds the names and the next blocks only
+/// the sequences, in the same order. The whitespace within the sequences is dropped.
+pub fn parse_all_records_phylip(path: &Path) -> Result<Vec<Record>, FastaParseError> {
+    let file = File::open(path)?;
+    let reader = BufReader::new(file);
+
+    let mut header: Option<(usize, usize)> = None;
+    let mut lines: Vec<(usize, String)> = vec![];
+    for (line_idx, line) in reader.lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        if header.is_none() {
+            header = Some(parse_header(&line).ok_or_else(|| format_error(format!(
+                "Expected '<number of records> <alignment length>' on line {}", line_idx + 1,
+            )))?);
+            continue;
+        }
+        lines.push((line_idx + 1, line));
+    }
+    let (num_records, length) = header.ok_or_else(|| FastaParseError {
+        message: "No records found.".to_owned(),
+        kind: FastaParseErrorKind::EmptyFile,
+        source: None,
+    })?;
+
+    // Sequential parsing of an interleaved file reads the name line of the second record as the end of
+    // the first one, and fails. The error of the interleaved parsing is only reported if its first block
+    // is made of named lines.
+    let records = match parse_sequential(&lines, num_records, length) {
+        Ok(records) => records,
+        Err(err) if lines.len() > num_records && lines.len().is_multiple_of(num_records) => {
+            parse_interleaved(&lines, num_records, length).map_err(|interleaved_err| match interleaved_err.kind {
+                FastaParseErrorKind::FormatError => err,
+                _ => interleaved_err,
+            })?
+        }
+        Err(err) => return Err(err),
+    };
+    check_record_lengths(&records)?;
+    Ok(records)
+}
+
+
+/// The number of records and the alignment length declared by the first line.
+fn parse_header(line: &str) -> Option<(usize, usize)> {
+    let mut fields = line.split_whitespace();
+    match (fields.next()?.parse().ok()?, fields.next()?.parse().ok()?, fields.next()) {
+        (num_records, length, None) if num_records > 0 => Some((num_records, length)),
+        _ => None,
+    }
+}
+
+
+/// The name of a record and the start of its sequence, with its whitespace dropped.
+fn split_name(line_number: usize, line: &str) -> Result<(String, Vec<u8>), FastaParseError> {
+    match line.trim().split_once(char::is_whitespace) {
+        Some((name, seq)) => Ok((name.to_owned(), sequence_bytes(seq))),
+        None => Err(format_error(format!("Expected '<name> <sequence>' on line {}", line_number))),
+    }
+}
+
+
+fn sequence_bytes(line: &str) -> Vec<u8> {
+    line.bytes().filter(|b| !b.is_ascii_whitespace()).collect()
+}
+
+
+fn parse_sequential(lines: &[(usize, String)], num_records: usize, length: usize) -> Result<Vec<Record>, FastaParseError> {
+    let mut records: Vec<Record> = Vec::with_capacity(num_records);
+    let mut lines = lines.iter();
+    while let Some((line_number, line)) = lines.next() {
+        if records.len() == num_records {
+            return Err(length_error(format!(
+                "The header declares {} records, but line {} starts another one", num_records, line_number,
+            )));
+        }
+        let (name, mut seq) = split_name(*line_number, line)?;
+        while seq.len() < length {
+            match lines.next() {
+                Some((_, line)) => seq.extend(sequence_bytes(line)),
+                None => break,
+            }
+        }
+        if seq.len() != length {
+            return Err(declared_length_error(&name, seq.len(), length));
+        }
+        records.push(Record::with_attrs(&name, None, &seq));
+    }
+    if records.len() != num_records {
+        return Err(length_error(format!(
+            "The header declares {} records, but the file holds {}", num_records, records.len(),
+        )));
+    }
+    Ok(records)
+}
+
+
+fn parse_interleaved(lines: &[(usize, String)], num_records: usize, length: usize) -> Result<Vec<Record>, FastaParseError> {
+    let mut names: Vec<String> = Vec::with_capacity(num_records);
+    let mut seqs: Vec<Vec<u8>> = Vec::with_capacity(num_records);
+    for (line_number, line) in lines[..num_records].iter() {
+        let (name, seq) = split_name(*line_number, line)?;
+        names.push(name);
+        seqs.push(seq);
+    }
+    for (line_idx, (_, line)) in lines[num_records..].iter().enumerate() {
+        seqs[line_idx % num_records].extend(sequence_bytes(line));
+    }
+    names.iter()
+        .zip(seqs.iter())
+        .map(|(name, seq)| if seq.len() == length {
+            Ok(Record::with_attrs(name, None, seq))
+        } else {
+            Err(declared_length_error(name, seq.len(), length))
+        })
+        .collect()
+}
+
+
+fn declared_length_error(name: &str, actual: usize, declared: usize) -> FastaParseError {
+    length_error(format!("Record {} has length {}, but the header declares length {}", name, actual, declared))
+}
+
+
+fn length_error(message: String) -> FastaParseError {
+    FastaParseError { message, kind: FastaParseErrorKind::LengthMismatch, source: None }
+}
+
+
+fn format_error(message: String) -> FastaParseError {
+    FastaParseError { message, kind: FastaParseErrorKind::FormatError, source: None }
+}
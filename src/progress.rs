@@ -0,0 +1,139 @@
+use std::time::Duration;
+#[cfg(feature = "cli")]
+use std::{thread::JoinHandle, time::Instant};
+#[cfg(feature = "cli")]
+use clap::ValueEnum;
+#[cfg(feature = "cli")]
+use indicatif::{ProgressBar, ProgressStyle};
+
+
+/// How often JSON progress lines are written by default.
+pub const DEFAULT_PROGRESS_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How often the JSON reporter checks whether the work has finished.
+#[cfg(feature = "cli")]
+const JSON_POLL: Duration = Duration::from_millis(50);
+
+
+/// How the progress of a long computation is reported.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "cli", derive(ValueEnum))]
+pub enum ProgressMode {
+    /// An interactive progress bar on stderr.
+    #[default]
+    Bar,
+    /// No progress output at all.
+    #[cfg_attr(feature = "cli", value(name = "none"))]
+    Hidden,
+    /// JSON lines of the form `{"done": N, "total": M, "pct": 0.42}` on stderr, at regular intervals.
+    #[cfg_attr(feature = "cli", value(name = "jsonl"))]
+    Json,
+}
+
+
+/// A progress tracker in the configured [`ProgressMode`].
+/// Dropping it finishes the progress report, writing a final JSON line in [`ProgressMode::Json`].
+#[cfg(feature = "cli")]
+pub struct Progress {
+    pbar: ProgressBar,
+    reporter: Option<JoinHandle<()>>,
+}
+
+
+/// A progress tracker that reports nothing, whatever the [`ProgressMode`]: the progress bar and the JSON
+/// lines are only written by the command line, built with the `cli` feature.
+#[cfg(not(feature = "cli"))]
+pub struct Progress;
+
+
+#[cfg(not(feature = "cli"))]
+impl Progress {
+    pub fn new(_len: u64, _mode: ProgressMode, _interval: Duration) -> Self {
+        Progress
+    }
+
+    pub fn inc(&self, _delta: u64) {}
+}
+
+
+#[cfg(feature = "cli")]
+impl Progress {
+    /// Track `len` units of work, writing JSON lines every `interval` in [`ProgressMode::Json`].
+    pub fn new(len: u64, mode: ProgressMode, interval: Duration) -> Self {
+        match mode {
+            ProgressMode::Bar => Progress { pbar: styled_bar(len), reporter: None },
+            ProgressMode::Hidden => Progress { pbar: ProgressBar::hidden(), reporter: None },
+            ProgressMode::Json => {
+                let pbar = ProgressBar::hidden();
+                pbar.set_length(len);
+                let reporter_pbar = pbar.clone();
+                let reporter = std::thread::spawn(move || report_json(reporter_pbar, interval));
+                Progress { pbar, reporter: Some(reporter) }
+            }
+        }
+    }
+
+    pub fn inc(&self, delta: u64) {
+        self.pbar.inc(delta);
+    }
+}
+
+
+#[cfg(feature = "cli")]
+impl Drop for Progress {
+    fn drop(&mut self) {
+        self.pbar.finish();
+        if let Some(reporter) = self.reporter.take() {
+            let _ = reporter.join();
+        }
+    }
+}
+
+
+#[cfg(feature = "cli")]
+fn styled_bar(len: u64) -> ProgressBar {
+    let pbar = ProgressBar::new(len);
+    pbar.set_style(
+        ProgressStyle::default_bar()
+            .template("{spinner:.green} [{elapsed_precise}] [{bar:40.cyan/blue}] {human_pos}/{human_len} pairs ({per_sec}, {eta})")
+            .unwrap()
+            .progress_chars("#>-")
+    );
+    // Enable steady tick to prevent multiple threads from causing line breaks
+    pbar.enable_steady_tick(Duration::from_millis(50));
+    pbar
+}
+
+
+/// Format one JSON progress line.
+pub fn progress_json_line(done: u64, total: u64) -> String {
+    let pct = if total == 0 { 1.0 } else { (done as f64) / (total as f64) };
+    format!("{{\"done\": {}, \"total\": {}, \"pct\": {:.4}}}", done, total, pct)
+}
+
+
+/// Write a JSON line every `interval` until the bar is finished, then a final one.
+#[cfg(feature = "cli")]
+fn report_json(pbar: ProgressBar, interval: Duration) {
+    let mut last_report = Instant::now();
+    while !pbar.is_finished() {
+        std::thread::sleep(JSON_POLL);
+        if last_report.elapsed() >= interval {
+            eprintln!("{}", progress_json_line(pbar.position(), pbar.length().unwrap_or(0)));
+            last_report = Instant::now();
+        }
+    }
+    eprintln!("{}", progress_json_line(pbar.position(), pbar.length().unwrap_or(0)));
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::progress_json_line;
+
+    #[test]
+    fn test_progress_json_line() {
+        assert_eq!(progress_json_line(21, 50), "{\"done\": 21, \"total\": 50, \"pct\": 0.4200}");
+        assert_eq!(progress_json_line(0, 0), "{\"done\": 0, \"total\": 0, \"pct\": 1.0000}");
+    }
+}
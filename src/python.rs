@@ -0,0 +1,45 @@
+use std::path::PathBuf;
+use pyo3::{
+    prelude::*,
+    exceptions::PyValueError,
+};
+use bio::io::fasta::Record;
+
+use crate::{
+    parse_all_records,
+    nearest_neighbor::{compute_nearest_neighbors_owned, filter_records, NeighborConfig, RecordSelector},
+};
+
+
+/// Compute the nearest neighbor of each query record in an aligned multi-FASTA file.
+///
+/// Returns a list of `(query_id, neighbor_id, identity)` tuples, in query order.
+/// Parsing and computation errors are raised as `ValueError`.
+#[pyfunction]
+#[pyo3(signature = (fasta_path, query_ids=None, db_ids=None))]
+fn compute_nearest_neighbors_py(
+    py: Python<'_>,
+    fasta_path: &str,
+    query_ids: Option<Vec<String>>,
+    db_ids: Option<Vec<String>>,
+) -> PyResult<Vec<(String, String, f64)>> {
+    let records = parse_all_records(PathBuf::from(fasta_path))
+        .map_err(|err| PyValueError::new_err(format!("Unable to parse FASTA file. Reason: {}", err.message)))?;
+
+    // The computation doesn't touch any Python objects, so let other Python threads run meanwhile.
+    py.allow_threads(|| {
+        let query_records: Vec<&Record> = filter_records(&records, &RecordSelector::ids(query_ids.as_deref()));
+        let db_records: Vec<&Record> = filter_records(&records, &RecordSelector::ids(db_ids.as_deref()));
+        let results = compute_nearest_neighbors_owned(&query_records, &db_records, &NeighborConfig::default())
+            .map_err(|err| PyValueError::new_err(err.to_string()))?;
+        Ok(results.into_iter().map(|hit| (hit.query_id, hit.neighbor_id, hit.identity)).collect())
+    })
+}
+
+
+#[pymodule]
+#[pyo3(name = "aligned_nearest_neighbor")]
+fn py_module(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add_function(wrap_pyfunction!(compute_nearest_neighbors_py, m)?)?;
+    Ok(())
+}
@@ -0,0 +1,164 @@
+//! Seeded random samples of records.
+//!
+//! The draws come from SplitMix64, written out in [`mix64`], rather than from `rand`'s `StdRng`: the algorithm
+//! behind `StdRng` may change between `rand` releases, which would change the records drawn for a given
+//! `--seed`, while a sample must stay reproducible from its seed alone. It also keeps the WebAssembly build
+//! free of another dependency, and the same finalizer serves as the hash of the MinHash sketches.
+
+use std::str::FromStr;
+use bio::io::fasta::Record;
+
+/// The 64-bit finalizer of SplitMix64, used to draw samples and to derive independent hash functions from one hash.
+pub(crate) fn mix64(mut x: u64) -> u64 {
+    x = (x ^ (x >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94d049bb133111eb);
+    x ^ (x >> 31)
+}
+
+
+/// How many records a [`RecordSample`] keeps.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SampleSize {
+    /// A number of records, parsed from an integer such as `10000`.
+    Count(usize),
+    /// A fraction of the records, parsed from a decimal number in `(0, 1]` such as `0.01` or `1.0`.
+    Fraction(f64),
+}
+
+
+impl SampleSize {
+    /// The number of records kept out of `num_records`: never more than all of them, and at least one of a
+    /// non-empty set for a fraction.
+    pub fn resolve(&self, num_records: usize) -> usize {
+        match *self {
+            SampleSize::Count(count) => count.min(num_records),
+            SampleSize::Fraction(fraction) => {
+                ((num_records as f64 * fraction).round() as usize).clamp(num_records.min(1), num_records)
+            }
+        }
+    }
+}
+
+
+impl FromStr for SampleSize {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Ok(count) = s.parse::<usize>() {
+            if count == 0 {
+                return Err("the sample must hold at least 1 record".to_owned());
+            }
+            return Ok(SampleSize::Count(count));
+        }
+        match s.parse::<f64>() {
+            Ok(fraction) if fraction > 0.0 && fraction <= 1.0 => Ok(SampleSize::Fraction(fraction)),
+            _ => Err(format!("expected a number of records or a fraction in (0, 1], got: {}", s)),
+        }
+    }
+}
+
+
+/// A random subset of records, for quick exploratory runs on large alignments.
+/// The subset only depends on the IDs of the candidate records, the size and the seed, also across releases,
+/// as the draws come from SplitMix64 rather than from a generator of `rand`, see the module docs.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RecordSample {
+    /// The number of records to keep.
+    pub size: SampleSize,
+    /// The seed of the pseudo-random draws.
+    pub seed: u64,
+}
+
+
+impl RecordSample {
+    /// Keep a sample of the records drawn uniformly at random without replacement, in their original order.
+    /// The records are drawn from their sorted IDs, so that the same seed draws the same records whatever
+    /// their order. All records are kept, with a warning, if the sample asks for more of them.
+    pub fn select<'a>(&self, records: Vec<&'a Record>) -> Vec<&'a Record> {
+        let size = self.size.resolve(records.len());
+        if size == records.len() {
+            match self.size {
+                SampleSize::Count(count) if count > records.len() => {
+                    tracing::warn!("Sampling {} records out of {}: using all of them", count, records.len());
+                }
+                _ => {}
+            }
+            return records;
+        }
+        let mut indices: Vec<usize> = (0..records.len()).collect();
+        indices.sort_by(|a, b| records[*a].id().cmp(records[*b].id()));
+        // A partial Fisher-Yates shuffle of the indices, drawn from SplitMix64.
+        let mut state: u64 = self.seed;
+        for pos in 0..size {
+            state = state.wrapping_add(0x9e3779b97f4a7c15);
+            let swap_pos = pos + (mix64(state) % ((records.len() - pos) as u64)) as usize;
+            indices.swap(pos, swap_pos);
+        }
+        let mut keep: Vec<bool> = vec![false; records.len()];
+        for idx in indices[..size].iter() {
+            keep[*idx] = true;
+        }
+        records.into_iter()
+            .zip(keep)
+            .filter(|(_, keep)| *keep)
+            .map(|(record, _)| record)
+            .collect()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use bio::io::fasta::Record;
+    use super::{RecordSample, SampleSize};
+
+    fn ids(records: Vec<&Record>) -> Vec<String> {
+        records.iter().map(|record| record.id().to_owned()).collect()
+    }
+
+    #[test]
+    fn test_sample_seeds() {
+        let records: Vec<Record> = (0..100).map(|idx| Record::with_attrs(&format!("r{:03}", idx), None, b"A")).collect();
+        let record_refs: Vec<&Record> = records.iter().collect();
+        let sample = RecordSample { size: SampleSize::Count(10), seed: 1 };
+        let first = ids(sample.select(record_refs.clone()));
+        assert_eq!(first.len(), 10);
+        assert!(first.windows(2).all(|pair| pair[0] < pair[1]), "The original order is kept");
+        assert_eq!(ids(sample.select(record_refs.clone())), first);
+
+        // The same records are drawn whatever their order.
+        let reversed: Vec<&Record> = record_refs.iter().rev().copied().collect();
+        let mut from_reversed = ids(sample.select(reversed));
+        from_reversed.reverse();
+        assert_eq!(from_reversed, first);
+
+        let other = ids(RecordSample { size: SampleSize::Count(10), seed: 2 }.select(record_refs.clone()));
+        assert_eq!(other.len(), 10);
+        assert_ne!(other, first);
+
+        let fraction = RecordSample { size: SampleSize::Fraction(0.25), seed: 1 }.select(record_refs);
+        assert_eq!(fraction.len(), 25);
+    }
+
+    #[test]
+    fn test_sample_larger_than_records() {
+        let records = [Record::with_attrs("a", None, b"A"), Record::with_attrs("b", None, b"A")];
+        let record_refs: Vec<&Record> = records.iter().collect();
+        assert_eq!(ids(RecordSample { size: SampleSize::Count(5), seed: 1 }.select(record_refs.clone())), ["a", "b"]);
+        assert_eq!(ids(RecordSample { size: SampleSize::Count(2), seed: 1 }.select(record_refs.clone())), ["a", "b"]);
+        assert_eq!(ids(RecordSample { size: SampleSize::Fraction(1.0), seed: 1 }.select(record_refs)), ["a", "b"]);
+    }
+
+    #[test]
+    fn test_parse_sample_size() {
+        assert_eq!("100".parse::<SampleSize>(), Ok(SampleSize::Count(100)));
+        assert_eq!("1".parse::<SampleSize>(), Ok(SampleSize::Count(1)));
+        assert_eq!("0.01".parse::<SampleSize>(), Ok(SampleSize::Fraction(0.01)));
+        assert_eq!("1.0".parse::<SampleSize>(), Ok(SampleSize::Fraction(1.0)));
+        for invalid in ["0", "0.0", "1.5", "-3", "many"] {
+            assert!(invalid.parse::<SampleSize>().is_err(), "{}", invalid);
+        }
+        assert_eq!(SampleSize::Fraction(0.001).resolve(10), 1);
+        assert_eq!(SampleSize::Fraction(0.5).resolve(0), 0);
+    }
+}
@@ -0,0 +1,197 @@
+use std::{
+    fmt::{Debug, Formatter},
+    path::Path,
+};
+#[cfg(feature = "cli")]
+use clap::ValueEnum;
+
+use crate::nearest_neighbor::NearestNeighborError;
+
+
+/// The residues scored by the built-in matrices, in NCBI order.
+const AMINO_ACIDS: &[u8] = b"ARNDCQEGHILKMFPSTWYVBZX*";
+
+/// BLOSUM45 in NCBI format, which `bio` doesn't provide.
+const BLOSUM45: &str = include_str!("matrices/BLOSUM45");
+
+
+/// The substitution matrices built into the binary.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "cli", derive(ValueEnum))]
+pub enum BuiltinMatrix {
+    Blosum62,
+    Blosum45,
+    Pam250,
+}
+
+
+/// A substitution matrix over single-byte residues, case-insensitive.
+/// Pairs involving a residue missing from the matrix get the matrix's lowest score.
+#[derive(Clone)]
+pub struct ScoreMatrix {
+    name: String,
+    /// The score of `(x, y)` at index `x * 256 + y`.
+    scores: Vec<i32>,
+}
+
+
+impl Debug for ScoreMatrix {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "ScoreMatrix({})", self.name)
+    }
+}
+
+
+impl ScoreMatrix {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn score(&self, x: u8, y: u8) -> i32 {
+        self.scores[((x as usize) << 8) | (y as usize)]
+    }
+
+    pub fn builtin(matrix: BuiltinMatrix) -> ScoreMatrix {
+        match matrix {
+            BuiltinMatrix::Blosum62 => ScoreMatrix::from_fn("BLOSUM62", AMINO_ACIDS, bio::scores::blosum62),
+            BuiltinMatrix::Pam250 => ScoreMatrix::from_fn("PAM250", AMINO_ACIDS, bio::scores::pam250),
+            BuiltinMatrix::Blosum45 => ScoreMatrix::parse_ncbi("BLOSUM45", BLOSUM45)
+                .expect("The built-in BLOSUM45 matrix is valid"),
+        }
+    }
+
+    /// Tabulate a scoring function over the upper-case `residues`.
+    fn from_fn(name: &str, residues: &[u8], score: impl Fn(u8, u8) -> i32) -> ScoreMatrix {
+        let entries: Vec<(u8, u8, i32)> = residues.iter()
+            .flat_map(|x| residues.iter().map(move |y| (*x, *y)))
+            .map(|(x, y)| (x, y, score(x, y)))
+            .collect();
+        ScoreMatrix::from_entries(name, &entries)
+    }
+
+    fn from_entries(name: &str, entries: &[(u8, u8, i32)]) -> ScoreMatrix {
+        let min_score = entries.iter().map(|(_, _, score)| *score).min().unwrap_or(0);
+        let mut scores: Vec<i32> = vec![min_score; 256 * 256];
+        for (x, y, score) in entries.iter() {
+            for x in [x.to_ascii_uppercase(), x.to_ascii_lowercase()] {
+                for y in [y.to_ascii_uppercase(), y.to_ascii_lowercase()] {
+                    scores[((x as usize) << 8) | (y as usize)] = *score;
+                }
+            }
+        }
+        ScoreMatrix { name: name.to_owned(), scores }
+    }
+
+    /// Parse a matrix in the NCBI format: `#` comment lines, a header line of residues, then one line
+    /// per residue, starting with the residue and followed by its scores in header order.
+    pub fn parse_ncbi(name: &str, text: &str) -> Result<ScoreMatrix, String> {
+        let mut lines = text.lines()
+            .map(|line| line.trim())
+            .filter(|line| !line.is_empty() && !line.starts_with('#'));
+        let header: Vec<u8> = lines.next()
+            .ok_or_else(|| "No header line".to_owned())?
+            .split_whitespace()
+            .map(single_residue)
+            .collect::<Result<_, _>>()?;
+
+        let mut entries: Vec<(u8, u8, i32)> = vec![];
+        for line in lines {
+            let mut fields = line.split_whitespace();
+            let x = single_residue(fields.next().unwrap_or_default())?;
+            let scores: Vec<i32> = fields
+                .map(|field| field.parse().map_err(|_| format!("Invalid score {} in row {}", field, x as char)))
+                .collect::<Result<_, _>>()?;
+            if scores.len() != header.len() {
+                return Err(format!("Row {} has {} scores, expected {}", x as char, scores.len(), header.len()));
+            }
+            entries.extend(header.iter().zip(scores).map(|(y, score)| (x, *y, score)));
+        }
+        Ok(ScoreMatrix::from_entries(name, &entries))
+    }
+
+    /// Read a matrix in the NCBI format from a file, named after the file.
+    pub fn from_file(path: &Path) -> Result<ScoreMatrix, NearestNeighborError> {
+        let text = std::fs::read_to_string(path)?;
+        let name = path.file_name().map(|name| name.to_string_lossy().into_owned()).unwrap_or_default();
+        ScoreMatrix::parse_ncbi(&name, &text)
+            .map_err(|message| NearestNeighborError::InvalidInput(format!("{}: {}", path.display(), message)))
+    }
+}
+
+
+fn single_residue(field: &str) -> Result<u8, String> {
+    match field.as_bytes() {
+        [residue] => Ok(*residue),
+        _ => Err(format!("Expected a single residue, got {}", field)),
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use bio::io::fasta::Record;
+    use crate::{
+        distance::Metric,
+        nearest_neighbor::{compare_records, compute_nearest_neighbors, ComparisonConfig, NeighborConfig},
+    };
+    use super::{BuiltinMatrix, ScoreMatrix};
+
+    #[test]
+    fn test_builtin_matrices() {
+        let blosum62 = ScoreMatrix::builtin(BuiltinMatrix::Blosum62);
+        assert_eq!(blosum62.score(b'W', b'W'), 11);
+        assert_eq!(blosum62.score(b'i', b'V'), 3);
+        assert_eq!(blosum62.score(b'A', b'-'), -4);
+
+        let blosum45 = ScoreMatrix::builtin(BuiltinMatrix::Blosum45);
+        assert_eq!(blosum45.score(b'C', b'C'), 12);
+        assert_eq!(blosum45.score(b'D', b'B'), 5);
+        assert_eq!(blosum45.score(b'B', b'D'), 5);
+
+        let pam250 = ScoreMatrix::builtin(BuiltinMatrix::Pam250);
+        assert_eq!(pam250.score(b'W', b'W'), 17);
+    }
+
+    #[test]
+    fn test_parse_ncbi() {
+        let matrix = ScoreMatrix::parse_ncbi("test", "# comment\n   A  C\nA  2 -1\nC -1  3\n").unwrap();
+        assert_eq!(matrix.score(b'a', b'C'), -1);
+        assert_eq!(matrix.score(b'C', b'C'), 3);
+        // Unknown residues get the lowest score.
+        assert_eq!(matrix.score(b'A', b'W'), -1);
+        assert!(ScoreMatrix::parse_ncbi("test", "   A  C\nA  2\n").is_err());
+    }
+
+    #[test]
+    fn test_similarity() {
+        let comparison = ComparisonConfig {
+            score_matrix: Some(Arc::new(ScoreMatrix::builtin(BuiltinMatrix::Blosum62))),
+            ..Default::default()
+        };
+        // A/A, K/R and V/I score 4, 2 and 3. The residue against a gap is compared but not scored.
+        let x = Record::with_attrs("x", None, b"AKV-W");
+        let y = Record::with_attrs("y", None, b"ARI--");
+        let counts = compare_records(&x, &y, &comparison).unwrap();
+        assert_eq!((counts.matches, counts.similar, counts.compared, counts.score), (1.0, 3.0, 4.0, 9));
+        assert_eq!(counts.identity(), 0.25);
+        assert_eq!(counts.similarity(), 0.75);
+
+        // K/R is similar, K/W is not: the nearest neighbor depends on the metric.
+        let records = [
+            Record::with_attrs("q", None, b"KKKK"),
+            Record::with_attrs("similar", None, b"RRRR"),
+            Record::with_attrs("identical", None, b"KWWW"),
+        ];
+        let record_refs: Vec<&Record> = records.iter().collect();
+        for (metric, expected) in [(Metric::Identity, "identical"), (Metric::Similarity, "similar"), (Metric::Score, "similar")] {
+            let config = NeighborConfig {
+                comparison: ComparisonConfig { metric, ..comparison.clone() },
+                exclude_self: true,
+                ..Default::default()
+            };
+            let results = compute_nearest_neighbors(&record_refs[..1], &record_refs, &config).unwrap();
+            assert_eq!(results[0].0.id(), expected);
+        }
+    }
+}
@@ -0,0 +1,86 @@
+use bio::io::fasta::Record;
+
+use crate::nearest_neighbor::{pct_identity_bytes, ComparisonConfig, NearestNeighborError};
+
+
+/// An aligned sequence, to compare sequences held as bytes without building FASTA records.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Sequence<'a>(pub &'a [u8]);
+
+
+impl<'a> Sequence<'a> {
+    pub fn as_bytes(&self) -> &'a [u8] {
+        self.0
+    }
+
+    /// The number of alignment columns.
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// The identity of the two sequences with the default comparison options, as
+    /// [`crate::nearest_neighbor::pct_identity`] computes it for records. NaN if no column was compared.
+    pub fn pct_identity(&self, other: Sequence) -> Result<f64, NearestNeighborError> {
+        self.check_length(other)?;
+        Ok(pct_identity_bytes(self.0, other.0, &ComparisonConfig::default()))
+    }
+
+    /// The number of columns holding different bytes, gaps and case included.
+    pub fn hamming_distance(&self, other: Sequence) -> Result<u64, NearestNeighborError> {
+        self.check_length(other)?;
+        Ok(self.0.iter().zip(other.0.iter()).filter(|(x, y)| x != y).count() as u64)
+    }
+
+    fn check_length(&self, other: Sequence) -> Result<(), NearestNeighborError> {
+        if self.len() != other.len() {
+            return Err(NearestNeighborError::InvalidInput(format!(
+                "Aligned sequences must have the same length, got {} and {} columns", self.len(), other.len(),
+            )));
+        }
+        Ok(())
+    }
+}
+
+
+impl<'a> From<&'a Record> for Sequence<'a> {
+    fn from(record: &'a Record) -> Self {
+        Sequence(record.seq())
+    }
+}
+
+
+impl<'a> From<&'a [u8]> for Sequence<'a> {
+    fn from(seq: &'a [u8]) -> Self {
+        Sequence(seq)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use bio::io::fasta::Record;
+    use crate::nearest_neighbor::{pct_identity, NearestNeighborError};
+    use super::Sequence;
+
+    #[test]
+    fn test_sequence_distances() {
+        let (x, y) = (Sequence(b"ACGT-"), Sequence(b"ACGA-"));
+        assert_eq!(x.pct_identity(y).unwrap(), 0.75);
+        assert_eq!(x.hamming_distance(y).unwrap(), 1);
+        assert_eq!(x.hamming_distance(x).unwrap(), 0);
+        assert_eq!(Sequence(b"ac-T").hamming_distance(Sequence(b"AC.T")).unwrap(), 3);
+        assert!(Sequence(b"--").pct_identity(Sequence(b"--")).unwrap().is_nan());
+        assert!(matches!(x.pct_identity(Sequence(b"ACG")), Err(NearestNeighborError::InvalidInput(_))));
+        assert!(matches!(x.hamming_distance(Sequence(b"ACG")), Err(NearestNeighborError::InvalidInput(_))));
+
+        let records = [Record::with_attrs("x", None, b"ACGT-"), Record::with_attrs("y", None, b"ACGA-")];
+        let from_records = Sequence::from(&records[0]).pct_identity(Sequence::from(&records[1])).unwrap();
+        assert_eq!(from_records, pct_identity(&records[0], &records[1]).unwrap());
+        let bytes: &[u8] = b"ACGA-";
+        assert_eq!(Sequence::from(bytes), y);
+    }
+}
@@ -0,0 +1,193 @@
+use std::{
+    collections::HashSet,
+    fs::File,
+    io::{BufRead, BufReader, Write},
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use crate::nearest_neighbor::NearestNeighborError;
+
+
+/// One of `count` disjoint slices of the queries, for splitting a run across jobs.
+/// Shard `index` holds the queries at positions `index`, `index + count`, `index + 2 * count`, ...
+/// of the filtered queries in FASTA order, so the assignment only depends on the input files.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Shard {
+    /// The 0-based shard index, smaller than `count`.
+    pub index: usize,
+    pub count: usize,
+}
+
+
+impl FromStr for Shard {
+    type Err = String;
+
+    /// Parse `I/N`, e.g. `3/100`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (index, count) = s.split_once('/')
+            .ok_or_else(|| format!("Expected I/N, got {}", s))?;
+        let index: usize = index.trim().parse().map_err(|_| format!("Invalid shard index: {}", index))?;
+        let count: usize = count.trim().parse().map_err(|_| format!("Invalid shard count: {}", count))?;
+        if count == 0 || index >= count {
+            return Err(format!("The shard index must be in 0..{}, got {}", count, index));
+        }
+        Ok(Shard { index, count })
+    }
+}
+
+
+impl Shard {
+    /// Keep the items assigned to this shard, in their original order.
+    pub fn select<T>(&self, items: Vec<T>) -> Vec<T> {
+        items.into_iter()
+            .enumerate()
+            .filter(|(idx, _)| idx % self.count == self.index)
+            .map(|(_, item)| item)
+            .collect()
+    }
+
+    /// The shard of an output path written by [`Shard::out_path`], if it has the shard suffix.
+    pub fn from_out_path(path: &Path) -> Option<Shard> {
+        let file_name = path.file_name()?.to_str()?;
+        let suffix = file_name.split('.').find(|part| part.starts_with("shard-"))?;
+        let (index, count) = suffix.strip_prefix("shard-")?.split_once("-of-")?;
+        Some(Shard { index: index.parse().ok()?, count: count.parse().ok()? })
+    }
+
+    /// The output path of this shard: `out.tsv` becomes `out.shard-3-of-100.tsv`.
+    pub fn out_path(&self, out_path: &Path) -> PathBuf {
+        let suffix = format!("shard-{}-of-{}", self.index, self.count);
+        let stem = out_path.file_stem().map(|stem| stem.to_string_lossy().into_owned()).unwrap_or_default();
+        let file_name = match out_path.extension() {
+            Some(ext) => format!("{}.{}.{}", stem, suffix, ext.to_string_lossy()),
+            None => format!("{}.{}", stem, suffix),
+        };
+        out_path.with_file_name(file_name)
+    }
+}
+
+
+/// Read the rows of a shard output, grouped by query ID (the first column) in file order.
+fn read_query_groups(path: &Path) -> Result<Vec<(String, Vec<String>)>, NearestNeighborError> {
+    let reader = BufReader::new(File::open(path)?);
+    let mut groups: Vec<(String, Vec<String>)> = vec![];
+    for line in reader.lines() {
+        let line = line?;
+        if line.is_empty() {
+            continue;
+        }
+        let query_id = line.split('\t').next().unwrap_or_default().to_owned();
+        match groups.last_mut() {
+            Some((last_id, rows)) if *last_id == query_id => rows.push(line),
+            _ => groups.push((query_id, vec![line])),
+        }
+    }
+    Ok(groups)
+}
+
+
+/// Merge the TSV outputs of all `N` shards of a run back into one table, in the original query order.
+///
+/// `shard_paths` must hold one output per shard, in shard order. Since shard `I` holds every `N`-th
+/// query starting from the `I`-th, the original order is recovered by taking one query's rows from each
+/// shard in turn. The shard sizes are checked to be consistent with that assignment, and query IDs to be
+/// unique, so that a missing or repeated shard output is reported rather than silently merged.
+///
+/// # Returns
+///
+/// The number of merged queries.
+pub fn merge_shards<W: Write>(shard_paths: &[PathBuf], writer: &mut W) -> Result<usize, NearestNeighborError> {
+    let shards: Vec<Vec<(String, Vec<String>)>> = shard_paths.iter()
+        .map(|path| read_query_groups(path))
+        .collect::<Result<_, _>>()?;
+    for (position, path) in shard_paths.iter().enumerate() {
+        if let Some(shard) = Shard::from_out_path(path)
+            && (shard.index != position || shard.count != shard_paths.len())
+        {
+            return Err(NearestNeighborError::InvalidInput(format!(
+                "Expected the output of shard {}/{}, got {}", position, shard_paths.len(), path.display(),
+            )));
+        }
+    }
+    // Shard sizes must be non-increasing and differ by at most one, e.g. 3, 3, 2, 2 but not 3, 2, 3, 2.
+    let sizes: Vec<usize> = shards.iter().map(|groups| groups.len()).collect();
+    let consistent = sizes.windows(2).all(|pair| pair[1] <= pair[0])
+        && sizes.first().zip(sizes.last()).is_none_or(|(first, last)| last + 1 >= *first);
+    if !consistent {
+        return Err(NearestNeighborError::InvalidInput(format!(
+            "Shard sizes {:?} are inconsistent with the shard assignment; check that every shard is given, in order",
+            sizes,
+        )));
+    }
+
+    let mut seen: HashSet<&str> = HashSet::new();
+    let mut num_queries: usize = 0;
+    let num_rounds = sizes.first().copied().unwrap_or(0);
+    for round in 0..num_rounds {
+        for (path, groups) in shard_paths.iter().zip(shards.iter()) {
+            let Some((query_id, rows)) = groups.get(round) else {
+                continue;
+            };
+            if !seen.insert(query_id.as_str()) {
+                return Err(NearestNeighborError::InvalidInput(format!(
+                    "Query {} appears more than once (again in {})", query_id, path.display(),
+                )));
+            }
+            for row in rows.iter() {
+                writeln!(writer, "{}", row)?;
+            }
+            num_queries += 1;
+        }
+    }
+    Ok(num_queries)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::path::{Path, PathBuf};
+    use super::{merge_shards, Shard};
+
+    #[test]
+    fn test_shard() {
+        let shard: Shard = "1/3".parse().unwrap();
+        assert_eq!(shard, Shard { index: 1, count: 3 });
+        assert_eq!(shard.select((0..8).collect()), vec![1, 4, 7]);
+        assert_eq!(shard.out_path(Path::new("out/nn.tsv")), Path::new("out/nn.shard-1-of-3.tsv"));
+        assert_eq!(Shard::from_out_path(Path::new("out/nn.shard-1-of-3.tsv")), Some(shard));
+        assert!("3/3".parse::<Shard>().is_err());
+        assert!("1".parse::<Shard>().is_err());
+    }
+
+    #[test]
+    fn test_merge_shards() {
+        let out_path = std::env::temp_dir().join("aligned_nearest_neighbor_test_merge.tsv");
+        let shard_rows = [
+            "q0\td1\t0.5\t1\nq0\td2\t0.25\t2\nq2\td0\t1\t1\n",
+            "q1\td0\t0.75\t1\n",
+        ];
+        let shard_paths: Vec<PathBuf> = shard_rows.iter()
+            .enumerate()
+            .map(|(index, rows)| {
+                let path = Shard { index, count: 2 }.out_path(&out_path);
+                std::fs::write(&path, rows).unwrap();
+                path
+            })
+            .collect();
+
+        let mut merged: Vec<u8> = vec![];
+        assert_eq!(merge_shards(&shard_paths, &mut merged).unwrap(), 3);
+        assert_eq!(
+            String::from_utf8(merged).unwrap(),
+            "q0\td1\t0.5\t1\nq0\td2\t0.25\t2\nq1\td0\t0.75\t1\nq2\td0\t1\t1\n",
+        );
+
+        // Swapped shards are rejected.
+        let swapped = [shard_paths[1].clone(), shard_paths[0].clone()];
+        assert!(merge_shards(&swapped, &mut vec![]).is_err());
+        for path in shard_paths.iter() {
+            std::fs::remove_file(path).unwrap();
+        }
+    }
+}
@@ -0,0 +1,200 @@
+use std::path::Path;
+use rusqlite::{params, params_from_iter, types::Value, Connection};
+
+use crate::{
+    nearest_neighbor::NearestNeighborError,
+    output::{detailed_counts, NearestNeighborResultRow, OutputConfig, OutputWriter, COMPLETED_KEY, DETAILED_COUNT_COLUMNS},
+};
+
+
+/// Writes the result rows to a SQLite database, in one transaction per batch of rows between two flushes.
+///
+/// The database has the tables
+/// `hits(query_id TEXT, neighbor_id TEXT, rank INTEGER, identity REAL, matches REAL, compared REAL)`, with a
+/// REAL column per detailed count with [`OutputConfig::with_detailed_counts`], and
+/// `run_metadata(key TEXT, value TEXT)`. The `matches` and `compared` counts are `NULL` for the rows without
+/// their [`NearestNeighborResultRow::counts`].
+///
+/// The indexes on `hits` are only created once the search completes, along with the [`COMPLETED_KEY`] row set
+/// to `true`, so that an interrupted run leaves a database that is readable, and detectably incomplete: the
+/// flag is `false` until then.
+pub struct SqliteWriter {
+    conn: Connection,
+    with_detailed_counts: bool,
+    insert_hit: String,
+}
+
+
+impl SqliteWriter {
+    /// Create a fresh database at `path`, replacing any existing file, holding the run's `metadata` and an
+    /// empty `hits` table.
+    pub fn create(
+        path: &Path,
+        config: &OutputConfig,
+        metadata: &[(String, String)],
+    ) -> Result<SqliteWriter, NearestNeighborError> {
+        if path.exists() {
+            std::fs::remove_file(path)?;
+        }
+        let detail_columns: &[&str] = if config.with_detailed_counts { &DETAILED_COUNT_COLUMNS } else { &[] };
+        let conn = Connection::open(path)?;
+        let detail_definitions: String = detail_columns.iter().map(|name| format!(", {} REAL", name)).collect();
+        conn.execute_batch(&format!(
+            "CREATE TABLE hits (
+                query_id TEXT NOT NULL, neighbor_id TEXT NOT NULL, rank INTEGER NOT NULL, identity REAL,
+                matches REAL, compared REAL{}
+             );
+             CREATE TABLE run_metadata (key TEXT PRIMARY KEY, value TEXT NOT NULL);",
+            detail_definitions,
+        ))?;
+        {
+            let mut stmt = conn.prepare("INSERT INTO run_metadata (key, value) VALUES (?1, ?2)")?;
+            for (key, value) in metadata.iter() {
+                stmt.execute(params![key, value])?;
+            }
+            stmt.execute(params![COMPLETED_KEY, "false"])?;
+        }
+
+        let detail_names: String = detail_columns.iter().map(|name| format!(", {}", name)).collect();
+        let detail_params: String = (0..detail_columns.len()).map(|idx| format!(", ?{}", idx + 7)).collect();
+        let insert_hit = format!(
+            "INSERT INTO hits (query_id, neighbor_id, rank, identity, matches, compared{}) \
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6{})",
+            detail_names, detail_params,
+        );
+        conn.execute_batch("BEGIN")?;
+        Ok(SqliteWriter { conn, with_detailed_counts: config.with_detailed_counts, insert_hit })
+    }
+}
+
+
+impl OutputWriter for SqliteWriter {
+    fn write_rows(&mut self, rows: &[NearestNeighborResultRow]) -> Result<(), NearestNeighborError> {
+        let mut stmt = self.conn.prepare_cached(&self.insert_hit)?;
+        for row in rows.iter() {
+            let mut params: Vec<Value> = vec![
+                row.query_id.clone().into(),
+                row.neighbor_id.clone().into(),
+                (row.rank as i64).into(),
+                row.identity.into(),
+                row.counts.map(|counts| counts.matches).into(),
+                row.counts.map(|counts| counts.compared).into(),
+            ];
+            if self.with_detailed_counts {
+                match detailed_counts(row) {
+                    Some(values) => params.extend(values.map(Value::from)),
+                    None => params.extend(std::iter::repeat_n(Value::Null, DETAILED_COUNT_COLUMNS.len())),
+                }
+            }
+            stmt.execute(params_from_iter(params))?;
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), NearestNeighborError> {
+        self.conn.execute_batch("COMMIT; BEGIN")?;
+        Ok(())
+    }
+
+    fn finish(&mut self, completed: bool) -> Result<(), NearestNeighborError> {
+        // The output of a cancelled search is only flushed, without the completion flag nor the indexes.
+        if !completed {
+            return self.flush();
+        }
+        self.conn.execute(
+            "UPDATE run_metadata SET value = 'true' WHERE key = ?1",
+            [COMPLETED_KEY],
+        )?;
+        self.conn.execute_batch(
+            "CREATE INDEX idx_hits_query_id ON hits (query_id);
+             CREATE INDEX idx_hits_neighbor_id ON hits (neighbor_id);
+             COMMIT;"
+        )?;
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::path::{Path, PathBuf};
+    use rusqlite::Connection;
+    use crate::{
+        nearest_neighbor::{compute_store_nearest_neighbors, NeighborConfig},
+        output::{NearestNeighborResultRow, OutputConfig, OutputFormat, OutputWriter},
+        parse_all_records,
+    };
+    use super::SqliteWriter;
+
+    fn metadata_value(conn: &Connection, key: &str) -> String {
+        conn.query_row("SELECT value FROM run_metadata WHERE key = ?1", [key], |r| r.get(0)).unwrap()
+    }
+
+    fn index_names(db_path: &Path) -> Vec<String> {
+        let conn = Connection::open(db_path).unwrap();
+        let mut stmt = conn
+            .prepare("SELECT name FROM sqlite_master WHERE type = 'index' AND tbl_name = 'hits' ORDER BY name")
+            .unwrap();
+        stmt.query_map([], |r| r.get(0)).unwrap().collect::<Result<_, _>>().unwrap()
+    }
+
+    #[test]
+    fn test_sqlite_output() {
+        let records = parse_all_records(PathBuf::from("tests/inputs/query_db/seqs.fasta")).unwrap();
+        let db_path = std::env::temp_dir().join("aligned_nearest_neighbor_test_sqlite.db");
+        let output_config = OutputConfig { format: OutputFormat::Sqlite, ..Default::default() };
+        compute_store_nearest_neighbors(
+            records,
+            &db_path,
+            Some(vec!["query_1".to_owned(), "query_2".to_owned()]),
+            Some(vec!["db_1".to_owned(), "db_2".to_owned()]),
+            &NeighborConfig::default(),
+            &output_config,
+        ).unwrap();
+
+        let conn = Connection::open(&db_path).unwrap();
+        let mut stmt = conn.prepare("SELECT query_id, neighbor_id, rank, identity, matches, compared FROM hits").unwrap();
+        let hits: Vec<(String, String, i64, f64, f64, f64)> = stmt
+            .query_map([], |r| Ok((r.get(0)?, r.get(1)?, r.get(2)?, r.get(3)?, r.get(4)?, r.get(5)?)))
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(hits, [
+            ("query_1".to_owned(), "db_1".to_owned(), 1, 3.0 / 16.0, 3.0, 16.0),
+            ("query_2".to_owned(), "db_2".to_owned(), 1, 4.0 / 16.0, 4.0, 16.0),
+        ]);
+        assert_eq!(metadata_value(&conn, "completed"), "true");
+        assert_eq!(metadata_value(&conn, "num_queries"), "2");
+        assert_eq!(metadata_value(&conn, "metric"), "identity");
+        drop(stmt);
+        drop(conn);
+        assert_eq!(index_names(&db_path), ["idx_hits_neighbor_id", "idx_hits_query_id"]);
+        std::fs::remove_file(&db_path).unwrap();
+    }
+
+    #[test]
+    fn test_sqlite_interrupted() {
+        let row = |query_id: &str| NearestNeighborResultRow {
+            query_id: query_id.to_owned(), neighbor_id: "d1".to_owned(), rank: 1, identity: 0.5, ..Default::default()
+        };
+        let db_path = std::env::temp_dir().join("aligned_nearest_neighbor_test_sqlite_interrupted.db");
+        let metadata = [("top_k".to_owned(), "1".to_owned())];
+        let mut writer = SqliteWriter::create(&db_path, &OutputConfig::default(), &metadata).unwrap();
+        writer.write_rows(&[row("q1"), row("q2")]).unwrap();
+        writer.flush().unwrap();
+        // The rows written after the last flush are in an uncommitted transaction, lost with the writer.
+        writer.write_rows(&[row("q3")]).unwrap();
+        drop(writer);
+
+        let conn = Connection::open(&db_path).unwrap();
+        let count: i64 = conn.query_row("SELECT COUNT(*) FROM hits", [], |r| r.get(0)).unwrap();
+        assert_eq!(count, 2);
+        let matches: Option<f64> = conn.query_row("SELECT matches FROM hits LIMIT 1", [], |r| r.get(0)).unwrap();
+        assert_eq!(matches, None);
+        assert_eq!(metadata_value(&conn, "completed"), "false");
+        assert_eq!(metadata_value(&conn, "top_k"), "1");
+        drop(conn);
+        assert!(index_names(&db_path).is_empty());
+        std::fs::remove_file(&db_path).unwrap();
+    }
+}
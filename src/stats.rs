@@ -0,0 +1,561 @@
+use std::{
+    fmt::{Display, Formatter},
+    io::Write,
+};
+use rayon::prelude::*;
+use bio::io::fasta::Record;
+#[cfg(feature = "cli")]
+use clap::ValueEnum;
+
+use crate::{
+    columns::compute_column_stats,
+    nearest_neighbor::{compare_records, ComparisonConfig, NearestNeighborError, GAP},
+    output::NearestNeighborResultRow,
+    sample::mix64,
+};
+
+
+/// IUPAC nucleotide ambiguity codes, upper-case.
+const AMBIGUOUS: &[u8] = b"NRYSWKMBDHV";
+
+/// The default percentiles of an [`IdentitySummary`].
+pub const DEFAULT_PERCENTILES: [f32; 7] = [1.0, 5.0, 25.0, 50.0, 75.0, 95.0, 99.0];
+
+/// The default histogram bin width of an [`IdentitySummary`]: 0.5% identity.
+pub const DEFAULT_BIN_WIDTH: f64 = 0.005;
+
+/// The default number of random pairs behind the mean identity of [`summarize_alignment`].
+pub const DEFAULT_SAMPLE_PAIRS: usize = 1000;
+
+/// The seed of the pair sampling, fixed so that summaries are reproducible.
+const SAMPLE_SEED: u64 = 0x5eed;
+
+
+/// Basic QC statistics of a single aligned record.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct RecordStats {
+    /// Number of non-gap columns.
+    pub ungapped_length: usize,
+    /// Fraction of the alignment columns that are gaps.
+    pub gap_fraction: f32,
+    /// Number of IUPAC ambiguity codes (e.g. `N`, `R`, `Y`), case-insensitive.
+    pub ambiguous_count: usize,
+    /// Length of the longest run of consecutive gaps.
+    pub longest_gap_run: usize,
+}
+
+
+/// Compute the QC statistics of a record in a single pass.
+pub fn record_stats(record: &Record) -> RecordStats {
+    let mut stats = RecordStats::default();
+    let mut gap_run: usize = 0;
+    for ch in record.seq().iter() {
+        if *ch == GAP {
+            gap_run += 1;
+            stats.longest_gap_run = stats.longest_gap_run.max(gap_run);
+            continue;
+        }
+        gap_run = 0;
+        stats.ungapped_length += 1;
+        if AMBIGUOUS.contains(&ch.to_ascii_uppercase()) {
+            stats.ambiguous_count += 1;
+        }
+    }
+    let aln_len = record.seq().len();
+    stats.gap_fraction = ((aln_len - stats.ungapped_length) as f32) / (aln_len as f32);
+    stats
+}
+
+
+/// The fraction of a record's alignment columns that are gaps, as in [`RecordStats::gap_fraction`].
+/// NaN for an empty record.
+pub fn gap_fraction(record: &Record) -> f32 {
+    let num_gaps = record.seq().iter().filter(|ch| **ch == GAP).count();
+    (num_gaps as f32) / (record.seq().len() as f32)
+}
+
+
+/// Compute the statistics of each record using multiple worker threads.
+pub fn compute_record_stats(records: &[&Record]) -> Vec<RecordStats> {
+    records.par_iter()
+        .map(|record| record_stats(record))
+        .collect()
+}
+
+
+/// Write per-record statistics as a TSV table with a header row.
+pub fn write_record_stats<W: Write>(
+    records: &[&Record],
+    stats: &[RecordStats],
+    writer: &mut W,
+) -> Result<(), NearestNeighborError> {
+    writeln!(writer, "id\tungapped_length\tgap_fraction\tambiguous_count\tlongest_gap_run")?;
+    for (record, record_stats) in records.iter().zip(stats.iter()) {
+        writeln!(
+            writer, "{}\t{}\t{}\t{}\t{}",
+            record.id(),
+            record_stats.ungapped_length,
+            record_stats.gap_fraction,
+            record_stats.ambiguous_count,
+            record_stats.longest_gap_run,
+        )?;
+    }
+    Ok(())
+}
+
+
+/// Summary statistics of a set of values, ignoring NaN values. All fields are NaN if there are none.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Distribution {
+    pub min: f32,
+    pub q1: f32,
+    pub median: f32,
+    pub q3: f32,
+    pub max: f32,
+    pub mean: f32,
+}
+
+
+/// The `q` quantile of sorted values, by linear interpolation between the closest ranks. NaN if there are none.
+fn quantile(sorted: &[f64], q: f64) -> f64 {
+    if sorted.is_empty() {
+        return f64::NAN;
+    }
+    let rank = q * ((sorted.len() - 1) as f64);
+    let (lo, hi) = (rank.floor() as usize, rank.ceil() as usize);
+    sorted[lo] + (sorted[hi] - sorted[lo]) * (rank - lo as f64)
+}
+
+
+/// The values without NaN, in increasing order.
+fn sorted_values(values: impl Iterator<Item = f64>) -> Vec<f64> {
+    let mut sorted: Vec<f64> = values.filter(|value| !value.is_nan()).collect();
+    sorted.sort_unstable_by(|a, b| a.total_cmp(b));
+    sorted
+}
+
+
+impl Distribution {
+    pub fn new(values: &[f32]) -> Self {
+        let sorted = sorted_values(values.iter().map(|value| *value as f64));
+        let quantile = |q: f64| quantile(&sorted, q) as f32;
+        Distribution {
+            min: quantile(0.0),
+            q1: quantile(0.25),
+            median: quantile(0.5),
+            q3: quantile(0.75),
+            max: quantile(1.0),
+            mean: (sorted.iter().sum::<f64>() / (sorted.len() as f64)) as f32,
+        }
+    }
+
+    fn to_json(self) -> String {
+        format!(
+            "{{\"min\": {}, \"q1\": {}, \"median\": {}, \"q3\": {}, \"max\": {}, \"mean\": {}}}",
+            json_number(self.min), json_number(self.q1), json_number(self.median),
+            json_number(self.q3), json_number(self.max), json_number(self.mean),
+        )
+    }
+}
+
+
+impl Display for Distribution {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f, "min {:.4}, q1 {:.4}, median {:.4}, q3 {:.4}, max {:.4}, mean {:.4}",
+            self.min, self.q1, self.median, self.q3, self.max, self.mean,
+        )
+    }
+}
+
+
+/// Whole-alignment statistics, reported by the `stats` subcommand before committing to a search.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AlignmentSummary {
+    pub num_records: usize,
+    pub alignment_length: usize,
+    /// The gap fraction of each column, in column order.
+    pub column_gap_fractions: Vec<f32>,
+    /// The mean identity of the sampled pairs with a defined identity.
+    pub mean_identity: f64,
+    /// The number of pairs behind `mean_identity`.
+    pub num_sampled_pairs: usize,
+    /// The distribution of the records' GC fractions, among their ungapped residues.
+    pub gc_content: Distribution,
+}
+
+
+impl AlignmentSummary {
+    /// The summary as a JSON object, with NaN values written as `null`.
+    pub fn to_json(&self) -> String {
+        let gap_fractions: Vec<String> = self.column_gap_fractions.iter().map(|value| json_number(*value)).collect();
+        format!(
+            "{{\"num_records\": {}, \"alignment_length\": {}, \"column_gap_fractions\": [{}], \
+             \"mean_identity\": {}, \"num_sampled_pairs\": {}, \"gc_content\": {}}}",
+            self.num_records, self.alignment_length, gap_fractions.join(", "),
+            json_number(self.mean_identity), self.num_sampled_pairs, self.gc_content.to_json(),
+        )
+    }
+}
+
+
+impl Display for AlignmentSummary {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let gap_fractions = Distribution::new(&self.column_gap_fractions);
+        let num_gappy = self.column_gap_fractions.iter().filter(|value| **value > 0.5).count();
+        writeln!(f, "Records:                {}", self.num_records)?;
+        writeln!(f, "Alignment length:       {}", self.alignment_length)?;
+        writeln!(f, "Column gap fraction:    mean {:.4}, max {:.4}", gap_fractions.mean, gap_fractions.max)?;
+        writeln!(f, "Mostly-gap columns:     {} (gap fraction above 0.5)", num_gappy)?;
+        writeln!(f, "Mean pairwise identity: {:.4} (over {} sampled pairs)", self.mean_identity, self.num_sampled_pairs)?;
+        writeln!(f, "GC content:             {}", self.gc_content)
+    }
+}
+
+
+/// The distribution of the best-hit identities of a search, or of the best values of a distance
+/// [`crate::distance::Metric`], to pick thresholds from.
+#[derive(Debug, Clone, PartialEq)]
+pub struct IdentitySummary {
+    pub num_queries: usize,
+    /// The number of queries without any reported neighbor.
+    pub num_no_hit: usize,
+    /// The number of best hits with a defined value, behind all statistics.
+    pub count: usize,
+    pub mean: f64,
+    pub min: f64,
+    pub median: f64,
+    pub max: f64,
+    /// The requested percentiles, in percent, with their values.
+    pub percentiles: Vec<(f32, f64)>,
+    pub bin_width: f64,
+    /// The start of each histogram bin of width `bin_width`, from the minimum to the maximum finite value,
+    /// with the number of values in `[start, start + bin_width)`.
+    pub histogram: Vec<(f64, usize)>,
+}
+
+
+impl IdentitySummary {
+    /// Summarize the rank-1 rows of a search over `num_queries` queries.
+    ///
+    /// # Arguments
+    ///
+    /// * `rows` - The result rows. Only the best hit of each query, with rank 1, is counted.
+    /// * `num_queries` - The number of searched queries, including those without any row.
+    /// * `percentiles` - The percentiles to report, in percent, e.g. `[5.0, 95.0]`.
+    /// * `bin_width` - The width of the histogram bins, e.g. `0.005` for 0.5% identity.
+    pub fn new(rows: &[NearestNeighborResultRow], num_queries: usize, percentiles: &[f32], bin_width: f64) -> Self {
+        let best_rows: Vec<&NearestNeighborResultRow> = rows.iter().filter(|row| row.rank == 1).collect();
+        let sorted = sorted_values(best_rows.iter().map(|row| row.identity));
+
+        let bin_index = |value: f64| -> i64 { (value / bin_width + 1e-9).floor() as i64 };
+        let finite: Vec<f64> = sorted.iter().copied().filter(|value| value.is_finite()).collect();
+        let histogram: Vec<(f64, usize)> = match (finite.first(), finite.last()) {
+            (Some(first), Some(last)) => {
+                let first_bin = bin_index(*first);
+                let mut counts: Vec<usize> = vec![0; (bin_index(*last) - first_bin + 1) as usize];
+                for value in finite.iter() {
+                    counts[(bin_index(*value) - first_bin) as usize] += 1;
+                }
+                counts.into_iter()
+                    .enumerate()
+                    .map(|(offset, count)| ((first_bin + offset as i64) as f64 * bin_width, count))
+                    .collect()
+            }
+            _ => vec![],
+        };
+
+        IdentitySummary {
+            num_queries,
+            num_no_hit: num_queries.saturating_sub(best_rows.len()),
+            count: sorted.len(),
+            mean: sorted.iter().sum::<f64>() / (sorted.len() as f64),
+            min: quantile(&sorted, 0.0),
+            median: quantile(&sorted, 0.5),
+            max: quantile(&sorted, 1.0),
+            percentiles: percentiles.iter().map(|p| (*p, quantile(&sorted, *p as f64 / 100.0))).collect(),
+            bin_width,
+            histogram,
+        }
+    }
+
+    /// Write the summary as two TSV tables separated by a blank line: `statistic, value` rows,
+    /// with a `pN` row per percentile, then the `bin_start, bin_end, count` histogram. The values are
+    /// written with `precision` decimal places.
+    pub fn write_tsv<W: Write>(&self, writer: &mut W, precision: usize) -> Result<(), NearestNeighborError> {
+        writeln!(writer, "statistic\tvalue")?;
+        writeln!(writer, "queries\t{}", self.num_queries)?;
+        writeln!(writer, "no_hit\t{}", self.num_no_hit)?;
+        writeln!(writer, "count\t{}", self.count)?;
+        writeln!(writer, "mean\t{:.*}", precision, self.mean)?;
+        writeln!(writer, "min\t{:.*}", precision, self.min)?;
+        writeln!(writer, "median\t{:.*}", precision, self.median)?;
+        writeln!(writer, "max\t{:.*}", precision, self.max)?;
+        for (percentile, value) in self.percentiles.iter() {
+            writeln!(writer, "p{}\t{:.*}", percentile, precision, value)?;
+        }
+        writeln!(writer)?;
+        writeln!(writer, "bin_start\tbin_end\tcount")?;
+        for (bin_start, count) in self.histogram.iter() {
+            writeln!(writer, "{:.*}\t{:.*}\t{}", precision, bin_start, precision, bin_start + self.bin_width, count)?;
+        }
+        Ok(())
+    }
+}
+
+
+fn json_number<T: Into<f64> + ToString + Copy>(value: T) -> String {
+    if value.into().is_finite() { value.to_string() } else { "null".to_owned() }
+}
+
+
+/// The fraction of `G`, `C` and `S` among the ungapped residues of a sequence, case-insensitive.
+/// NaN for an all-gap sequence.
+pub fn gc_fraction(seq: &[u8]) -> f32 {
+    let residues = seq.iter().filter(|ch| **ch != GAP);
+    let (num_gc, num_residues) = residues.fold((0usize, 0usize), |(num_gc, num_residues), ch| {
+        let is_gc = matches!(ch.to_ascii_uppercase(), b'G' | b'C' | b'S');
+        (num_gc + is_gc as usize, num_residues + 1)
+    });
+    (num_gc as f32) / (num_residues as f32)
+}
+
+
+/// The GC content of a record: the fraction of `G` and `C` among its ungapped residues, case-insensitive,
+/// see [`gc_fraction`]. NaN for an all-gap record, which has no residue to count.
+pub fn gc_content(record: &Record) -> f32 {
+    gc_fraction(record.seq())
+}
+
+
+/// Draw up to `sample_pairs` pairs of distinct record indices. All pairs are returned, in order,
+/// when there are no more than `sample_pairs` of them; otherwise pairs are drawn uniformly at random,
+/// with replacement, using a fixed seed.
+fn sample_pairs(num_records: usize, sample_pairs: usize) -> Vec<(usize, usize)> {
+    let num_pairs = num_records * num_records.saturating_sub(1) / 2;
+    if num_pairs <= sample_pairs {
+        return (0..num_records)
+            .flat_map(|x| (x + 1..num_records).map(move |y| (x, y)))
+            .collect();
+    }
+    let mut state: u64 = SAMPLE_SEED;
+    let mut next = |bound: usize| -> usize {
+        state = state.wrapping_add(0x9e3779b97f4a7c15);
+        (mix64(state) % (bound as u64)) as usize
+    };
+    (0..sample_pairs)
+        .map(|_| {
+            let x = next(num_records);
+            let y = next(num_records - 1);
+            (x, if y >= x { y + 1 } else { y })
+        })
+        .collect()
+}
+
+
+/// Summarize an alignment without searching it. The mean pairwise identity is estimated from
+/// `sample_pairs` random pairs, computed using multiple worker threads, and is exact for alignments
+/// with no more pairs than that.
+pub fn summarize_alignment(records: &[&Record], sample_pairs: usize) -> Result<AlignmentSummary, NearestNeighborError> {
+    let column_gap_fractions: Vec<f32> = compute_column_stats(records).iter()
+        .map(|stats| stats.gap_fraction())
+        .collect();
+    let identities: Vec<f64> = self::sample_pairs(records.len(), sample_pairs).par_iter()
+        .map(|(x, y)| {
+            compare_records(records[*x], records[*y], &ComparisonConfig::default()).map(|counts| counts.identity())
+        })
+        .collect::<Result<Vec<f64>, NearestNeighborError>>()?;
+    let defined: Vec<f64> = identities.into_iter().filter(|idty| !idty.is_nan()).collect();
+    let gc_fractions: Vec<f32> = records.par_iter().map(|record| gc_fraction(record.seq())).collect();
+
+    Ok(AlignmentSummary {
+        num_records: records.len(),
+        alignment_length: column_gap_fractions.len(),
+        column_gap_fractions,
+        mean_identity: defined.iter().sum::<f64>() / (defined.len() as f64),
+        num_sampled_pairs: defined.len(),
+        gc_content: Distribution::new(&gc_fractions),
+    })
+}
+
+
+/// How the identities of a search are rescaled before they are written, to compare searches over different
+/// databases, see [`Normalization::apply`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "cli", derive(ValueEnum))]
+pub enum Normalization {
+    /// The number of standard deviations from the mean, see [`zscore_normalize`].
+    Zscore,
+    /// The position between the smallest and the largest value, from 0 to 1, see [`minmax_normalize`].
+    Minmax,
+}
+
+
+impl Normalization {
+    /// Replace the identity of each row, or its distance, by its normalized value over all the rows.
+    /// The normalization keeps the order of the values, so the rows are ranked and sorted as before.
+    pub fn apply(&self, rows: &mut [NearestNeighborResultRow]) {
+        let values: Vec<f32> = rows.iter().map(|row| row.identity as f32).collect();
+        let normalized = match self {
+            Normalization::Zscore => zscore_normalize(&values),
+            Normalization::Minmax => minmax_normalize(&values),
+        };
+        for (row, value) in rows.iter_mut().zip(normalized) {
+            row.identity = value as f64;
+        }
+    }
+}
+
+
+/// The z-score of each value, `(value - mean) / std`, with the population standard deviation of the values.
+/// All the z-scores are 0 if the values are all equal.
+pub fn zscore_normalize(values: &[f32]) -> Vec<f32> {
+    // The mean of equal values may differ from them by a rounding error, which would be scaled up.
+    if values.iter().all(|value| *value == values[0]) {
+        return vec![0.0; values.len()];
+    }
+    let num_values = values.len() as f64;
+    let mean = values.iter().map(|value| *value as f64).sum::<f64>() / num_values;
+    let variance = values.iter().map(|value| (*value as f64 - mean).powi(2)).sum::<f64>() / num_values;
+    let std = variance.sqrt();
+    values.iter().map(|value| ((*value as f64 - mean) / std) as f32).collect()
+}
+
+
+/// The position of each value between the smallest and the largest value, `(value - min) / (max - min)`.
+/// All the positions are 0 if the values are all equal.
+pub fn minmax_normalize(values: &[f32]) -> Vec<f32> {
+    let min = values.iter().copied().fold(f32::INFINITY, f32::min);
+    let max = values.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+    values.iter()
+        .map(|value| if max > min { (value - min) / (max - min) } else { 0.0 })
+        .collect()
+}
+
+
+#[cfg(test)]
+mod tests {
+    use bio::io::fasta::Record;
+    use crate::output::NearestNeighborResultRow;
+    use super::{
+        gc_content, gc_fraction, minmax_normalize, record_stats, summarize_alignment, zscore_normalize, Distribution,
+        IdentitySummary, Normalization, RecordStats,
+    };
+
+    #[test]
+    fn test_record_stats() {
+        let x = Record::with_attrs("x", None, b"----AAnN--RT");
+        assert_eq!(record_stats(&x), RecordStats {
+            ungapped_length: 6,
+            gap_fraction: 0.5,
+            ambiguous_count: 3,
+            longest_gap_run: 4,
+        });
+
+        let x = Record::with_attrs("x", None, b"-------------------------CCCCCCCCCCCCCCCCCCCCCCCCCCCCCCCCCC-------------");
+        let stats = record_stats(&x);
+        assert_eq!(stats.ungapped_length, 34);
+        assert_eq!(stats.longest_gap_run, 25);
+        assert_eq!(stats.ambiguous_count, 0);
+        assert_eq!(stats.gap_fraction, 38.0 / 72.0);
+    }
+
+    #[test]
+    fn test_summarize_alignment() {
+        // The identity of records i and j is 1 - |i - j| / 40.
+        let records: Vec<Record> = (0..40)
+            .map(|i| {
+                let seq: Vec<u8> = (0..40).map(|col| if col < i { b'G' } else { b'A' }).collect();
+                Record::with_attrs(&format!("r{}", i), None, &seq)
+            })
+            .collect();
+        let record_refs: Vec<&Record> = records.iter().collect();
+
+        let exact = summarize_alignment(&record_refs, 1000).unwrap();
+        assert_eq!(exact.num_records, 40);
+        assert_eq!(exact.column_gap_fractions.len(), exact.alignment_length);
+        assert_eq!(exact.num_sampled_pairs, 780);
+        // The mean |i - j| over all pairs of 0..n is (n + 1) / 3.
+        assert!((exact.mean_identity - (1.0 - (41.0 / 3.0) / 40.0)).abs() < 1e-4);
+        assert_eq!(exact.gc_content.min, 0.0);
+        assert_eq!(exact.gc_content.max, 39.0 / 40.0);
+
+        let sampled = summarize_alignment(&record_refs, 200).unwrap();
+        assert_eq!(sampled.num_sampled_pairs, 200);
+        assert!((sampled.mean_identity - exact.mean_identity).abs() < 0.05);
+    }
+
+    #[test]
+    fn test_gc_content() {
+        let gc = |seq: &[u8]| gc_content(&Record::with_attrs("r", None, seq));
+        assert_eq!(gc(b"GCGCCG"), 1.0);
+        assert_eq!(gc(b"ATTA-A"), 0.0);
+        assert_eq!(gc(b"AC-GT-"), 0.5);
+        assert_eq!(gc(b"gcat"), 0.5);
+        assert!(gc(b"----").is_nan());
+    }
+
+    #[test]
+    fn test_gc_distribution() {
+        assert_eq!(gc_fraction(b"GC--at"), 0.5);
+        assert!(gc_fraction(b"---").is_nan());
+        let distribution = Distribution::new(&[0.4, f32::NAN, 0.0, 0.2, 0.6]);
+        assert!((distribution.median - 0.3).abs() < 1e-6);
+        assert!((distribution.q1 - 0.15).abs() < 1e-6);
+        assert!(Distribution::new(&[]).mean.is_nan());
+    }
+
+    #[test]
+    fn test_identity_summary() {
+        let row = |query_id: &str, rank: usize, identity: f64| NearestNeighborResultRow {
+            query_id: query_id.to_owned(),
+            neighbor_id: "n".to_owned(),
+            rank,
+            identity,
+            ..Default::default()
+        };
+        // Four best hits, a second-ranked hit that isn't counted, and a fifth query without any hit.
+        let rows = [row("a", 1, 0.5), row("b", 1, 0.75), row("b", 2, 0.25), row("c", 1, 0.75), row("d", 1, 1.0)];
+        let summary = IdentitySummary::new(&rows, 5, &[25.0, 50.0, 75.0], 0.25);
+        assert_eq!((summary.num_queries, summary.num_no_hit, summary.count), (5, 1, 4));
+        assert_eq!((summary.mean, summary.min, summary.median, summary.max), (0.75, 0.5, 0.75, 1.0));
+        assert_eq!(summary.percentiles, [(25.0, 0.6875), (50.0, 0.75), (75.0, 0.8125)]);
+        assert_eq!(summary.histogram, [(0.5, 1), (0.75, 2), (1.0, 1)]);
+
+        let mut buf: Vec<u8> = vec![];
+        summary.write_tsv(&mut buf, 4).unwrap();
+        let tsv = String::from_utf8(buf).unwrap();
+        assert!(tsv.starts_with("statistic\tvalue\nqueries\t5\nno_hit\t1\ncount\t4\nmean\t0.7500\n"));
+        assert!(tsv.contains("p25\t0.6875\n"));
+        assert!(tsv.ends_with(
+            "\nbin_start\tbin_end\tcount\n0.5000\t0.7500\t1\n0.7500\t1.0000\t2\n1.0000\t1.2500\t1\n"
+        ));
+
+        let empty = IdentitySummary::new(&[], 2, &[50.0], 0.25);
+        assert_eq!((empty.num_no_hit, empty.count, empty.histogram.len()), (2, 0, 0));
+        assert!(empty.percentiles[0].1.is_nan());
+    }
+
+    #[test]
+    fn test_normalize() {
+        let values = [0.9f32, 0.95, 0.8, 0.99, 0.5, 0.75];
+        let zscores = zscore_normalize(&values);
+        let mean = zscores.iter().sum::<f32>() / zscores.len() as f32;
+        let std = (zscores.iter().map(|z| (z - mean).powi(2)).sum::<f32>() / zscores.len() as f32).sqrt();
+        assert!(mean.abs() < 1e-5, "{}", mean);
+        assert!((std - 1.0).abs() < 1e-5, "{}", std);
+        assert!(zscores[3] > zscores[1] && zscores[1] > zscores[0], "The order is kept");
+
+        assert_eq!(minmax_normalize(&[0.5, 1.0, 0.75, 0.625]), [0.0, 1.0, 0.5, 0.25]);
+        let mut rows: Vec<NearestNeighborResultRow> = [0.5, 1.0, 0.75].iter()
+            .map(|identity| NearestNeighborResultRow { identity: *identity, ..Default::default() })
+            .collect();
+        Normalization::Minmax.apply(&mut rows);
+        assert_eq!(rows.iter().map(|row| row.identity).collect::<Vec<_>>(), [0.0, 1.0, 0.5]);
+
+        // Equal values have no spread.
+        assert_eq!(zscore_normalize(&[0.1, 0.1, 0.1]), [0.0, 0.0, 0.0]);
+        assert_eq!(minmax_normalize(&[0.1, 0.1, 0.1]), [0.0, 0.0, 0.0]);
+        assert!(zscore_normalize(&[]).is_empty());
+    }
+}
@@ -0,0 +1,121 @@
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{BufRead, BufReader},
+    path::Path,
+};
+use bio::io::fasta::Record;
+
+use crate::{check_record_lengths, FastaParseError, FastaParseErrorKind};
+
+
+const STOCKHOLM_HEADER: &str = "# STOCKHOLM";
+const STOCKHOLM_END: &str = "//";
+
+
+/// An alignment parsed from a Stockholm file, see [`parse_stockholm`].
+#[derive(Debug, Clone)]
+pub struct StockholmAlignment {
+    /// The aligned records, with `.` gaps normalized to `-` and `#=GS <id> DE` lines as descriptions.
+    pub records: Vec<Record>,
+    /// The `#=GC RF` reference annotation, as long as the alignment, if the file has one.
+    /// Its `.` and `-` columns mark the insert columns of the alignment.
+    pub reference: Option<Vec<u8>>,
+}
+
+
+/// Parse an aligned Stockholm file (as used by Rfam and Pfam).
+///
+/// Multi-block files, where the same ID appears once per block, are supported by concatenating the rows of
+/// each ID in order, and the `.` gaps are normalized to `-`. The `#=GS <id> DE` lines become the descriptions
+/// of the records and the `#=GC RF` lines the reference annotation; other annotation lines are skipped.
+/// Parsing stops at the first `//` end marker, so only the first alignment of a file is read.
+pub fn parse_stockholm(path: &Path) -> Result<StockholmAlignment, FastaParseError> {
+    let file = File::open(path)?;
+    let reader = BufReader::new(file);
+
+    let mut saw_header = false;
+    let mut ids: Vec<String> = vec![];
+    let mut seqs: Vec<Vec<u8>> = vec![];
+    let mut id_index: HashMap<String, usize> = HashMap::new();
+    let mut descriptions: HashMap<String, String> = HashMap::new();
+    let mut reference: Option<Vec<u8>> = None;
+    for (line_idx, line) in reader.lines().enumerate() {
+        let line = line?;
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        if !saw_header {
+            if !line.starts_with(STOCKHOLM_HEADER) {
+                return Err(format_error(format!("Missing '{}' header on line {}", STOCKHOLM_HEADER, line_idx + 1)));
+            }
+            saw_header = true;
+            continue;
+        }
+        if line == STOCKHOLM_END {
+            break;
+        }
+        if let Some(annotation) = line.strip_prefix("#=GS") {
+            let mut fields = annotation.split_whitespace();
+            if let (Some(id), Some("DE")) = (fields.next(), fields.next()) {
+                let description = descriptions.entry(id.to_owned()).or_default();
+                if !description.is_empty() {
+                    description.push(' ');
+                }
+                description.push_str(&fields.collect::<Vec<_>>().join(" "));
+            }
+            continue;
+        }
+        if let Some(annotation) = line.strip_prefix("#=GC") {
+            if let Some(("RF", columns)) = annotation.trim_start().split_once(char::is_whitespace) {
+                reference.get_or_insert_with(Vec::new).extend_from_slice(columns.trim().as_bytes());
+            }
+            continue;
+        }
+        if line.starts_with('#') {
+            continue;
+        }
+
+        let (id, seq) = match line.split_once(char::is_whitespace) {
+            Some((id, seq)) => (id, seq.trim()),
+            None => return Err(format_error(format!("Expected '<id> <sequence>' on line {}", line_idx + 1))),
+        };
+        let idx = *id_index.entry(id.to_owned()).or_insert_with(|| {
+            ids.push(id.to_owned());
+            seqs.push(vec![]);
+            ids.len() - 1
+        });
+        seqs[idx].extend(seq.bytes().map(|b| if b == b'.' { b'-' } else { b }));
+    }
+
+    let records: Vec<Record> = ids.iter()
+        .zip(seqs.iter())
+        .map(|(id, seq)| Record::with_attrs(id, descriptions.get(id).map(String::as_str), seq))
+        .collect();
+    check_record_lengths(&records)?;
+    if let (Some(reference), Some(first)) = (&reference, records.first())
+        && reference.len() != first.seq().len()
+    {
+        return Err(FastaParseError {
+            message: format!(
+                "The #=GC RF line has length {}, but the alignment has length {}",
+                reference.len(), first.seq().len(),
+            ),
+            kind: FastaParseErrorKind::LengthMismatch,
+            source: None,
+        });
+    }
+    Ok(StockholmAlignment { records, reference })
+}
+
+
+/// Parse the records of an aligned Stockholm file, see [`parse_stockholm`].
+pub fn parse_all_records_stockholm(path: &Path) -> Result<Vec<Record>, FastaParseError> {
+    parse_stockholm(path).map(|alignment| alignment.records)
+}
+
+
+fn format_error(message: String) -> FastaParseError {
+    FastaParseError { message, kind: FastaParseErrorKind::FormatError, source: None }
+}
@@ -0,0 +1,78 @@
+use std::fmt::{Display, Formatter};
+use bio::io::fasta::Record;
+
+
+/// The strand of a query a pair was compared on, see [`crate::nearest_neighbor::NeighborConfig::reverse_complement`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Strand {
+    /// The query as it is.
+    Forward,
+    /// The reverse complement of the query.
+    Reverse,
+}
+
+
+impl Display for Strand {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Strand::Forward => write!(f, "+"),
+            Strand::Reverse => write!(f, "-"),
+        }
+    }
+}
+
+
+/// The complement of each byte: `A`/`T` and `C`/`G` swapped, as well as the IUPAC ambiguity codes of
+/// complementary sets (`R`/`Y`, `K`/`M`, `B`/`V`, `D`/`H`), keeping the case. `N`, `S`, `W`, gaps and any
+/// other byte are their own complement.
+const COMPLEMENT: [u8; 256] = {
+    let mut table = [0u8; 256];
+    let mut idx = 0;
+    while idx < 256 {
+        table[idx] = idx as u8;
+        idx += 1;
+    }
+    let pairs: [(u8, u8); 7] = [(b'A', b'T'), (b'C', b'G'), (b'U', b'A'), (b'R', b'Y'), (b'K', b'M'), (b'B', b'V'), (b'D', b'H')];
+    let mut pair_idx = 0;
+    while pair_idx < pairs.len() {
+        let (x, y) = pairs[pair_idx];
+        table[x as usize] = y;
+        table[x.to_ascii_lowercase() as usize] = y.to_ascii_lowercase();
+        // `A` is the complement of `U`, whose own complement stays `T`.
+        if x != b'U' {
+            table[y as usize] = x;
+            table[y.to_ascii_lowercase() as usize] = x.to_ascii_lowercase();
+        }
+        pair_idx += 1;
+    }
+    table
+};
+
+
+/// The reverse complement of a nucleotide sequence, see [`COMPLEMENT`]. Reversing an aligned sequence also
+/// reverses its columns, so that it is compared from the last alignment column to the first.
+pub fn reverse_complement(seq: &[u8]) -> Vec<u8> {
+    seq.iter().rev().map(|ch| COMPLEMENT[*ch as usize]).collect()
+}
+
+
+/// A record holding the reverse complement of `record`'s sequence, under the same ID and description.
+pub fn reverse_complement_record(record: &Record) -> Record {
+    Record::with_attrs(record.id(), record.desc(), &reverse_complement(record.seq()))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::{reverse_complement, Strand};
+
+    #[test]
+    fn test_reverse_complement() {
+        assert_eq!(reverse_complement(b"ACGT-N"), b"N-ACGT");
+        assert_eq!(reverse_complement(b"aacg"), b"cgtt");
+        assert_eq!(reverse_complement(b"RYKMBVDHSW"), b"WSDHBVKMRY");
+        assert_eq!(reverse_complement(b"ACGU"), b"ACGT");
+        assert_eq!(reverse_complement(&reverse_complement(b"GATTACA-ryn")), b"GATTACA-ryn");
+        assert_eq!(format!("{}{}", Strand::Forward, Strand::Reverse), "+-");
+    }
+}
@@ -0,0 +1,290 @@
+use std::{
+    collections::HashSet,
+    io::BufReader,
+    path::{Path, PathBuf},
+};
+use rayon::prelude::*;
+use bio::io::fasta::{Reader as FastaReader, Record};
+
+use crate::{
+    open_input,
+    nearest_neighbor::{compare_records_with, no_neighbor_error, with_scored_scratch, NearestNeighborError, NeighborConfig},
+    output::{sort_rows, write_identity_summary, write_results, NearestNeighborResultRow, OutputConfig},
+    progress::Progress,
+    stats::{record_stats, RecordStats},
+};
+
+
+/// A database record kept as one of a query's neighbors, owning its data so that it outlives the
+/// chunk it was read from.
+#[derive(Debug, Clone, PartialEq)]
+pub struct StreamedNeighbor {
+    /// The position of the record among all streamed database records.
+    pub db_index: usize,
+    pub neighbor_id: String,
+    /// The identity, or the distance for a distance [`crate::distance::Metric`].
+    pub identity: f64,
+    pub neighbor_stats: RecordStats,
+}
+
+
+/// Read the records of the given FASTA files in order, passing them to `on_chunk` in chunks of
+/// at most `chunk_records` records. Only one chunk is held in memory at a time.
+/// The alignment lengths are checked as the records are read, against the first record.
+fn for_each_chunk<F>(paths: &[PathBuf], chunk_records: usize, mut on_chunk: F) -> Result<(), NearestNeighborError>
+where
+    F: FnMut(Vec<Record>) -> Result<(), NearestNeighborError>,
+{
+    let mut first: Option<(String, usize)> = None;
+    let mut chunk: Vec<Record> = Vec::with_capacity(chunk_records);
+    for path in paths.iter() {
+        let reader = FastaReader::new(BufReader::new(open_input(path)?));
+        for record in reader.records() {
+            let record = record?;
+            match &first {
+                None => first = Some((record.id().to_owned(), record.seq().len())),
+                Some((first_id, first_len)) => {
+                    if record.seq().len() != *first_len {
+                        return Err(NearestNeighborError::HammingDistanceError(first_id.clone(), record.id().to_owned()));
+                    }
+                }
+            }
+            chunk.push(record);
+            if chunk.len() == chunk_records {
+                on_chunk(std::mem::replace(&mut chunk, Vec::with_capacity(chunk_records)))?;
+            }
+        }
+    }
+    if !chunk.is_empty() {
+        on_chunk(chunk)?;
+    }
+    Ok(())
+}
+
+
+/// Compute the `config.top_k` nearest neighbors of each query, streaming the database from
+/// `db_paths` in chunks of `chunk_records` records instead of holding it in memory.
+///
+/// Each chunk is compared against all queries in parallel, and merged into each query's running
+/// neighbor list before being dropped. Neighbors are ranked as in
+/// [`crate::nearest_neighbor::compute_top_k_neighbors`], with ties going to the later database record.
+///
+/// # Arguments
+///
+/// * `query_records` - The query Fasta records, held in memory.
+/// * `db_paths` - The FASTA files to stream the database from.
+/// * `db_ids` - If given, only database records with these IDs are compared.
+/// * `chunk_records` - The number of database records read per chunk.
+/// * `db_count` - The number of database records, for the progress bar, which counts compared pairs.
+/// * `config` - The search options. The consensus and column compression options are not applied.
+///   A cancellation stops the stream between chunks, with [`NearestNeighborError::Cancelled`] and no results.
+pub fn stream_top_k_neighbors(
+    query_records: &[&Record],
+    db_paths: &[PathBuf],
+    db_ids: Option<&HashSet<String>>,
+    chunk_records: usize,
+    db_count: usize,
+    config: &NeighborConfig,
+) -> Result<Vec<Vec<StreamedNeighbor>>, NearestNeighborError> {
+    let _span = tracing::info_span!(
+        "compute",
+        num_workers = rayon::current_num_threads(),
+        query_count = query_records.len(),
+        db_count,
+        chunk_records,
+    ).entered();
+    tracing::info!("Computing nearest neighbors");
+
+    let progress = Progress::new((query_records.len() * db_count) as u64, config.progress, config.progress_interval);
+    let mut best: Vec<Vec<StreamedNeighbor>> = vec![vec![]; query_records.len()];
+    let mut db_offset: usize = 0;
+    for_each_chunk(db_paths, chunk_records, |chunk| {
+        // Every query needs the whole database, so a partial stream has no usable results.
+        if config.cancel.is_cancelled() {
+            return Err(NearestNeighborError::Cancelled(0));
+        }
+        let db_chunk: Vec<&Record> = chunk.iter()
+            .filter(|record| db_ids.is_none_or(|ids| ids.contains(record.id())))
+            .collect();
+        tracing::trace!(
+            "Comparing database records {}..{} against {} queries",
+            db_offset, db_offset + db_chunk.len(), query_records.len(),
+        );
+        best.par_iter_mut()
+            .zip(query_records.par_iter())
+            .try_for_each(|(neighbors, query)| {
+                merge_chunk(query, &db_chunk, db_offset, neighbors, config)
+            })?;
+        let chunk_pairs = (query_records.len() * db_chunk.len()) as u64;
+        db_offset += db_chunk.len();
+        progress.inc(chunk_pairs);
+        config.counters.add_pairs(chunk_pairs);
+        Ok(())
+    })?;
+    drop(progress);
+
+    for (query, neighbors) in query_records.iter().zip(best.iter()) {
+        if neighbors.is_empty() {
+            return Err(no_neighbor_error(query, &config.comparison));
+        }
+    }
+    Ok(best)
+}
+
+
+/// Compare a query against one database chunk, and merge the chunk's best hits into its neighbors.
+fn merge_chunk(
+    query: &Record,
+    db_chunk: &[&Record],
+    db_offset: usize,
+    neighbors: &mut Vec<StreamedNeighbor>,
+    config: &NeighborConfig,
+) -> Result<(), NearestNeighborError> {
+    let ranking = config.ranking();
+    with_scored_scratch(|scored| {
+        for (chunk_idx, other) in db_chunk.iter().enumerate() {
+            if config.exclude_self && other.id() == query.id() {
+                continue;
+            }
+            let value = compare_records_with(query, other, config.pair_metric())?;
+            if !value.is_nan() {
+                scored.push((chunk_idx, value));
+            }
+        }
+        scored.sort_unstable_by(|a, b| ranking.cmp_best_first(a.1, b.1).then(b.0.cmp(&a.0)));
+
+        neighbors.extend(scored.iter().take(config.top_k).map(|(chunk_idx, value)| StreamedNeighbor {
+            db_index: db_offset + chunk_idx,
+            neighbor_id: db_chunk[*chunk_idx].id().to_owned(),
+            identity: *value,
+            neighbor_stats: record_stats(db_chunk[*chunk_idx]),
+        }));
+        Ok::<(), NearestNeighborError>(())
+    })?;
+    neighbors.sort_unstable_by(|a, b| ranking.cmp_best_first(a.identity, b.identity).then(b.db_index.cmp(&a.db_index)));
+    neighbors.truncate(config.top_k);
+    Ok(())
+}
+
+
+/// Compute all nearest neighbors while streaming the database in chunks, and write each result
+/// to `out_path` in the configured format.
+///
+/// The input files are read twice: once to collect the queries and count the database records,
+/// and once to stream the database. Only the queries and one chunk are held in memory.
+pub fn compute_store_nearest_neighbors_streaming(
+    input_paths: &[PathBuf],
+    out_path: &Path,
+    query_ids: Option<Vec<String>>,
+    db_ids: Option<Vec<String>>,
+    chunk_records: usize,
+    config: &NeighborConfig,
+    output_config: &OutputConfig,
+) -> Result<(), NearestNeighborError> {
+    let db_ids: Option<HashSet<String>> = db_ids.map(HashSet::from_iter);
+    let query_ids: Option<HashSet<String>> = query_ids.map(HashSet::from_iter);
+    let mut query_pool: Vec<Record> = vec![];
+    let mut db_count: usize = 0;
+    for_each_chunk(input_paths, chunk_records, |chunk| {
+        db_count += chunk.iter()
+            .filter(|record| db_ids.as_ref().is_none_or(|ids| ids.contains(record.id())))
+            .count();
+        query_pool.extend(
+            chunk.into_iter().filter(|record| query_ids.as_ref().is_none_or(|ids| ids.contains(record.id())))
+        );
+        Ok(())
+    })?;
+    let query_records: Vec<&Record> = query_pool.iter().collect();
+    let query_records: Vec<&Record> = config.select_queries(query_records);
+    tracing::info!("Holding {} queries in memory; streaming {} database records", query_records.len(), db_count);
+
+    let results = stream_top_k_neighbors(
+        &query_records, input_paths, db_ids.as_ref(), chunk_records, db_count, config,
+    )?;
+    let mut rows: Vec<NearestNeighborResultRow> = query_records.iter()
+        .zip(results.iter())
+        .flat_map(|(query_record, neighbors)| {
+            neighbors.iter()
+                .enumerate()
+                .map(move |(rank_idx, neighbor)| NearestNeighborResultRow {
+                    query_id: query_record.id().to_owned(),
+                    neighbor_id: neighbor.neighbor_id.clone(),
+                    rank: rank_idx + 1,
+                    identity: neighbor.identity,
+                    query_stats: output_config.with_stats.then(|| record_stats(query_record)),
+                    neighbor_stats: output_config.with_stats.then_some(neighbor.neighbor_stats),
+                    counts: None,
+                    runner_up: None,
+                    comparison: None,
+                    mismatches: None,
+                    blast: None,
+                    query_header: None,
+                    neighbor_header: None,
+                    query_gc: None,
+                    neighbor_gc: None,
+                    strand: None,
+                    db_rank: None,
+                })
+        })
+        .collect();
+    // The rows are all buffered before writing, so any order is available.
+    if let Some(normalization) = output_config.normalize {
+        normalization.apply(&mut rows);
+    }
+    sort_rows(&mut rows, output_config.sort_by);
+    let metadata = config.run_metadata(query_records.len(), db_count);
+    write_results(&rows, out_path, output_config, &metadata)?;
+    if let Some(summary_output) = &output_config.identity_summary {
+        write_identity_summary(&rows, results.len(), summary_output, output_config.precision)?;
+    }
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+    use bio::io::fasta::Record;
+    use crate::{
+        parse_all_records,
+        nearest_neighbor::{compute_top_k_neighbors, NeighborConfig, NearestNeighborError},
+    };
+    use super::stream_top_k_neighbors;
+
+    #[test]
+    fn test_streaming_matches_in_memory() {
+        let fasta_path = PathBuf::from("tests/inputs/simple_test_2.fasta");
+        let records = parse_all_records(fasta_path.clone()).unwrap();
+        let record_refs: Vec<&Record> = records.iter().collect();
+
+        for top_k in [1, 2, 3] {
+            let config = NeighborConfig { exclude_self: true, top_k, ..Default::default() };
+            let expected = compute_top_k_neighbors(&record_refs, &record_refs, &config).unwrap();
+            for chunk_records in [1, 3, 10] {
+                let streamed = stream_top_k_neighbors(
+                    &record_refs, std::slice::from_ref(&fasta_path), None, chunk_records, records.len(), &config,
+                ).unwrap();
+                for (expected_hits, streamed_hits) in expected.iter().zip(streamed.iter()) {
+                    let expected_hits: Vec<(&str, f64)> = expected_hits.iter().map(|(r, idty)| (r.id(), *idty)).collect();
+                    let streamed_hits: Vec<(&str, f64)> = streamed_hits.iter()
+                        .map(|hit| (hit.neighbor_id.as_str(), hit.identity))
+                        .collect();
+                    assert_eq!(expected_hits, streamed_hits);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_streaming_length_mismatch() {
+        // The first chunk is compared fine; the second record is rejected as it is read.
+        let query = Record::with_attrs("q", None, b"AAAAAAAAAAAAA");
+        let result = stream_top_k_neighbors(
+            &[&query], &[PathBuf::from("tests/inputs/mismatched_lengths.fasta")], None, 1, 2, &NeighborConfig::default(),
+        );
+        assert_eq!(
+            result,
+            Err(NearestNeighborError::HammingDistanceError("entry_1".to_owned(), "entry_2".to_owned())),
+        );
+    }
+}
@@ -0,0 +1,222 @@
+use std::{
+    fmt::{Display, Formatter},
+    sync::{atomic::{AtomicU64, Ordering}, OnceLock},
+    time::Duration,
+};
+
+use crate::output::SortKey;
+
+
+/// Counters shared by the workers of a search.
+///
+/// They are updated once per query with the number of pairs it was compared against, rather than
+/// once per pair, so the cost of the atomic update is amortized over a whole database scan. The pairs
+/// compared and the pairs skipped are counted apart, so that neither is derived from the other.
+#[derive(Debug, Default)]
+pub struct SearchCounters {
+    pairs_compared: AtomicU64,
+    pairs_skipped: AtomicU64,
+    sampled_queries: OnceLock<usize>,
+    sampled_db_records: OnceLock<usize>,
+    unique_queries: AtomicU64,
+    collapsed_queries: AtomicU64,
+}
+
+
+impl SearchCounters {
+    pub fn add_pairs(&self, num_pairs: u64) {
+        self.pairs_compared.fetch_add(num_pairs, Ordering::Relaxed);
+    }
+
+    /// Record the pairs of a query left uncompared after its nearest neighbor was found with a perfect value,
+    /// see [`crate::nearest_neighbor::NeighborConfig::perfect_value`].
+    pub fn add_skipped_pairs(&self, num_pairs: u64) {
+        self.pairs_skipped.fetch_add(num_pairs, Ordering::Relaxed);
+    }
+
+    pub fn pairs_compared(&self) -> u64 {
+        self.pairs_compared.load(Ordering::Relaxed)
+    }
+
+    pub fn pairs_skipped(&self) -> u64 {
+        self.pairs_skipped.load(Ordering::Relaxed)
+    }
+
+    /// Record the number of queries kept by the query sample. Only the first count is kept.
+    pub fn set_sampled_queries(&self, num_queries: usize) {
+        let _ = self.sampled_queries.set(num_queries);
+    }
+
+    /// The number of queries kept by the query sample, if the queries were sampled.
+    pub fn sampled_queries(&self) -> Option<usize> {
+        self.sampled_queries.get().copied()
+    }
+
+    /// Record the number of database records kept by the database sample. Only the first count is kept.
+    pub fn set_sampled_db_records(&self, num_db_records: usize) {
+        let _ = self.sampled_db_records.set(num_db_records);
+    }
+
+    /// The number of database records kept by the database sample, if the database was sampled.
+    pub fn sampled_db_records(&self) -> Option<usize> {
+        self.sampled_db_records.get().copied()
+    }
+
+    /// Record the number of distinct sequences among the queries of a search with collapsed duplicates.
+    /// The counts of several searches, e.g. the batches of a checkpointed search, add up.
+    pub fn add_collapsed_queries(&self, num_unique: usize, num_queries: usize) {
+        self.unique_queries.fetch_add(num_unique as u64, Ordering::Relaxed);
+        self.collapsed_queries.fetch_add(num_queries as u64, Ordering::Relaxed);
+    }
+
+    /// The number of distinct query sequences searched and of queries they stand for, if the duplicates
+    /// were collapsed.
+    pub fn collapsed_queries(&self) -> Option<(u64, u64)> {
+        let num_queries = self.collapsed_queries.load(Ordering::Relaxed);
+        (num_queries > 0).then(|| (self.unique_queries.load(Ordering::Relaxed), num_queries))
+    }
+}
+
+
+/// Throughput figures of a finished run.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RunSummary {
+    pub pairs_compared: u64,
+    /// The pairs left uncompared after a perfect match, see [`SearchCounters::add_skipped_pairs`].
+    pub pairs_skipped: u64,
+    pub wall_time: Duration,
+    /// The peak resident set size of the process, where the platform reports it.
+    pub peak_rss_bytes: Option<u64>,
+    /// The order of the output rows.
+    pub sort_by: SortKey,
+    /// The seed of the query and database samples, if either was sampled.
+    pub seed: Option<u64>,
+    pub sampled_queries: Option<usize>,
+    pub sampled_db_records: Option<usize>,
+    /// The number of distinct query sequences searched and of queries they stand for, if the duplicates
+    /// were collapsed.
+    pub collapsed_queries: Option<(u64, u64)>,
+    /// The sequence type of the alignment, given or detected, see [`crate::validate::Alphabet::name`].
+    pub seq_type: Option<&'static str>,
+    /// The normalizations that changed records at parse time: `gaps` for the gap characters replaced by `-`,
+    /// and `u_to_t` for the `U` replaced by `T`.
+    pub normalizations: Vec<&'static str>,
+}
+
+
+impl RunSummary {
+    pub fn new(counters: &SearchCounters, wall_time: Duration) -> Self {
+        RunSummary {
+            pairs_compared: counters.pairs_compared(),
+            pairs_skipped: counters.pairs_skipped(),
+            wall_time,
+            peak_rss_bytes: peak_rss_bytes(),
+            sort_by: SortKey::default(),
+            seed: None,
+            sampled_queries: counters.sampled_queries(),
+            sampled_db_records: counters.sampled_db_records(),
+            collapsed_queries: counters.collapsed_queries(),
+            seq_type: None,
+            normalizations: vec![],
+        }
+    }
+
+    pub fn pairs_per_sec(&self) -> f64 {
+        (self.pairs_compared as f64) / self.wall_time.as_secs_f64()
+    }
+
+    /// The summary as a single-line JSON object. The missing values are `null`.
+    pub fn to_json(&self) -> String {
+        fn json_value(value: Option<impl ToString>) -> String {
+            value.map_or("null".to_owned(), |value| value.to_string())
+        }
+        format!(
+            "{{\"pairs_compared\": {}, \"pairs_skipped\": {}, \"wall_time_secs\": {:.3}, \"pairs_per_sec\": {:.1}, \
+             \"peak_rss_bytes\": {}, \"sort_by\": \"{}\", \"seed\": {}, \"sampled_queries\": {}, \"sampled_db_records\": {}, \
+             \"unique_queries\": {}, \"collapsed_queries\": {}, \"seq_type\": {}, \"normalizations\": [{}]}}",
+            self.pairs_compared, self.pairs_skipped, self.wall_time.as_secs_f64(), self.pairs_per_sec(),
+            json_value(self.peak_rss_bytes),
+            self.sort_by.name(), json_value(self.seed), json_value(self.sampled_queries),
+            json_value(self.sampled_db_records), json_value(self.collapsed_queries.map(|(num_unique, _)| num_unique)),
+            json_value(self.collapsed_queries.map(|(_, num_queries)| num_queries)), json_value(self.seq_type.map(|seq_type| format!("\"{}\"", seq_type))),
+            self.normalizations.iter().map(|name| format!("\"{}\"", name)).collect::<Vec<String>>().join(", "),
+        )
+    }
+}
+
+
+impl Display for RunSummary {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f, "Compared {} pairs in {:.2}s ({:.0} pairs/s)",
+            self.pairs_compared, self.wall_time.as_secs_f64(), self.pairs_per_sec(),
+        )?;
+        if self.pairs_skipped > 0 {
+            write!(f, ", skipped {} pairs after perfect matches", self.pairs_skipped)?;
+        }
+        if let Some((num_unique, num_queries)) = self.collapsed_queries {
+            write!(f, ", {} unique sequences of {} queries", num_unique, num_queries)?;
+        }
+        if let Some(bytes) = self.peak_rss_bytes {
+            write!(f, ", peak RSS {:.1} MiB", (bytes as f64) / (1024.0 * 1024.0))?;
+        }
+        Ok(())
+    }
+}
+
+
+/// The peak resident set size, read from the `VmHWM` line of `/proc/self/status`.
+/// Returns `None` on platforms without procfs.
+pub fn peak_rss_bytes() -> Option<u64> {
+    let status = std::fs::read_to_string("/proc/self/status").ok()?;
+    let line = status.lines().find(|line| line.starts_with("VmHWM:"))?;
+    let kib: u64 = line.trim_start_matches("VmHWM:").trim().trim_end_matches("kB").trim().parse().ok()?;
+    Some(kib * 1024)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+    use super::{RunSummary, SearchCounters};
+
+    #[test]
+    fn test_run_summary() {
+        let counters = SearchCounters::default();
+        counters.add_pairs(300);
+        counters.add_pairs(200);
+        let summary = RunSummary { peak_rss_bytes: None, ..RunSummary::new(&counters, Duration::from_secs(2)) };
+        assert_eq!(summary.pairs_compared, 500);
+        assert_eq!(summary.pairs_per_sec(), 250.0);
+        assert_eq!(
+            summary.to_json(),
+            "{\"pairs_compared\": 500, \"pairs_skipped\": 0, \"wall_time_secs\": 2.000, \"pairs_per_sec\": 250.0, \
+             \"peak_rss_bytes\": null, \"sort_by\": \"query-order\", \"seed\": null, \"sampled_queries\": null, \"sampled_db_records\": null, \
+             \"unique_queries\": null, \"collapsed_queries\": null, \"seq_type\": null, \"normalizations\": []}",
+        );
+
+        counters.set_sampled_queries(10);
+        counters.set_sampled_queries(20);
+        counters.add_collapsed_queries(3, 8);
+        counters.add_collapsed_queries(1, 2);
+        counters.add_skipped_pairs(100);
+        let summary = RunSummary {
+            peak_rss_bytes: None,
+            seed: Some(7),
+            seq_type: Some("dna"),
+            normalizations: vec!["gaps", "u_to_t"],
+            ..RunSummary::new(&counters, Duration::from_secs(2))
+        };
+        assert_eq!(
+            summary.to_json(),
+            "{\"pairs_compared\": 500, \"pairs_skipped\": 100, \"wall_time_secs\": 2.000, \"pairs_per_sec\": 250.0, \
+             \"peak_rss_bytes\": null, \"sort_by\": \"query-order\", \"seed\": 7, \"sampled_queries\": 10, \"sampled_db_records\": null, \
+             \"unique_queries\": 4, \"collapsed_queries\": 10, \"seq_type\": \"dna\", \
+             \"normalizations\": [\"gaps\", \"u_to_t\"]}",
+        );
+        assert_eq!(
+            summary.to_string(),
+            "Compared 500 pairs in 2.00s (250 pairs/s), skipped 100 pairs after perfect matches, 4 unique sequences of 10 queries",
+        );
+    }
+}
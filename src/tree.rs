@@ -0,0 +1,199 @@
+use std::io::Write;
+
+use crate::nearest_neighbor::NearestNeighborError;
+
+
+/// An unrooted neighbor-joining tree, in Newick format.
+#[derive(Debug, Clone, PartialEq)]
+pub struct NjTree {
+    /// The tree, ending with `;`.
+    pub newick: String,
+    /// The number of negative branch lengths that were clamped to 0.
+    pub clamped_branches: usize,
+}
+
+
+/// Whether an ID must be quoted in Newick: it contains a blank, or a character with a meaning in Newick.
+/// Underscores are kept unquoted, though strict readers turn them into blanks.
+fn needs_quotes(id: &str) -> bool {
+    id.is_empty() || id.chars().any(|ch| ch.is_whitespace() || "()[]':;,".contains(ch))
+}
+
+
+/// Format an ID as a Newick label, single-quoted with doubled inner quotes if needed.
+pub fn newick_label(id: &str) -> String {
+    if needs_quotes(id) {
+        format!("'{}'", id.replace('\'', "''"))
+    } else {
+        id.to_owned()
+    }
+}
+
+
+/// The distances `1 - identity` of an identity matrix, with a zero diagonal. Fails if any other identity
+/// is undefined, as for a pair of records without any compared column.
+pub fn identity_distances(ids: &[&str], matrix: &[Vec<f64>]) -> Result<Vec<Vec<f64>>, NearestNeighborError> {
+    matrix.iter()
+        .enumerate()
+        .map(|(row, values)| {
+            values.iter()
+                .enumerate()
+                .map(|(col, idty)| {
+                    if row == col {
+                        Ok(0.0)
+                    } else if idty.is_nan() {
+                        Err(NearestNeighborError::InvalidInput(format!(
+                            "The identity of {} and {} is undefined: they share no compared column", ids[row], ids[col],
+                        )))
+                    } else {
+                        Ok(1.0 - *idty)
+                    }
+                })
+                .collect()
+        })
+        .collect()
+}
+
+
+/// Build the neighbor-joining tree (Saitou & Nei, 1987) of a symmetric distance matrix.
+///
+/// This is the standard O(n³) algorithm over a full matrix, which is practical up to a few thousand
+/// records: 5,000 records take about 200 MB and minutes of computation. Ties between pairs go to the
+/// first pair in the order of the IDs. Negative branch lengths are clamped to 0, and counted.
+///
+/// # Arguments
+///
+/// * `ids` - The names of the leaves, in the order of the matrix rows.
+/// * `distances` - The symmetric distance matrix.
+///
+/// # Returns
+///
+/// The unrooted tree, with a trifurcation at its root when there are at least 3 leaves.
+pub fn neighbor_joining(ids: &[&str], distances: &[Vec<f64>]) -> Result<NjTree, NearestNeighborError> {
+    if ids.is_empty() {
+        return Err(NearestNeighborError::InvalidInput("Can't build a tree without records".to_owned()));
+    }
+    let mut dist: Vec<Vec<f64>> = distances.to_vec();
+    let mut subtrees: Vec<String> = ids.iter().map(|id| newick_label(id)).collect();
+    let mut active: Vec<usize> = (0..ids.len()).collect();
+    let mut clamped_branches: usize = 0;
+    let mut branch = |length: f64| -> f64 {
+        if length < 0.0 {
+            clamped_branches += 1;
+            0.0
+        } else {
+            length
+        }
+    };
+
+    while active.len() > 3 {
+        let n = active.len();
+        let row_sums: Vec<f64> = active.iter()
+            .map(|i| active.iter().filter(|k| *k != i).map(|k| dist[*i][*k]).sum())
+            .collect();
+        let mut best: (usize, usize) = (0, 1);
+        let mut best_q = f64::INFINITY;
+        for a in 0..n {
+            for b in (a + 1)..n {
+                let q = (n - 2) as f64 * dist[active[a]][active[b]] - row_sums[a] - row_sums[b];
+                if q < best_q {
+                    best_q = q;
+                    best = (a, b);
+                }
+            }
+        }
+
+        let (a, b) = best;
+        let (i, j) = (active[a], active[b]);
+        let d_ij = dist[i][j];
+        let length_i = d_ij / 2.0 + (row_sums[a] - row_sums[b]) / (2.0 * (n - 2) as f64);
+        let length_j = d_ij - length_i;
+        let (length_i, length_j) = (branch(length_i), branch(length_j));
+        subtrees[i] = format!("({}:{},{}:{})", subtrees[i], length_i, subtrees[j], length_j);
+        // The joined node takes the place of `i`.
+        for k in active.iter().copied().filter(|k| *k != i && *k != j) {
+            let d_uk = (dist[i][k] + dist[j][k] - d_ij) / 2.0;
+            dist[i][k] = d_uk;
+            dist[k][i] = d_uk;
+        }
+        active.remove(b);
+    }
+
+    let newick = match active[..] {
+        [i] => format!("{};", subtrees[i]),
+        [i, j] => {
+            let half = branch(dist[i][j] / 2.0);
+            format!("({}:{},{}:{});", subtrees[i], half, subtrees[j], half)
+        }
+        [i, j, k] => {
+            let length_i = branch((dist[i][j] + dist[i][k] - dist[j][k]) / 2.0);
+            let length_j = branch((dist[i][j] + dist[j][k] - dist[i][k]) / 2.0);
+            let length_k = branch((dist[i][k] + dist[j][k] - dist[i][j]) / 2.0);
+            format!("({}:{},{}:{},{}:{});", subtrees[i], length_i, subtrees[j], length_j, subtrees[k], length_k)
+        }
+        _ => unreachable!("The joins stop at 3 clusters"),
+    };
+    Ok(NjTree { newick, clamped_branches })
+}
+
+
+/// Write a tree as a single Newick line.
+pub fn write_newick<W: Write>(tree: &NjTree, writer: &mut W) -> Result<(), NearestNeighborError> {
+    writeln!(writer, "{}", tree.newick)?;
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::{identity_distances, neighbor_joining, newick_label};
+
+    #[test]
+    fn test_neighbor_joining() {
+        // The 5-taxon example of the neighbor-joining Wikipedia article.
+        let distances = vec![
+            vec![0.0, 5.0, 9.0, 9.0, 8.0],
+            vec![5.0, 0.0, 10.0, 10.0, 9.0],
+            vec![9.0, 10.0, 0.0, 8.0, 7.0],
+            vec![9.0, 10.0, 8.0, 0.0, 3.0],
+            vec![8.0, 9.0, 7.0, 3.0, 0.0],
+        ];
+        let tree = neighbor_joining(&["a", "b", "c", "d", "e"], &distances).unwrap();
+        assert_eq!(tree.newick, "(((a:2,b:3):3,c:4):2,d:2,e:1);");
+        assert_eq!(tree.clamped_branches, 0);
+    }
+
+    #[test]
+    fn test_negative_branches() {
+        // Not additive: the branch to c would be negative.
+        let distances = vec![
+            vec![0.0, 1.0, 0.2],
+            vec![1.0, 0.0, 0.2],
+            vec![0.2, 0.2, 0.0],
+        ];
+        let tree = neighbor_joining(&["a", "b", "c"], &distances).unwrap();
+        assert_eq!(tree.newick, "(a:0.5,b:0.5,c:0);");
+        assert_eq!(tree.clamped_branches, 1);
+
+        let tree = neighbor_joining(&["a", "b"], &[vec![0.0, 1.0], vec![1.0, 0.0]]).unwrap();
+        assert_eq!(tree.newick, "(a:0.5,b:0.5);");
+        assert_eq!(neighbor_joining(&["a"], &[vec![0.0]]).unwrap().newick, "a;");
+    }
+
+    #[test]
+    fn test_newick_label() {
+        assert_eq!(newick_label("seq_1"), "seq_1");
+        assert_eq!(newick_label("seq 1"), "'seq 1'");
+        assert_eq!(newick_label("a:b(c)"), "'a:b(c)'");
+        assert_eq!(newick_label("o'neil"), "'o''neil'");
+    }
+
+    #[test]
+    fn test_identity_distances() {
+        // An all-gap record has an undefined identity to itself, but a zero distance.
+        let distances = identity_distances(&["x", "y"], &[vec![f64::NAN, 0.75], vec![0.75, 1.0]]).unwrap();
+        assert_eq!(distances, [vec![0.0, 0.25], vec![0.25, 0.0]]);
+        let err = identity_distances(&["x", "y"], &[vec![1.0, f64::NAN], vec![f64::NAN, 1.0]]).unwrap_err();
+        assert!(err.to_string().contains("x and y"));
+    }
+}
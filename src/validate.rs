@@ -0,0 +1,309 @@
+use std::{
+    collections::HashSet,
+    fmt::{Display, Formatter},
+    fs::File,
+    io::BufReader,
+    path::{Path, PathBuf},
+};
+use bio::io::fasta::{Reader as FastaReader, Record};
+#[cfg(feature = "cli")]
+use clap::ValueEnum;
+
+use crate::{gaps::GapChars, nearest_neighbor::GAP, parse_record_ids};
+
+
+/// The residue alphabet expected by `validate --alphabet`. Gaps are valid in every alphabet, and
+/// residues are case-insensitive.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "cli", derive(ValueEnum))]
+pub enum Alphabet {
+    /// Nucleotides `ACGT` and the IUPAC ambiguity codes.
+    Dna,
+    /// Nucleotides `ACGU` and the IUPAC ambiguity codes.
+    Rna,
+    /// The 20 standard amino acids, `BZJX` ambiguity codes, `UO` and the stop codon `*`.
+    Protein,
+}
+
+
+impl Alphabet {
+    pub fn is_valid(&self, ch: u8) -> bool {
+        if ch == GAP {
+            return true;
+        }
+        let residues: &[u8] = match self {
+            Alphabet::Dna => b"ACGTRYSWKMBDHVN",
+            Alphabet::Rna => b"ACGURYSWKMBDHVN",
+            Alphabet::Protein => b"ACDEFGHIKLMNPQRSTVWYBZJXUO*",
+        };
+        residues.contains(&ch.to_ascii_uppercase())
+    }
+
+    /// The name of the alphabet, as given on the command line.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Alphabet::Dna => "dna",
+            Alphabet::Rna => "rna",
+            Alphabet::Protein => "protein",
+        }
+    }
+}
+
+
+/// The smallest fraction of `ACGTUN` among the residues of a nucleotide alignment, see [`detect_alphabet`].
+const MIN_NUCLEOTIDE_FRACTION: f64 = 0.9;
+
+
+/// The alphabet of the records, from the frequencies of their residues, the letters other than `gap_chars`,
+/// case-insensitive:
+/// nucleotides if at least 90% of them are `ACGTUN`, RNA if they hold more `U` than `T` and DNA otherwise,
+/// or else protein. An alignment without any residue is DNA.
+pub fn detect_alphabet(records: &[Record], gap_chars: &GapChars) -> Alphabet {
+    let mut counts = [0usize; 256];
+    for record in records.iter() {
+        for ch in record.seq().iter() {
+            counts[ch.to_ascii_uppercase() as usize] += 1;
+        }
+    }
+    let num_residues: usize = (0..=255u8)
+        .filter(|ch| ch.is_ascii_uppercase() && !gap_chars.contains(*ch))
+        .map(|ch| counts[ch as usize])
+        .sum();
+    let num_nucleotides: usize = b"ACGTUN".iter().map(|ch| counts[*ch as usize]).sum();
+    if num_residues > 0 && (num_nucleotides as f64) < MIN_NUCLEOTIDE_FRACTION * (num_residues as f64) {
+        Alphabet::Protein
+    } else if counts[b'U' as usize] > counts[b'T' as usize] {
+        Alphabet::Rna
+    } else {
+        Alphabet::Dna
+    }
+}
+
+
+/// Replace the `U` of the records by `T`, keeping their case, so that `U` and `T` match in a nucleotide alignment
+/// mixing DNA and RNA records. Unless `always`, the records are only changed if they hold both `U` and `T`.
+/// Returns the number of records changed.
+pub fn normalize_uracil(records: &mut [Record], always: bool) -> usize {
+    let holds = |symbol: u8| records.iter().any(|record| record.seq().iter().any(|ch| ch.to_ascii_uppercase() == symbol));
+    if !always && (!holds(b'U') || !holds(b'T')) {
+        return 0;
+    }
+    let mut num_changed: usize = 0;
+    for record in records.iter_mut() {
+        if !record.seq().iter().any(|ch| ch.eq_ignore_ascii_case(&b'U')) {
+            continue;
+        }
+        let seq: Vec<u8> = record.seq().iter()
+            .map(|ch| match ch {
+                b'U' => b'T',
+                b'u' => b't',
+                _ => *ch,
+            })
+            .collect();
+        *record = Record::with_attrs(record.id(), record.desc(), &seq);
+        num_changed += 1;
+    }
+    num_changed
+}
+
+
+/// Replace every gap character of the records by `-`, so that the comparisons only have `-` gaps to skip.
+/// Returns the number of records changed.
+pub fn normalize_gaps(records: &mut [Record], gap_chars: &GapChars) -> usize {
+    let is_other_gap = |ch: &u8| *ch != GAP && gap_chars.contains(*ch);
+    let mut num_changed: usize = 0;
+    for record in records.iter_mut() {
+        if !record.seq().iter().any(is_other_gap) {
+            continue;
+        }
+        let seq: Vec<u8> = record.seq().iter().map(|ch| if is_other_gap(ch) { GAP } else { *ch }).collect();
+        *record = Record::with_attrs(record.id(), record.desc(), &seq);
+        num_changed += 1;
+    }
+    num_changed
+}
+
+
+/// One failed check of [`validate_inputs`].
+#[derive(Debug, Clone, PartialEq)]
+pub enum ValidationIssue {
+    /// A FASTA or ID file could not be read or parsed.
+    Unparseable { path: PathBuf, message: String },
+    /// No FASTA records were found at all.
+    NoRecords,
+    /// A record's length differs from the length of the first record.
+    LengthMismatch { id: String, len: usize, expected: usize },
+    /// A record ID that was already seen, in the same file or an earlier one.
+    DuplicateId { id: String, path: PathBuf },
+    /// An ID of a query or database ID file that is not in the FASTA files.
+    MissingId { id: String, id_file: PathBuf },
+    /// The first residue of a record outside the alphabet, at a 1-based column.
+    InvalidResidue { id: String, column: usize, residue: char },
+}
+
+
+impl Display for ValidationIssue {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ValidationIssue::Unparseable { path, message } => {
+                write!(f, "{}: unable to parse: {}", path.display(), message)
+            }
+            ValidationIssue::NoRecords => write!(f, "no FASTA records found"),
+            ValidationIssue::LengthMismatch { id, len, expected } => {
+                write!(f, "record {} has length {}, expected {}", id, len, expected)
+            }
+            ValidationIssue::DuplicateId { id, path } => {
+                write!(f, "{}: duplicate record ID {}", path.display(), id)
+            }
+            ValidationIssue::MissingId { id, id_file } => {
+                write!(f, "{}: ID {} is not in the FASTA files", id_file.display(), id)
+            }
+            ValidationIssue::InvalidResidue { id, column, residue } => {
+                write!(f, "record {} has an invalid residue {:?} at column {}", id, residue, column)
+            }
+        }
+    }
+}
+
+
+fn parse_fasta_file(path: &Path) -> Result<Vec<Record>, std::io::Error> {
+    FastaReader::new(BufReader::new(File::open(path)?))
+        .records()
+        .collect()
+}
+
+
+/// Run every input check of the `validate` subcommand, and report all failures rather than the first.
+///
+/// Unlike the parsers used by the other subcommands, duplicate IDs are reported whatever the
+/// duplicate ID policy, since they are most likely a mistake when preparing the inputs.
+///
+/// # Arguments
+///
+/// * `fasta_paths` - The aligned FASTA files, which must be parseable and hold records of equal lengths
+///   with unique IDs.
+/// * `id_files` - Query or database ID files, whose IDs must all be in the FASTA files.
+/// * `alphabet` - If given, the alphabet that all residues must belong to.
+///
+/// # Returns
+///
+/// The failed checks, in the order of the inputs; empty if all checks passed.
+pub fn validate_inputs(fasta_paths: &[PathBuf], id_files: &[PathBuf], alphabet: Option<Alphabet>) -> Vec<ValidationIssue> {
+    let mut issues: Vec<ValidationIssue> = vec![];
+    let mut records: Vec<Record> = vec![];
+    let mut seen_ids: HashSet<String> = HashSet::new();
+    for path in fasta_paths.iter() {
+        let file_records = match parse_fasta_file(path) {
+            Ok(file_records) => file_records,
+            Err(err) => {
+                issues.push(ValidationIssue::Unparseable { path: path.clone(), message: err.to_string() });
+                continue;
+            }
+        };
+        for record in file_records.iter() {
+            if !seen_ids.insert(record.id().to_owned()) {
+                issues.push(ValidationIssue::DuplicateId { id: record.id().to_owned(), path: path.clone() });
+            }
+        }
+        records.extend(file_records);
+    }
+
+    match records.first() {
+        None if issues.is_empty() => issues.push(ValidationIssue::NoRecords),
+        None => {}
+        Some(first) => {
+            let expected = first.seq().len();
+            issues.extend(records.iter()
+                .filter(|record| record.seq().len() != expected)
+                .map(|record| ValidationIssue::LengthMismatch {
+                    id: record.id().to_owned(),
+                    len: record.seq().len(),
+                    expected,
+                }));
+        }
+    }
+
+    if let Some(alphabet) = alphabet {
+        issues.extend(records.iter().filter_map(|record| {
+            let column = record.seq().iter().position(|ch| !alphabet.is_valid(*ch))?;
+            Some(ValidationIssue::InvalidResidue {
+                id: record.id().to_owned(),
+                column: column + 1,
+                residue: record.seq()[column] as char,
+            })
+        }));
+    }
+
+    let record_ids: HashSet<&str> = records.iter().map(|record| record.id()).collect();
+    for id_file in id_files.iter() {
+        match parse_record_ids(id_file) {
+            Ok(ids) => issues.extend(ids.into_iter()
+                .filter(|id| !record_ids.contains(id.as_str()))
+                .map(|id| ValidationIssue::MissingId { id, id_file: id_file.clone() })),
+            Err(err) => {
+                issues.push(ValidationIssue::Unparseable { path: id_file.clone(), message: err.to_string() });
+            }
+        }
+    }
+    issues
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+    use bio::io::fasta::Record;
+    use crate::gaps::GapChars;
+    use super::{detect_alphabet, normalize_gaps, normalize_uracil, validate_inputs, Alphabet, ValidationIssue};
+
+    #[test]
+    fn test_alphabet() {
+        assert!(b"ACGTN-acgtn".iter().all(|ch| Alphabet::Dna.is_valid(*ch)));
+        assert!(!Alphabet::Dna.is_valid(b'U'));
+        assert!(Alphabet::Rna.is_valid(b'u'));
+        assert!(Alphabet::Protein.is_valid(b'W'));
+        assert!(!Alphabet::Protein.is_valid(b'1'));
+    }
+
+    #[test]
+    fn test_detect_alphabet() {
+        let detect = |seqs: &[&[u8]]| {
+            let records: Vec<Record> = seqs.iter().map(|seq| Record::with_attrs("r", None, seq)).collect();
+            detect_alphabet(&records, &GapChars::default())
+        };
+        assert_eq!(detect(&[b"ACGT--acgtN", b"ACGTTACGTAR"]), Alphabet::Dna);
+        assert_eq!(detect(&[b"ACGU--acguN", b"ACGUUACGUAT"]), Alphabet::Rna);
+        assert_eq!(detect(&[b"MKV-LLAGW", b"MKVELLPGW"]), Alphabet::Protein);
+        assert_eq!(detect(&[b"----"]), Alphabet::Dna);
+
+        let mut records = [Record::with_attrs("d", None, b"ACGT"), Record::with_attrs("r", Some("rna"), b"ACGu")];
+        assert_eq!(normalize_uracil(&mut records, false), 1);
+        assert_eq!((records[1].seq(), records[1].desc()), (b"ACGt".as_slice(), Some("rna")));
+        // Without any T, the U are kept.
+        let mut records = [Record::with_attrs("r", None, b"ACGU")];
+        assert_eq!(normalize_uracil(&mut records, false), 0);
+        assert_eq!(records[0].seq(), b"ACGU");
+        assert_eq!(normalize_uracil(&mut records, true), 1);
+        assert_eq!(records[0].seq(), b"ACGT");
+    }
+
+    #[test]
+    fn test_normalize_gaps() {
+        let mut records = [Record::with_attrs("a", Some("x"), b"AC.G~T-?"), Record::with_attrs("b", None, b"ACGT----")];
+        assert_eq!(normalize_gaps(&mut records, &"-.~".parse().unwrap()), 1);
+        assert_eq!((records[0].seq(), records[0].desc()), (b"AC-G-T-?".as_slice(), Some("x")));
+        assert_eq!(records[1].seq(), b"ACGT----");
+    }
+
+    #[test]
+    fn test_validate_inputs() {
+        let fasta_path = PathBuf::from("tests/inputs/query_db/seqs.fasta");
+        let id_files = [PathBuf::from("tests/inputs/query_db/query.txt"), PathBuf::from("tests/inputs/query_db/db.txt")];
+        assert!(validate_inputs(std::slice::from_ref(&fasta_path), &id_files, Some(Alphabet::Dna)).is_empty());
+
+        // The same file twice duplicates every ID.
+        let issues = validate_inputs(&[fasta_path.clone(), fasta_path.clone()], &[], None);
+        assert_eq!(issues.len(), 4);
+        assert_eq!(issues[0], ValidationIssue::DuplicateId { id: "query_1".to_owned(), path: fasta_path });
+    }
+}
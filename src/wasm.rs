@@ -0,0 +1,50 @@
+//! JavaScript bindings for WebAssembly, built with the `wasm` feature.
+//!
+//! The WebAssembly build leaves out the command line and its dependencies, so it needs
+//! `--no-default-features`. Build a package for Node.js into `pkg/` with
+//! [wasm-pack](https://rustwasm.github.io/wasm-pack/):
+//!
+//! ```text
+//! wasm-pack build --target nodejs -- --no-default-features --features wasm
+//! ```
+//!
+//! and use `--target web` or `--target bundler` for the browser. The package exports `computePctIdentity`:
+//!
+//! ```text
+//! const { computePctIdentity } = require("./pkg/aligned_nearest_neighbor.js");
+//! computePctIdentity("ACGT-", "ACGA-"); // 0.75
+//! ```
+//!
+//! The tests of this module run in a browser with
+//! `wasm-pack test --headless --firefox -- --no-default-features --features wasm --lib`. Without threads in
+//! `wasm32-unknown-unknown`, the rayon searches of the library run on the calling thread.
+use wasm_bindgen::prelude::*;
+
+use crate::nearest_neighbor::{pct_identity_bytes, ComparisonConfig};
+
+
+/// The percent identity of two aligned sequences, as a fraction between 0 and 1, with the default
+/// comparison options, in single precision. NaN if the sequences differ in length or have no compared column.
+#[wasm_bindgen(js_name = computePctIdentity)]
+pub fn compute_pct_identity_wasm(x: &str, y: &str) -> f32 {
+    if x.len() != y.len() {
+        return f32::NAN;
+    }
+    pct_identity_bytes(x.as_bytes(), y.as_bytes(), &ComparisonConfig::default()) as f32
+}
+
+
+#[cfg(all(test, target_arch = "wasm32"))]
+mod tests {
+    use wasm_bindgen_test::{wasm_bindgen_test, wasm_bindgen_test_configure};
+    use super::compute_pct_identity_wasm;
+
+    wasm_bindgen_test_configure!(run_in_browser);
+
+    #[wasm_bindgen_test]
+    fn test_compute_pct_identity_wasm() {
+        assert_eq!(compute_pct_identity_wasm("ACGT-", "ACGA-"), 0.75);
+        assert!(compute_pct_identity_wasm("ACGT", "ACG").is_nan());
+        assert!(compute_pct_identity_wasm("--", "--").is_nan());
+    }
+}
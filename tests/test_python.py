@@ -0,0 +1,31 @@
+"""Tests for the Python bindings.
+
+Build and install the extension first, e.g. with `maturin develop`, then run `pytest tests/test_python.py`
+from the repository root.
+"""
+import pytest
+
+from aligned_nearest_neighbor import compute_nearest_neighbors_py
+
+
+FASTA_PATH = "tests/inputs/query_db/seqs.fasta"
+
+
+def test_query_db_match():
+    results = compute_nearest_neighbors_py(FASTA_PATH, ["query_1", "query_2"], ["db_1", "db_2"])
+    assert len(results) == 2
+
+    query_id, neighbor_id, identity = results[0]
+    assert query_id == "query_1"
+    assert neighbor_id == "db_1"
+    assert identity == pytest.approx(3.0 / 16.0)
+
+    query_id, neighbor_id, identity = results[1]
+    assert query_id == "query_2"
+    assert neighbor_id == "db_2"
+    assert identity == pytest.approx(4.0 / 16.0)
+
+
+def test_missing_file_raises_value_error():
+    with pytest.raises(ValueError):
+        compute_nearest_neighbors_py("tests/inputs/does_not_exist.fasta")
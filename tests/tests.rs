@@ -1,6 +1,10 @@
 use rstest::rstest;
-use std::path::PathBuf;
-use aligned_nearest_neighbor::parse_all_records;
+use std::{io::Read, path::{Path, PathBuf}, process::{Command, Stdio}};
+use bio::io::fasta::Record;
+use aligned_nearest_neighbor::{
+    parse_all_records, parse_all_records_clustal, parse_all_records_phylip, parse_all_records_stockholm,
+    stockholm::parse_stockholm,
+};
 
 #[rstest]
 #[case("simple_test")]
@@ -11,3 +15,744 @@ fn test_with_expected_output(#[case] test_name: &str) {
     let _records = parse_all_records(input_path);
     // assert!(records.len() > 0);
 }
+
+
+#[test]
+fn test_parse_stockholm() {
+    let records = parse_all_records_stockholm(&PathBuf::from("tests/inputs/simple_stockholm.sto")).unwrap();
+    assert_eq!(records.len(), 3);
+    assert_eq!(records[0].id(), "seq_1");
+    assert_eq!(records[0].seq(), b"ACGT-ACGTACCGG--");
+    assert_eq!(records[1].id(), "seq_2");
+    assert_eq!(records[1].seq(), b"ACGTTACG-ACCGGAA");
+    assert_eq!(records[2].id(), "seq_3");
+    assert_eq!(records[2].seq(), b"TTTT-ACGTACC----");
+}
+
+
+#[test]
+fn test_parse_stockholm_annotations() {
+    let alignment = parse_stockholm(Path::new("tests/inputs/rfam_like.sto")).unwrap();
+    let ids: Vec<&str> = alignment.records.iter().map(|record| record.id()).collect();
+    assert_eq!(ids, ["seq_a/1-20", "seq_b/3-21", "seq_c/5-22"]);
+    assert_eq!(alignment.records[0].seq(), b"GCGGAUUUAGCUCAGuu-GGGAGAGCGCCAGA");
+    assert_eq!(alignment.records[1].seq(), b"GCCGAUAUAGCUCAG---GGGAGAGC-CCAGA");
+    assert_eq!(alignment.records[2].seq(), b"GCGGACUUAGCUCA-uuaGGGA-AGCGCCAGA");
+    assert_eq!(alignment.records[0].desc(), Some("Example organism A"));
+    assert_eq!(alignment.reference.as_deref(), Some(b"xxxxxxxxxxxxxxx...xxxxxxxxxxxxxx".as_slice()));
+
+    // The file without a #=GC RF line has no reference.
+    let alignment = parse_stockholm(Path::new("tests/inputs/simple_stockholm.sto")).unwrap();
+    assert_eq!(alignment.reference, None);
+    assert_eq!(alignment.records[0].desc(), None);
+}
+
+
+#[test]
+fn test_nn_stockholm_input() {
+    let output = Command::new(env!("CARGO_BIN_EXE_aligned_nearest_neighbor"))
+        .args(["nn", "-i", "tests/inputs/rfam_like.sto", "-o", "-"])
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let queries: Vec<&str> = stdout.lines().map(|line| line.split('\t').next().unwrap()).collect();
+    assert_eq!(queries, ["seq_a/1-20", "seq_b/3-21", "seq_c/5-22"]);
+
+    // The extension is not needed with --input-format.
+    let copy = std::env::temp_dir().join("aligned_nearest_neighbor_rfam_like.txt");
+    std::fs::copy("tests/inputs/rfam_like.sto", &copy).unwrap();
+    let output = Command::new(env!("CARGO_BIN_EXE_aligned_nearest_neighbor"))
+        .args(["nn", "--input-format", "stockholm", "-o", "-", "-i"])
+        .arg(&copy)
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), stdout);
+}
+
+
+
+#[test]
+fn test_nn_fastq_matches_fasta() {
+    let run_nn = |input: &str| {
+        let output = Command::new(env!("CARGO_BIN_EXE_aligned_nearest_neighbor"))
+            .args(["nn", "--top-k", "2", "-o", "-", "-i", input])
+            .output()
+            .unwrap();
+        assert!(output.status.success(), "{}: {}", input, String::from_utf8_lossy(&output.stderr));
+        String::from_utf8(output.stdout).unwrap()
+    };
+    let expected = run_nn("tests/inputs/fastq/aligned_reads.fasta");
+    assert_eq!(expected.lines().count(), 8);
+    assert_eq!(run_nn("tests/inputs/fastq/aligned_reads.fastq"), expected);
+    assert_eq!(run_nn("tests/inputs/fastq/aligned_reads.fastq.gz"), expected);
+}
+
+
+#[test]
+fn test_nn_normalize() {
+    let output = Command::new(env!("CARGO_BIN_EXE_aligned_nearest_neighbor"))
+        .args(["nn", "--normalize", "zscore", "--top-k", "2", "-o", "-"])
+        .args(["-i", "tests/inputs/fastq/aligned_reads.fasta"])
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let zscores: Vec<f64> = stdout.lines().map(|line| line.split('\t').nth(2).unwrap().parse().unwrap()).collect();
+    assert_eq!(zscores.len(), 8);
+    assert!(zscores.iter().sum::<f64>().abs() < 1e-4, "{:?}", zscores);
+    assert!(zscores.iter().any(|zscore| *zscore != 0.0), "{:?}", zscores);
+}
+
+
+#[test]
+fn test_nn_normalize_ut_and_gaps() {
+    let fasta_path = std::env::temp_dir().join("aligned_nearest_neighbor_test_normalize_ut.fasta");
+    let metadata_path = std::env::temp_dir().join("aligned_nearest_neighbor_test_normalize_ut.json");
+    std::fs::write(&fasta_path, ">dna\nACGTTGCA-ACGT--\n>rna\nACGUUGCA.ACGU~.\n>other\nACGAAGCAAACGAAA\n").unwrap();
+    let output = Command::new(env!("CARGO_BIN_EXE_aligned_nearest_neighbor"))
+        .args(["nn", "--no-progress", "--leave-one-out", "--normalize-ut", "--gap-chars=-.~", "-o", "-", "--metadata-out"])
+        .arg(&metadata_path)
+        .arg("-i")
+        .arg(&fasta_path)
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let rna_row: Vec<&str> = stdout.lines().find(|line| line.starts_with("rna\t")).unwrap().split('\t').collect();
+    assert_eq!(rna_row[1], "dna");
+    assert_eq!(rna_row[2].parse::<f64>().unwrap(), 1.0);
+    let metadata = std::fs::read_to_string(&metadata_path).unwrap();
+    assert!(metadata.contains("\"normalizations\": [\"gaps\", \"u_to_t\"]"), "{}", metadata);
+    for path in [&fasta_path, &metadata_path] {
+        std::fs::remove_file(path).unwrap();
+    }
+}
+
+
+#[test]
+fn test_nn_min_query_coverage() {
+    let query_row = |extra_args: &[&str]| {
+        let output = Command::new(env!("CARGO_BIN_EXE_aligned_nearest_neighbor"))
+            .args(["nn", "--no-progress", "--with-query-coverage", "-o", "-", "-i", "tests/inputs/coverage.fasta"])
+            .args(extra_args)
+            .output()
+            .unwrap();
+        assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+        let stdout = String::from_utf8(output.stdout).unwrap();
+        let row = stdout.lines().find(|line| line.starts_with("query\t")).unwrap();
+        row.split('\t').map(str::to_owned).collect::<Vec<String>>()
+    };
+    // The fragment matches the first half of the query exactly, and wins on identity.
+    let row = query_row(&[]);
+    assert_eq!(row[1], "short_fragment");
+    assert_eq!(row[3].parse::<f64>().unwrap(), 0.5);
+    // Covering half of the query only, it is disqualified during the search.
+    let row = query_row(&["--min-query-coverage", "0.8"]);
+    assert_eq!(row[1], "full_length");
+    assert_eq!(row[2].parse::<f64>().unwrap(), 0.45);
+    assert_eq!(row[3].parse::<f64>().unwrap(), 1.0);
+}
+
+
+#[test]
+fn test_nn_collapse_duplicates() {
+    let metadata_path = std::env::temp_dir().join("aligned_nearest_neighbor_test_collapse_metadata.json");
+    let run = |extra_args: &[&str]| {
+        let output = Command::new(env!("CARGO_BIN_EXE_aligned_nearest_neighbor"))
+            .args(["nn", "--no-progress", "--leave-one-out", "-o", "-", "-i", "tests/inputs/duplicates.fasta"])
+            .arg("--metadata-out")
+            .arg(&metadata_path)
+            .args(extra_args)
+            .output()
+            .unwrap();
+        assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+        String::from_utf8(output.stdout).unwrap()
+    };
+    // Each copy of `a` is its own copies' neighbor, unless they are collapsed.
+    let rows = run(&[]);
+    assert!(rows.starts_with("a1\ta3\t1.000000\nb\ta3\t0.875000\na2\ta3\t1.000000\n"), "{}", rows);
+    assert!(std::fs::read_to_string(&metadata_path).unwrap().contains("\"unique_queries\": null"));
+
+    // Collapsed, every query keeps its row, and the copies of a query are left out with it.
+    let rows = run(&["--collapse-duplicates"]);
+    assert_eq!(rows, "a1\tb\t0.875000\nb\ta3\t0.875000\na2\tb\t0.875000\nc\tb\t0.750000\na3\tb\t0.875000\n");
+    let metadata = std::fs::read_to_string(&metadata_path).unwrap();
+    assert!(metadata.contains("\"unique_queries\": 3, \"collapsed_queries\": 5"), "{}", metadata);
+    std::fs::remove_file(&metadata_path).unwrap();
+}
+
+
+#[test]
+fn test_nn_config_file() {
+    let config_path = std::env::temp_dir().join("aligned_nearest_neighbor_test_config.toml");
+    std::fs::write(&config_path, concat!(
+        "input_fasta = [\"tests/inputs/simple_test.fasta\"]\n",
+        "out_path = \"-\"\n",
+        "num_workers = 4\n",
+        "top_k = 2\n",
+        "leave_one_out = true\n",
+        "no_progress = true\n",
+    )).unwrap();
+    let run = |extra_args: &[&str]| {
+        Command::new(env!("CARGO_BIN_EXE_aligned_nearest_neighbor"))
+            .arg("nn")
+            .arg("--config")
+            .arg(&config_path)
+            .args(extra_args)
+            .output()
+            .unwrap()
+    };
+    let output = run(&[]);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("Number of workers = 4"));
+    let rows = String::from_utf8(output.stdout).unwrap();
+    assert_eq!(rows.lines().count(), 8, "{}", rows);
+    assert!(rows.lines().all(|row| row.split('\t').count() == 4 && !row.starts_with("entry_1\tentry_1")), "{}", rows);
+
+    // The command line overrides the file, whose other options still apply.
+    let output = run(&["--num-workers", "2"]);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("Number of workers = 2"));
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), rows);
+
+    std::fs::write(&config_path, "num_threads = 2\n").unwrap();
+    let output = run(&["-i", "tests/inputs/simple_test.fasta", "-o", "-"]);
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("unknown option `num_threads`"));
+    std::fs::remove_file(&config_path).unwrap();
+}
+
+
+#[test]
+fn test_nn_query_pfm() {
+    let output = Command::new(env!("CARGO_BIN_EXE_aligned_nearest_neighbor"))
+        .args(["nn", "--no-progress", "-i", "tests/inputs/simple_test.fasta", "-o", "-", "--top-k", "2"])
+        .args(["--query-pfm", "tests/inputs/motif.jaspar"])
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    // The poly-A record matches the profile with frequency 0.7, the others tie at 0.1.
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "motif\tentry_1\t0.700000\t1\nmotif\tentry_4\t0.100000\t2\n");
+
+    // No consensus is appended to the database searched against a profile.
+    let output = Command::new(env!("CARGO_BIN_EXE_aligned_nearest_neighbor"))
+        .args(["nn", "--no-progress", "-i", "tests/inputs/simple_test.fasta", "-o", "-", "--include-consensus"])
+        .args(["--query-pfm", "tests/inputs/motif.jaspar"])
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+}
+
+
+#[test]
+fn test_matrix_from_matrix() {
+    let upper_path = std::env::temp_dir().join("aligned_nearest_neighbor_test_matrix_upper.tsv");
+    let run = |args: &[&str]| {
+        let output = Command::new(env!("CARGO_BIN_EXE_aligned_nearest_neighbor")).args(args).output().unwrap();
+        assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+        String::from_utf8(output.stdout).unwrap()
+    };
+    let square = run(&["matrix", "-i", "tests/inputs/simple_test.fasta"]);
+    let upper = run(&["matrix", "--upper-triangle", "-i", "tests/inputs/simple_test.fasta"]);
+    std::fs::write(&upper_path, &upper).unwrap();
+    assert_eq!(run(&["matrix", "--from-matrix", upper_path.to_str().unwrap()]), square);
+    std::fs::remove_file(&upper_path).unwrap();
+}
+
+
+#[test]
+fn test_invalid_symbols() {
+    let fasta_path = std::env::temp_dir().join("aligned_nearest_neighbor_test_invalid_symbols.fasta");
+    std::fs::write(&fasta_path, ">a\nACGTACGT\n>b\nACGTAC7T\n>c\nACGAACGT\n").unwrap();
+    let run = |extra_args: &[&str]| {
+        Command::new(env!("CARGO_BIN_EXE_aligned_nearest_neighbor"))
+            .args(["nn", "--no-progress", "-o", "-", "-i"])
+            .arg(&fasta_path)
+            .args(extra_args)
+            .output()
+            .unwrap()
+    };
+    let output = run(&[]);
+    assert!(!output.status.success());
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(stderr.contains("Record b has the invalid symbol '7' (byte 0x37) at column 7"), "{}", stderr);
+
+    let output = run(&["--allow-chars", "7"]);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert!(String::from_utf8(output.stderr).unwrap().contains("of sequence type dna (detected)"));
+    std::fs::remove_file(&fasta_path).unwrap();
+}
+
+
+#[test]
+fn test_parse_clustal() {
+    let records = parse_all_records_clustal(&PathBuf::from("tests/inputs/simple_clustal.aln")).unwrap();
+    assert_eq!(records.len(), 3);
+    assert_eq!(records[0].id(), "seq_1");
+    assert_eq!(records[0].seq(), b"ACGT-ACGTACCGG--");
+    assert_eq!(records[1].id(), "seq_2");
+    assert_eq!(records[1].seq(), b"ACGTTACG-ACCGGAA");
+    assert_eq!(records[2].id(), "seq_3");
+    assert_eq!(records[2].seq(), b"TTTT-ACGTACC----");
+
+    // A FASTA file has no Clustal header.
+    assert!(parse_all_records_clustal(&PathBuf::from("tests/inputs/simple_test.fasta")).is_err());
+
+    let omega = parse_all_records_clustal(&PathBuf::from("tests/inputs/clustal_omega.aln")).unwrap();
+    assert_eq!(id_seqs(&omega), id_seqs(&records));
+    let err = parse_all_records_clustal(&PathBuf::from("tests/inputs/malformed_clustal.aln")).unwrap_err();
+    assert_eq!(err.message, "Record seq_2 has 9 residues up to line 5, but the line counts 10");
+}
+
+
+fn id_seqs(records: &[Record]) -> Vec<(&str, &[u8])> {
+    records.iter().map(|record| (record.id(), record.seq())).collect()
+}
+
+
+#[test]
+fn test_parse_phylip() {
+    let expected = parse_all_records_clustal(&PathBuf::from("tests/inputs/simple_clustal.aln")).unwrap();
+    for layout in ["sequential", "interleaved"] {
+        let records = parse_all_records_phylip(&PathBuf::from(format!("tests/inputs/{}.phy", layout))).unwrap();
+        assert_eq!(id_seqs(&records), id_seqs(&expected), "{}", layout);
+    }
+
+    let err = parse_all_records_phylip(&PathBuf::from("tests/inputs/malformed.phy")).unwrap_err();
+    assert_eq!(err.message, "Record seq_2 has length 15, but the header declares length 16");
+    // A FASTA file has no PHYLIP header.
+    assert!(parse_all_records_phylip(&PathBuf::from("tests/inputs/simple_test.fasta")).is_err());
+}
+
+
+#[test]
+fn test_nn_detects_input_format() {
+    let run_nn = |input: &str| {
+        let output = Command::new(env!("CARGO_BIN_EXE_aligned_nearest_neighbor"))
+            .args(["nn", "-o", "-", "-i", input])
+            .output()
+            .unwrap();
+        assert!(output.status.success(), "{}: {}", input, String::from_utf8_lossy(&output.stderr));
+        String::from_utf8(output.stdout).unwrap()
+    };
+    let expected = run_nn("tests/inputs/simple_clustal.aln");
+    assert_eq!(expected.lines().count(), 3);
+    for input in ["tests/inputs/clustal_omega.aln", "tests/inputs/sequential.phy", "tests/inputs/interleaved.phy"] {
+        assert_eq!(run_nn(input), expected, "{}", input);
+    }
+}
+
+
+fn run_nn_stderr(extra_args: &[&str], out_name: &str) -> String {
+    let out_path = std::env::temp_dir().join(out_name);
+    let output = Command::new(env!("CARGO_BIN_EXE_aligned_nearest_neighbor"))
+        .args(["nn", "-i", "tests/inputs/simple_test.fasta", "-o"])
+        .arg(&out_path)
+        .args(extra_args)
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    std::fs::remove_file(&out_path).unwrap();
+    String::from_utf8(output.stderr).unwrap()
+}
+
+
+#[test]
+fn test_no_progress_has_no_ansi_codes() {
+    let stderr = run_nn_stderr(&["--no-progress"], "aligned_nearest_neighbor_test_no_progress.tsv");
+    assert!(!stderr.contains('\x1b'));
+}
+
+
+#[test]
+fn test_progress_json() {
+    let stderr = run_nn_stderr(&["--progress-json"], "aligned_nearest_neighbor_test_progress_json.tsv");
+    assert!(!stderr.contains('\x1b'));
+    let last_line = stderr.lines().rfind(|line| line.starts_with('{')).unwrap();
+    assert_eq!(last_line, "{\"done\": 16, \"total\": 16, \"pct\": 1.0000}");
+}
+
+
+/// Run `validate` with the given arguments, and return its exit code and stdout.
+fn run_validate(args: &[&str]) -> (i32, String) {
+    let output = Command::new(env!("CARGO_BIN_EXE_aligned_nearest_neighbor"))
+        .arg("validate")
+        .args(args)
+        .output()
+        .unwrap();
+    (output.status.code().unwrap(), String::from_utf8(output.stdout).unwrap())
+}
+
+
+#[test]
+fn test_validate_ok() {
+    let (code, stdout) = run_validate(&[
+        "-i", "tests/inputs/query_db/seqs.fasta",
+        "-q", "tests/inputs/query_db/query.txt",
+        "-d", "tests/inputs/query_db/db.txt",
+        "--alphabet", "dna",
+    ]);
+    assert_eq!(code, 0);
+    assert_eq!(stdout, "OK\n");
+}
+
+
+#[test]
+fn test_validate_mismatched_lengths() {
+    let (code, stdout) = run_validate(&["-i", "tests/inputs/mismatched_lengths.fasta"]);
+    assert_eq!(code, 1);
+    assert_eq!(stdout, "FAILED (1 errors)\n- record entry_2 has length 12, expected 13\n");
+}
+
+
+#[test]
+fn test_validate_missing_ids() {
+    let (code, stdout) = run_validate(&[
+        "-i", "tests/inputs/query_db/seqs.fasta",
+        "-q", "tests/inputs/validate/missing_ids.txt",
+    ]);
+    assert_eq!(code, 1);
+    assert_eq!(
+        stdout,
+        "FAILED (1 errors)\n- tests/inputs/validate/missing_ids.txt: ID query_3 is not in the FASTA files\n",
+    );
+}
+
+
+#[test]
+fn test_validate_duplicate_ids() {
+    let (code, stdout) = run_validate(&["-i", "tests/inputs/simple_test.fasta", "-i", "tests/inputs/simple_test.fasta"]);
+    assert_eq!(code, 1);
+    assert!(stdout.starts_with("FAILED (4 errors)\n- tests/inputs/simple_test.fasta: duplicate record ID entry_1\n"));
+}
+
+
+#[test]
+fn test_validate_bad_alphabet() {
+    let (code, stdout) = run_validate(&["-i", "tests/inputs/validate/bad_alphabet.fasta", "--alphabet", "dna"]);
+    assert_eq!(code, 1);
+    assert_eq!(
+        stdout,
+        "FAILED (2 errors)\n- record seq_2 has an invalid residue 'Z' at column 9\n- record seq_3 has an invalid residue 'u' at column 4\n",
+    );
+    // RNA accepts the U of seq_3, but not the T of the others.
+    let (_, stdout) = run_validate(&["-i", "tests/inputs/validate/bad_alphabet.fasta", "--alphabet", "rna"]);
+    assert_eq!(
+        stdout,
+        "FAILED (2 errors)\n- record seq_1 has an invalid residue 'T' at column 4\n- record seq_2 has an invalid residue 'T' at column 4\n",
+    );
+}
+
+
+#[test]
+fn test_validate_unparseable() {
+    let (code, stdout) = run_validate(&["-i", "tests/inputs/does_not_exist.fasta"]);
+    assert_eq!(code, 1);
+    assert!(stdout.starts_with("FAILED (1 errors)\n- tests/inputs/does_not_exist.fasta: unable to parse: "));
+}
+
+
+/// Run `nn` on an existing output file with the given arguments, and return its exit code, stderr and the
+/// final content of the output file.
+fn run_nn_over_existing_output(extra_args: &[&str], out_name: &str) -> (i32, String, String) {
+    let out_path = std::env::temp_dir().join(out_name);
+    std::fs::write(&out_path, "original\n").unwrap();
+    let output = Command::new(env!("CARGO_BIN_EXE_aligned_nearest_neighbor"))
+        .args(["nn", "-i", "tests/inputs/simple_test.fasta", "--no-progress", "-o"])
+        .arg(&out_path)
+        .args(extra_args)
+        .output()
+        .unwrap();
+    let content = std::fs::read_to_string(&out_path).unwrap();
+    std::fs::remove_file(&out_path).unwrap();
+    (output.status.code().unwrap(), String::from_utf8(output.stderr).unwrap(), content)
+}
+
+
+#[test]
+fn test_overwrite_flags() {
+    // By default, the output is overwritten with a warning.
+    let (code, stderr, content) = run_nn_over_existing_output(&[], "aligned_nearest_neighbor_test_overwrite_default.tsv");
+    assert_eq!(code, 0);
+    assert!(stderr.contains("already exists. It will be overwritten!"));
+    assert_ne!(content, "original\n");
+
+    // --overwrite overwrites it silently.
+    let (code, stderr, content) = run_nn_over_existing_output(
+        &["--overwrite"], "aligned_nearest_neighbor_test_overwrite.tsv",
+    );
+    assert_eq!(code, 0);
+    assert!(!stderr.contains("already exists"));
+    assert_ne!(content, "original\n");
+
+    // --no-overwrite fails, and leaves the file as it was.
+    let (code, stderr, content) = run_nn_over_existing_output(
+        &["--no-overwrite"], "aligned_nearest_neighbor_test_no_overwrite.tsv",
+    );
+    assert_eq!(code, 1);
+    assert!(stderr.contains("--no-overwrite"));
+    assert_eq!(content, "original\n");
+}
+
+
+#[test]
+fn test_stdout_output() {
+    let output = Command::new(env!("CARGO_BIN_EXE_aligned_nearest_neighbor"))
+        .args(["nn", "-i", "tests/inputs/simple_test.fasta", "--no-progress", "-o", "-"])
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    // The results alone go to stdout, one row per record, and the logs to stderr.
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert_eq!(stdout.lines().count(), 4);
+    assert!(stdout.lines().all(|line| line.split('\t').count() == 3));
+    assert!(String::from_utf8(output.stderr).unwrap().contains("Number of workers"));
+    assert!(!Path::new("-").exists());
+}
+
+
+#[test]
+fn test_stdout_broken_pipe() {
+    // More results than a pipe buffers, so that the search writes to the pipe after its reader has exited.
+    let fasta_path = std::env::temp_dir().join("aligned_nearest_neighbor_test_broken_pipe.fasta");
+    let fasta: String = (0..3000)
+        .map(|idx| format!(">record_with_a_rather_long_identifier_{:06}\n{:020b}\n", idx, idx))
+        .collect();
+    std::fs::write(&fasta_path, fasta).unwrap();
+    let mut child = Command::new(env!("CARGO_BIN_EXE_aligned_nearest_neighbor"))
+        .args(["nn", "--no-progress", "--allow-chars", "01", "-o", "-", "-i"])
+        .arg(&fasta_path)
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let mut stdout = child.stdout.take().unwrap();
+    stdout.read_exact(&mut [0u8; 1]).unwrap();
+    drop(stdout);
+    let output = child.wait_with_output().unwrap();
+    std::fs::remove_file(&fasta_path).unwrap();
+    assert_eq!(output.status.code(), Some(0));
+    assert!(!String::from_utf8(output.stderr).unwrap().contains("ERROR"));
+}
+
+
+#[test]
+fn test_sample_queries_and_db() {
+    let entries: Vec<String> = (0..40).map(|idx| format!(">record_{:02}\n{:012b}\n", idx, idx * 37 % 4096)).collect();
+    let fasta_path = std::env::temp_dir().join("aligned_nearest_neighbor_test_sample.fasta");
+    let reversed_path = std::env::temp_dir().join("aligned_nearest_neighbor_test_sample_reversed.fasta");
+    let metadata_path = std::env::temp_dir().join("aligned_nearest_neighbor_test_sample.json");
+    std::fs::write(&fasta_path, entries.concat()).unwrap();
+    std::fs::write(&reversed_path, entries.iter().rev().cloned().collect::<String>()).unwrap();
+    let run = |fasta_path: &Path, extra_args: &[&str]| -> Vec<String> {
+        let output = Command::new(env!("CARGO_BIN_EXE_aligned_nearest_neighbor"))
+            .args(["nn", "--no-progress", "--allow-chars", "01", "-o", "-", "--metadata-out"])
+            .arg(&metadata_path)
+            .arg("-i")
+            .arg(fasta_path)
+            .args(extra_args)
+            .output()
+            .unwrap();
+        assert!(output.status.success());
+        let mut rows: Vec<String> = String::from_utf8(output.stdout).unwrap().lines().map(str::to_owned).collect();
+        rows.sort();
+        rows
+    };
+
+    let sample_args = ["--sample-queries", "5", "--sample-db", "0.5", "--seed", "3"];
+    let first = run(&fasta_path, &sample_args);
+    assert_eq!(first.len(), 5);
+    let metadata = std::fs::read_to_string(&metadata_path).unwrap();
+    assert!(metadata.contains("\"seed\": 3, \"sampled_queries\": 5, \"sampled_db_records\": 20"));
+    // The same seed draws the same records, whatever their order in the FASTA file.
+    assert_eq!(run(&fasta_path, &sample_args), first);
+    assert_eq!(run(&reversed_path, &sample_args), first);
+    assert_ne!(run(&fasta_path, &["--sample-queries", "5", "--sample-db", "0.5", "--seed", "4"]), first);
+
+    // A sample larger than the records takes all of them.
+    assert_eq!(run(&fasta_path, &["--sample-queries", "1000", "--sample-db", "1000"]).len(), 40);
+    let metadata = std::fs::read_to_string(&metadata_path).unwrap();
+    assert!(metadata.contains("\"seed\": 0, \"sampled_queries\": 40, \"sampled_db_records\": 40"));
+    for path in [&fasta_path, &reversed_path, &metadata_path] {
+        std::fs::remove_file(path).unwrap();
+    }
+}
+
+
+#[test]
+fn test_input_fasta_directory() {
+    let run = |input: &str, extra_args: &[&str]| {
+        Command::new(env!("CARGO_BIN_EXE_aligned_nearest_neighbor"))
+            .args(["nn", "--no-progress", "-o", "-", "-i", input])
+            .args(extra_args)
+            .output()
+            .unwrap()
+    };
+    let from_file = run("tests/inputs/query_db/seqs.fasta", &[]);
+    let from_dir = run("tests/inputs/query_db", &["--fasta-glob", "*.fasta"]);
+    assert!(from_dir.status.success());
+    assert_eq!(from_dir.stdout, from_file.stdout);
+
+    let output = run("tests/inputs/query_db", &["--fasta-glob", "*.fa"]);
+    assert!(!output.status.success());
+    assert!(String::from_utf8(output.stderr).unwrap().contains("No file in tests/inputs/query_db matches *.fa"));
+}
+
+
+#[test]
+fn test_max_gap_fraction() {
+    let fasta_path = std::env::temp_dir().join("aligned_nearest_neighbor_test_gap_fraction.fasta");
+    std::fs::write(&fasta_path, ">full\nACGTACGTAC\n>gappy\nAC--------\n>close\nACGTACGTAA\n>far\nTTTTACGTAC\n").unwrap();
+    let run = |extra_args: &[&str]| {
+        let output = Command::new(env!("CARGO_BIN_EXE_aligned_nearest_neighbor"))
+            .args(["nn", "--no-progress", "--leave-one-out", "-o", "-", "-i"])
+            .arg(&fasta_path)
+            .args(extra_args)
+            .output()
+            .unwrap();
+        assert!(output.status.success());
+        (String::from_utf8(output.stdout).unwrap(), String::from_utf8(output.stderr).unwrap())
+    };
+    let pairs = |stdout: &str| -> Vec<String> {
+        stdout.lines().map(|line| line.split('\t').take(2).collect::<Vec<_>>().join(" ")).collect()
+    };
+
+    let (stdout, stderr) = run(&["--max-query-gap-fraction", "0.5"]);
+    assert_eq!(pairs(&stdout), ["full close", "close full", "far full"]);
+    assert!(stderr.contains("Filtered out 1 of 4 records with a gap fraction above 0.5"));
+    // The gappy record is still a neighbor candidate, unless the database is filtered too.
+    let (stdout, _) = run(&["--max-query-gap-fraction", "0.5", "--max-db-gap-fraction", "0.5"]);
+    assert!(!stdout.contains("gappy"));
+    std::fs::remove_file(&fasta_path).unwrap();
+}
+
+
+#[test]
+fn test_verbosity_flags() {
+    let stderr = |extra_args: &[&str]| -> String {
+        let output = Command::new(env!("CARGO_BIN_EXE_aligned_nearest_neighbor"))
+            .args(["nn", "-i", "tests/inputs/query_db/seqs.fasta", "-q", "tests/inputs/query_db/query.txt"])
+            .args(["--no-progress", "-o", "-"])
+            .args(extra_args)
+            .output()
+            .unwrap();
+        assert!(output.status.success());
+        String::from_utf8(output.stderr).unwrap()
+    };
+    assert!(stderr(&[]).contains("Number of workers"));
+    assert!(!stderr(&[]).contains("Selected"));
+    assert!(stderr(&["--quiet"]).is_empty());
+    assert!(stderr(&["-v"]).contains("Selected 2 of"));
+}
+
+
+#[test]
+fn test_id_regex_selection() {
+    let run = |extra_args: &[&str]| {
+        Command::new(env!("CARGO_BIN_EXE_aligned_nearest_neighbor"))
+            .args(["nn", "-i", "tests/inputs/query_db/seqs.fasta", "--no-progress", "-o", "-"])
+            .args(extra_args)
+            .output()
+            .unwrap()
+    };
+    let output = run(&["--query-id-regex", "^query_", "--db-id-regex", "^db_"]);
+    assert!(output.status.success());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let pairs: Vec<(&str, &str)> = stdout.lines()
+        .map(|line| {
+            let mut fields = line.split('\t');
+            (fields.next().unwrap(), fields.next().unwrap())
+        })
+        .collect();
+    assert_eq!(pairs.len(), 2);
+    assert!(pairs.iter().all(|(query, neighbor)| query.starts_with("query_") && neighbor.starts_with("db_")));
+
+    // The regex and the ID file select their intersection.
+    let output = run(&["-q", "tests/inputs/query_db/query.txt", "--query-id-regex", "_2$", "--db-id-regex", "^db_"]);
+    assert!(output.status.success());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert_eq!(stdout.lines().map(|line| line.split('\t').next().unwrap()).collect::<Vec<_>>(), ["query_2"]);
+
+    let output = run(&["--query-id-regex", "^nothing"]);
+    assert!(!output.status.success());
+    assert!(String::from_utf8(output.stderr).unwrap().contains("No query record was selected"));
+    let output = run(&["--db-id-regex", "^nothing"]);
+    assert!(!output.status.success());
+    assert!(String::from_utf8(output.stderr).unwrap().contains("No database record could be compared against query: query_1"));
+
+    let output = run(&["--db-id-regex", "db_("]);
+    assert_eq!(output.status.code(), Some(2));
+    assert!(String::from_utf8(output.stderr).unwrap().contains("unclosed group"));
+}
+
+
+#[test]
+fn test_json_log_format() {
+    let output = Command::new(env!("CARGO_BIN_EXE_aligned_nearest_neighbor"))
+        .args(["nn", "-i", "tests/inputs/simple_test.fasta", "--no-progress", "--log-format", "json", "-o", "-"])
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    // One JSON object per line, among which the end of each phase.
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(stderr.lines().all(|line| line.starts_with('{') && line.ends_with('}')));
+    for phase in ["parse_fasta", "compute", "write_output"] {
+        assert!(stderr.contains(&format!("\"name\":\"{}\"", phase)), "no {} span in {}", phase, stderr);
+    }
+}
+
+
+fn run_nn_hostile_ids(on_bad_id: &str) -> (i32, String, String) {
+    let output = Command::new(env!("CARGO_BIN_EXE_aligned_nearest_neighbor"))
+        .args(["nn", "-i", "tests/inputs/hostile_ids.fasta", "--no-progress", "--include-description", "-o", "-"])
+        .args(["--on-bad-id", on_bad_id])
+        .output()
+        .unwrap();
+    (
+        output.status.code().unwrap(),
+        String::from_utf8(output.stdout).unwrap(),
+        String::from_utf8(output.stderr).unwrap(),
+    )
+}
+
+
+#[test]
+fn test_on_bad_id() {
+    // A tab in a description would otherwise add a column to the rows showing it.
+    let (code, stdout, _) = run_nn_hostile_ids("escape");
+    assert_eq!(code, 0);
+    assert!(stdout.lines().all(|line| line.split('\t').count() == 5));
+    assert!(stdout.contains("seq_1 strain A\\tisolated 2019"));
+
+    let (code, stdout, _) = run_nn_hostile_ids("replace");
+    assert_eq!(code, 0);
+    assert!(stdout.lines().all(|line| line.split('\t').count() == 5));
+    assert!(stdout.contains("seq_1 strain A_isolated 2019"));
+
+    let (code, stdout, stderr) = run_nn_hostile_ids("error");
+    assert_eq!(code, 1);
+    assert!(stdout.is_empty());
+    assert!(stderr.contains("Record seq_1 (index 0) has a tab"));
+}
+
+
+#[test]
+fn test_id_split_char() {
+    let output = Command::new(env!("CARGO_BIN_EXE_aligned_nearest_neighbor"))
+        .args(["nn", "-i", "tests/inputs/ncbi_ids.fasta", "-q", "tests/inputs/ncbi_query.txt", "--no-progress"])
+        .args(["--leave-one-out", "--id-split-char", "|", "--id-field", "4", "-o", "-"])
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    // The query IDs of the ID file select the records, and the output shows the shortened IDs.
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let pairs: Vec<(&str, &str)> = stdout.lines()
+        .map(|line| {
+            let fields: Vec<&str> = line.split('\t').collect();
+            (fields[0], fields[1])
+        })
+        .collect();
+    assert_eq!(pairs, [("NR_000001.1", "NR_000002.1"), ("NR_000003.1", "NR_000002.1")]);
+}
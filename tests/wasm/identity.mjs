@@ -0,0 +1,10 @@
+// Smoke test of the Node.js package built by `wasm-pack build --target nodejs -- --no-default-features --features wasm`.
+import assert from "node:assert/strict";
+import { createRequire } from "node:module";
+
+const require = createRequire(import.meta.url);
+const { computePctIdentity } = require("../../pkg/aligned_nearest_neighbor.js");
+
+assert.equal(computePctIdentity("ACGT-", "ACGA-"), 0.75);
+assert.ok(Number.isNaN(computePctIdentity("ACGT", "ACG")));
+console.log("OK");